@@ -0,0 +1,82 @@
+/* Kompusim lockstep co-simulation library: Kompusim as the golden model of an RTL testbench.
+ * Build: cargo build --release -p kompusim-cosim, link with target/release/libkompusim_cosim.so
+ */
+#ifndef KOMPUSIM_COSIM_H
+#define KOMPUSIM_COSIM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KOMPUSIM_MAX_WRITES 8
+#define KOMPUSIM_MAX_MEM 2
+
+typedef struct Cosim KompusimCosim;
+
+typedef struct {
+    uint8_t kind; /* 0 - x register, 1 - f register, 2 - CSR */
+    uint16_t index; /* register number or CSR address */
+    uint64_t value;
+} KompusimRegWrite;
+
+typedef struct {
+    uint64_t addr;
+    uint64_t data;
+    uint8_t size; /* 1, 2, 4 or 8 bytes */
+    uint8_t write; /* 0 - load, 1 - store */
+} KompusimMemAccess;
+
+/* Effects of an executed instruction */
+typedef struct {
+    uint64_t index; /* number of instructions executed before this one */
+    uint64_t pc;
+    uint32_t instr; /* 16 bit for compressed instructions */
+    uint8_t priv_mode;
+    uint8_t trapped; /* 1 - raised an exception with cause trap_cause, didn't retire */
+    uint64_t trap_cause;
+    uint8_t n_writes;
+    KompusimRegWrite writes[KOMPUSIM_MAX_WRITES];
+    uint8_t n_mem;
+    KompusimMemAccess mem[KOMPUSIM_MAX_MEM];
+    uint8_t overflow; /* 1 - more writes or accesses than the arrays hold, they have the first */
+} KompusimCommit;
+
+/* A failure of the model inside any call is printed to stderr and reported as an error return
+ * (NULL, -1, 0 for the getters) */
+
+/* machine - machine description (TOML) with the memory images, elf - ELF or NULL.
+ * Returns NULL on errors, they're printed to stderr. */
+KompusimCosim *kompusim_cosim_new(const char *machine, const char *elf);
+void kompusim_cosim_free(KompusimCosim *cosim);
+
+/* Executes one instruction. Returns 0, 1 - the machine has exited, -1 - the model failed (commit
+ * isn't filled in both cases). Faults of the program are trapped commits. */
+int kompusim_cosim_step(KompusimCosim *cosim, KompusimCommit *commit);
+
+uint64_t kompusim_cosim_get_pc(const KompusimCosim *cosim);
+void kompusim_cosim_set_pc(KompusimCosim *cosim, uint64_t pc);
+uint64_t kompusim_cosim_get_xreg(const KompusimCosim *cosim, uint8_t reg);
+void kompusim_cosim_set_xreg(KompusimCosim *cosim, uint8_t reg, uint64_t val);
+uint64_t kompusim_cosim_get_freg(const KompusimCosim *cosim, uint8_t reg);
+void kompusim_cosim_set_freg(KompusimCosim *cosim, uint8_t reg, uint64_t val);
+/* CSRs are accessed with M-mode privilege. Return 0, -1 - no such (writable) CSR */
+int kompusim_cosim_get_csr(const KompusimCosim *cosim, uint16_t csr, uint64_t *val);
+int kompusim_cosim_set_csr(KompusimCosim *cosim, uint16_t csr, uint64_t val);
+
+/* RAM at physical addresses. Return 0, -1 - not RAM */
+int kompusim_cosim_read_mem(const KompusimCosim *cosim, uint64_t addr, uint8_t *buf, size_t len);
+int kompusim_cosim_write_mem(KompusimCosim *cosim, uint64_t addr, const uint8_t *data,
+                             size_t len);
+
+/* Drives the interrupt lines (mip bits, e.g. 1 << 11 - machine external interrupt), the set bits
+ * are pending until cleared by the next call */
+void kompusim_cosim_set_irqs(KompusimCosim *cosim, uint64_t irqs);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KOMPUSIM_COSIM_H */
@@ -0,0 +1,516 @@
+// Lockstep co-simulation library: Kompusim is the golden model of an RTL testbench. The testbench
+// steps the model one instruction per retirement of the RTL and compares the effects (register
+// and CSR writes, memory accesses, exceptions), it can also peek and poke registers and memory
+// and drive the interrupt lines. The C API is declared in include/kompusim_cosim.h. A panic of
+// the model doesn't unwind into the testbench, the entry points return an error instead.
+
+use std::any::Any;
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use kompusim::elf::Elf;
+use kompusim::machine::{MachineBuilder, MachineConfig};
+use kompusim::rv64i_cpu::{ExecEvent, RV64ICpu};
+use kompusim::trace::{Commit, RegWrite};
+
+/// Register writes of a KompusimCommit: an instruction writes a register and a few CSRs (e.g.
+/// fflags), with room for the four CSRs of a trap entry. More writes set `overflow`.
+pub const MAX_WRITES: usize = 8;
+/// Memory accesses of a KompusimCommit, an AMO does a load and a store. More accesses set
+/// `overflow`.
+pub const MAX_MEM: usize = 2;
+
+/// The model: a machine built from a machine description
+pub struct Cosim {
+    pub cpu: RV64ICpu,
+}
+
+impl Cosim {
+    /// Builds the machine described by the TOML file (its memory images are loaded) and loads the
+    /// ELF. Execution starts at the ELF entry or at the first memory region
+    pub fn new(machine: &Path, elf: Option<&Path>) -> Result<Cosim, String> {
+        let config = MachineConfig::from_file(machine)?;
+        let mut cpu = MachineBuilder::from_config(config).build()?;
+        if let Some(elf) = elf {
+            let elf = Elf::from_file(elf)?;
+            elf.load(&mut cpu.bus)?;
+            cpu.pc_jump(elf.entry);
+        }
+        Ok(Cosim { cpu })
+    }
+
+    /// Executes one instruction, None - the machine has exited
+    pub fn step(&mut self) -> Option<Commit> {
+        match self.cpu.step_commit() {
+            (ExecEvent::Exit(_), _) => None,
+            (_, commit) => commit,
+        }
+    }
+
+    pub fn read_mem(&self, addr: u64, buf: &mut [u8]) -> Result<(), String> {
+        let ram = self
+            .cpu
+            .get_ram(addr, buf.len() as u64)
+            .ok_or(format!("0x{addr:x}: not RAM"))?;
+        buf.copy_from_slice(ram);
+        Ok(())
+    }
+
+    pub fn write_mem(&mut self, addr: u64, data: &[u8]) -> Result<(), String> {
+        self.cpu
+            .bus
+            .write_bytes(addr, data)
+            .map_err(|e| format!("0x{addr:x}: {e}"))
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KompusimRegWrite {
+    /// 0 - x register, 1 - f register, 2 - CSR
+    pub kind: u8,
+    /// Register number or CSR address
+    pub index: u16,
+    pub value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KompusimMemAccess {
+    pub addr: u64,
+    pub data: u64,
+    /// 1, 2, 4 or 8 bytes
+    pub size: u8,
+    /// 0 - load, 1 - store
+    pub write: u8,
+}
+
+/// Effects of an executed instruction
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KompusimCommit {
+    /// Number of instructions executed before this one
+    pub index: u64,
+    pub pc: u64,
+    /// 16 bit for compressed instructions
+    pub instr: u32,
+    /// Privilege level the instruction was executed in
+    pub priv_mode: u8,
+    /// 1 - the instruction raised an exception with cause `trap_cause`, it didn't retire
+    pub trapped: u8,
+    pub trap_cause: u64,
+    pub n_writes: u8,
+    pub writes: [KompusimRegWrite; MAX_WRITES],
+    pub n_mem: u8,
+    pub mem: [KompusimMemAccess; MAX_MEM],
+    /// 1 - the instruction has more register writes or memory accesses than the arrays hold,
+    /// only the first ones are there
+    pub overflow: u8,
+}
+
+impl From<&Commit> for KompusimCommit {
+    fn from(commit: &Commit) -> Self {
+        let mut c = KompusimCommit {
+            index: commit.index,
+            pc: commit.pc,
+            instr: commit.instr,
+            priv_mode: commit.priv_mode,
+            trapped: commit.trap.is_some() as u8,
+            trap_cause: commit.trap.unwrap_or(0),
+            n_writes: commit.writes.len().min(MAX_WRITES) as u8,
+            n_mem: commit.mem.len().min(MAX_MEM) as u8,
+            overflow: (commit.writes.len() > MAX_WRITES || commit.mem.len() > MAX_MEM) as u8,
+            ..Default::default()
+        };
+        for (w, write) in c.writes.iter_mut().zip(&commit.writes) {
+            let (kind, index, value) = match *write {
+                RegWrite::X(i, val) => (0, i as u16, val),
+                RegWrite::F(i, val) => (1, i as u16, val),
+                RegWrite::Csr(csr, val) => (2, csr, val),
+            };
+            *w = KompusimRegWrite { kind, index, value };
+        }
+        for (m, access) in c.mem.iter_mut().zip(&commit.mem) {
+            *m = KompusimMemAccess {
+                addr: access.addr,
+                data: access.data,
+                size: access.size,
+                write: access.write as u8,
+            };
+        }
+        c
+    }
+}
+
+/// Runs the body of an entry point, a panic is printed and `on_panic` is returned
+fn ffi<T>(name: &str, on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        eprintln!("ERROR: {name}: panic: {}", panic_msg(payload.as_ref()));
+        on_panic
+    })
+}
+
+fn panic_msg(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown")
+}
+
+fn c_path<'a>(path: *const c_char) -> Option<&'a Path> {
+    if path.is_null() {
+        return None;
+    }
+    // SAFETY: the caller passes a NUL terminated string
+    let path = unsafe { CStr::from_ptr(path) };
+    path.to_str().ok().map(Path::new)
+}
+
+/// Creates the model from a machine description (TOML) and an optional ELF (NULL - none).
+/// Returns NULL on errors, they're printed to stderr.
+///
+/// # Safety
+/// `machine` and `elf` are NUL terminated strings or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_new(
+    machine: *const c_char,
+    elf: *const c_char,
+) -> *mut Cosim {
+    ffi("kompusim_cosim_new", std::ptr::null_mut(), || {
+        let Some(machine) = c_path(machine) else {
+            eprintln!("ERROR: kompusim_cosim_new: no machine description");
+            return std::ptr::null_mut();
+        };
+        match Cosim::new(machine, c_path(elf)) {
+            Ok(cosim) => Box::into_raw(Box::new(cosim)),
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new() or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_free(cosim: *mut Cosim) {
+    ffi("kompusim_cosim_free", (), || {
+        if !cosim.is_null() {
+            drop(Box::from_raw(cosim));
+        }
+    })
+}
+
+/// Executes one instruction and fills `commit` with its effects. Returns 0, 1 - the machine has
+/// exited (e.g. semihosting or HTIF exit), -1 - the model failed, `commit` isn't filled in both
+/// cases. Faults of the program (e.g. an access to unmapped memory) are trapped commits.
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new(), `commit` points to a KompusimCommit.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_step(
+    cosim: *mut Cosim,
+    commit: *mut KompusimCommit,
+) -> c_int {
+    ffi("kompusim_cosim_step", -1, || match (*cosim).step() {
+        Some(c) => {
+            *commit = KompusimCommit::from(&c);
+            0
+        }
+        None => 1,
+    })
+}
+
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_get_pc(cosim: *const Cosim) -> u64 {
+    ffi("kompusim_cosim_get_pc", 0, || (*cosim).cpu.get_pc())
+}
+
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_set_pc(cosim: *mut Cosim, pc: u64) {
+    ffi("kompusim_cosim_set_pc", (), || (*cosim).cpu.pc_jump(pc))
+}
+
+/// Reads x register `reg` (0 - 31)
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_get_xreg(cosim: *const Cosim, reg: u8) -> u64 {
+    ffi("kompusim_cosim_get_xreg", 0, || {
+        (*cosim).cpu.regs_r64(reg & 31)
+    })
+}
+
+/// Writes x register `reg` (1 - 31)
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_set_xreg(cosim: *mut Cosim, reg: u8, val: u64) {
+    ffi("kompusim_cosim_set_xreg", (), || {
+        (*cosim).cpu.regs_w64(reg & 31, val)
+    })
+}
+
+/// Reads f register `reg` (0 - 31), NaN-boxed single precision values as they're stored
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_get_freg(cosim: *const Cosim, reg: u8) -> u64 {
+    ffi("kompusim_cosim_get_freg", 0, || {
+        (*cosim).cpu.get_fregs().f[reg as usize & 31]
+    })
+}
+
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_set_freg(cosim: *mut Cosim, reg: u8, val: u64) {
+    ffi("kompusim_cosim_set_freg", (), || {
+        (*cosim).cpu.get_fregs_mut().f[reg as usize & 31] = val
+    })
+}
+
+/// Reads a CSR with M-mode privilege into `val`. Returns 0, -1 - the CSR doesn't exist
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new(), `val` points to a u64.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_get_csr(
+    cosim: *const Cosim,
+    csr: u16,
+    val: *mut u64,
+) -> c_int {
+    ffi("kompusim_cosim_get_csr", -1, || {
+        match (*cosim).cpu.csr_r64_debug(csr) {
+            Some(v) => {
+                *val = v;
+                0
+            }
+            None => -1,
+        }
+    })
+}
+
+/// Writes a CSR with M-mode privilege. Returns 0, -1 - the CSR is not writable
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_set_csr(cosim: *mut Cosim, csr: u16, val: u64) -> c_int {
+    ffi("kompusim_cosim_set_csr", -1, || {
+        if (*cosim).cpu.csr_w64_debug(csr, val) {
+            0
+        } else {
+            -1
+        }
+    })
+}
+
+/// Reads `len` bytes of RAM at physical address `addr`. Returns 0, -1 - not RAM
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new(), `buf` points to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_read_mem(
+    cosim: *const Cosim,
+    addr: u64,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    ffi("kompusim_cosim_read_mem", -1, || {
+        let buf = std::slice::from_raw_parts_mut(buf, len);
+        match (*cosim).read_mem(addr, buf) {
+            Ok(()) => 0,
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Writes `len` bytes to RAM at physical address `addr`. Returns 0, -1 - not RAM
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new(), `data` points to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_write_mem(
+    cosim: *mut Cosim,
+    addr: u64,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    ffi("kompusim_cosim_write_mem", -1, || {
+        let data = std::slice::from_raw_parts(data, len);
+        match (*cosim).write_mem(addr, data) {
+            Ok(()) => 0,
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Drives the interrupt lines (mip bits, e.g. 1 << 11 - machine external interrupt): the set
+/// bits are pending until cleared by the next call
+///
+/// # Safety
+/// `cosim` is returned by kompusim_cosim_new().
+#[no_mangle]
+pub unsafe extern "C" fn kompusim_cosim_set_irqs(cosim: *mut Cosim, irqs: u64) {
+    ffi("kompusim_cosim_set_irqs", (), || {
+        (*cosim).cpu.set_external_irqs(irqs)
+    })
+}
+
+#[test]
+fn test_cosim_c_api() {
+    use kompusim::csr;
+    use std::ffi::CString;
+
+    let program: [u32; 6] = [
+        0x04200293, // li t0,0x42
+        0x00001317, // auipc t1,0x1
+        0x00532023, // sw t0,0(t1)
+        0x34029073, // csrw mscratch,t0
+        0x0000006f, // j .
+        0x0000006f, // j . (mtvec)
+    ];
+    let dir = std::env::temp_dir();
+    let image = dir.join(format!("kompusim-cosim-{}.bin", std::process::id()));
+    let machine = dir.join(format!("kompusim-cosim-{}.toml", std::process::id()));
+    let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes()).collect();
+    std::fs::write(&image, bytes).unwrap();
+    std::fs::write(
+        &machine,
+        format!(
+            "[[memory]]\nbase = 0x8000_0000\nsize = \"8K\"\nimage = {:?}\n",
+            image.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let machine_c = CString::new(machine.to_str().unwrap()).unwrap();
+    let cosim = unsafe { kompusim_cosim_new(machine_c.as_ptr(), std::ptr::null()) };
+    std::fs::remove_file(&image).unwrap();
+    std::fs::remove_file(&machine).unwrap();
+    assert!(!cosim.is_null());
+
+    let mut commit = KompusimCommit::default();
+    unsafe {
+        assert_eq!(kompusim_cosim_get_pc(cosim), 0x8000_0000);
+        assert_eq!(kompusim_cosim_step(cosim, &mut commit), 0);
+        assert_eq!(
+            (commit.pc, commit.instr, commit.priv_mode),
+            (0x8000_0000, 0x04200293, 3)
+        );
+        assert_eq!(commit.n_writes, 1);
+        assert_eq!(
+            commit.writes[0],
+            KompusimRegWrite {
+                kind: 0,
+                index: 5,
+                value: 0x42
+            }
+        );
+        kompusim_cosim_step(cosim, &mut commit);
+        kompusim_cosim_step(cosim, &mut commit);
+        assert_eq!(commit.n_mem, 1);
+        let store = KompusimMemAccess {
+            addr: 0x8000_1004,
+            data: 0x42,
+            size: 4,
+            write: 1,
+        };
+        assert_eq!(commit.mem[0], store);
+        let mut word = [0; 4];
+        assert_eq!(
+            kompusim_cosim_read_mem(cosim, 0x8000_1004, word.as_mut_ptr(), 4),
+            0
+        );
+        assert_eq!(u32::from_le_bytes(word), 0x42);
+        assert_eq!(
+            kompusim_cosim_read_mem(cosim, 0x1000, word.as_mut_ptr(), 4),
+            -1
+        );
+
+        // poke t0 before the CSR write
+        kompusim_cosim_set_xreg(cosim, 5, 0x77);
+        kompusim_cosim_step(cosim, &mut commit);
+        assert_eq!(
+            commit.writes[0],
+            KompusimRegWrite {
+                kind: 2,
+                index: csr::MSCRATCH,
+                value: 0x77
+            }
+        );
+        let mut mscratch = 0;
+        assert_eq!(
+            kompusim_cosim_get_csr(cosim, csr::MSCRATCH, &mut mscratch),
+            0
+        );
+        assert_eq!(mscratch, 0x77);
+
+        // the external interrupt is taken before the next instruction
+        assert_eq!(kompusim_cosim_set_csr(cosim, csr::MTVEC, 0x8000_0014), 0);
+        assert_eq!(kompusim_cosim_set_csr(cosim, csr::MIE, csr::MIP_MEIP), 0);
+        assert_eq!(kompusim_cosim_set_csr(cosim, csr::MSTATUS, 1 << 3), 0);
+        kompusim_cosim_step(cosim, &mut commit);
+        assert_eq!(commit.pc, 0x8000_0010);
+        kompusim_cosim_set_irqs(cosim, csr::MIP_MEIP);
+        kompusim_cosim_step(cosim, &mut commit);
+        assert_eq!(commit.pc, 0x8000_0014);
+        assert_eq!(commit.trapped, 0);
+        let mut mcause = 0;
+        kompusim_cosim_get_csr(cosim, csr::MCAUSE, &mut mcause);
+        assert_eq!(mcause, csr::MCAUSE_INTERRUPT | 11);
+
+        // a load from unmapped memory is a trapped commit
+        kompusim_cosim_set_irqs(cosim, 0);
+        let ld = 0x00003503u32; // ld a0,0(zero)
+        let ld_addr = 0x8000_0100;
+        assert_eq!(
+            kompusim_cosim_write_mem(cosim, ld_addr, ld.to_le_bytes().as_ptr(), 4),
+            0
+        );
+        kompusim_cosim_set_pc(cosim, ld_addr);
+        assert_eq!(kompusim_cosim_step(cosim, &mut commit), 0);
+        assert_eq!((commit.pc, commit.instr), (ld_addr, ld));
+        assert_eq!(
+            (commit.trapped, commit.trap_cause),
+            (1, csr::EXC_LOAD_ACCESS)
+        );
+        assert_eq!(commit.overflow, 0);
+        assert_eq!(kompusim_cosim_get_pc(cosim), 0x8000_0014);
+        kompusim_cosim_free(cosim);
+    }
+}
+
+#[test]
+fn test_commit_overflow() {
+    let commit = Commit {
+        writes: (0..MAX_WRITES as u8 + 1)
+            .map(|i| RegWrite::X(i, i as u64))
+            .collect(),
+        ..Default::default()
+    };
+    let c = KompusimCommit::from(&commit);
+    assert_eq!((c.n_writes, c.overflow), (MAX_WRITES as u8, 1));
+    assert_eq!(c.writes[MAX_WRITES - 1].value, MAX_WRITES as u64 - 1);
+    let commit = Commit {
+        writes: commit.writes[..MAX_WRITES].to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(KompusimCommit::from(&commit).overflow, 0);
+}
+
+#[test]
+fn test_ffi_panic() {
+    assert_eq!(ffi("test", -1, || panic!("model bug")), -1);
+    assert_eq!(ffi("test", -1, || 0), 0);
+}
@@ -3,16 +3,22 @@ use std::thread;
 
 use eframe::{self, glow::Context};
 use egui::{Button, Modifiers};
+use kompusim::machine::MachineConfig;
 
 use crate::{
     base_uregs::BaseURegs,
+    breakpoints::{Breakpoints, BreakpointsCmd},
+    call_stack::{CallStack, CallStackCmd},
     cmdline::{parse_breakpoints, parse_size_with_suffix, CmdLCommand},
     console::Console,
     instr_decoder::InstrDecoder,
     instr_list::InstrList,
     load_demo::LoadDemo,
+    profiler::{Profiler, ProfilerCmd},
     sim::{Simulator, DEFAULT_MEM_SZ},
+    snapshots::{Snapshots, SnapshotsCmd},
     status_control::{StatusControl, StatusControlCmd},
+    watchpoints::{Watchpoints, WatchpointsCmd},
 };
 
 /// Deserialize/Serialize so we can persist app state on shutdown.
@@ -33,6 +39,16 @@ pub struct KompusimApp {
     #[serde(skip)] // this how you opt-out of serialization of a member
     load_demo: LoadDemo,
     #[serde(skip)]
+    breakpoints: Breakpoints,
+    #[serde(skip)]
+    watchpoints: Watchpoints,
+    #[serde(skip)]
+    snapshots: Snapshots,
+    #[serde(skip)]
+    profiler: Profiler,
+    #[serde(skip)]
+    call_stack: CallStack,
+    #[serde(skip)]
     sim: Simulator,
     #[serde(skip)]
     gui_update_thread: Option<thread::JoinHandle<()>>,
@@ -49,6 +65,11 @@ impl Default for KompusimApp {
             decode_instr: InstrDecoder::default(),
             load_demo: LoadDemo::default(),
             console: Console::default(),
+            breakpoints: Breakpoints::default(),
+            watchpoints: Watchpoints::default(),
+            snapshots: Snapshots::default(),
+            profiler: Profiler::default(),
+            call_stack: CallStack::default(),
             sim: Simulator::new(),
             gui_update_thread: None,
         }
@@ -81,8 +102,17 @@ impl KompusimApp {
                 bin,
                 ram,
                 breakpoints,
+                machine,
+                restore,
+                symbols,
                 ..
             } = cmdl_cmd;
+            if let Some(machine) = machine {
+                match MachineConfig::from_file(&machine) {
+                    Ok(config) => app.sim.set_machine(config),
+                    Err(e) => eprintln!("Failed to load the machine: {e}. Using the default one"),
+                }
+            }
             if let Some(ref ram) = ram {
                 if let Some(ram_sz) = parse_size_with_suffix(ram) {
                     app.sim.set_ram_sz(ram_sz);
@@ -102,6 +132,12 @@ impl KompusimApp {
             let load_addr = u64::from_str_radix(load_addr.trim_start_matches("0x"), 16)
                 .expect("Load address is wrong format");
             app.sim.load_bin_file(load_addr, bin);
+            if let Some(restore) = restore {
+                app.sim.restore_snapshot(restore);
+            }
+            if let Some(symbols) = symbols {
+                app.sim.load_symbols(symbols);
+            }
             // Do not show windows that doesn't make sense to show:
             app.load_demo.window_open = false;
         }
@@ -132,6 +168,11 @@ impl eframe::App for KompusimApp {
             decode_instr,
             load_demo,
             console,
+            breakpoints,
+            watchpoints,
+            snapshots,
+            profiler,
+            call_stack,
             sim,
             gui_update_thread: _,
         } = self;
@@ -227,6 +268,26 @@ impl eframe::App for KompusimApp {
                         console.open();
                         ui.close_menu();
                     }
+                    if ui.button("Breakpoints").clicked() {
+                        breakpoints.open();
+                        ui.close_menu();
+                    }
+                    if ui.button("Watchpoints").clicked() {
+                        watchpoints.open();
+                        ui.close_menu();
+                    }
+                    if ui.button("Snapshots").clicked() {
+                        snapshots.open();
+                        ui.close_menu();
+                    }
+                    if ui.button("Profiler").clicked() {
+                        profiler.open();
+                        ui.close_menu();
+                    }
+                    if ui.button("Call stack").clicked() {
+                        call_stack.open();
+                        ui.close_menu();
+                    }
                     ui.add_enabled_ui(false, |ui| {
                         if ui.button("Memory (unimplemented)").clicked() {
                             ui.close_menu();
@@ -266,15 +327,15 @@ impl eframe::App for KompusimApp {
             Some(StatusControlCmd::Run) => sim.carry_on(),
             Some(StatusControlCmd::Stop) => sim.step(),
             Some(StatusControlCmd::Step) => sim.step(),
+            Some(StatusControlCmd::StepBack) => sim.step_back(),
+            Some(StatusControlCmd::ReverseContinue) => sim.reverse_continue(),
         }
         let cur_instr = sim.get_cur_instr();
         base_uregs.show_if_opened(ui_ctx, sim.get_regs(), cur_instr);
         let pc = sim.get_regs().pc;
-        instr_list.show_if_opened(
-            ui_ctx,
-            sim.get_instructions(instr_list.get_start_addr(), instr_list.get_num_instr()),
-            pc,
-        );
+        let (instructions, start_addr, coverage) =
+            sim.get_instructions(instr_list.get_start_addr(), instr_list.get_num_instr());
+        instr_list.show_if_opened(ui_ctx, (instructions, start_addr), coverage, pc);
         decode_instr.show_if_opened(ui_ctx, sim.get_regs().pc, cur_instr);
 
         if let Some(demo_image) = load_demo.show_pick_demo(ui_ctx) {
@@ -287,6 +348,43 @@ impl eframe::App for KompusimApp {
 
         console.show(ui_ctx, sim.console_recv());
 
+        match breakpoints.show_if_opened(ui_ctx, sim.get_breakpoints()) {
+            None => {}
+            Some(BreakpointsCmd::Set(breakpoint)) => sim.set_breakpoint(breakpoint),
+            Some(BreakpointsCmd::Enable(addr, enabled)) => sim.enable_breakpoint(addr, enabled),
+            Some(BreakpointsCmd::Remove(addr)) => sim.remove_breakpoint(addr),
+            Some(BreakpointsCmd::Clear) => sim.clear_breakpoints(),
+        }
+
+        match watchpoints.show_if_opened(ui_ctx, sim.get_watch_hit()) {
+            None => {}
+            Some(WatchpointsCmd::Add(watchpoint)) => sim.add_watchpoint(watchpoint),
+            Some(WatchpointsCmd::Remove(watchpoint)) => sim.remove_watchpoint(watchpoint),
+        }
+
+        match snapshots.show_if_opened(ui_ctx) {
+            None => {}
+            Some(SnapshotsCmd::Save(path)) => sim.save_snapshot(path),
+            Some(SnapshotsCmd::Restore(path)) => sim.restore_snapshot(path),
+        }
+
+        if let Some(profile) = sim.take_profile() {
+            profiler.set_profile(*profile);
+        }
+        match profiler.show_if_opened(ui_ctx) {
+            None => {}
+            Some(ProfilerCmd::Enable(enabled)) => sim.enable_profiler(enabled),
+            Some(ProfilerCmd::Refresh) => sim.request_profile(),
+        }
+
+        if let Some((frames, unwind)) = sim.take_backtrace() {
+            call_stack.set_frames(frames, unwind);
+        }
+        match call_stack.show_if_opened(ui_ctx, sim.get_state(), sim.get_num_exec_instr()) {
+            None => {}
+            Some(CallStackCmd::Refresh) => sim.request_backtrace(),
+        }
+
         egui::Window::new("Settings")
             .open(show_settings)
             .show(ui_ctx, |ui| {
@@ -0,0 +1,132 @@
+use kompusim::breakpoint::{Breakpoint, Condition};
+
+pub enum BreakpointsCmd {
+    /// Add or replace the breakpoint at the same address
+    Set(Breakpoint),
+    Enable(u64, bool),
+    Remove(u64),
+    Clear,
+}
+
+pub struct Breakpoints {
+    /// Is window open or not
+    window_open: bool,
+    /// Address in hex of the new breakpoint
+    addr: String,
+    condition: String,
+    ignore_count: String,
+    temporary: bool,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Breakpoints {
+            window_open: false,
+            addr: String::new(),
+            condition: String::new(),
+            ignore_count: "0".to_string(),
+            temporary: false,
+        }
+    }
+}
+
+impl Breakpoints {
+    pub fn open(&mut self) {
+        self.window_open = true;
+    }
+
+    fn parse_new(&self) -> Result<Breakpoint, String> {
+        let addr = u64::from_str_radix(self.addr.trim().trim_start_matches("0x"), 16)
+            .map_err(|_| "wrong address".to_string())?;
+        let condition = match self.condition.trim() {
+            "" => None,
+            condition => Some(Condition::parse(condition)?),
+        };
+        let ignore_count = self
+            .ignore_count
+            .trim()
+            .parse()
+            .map_err(|_| "wrong ignore count".to_string())?;
+        Ok(Breakpoint {
+            condition,
+            ignore_count,
+            temporary: self.temporary,
+            ..Breakpoint::new(addr)
+        })
+    }
+
+    pub fn show_if_opened(
+        &mut self,
+        ui_ctx: &egui::Context,
+        breakpoints: &[Breakpoint],
+    ) -> Option<BreakpointsCmd> {
+        if !self.window_open {
+            return None;
+        }
+        let mut command: Option<BreakpointsCmd> = None;
+        let mut window_opened = self.window_open;
+        egui::Window::new("Breakpoints")
+            .open(&mut window_opened)
+            .resizable(true)
+            .default_width(500.0)
+            .show(ui_ctx, |ui| {
+                egui::Grid::new("new_breakpoint_grid")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Address:");
+                        ui.text_edit_singleline(&mut self.addr);
+                        ui.end_row();
+                        ui.label("Condition:")
+                            .on_hover_text("e.g. a0 == 0x42 && [sp+8] != 0");
+                        ui.text_edit_singleline(&mut self.condition);
+                        ui.end_row();
+                        ui.label("Ignore hits:");
+                        ui.text_edit_singleline(&mut self.ignore_count);
+                        ui.end_row();
+                        ui.checkbox(&mut self.temporary, "Temporary");
+                        match self.parse_new() {
+                            Ok(breakpoint) => {
+                                if ui.button("Set").clicked() {
+                                    command = Some(BreakpointsCmd::Set(breakpoint));
+                                }
+                            }
+                            Err(e) => {
+                                ui.label(e);
+                            }
+                        }
+                        ui.end_row();
+                    });
+                ui.separator();
+                ui.add_enabled_ui(!breakpoints.is_empty(), |ui| {
+                    if ui.button("Remove all").clicked() {
+                        command = Some(BreakpointsCmd::Clear);
+                    }
+                });
+                egui::Grid::new("breakpoints_grid")
+                    .num_columns(7)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for b in breakpoints {
+                            let mut enabled = b.enabled;
+                            if ui.checkbox(&mut enabled, "").changed() {
+                                command = Some(BreakpointsCmd::Enable(b.addr, enabled));
+                            }
+                            ui.label(format!("0x{:016x}", b.addr));
+                            match &b.condition {
+                                Some(condition) => ui.label(format!("if {condition}")),
+                                None => ui.label(""),
+                            };
+                            ui.label(format!("hits: {}", b.hit_count));
+                            ui.label(format!("ignore: {}", b.ignore_count));
+                            ui.label(if b.temporary { "temporary" } else { "" });
+                            if ui.button("Remove").clicked() {
+                                command = Some(BreakpointsCmd::Remove(b.addr));
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+        self.window_open = window_opened;
+        command
+    }
+}
@@ -0,0 +1,108 @@
+use egui_extras::{Column, TableBuilder};
+
+use crate::sim::SimState;
+
+pub enum CallStackCmd {
+    /// Request the backtrace of the current instruction
+    Refresh,
+}
+
+/// Frame PC (the return address of the outer frames) and the function
+pub type CallStackFrame = (u64, String);
+
+#[derive(Default)]
+pub struct CallStack {
+    /// Is window open or not
+    window_open: bool,
+    frames: Vec<CallStackFrame>,
+    /// How the frames were unwound
+    unwind: String,
+    /// Number of executed instructions of the requested backtrace
+    requested_at: Option<u64>,
+}
+
+impl CallStack {
+    pub fn open(&mut self) {
+        self.window_open = true;
+        self.requested_at = None;
+    }
+
+    /// New backtrace from the simulator
+    pub fn set_frames(&mut self, frames: Vec<CallStackFrame>, unwind: String) {
+        self.frames = frames;
+        self.unwind = unwind;
+    }
+
+    pub fn show_if_opened(
+        &mut self,
+        ui_ctx: &egui::Context,
+        sim_state: SimState,
+        num_exec_instr: u64,
+    ) -> Option<CallStackCmd> {
+        if !self.window_open {
+            return None;
+        }
+        let mut command: Option<CallStackCmd> = None;
+        // the stack is requested once the simulator stopped at a new instruction
+        let stopped = !matches!(sim_state, SimState::Running | SimState::Initializing);
+        if stopped && self.requested_at != Some(num_exec_instr) {
+            self.requested_at = Some(num_exec_instr);
+            command = Some(CallStackCmd::Refresh);
+        }
+        let mut window_opened = self.window_open;
+        egui::Window::new("Call stack")
+            .open(&mut window_opened)
+            .resizable(true)
+            .default_width(400.0)
+            .show(ui_ctx, |ui| {
+                if !stopped {
+                    ui.label("Stop the simulator to see the call stack");
+                    return;
+                }
+                ui.label(format!("Unwound with {}", self.unwind));
+                ui.separator();
+                self.show_table(ui);
+            });
+        self.window_open = window_opened;
+        command
+    }
+
+    fn show_table(&self, ui: &mut egui::Ui) {
+        let text_height = egui::TextStyle::Body.resolve(ui.style()).size;
+        ui.style_mut().override_text_style = Some(egui::TextStyle::Monospace);
+        TableBuilder::new(ui)
+            .striped(true)
+            .resizable(true)
+            .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+            .column(Column::auto())
+            .column(Column::auto())
+            .column(Column::remainder())
+            .min_scrolled_height(1.0)
+            .header(20.0, |mut header| {
+                header.col(|ui| {
+                    ui.strong("#");
+                });
+                header.col(|ui| {
+                    ui.strong("PC");
+                });
+                header.col(|ui| {
+                    ui.strong("Function");
+                });
+            })
+            .body(|body| {
+                body.rows(text_height, self.frames.len(), |mut row| {
+                    let (pc, function) = &self.frames[row.index()];
+                    let i = row.index();
+                    row.col(|ui| {
+                        ui.label(i.to_string());
+                    });
+                    row.col(|ui| {
+                        ui.label(format!("0x{pc:016x}"));
+                    });
+                    row.col(|ui| {
+                        ui.label(function);
+                    });
+                })
+            });
+    }
+}
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::{arg, Parser, Subcommand};
+use clap::{Parser, Subcommand};
 use nom::{
     branch::alt,
     bytes::complete::tag_no_case,
@@ -50,6 +50,18 @@ pub enum CmdLCommand {
         /// Run in with interactive menu, don't execute
         #[arg(short, long, action=clap::ArgAction::SetTrue)]
         interactive: Option<bool>,
+
+        /// Machine description file (TOML) with RAM/ROM regions, devices, harts and ISA
+        #[arg(long)]
+        machine: Option<PathBuf>,
+
+        /// Restore the machine from a snapshot file after loading the binary
+        #[arg(long)]
+        restore: Option<PathBuf>,
+
+        /// ELF file with the symbols of the binary, e.g. for the profiler
+        #[arg(long)]
+        symbols: Option<PathBuf>,
     },
 }
 
@@ -103,7 +115,7 @@ pub fn parse_size_with_suffix(input: &str) -> Option<u64> {
 /// parses string "0x8001f234,0x8001f348,0x8001f376" to Vec<u64>
 pub fn parse_breakpoints(str: &str) -> Vec<u64> {
     let v: Vec<u64> = str
-        .splitn(256, |s| s == ',' || s == ' ')
+        .splitn(256, [',', ' '])
         .filter_map(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
         .collect();
     v
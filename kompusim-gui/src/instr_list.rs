@@ -1,8 +1,10 @@
+use std::collections::HashMap;
 use std::iter::zip;
 
 use egui::Color32;
 use egui_extras::TableRow;
 use egui_extras::{Column, TableBuilder};
+use kompusim::profile::{instr_class, InstrClass, PcCount};
 use kompusim::rv64i_disasm::{disasm, instr_hex, u64_hex4};
 use kompusim::rvc_dec::instr_is_rvc;
 
@@ -43,6 +45,7 @@ impl InstrList {
         &mut self,
         ui_ctx: &egui::Context,
         instructions: (&Vec<u8>, u64),
+        coverage: Option<&HashMap<u64, PcCount>>,
         pc: u64,
     ) {
         let mut open = self.open;
@@ -51,13 +54,27 @@ impl InstrList {
             .resizable(true)
             .default_width(400.0)
             .show(ui_ctx, |ui| {
-                egui::ScrollArea::vertical().show(ui, |ui| self.show_table(ui, instructions, pc));
+                if coverage.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(UNCOVERED_COLOR, "not executed");
+                        ui.colored_label(PARTIAL_BRANCH_COLOR, "branch taken one way only");
+                    });
+                }
+                egui::ScrollArea::vertical()
+                    .show(ui, |ui| self.show_table(ui, instructions, coverage, pc));
             });
         self.open = open;
     }
 
     /// instructions - (instructions_array, start_addres)
-    fn show_table(&mut self, ui: &mut egui::Ui, instructions: (&Vec<u8>, u64), pc: u64) {
+    /// coverage - execution counts of the instructions, None if not collected
+    fn show_table(
+        &mut self,
+        ui: &mut egui::Ui,
+        instructions: (&Vec<u8>, u64),
+        coverage: Option<&HashMap<u64, PcCount>>,
+        pc: u64,
+    ) {
         self.instr_cache
             .update_cache(instructions.1, instructions.0);
         // update view window of instructions:
@@ -101,7 +118,14 @@ impl InstrList {
                     let (instr_addr, addr_hex, instr_hex, instr_mnemonic) =
                         self.instr_cache.get_disasm(row_index);
                     if pc == instr_addr {
-                        highlight_col(&mut row, "➡", addr_hex, instr_hex, instr_mnemonic);
+                        highlight_col(
+                            &mut row,
+                            Color32::YELLOW,
+                            ["➡", addr_hex, instr_hex, instr_mnemonic],
+                        );
+                    } else if let Some(color) = coverage.and_then(|c| coverage_color(c, instr_addr))
+                    {
+                        highlight_col(&mut row, color, ["", addr_hex, instr_hex, instr_mnemonic]);
                     } else {
                         row.col(|ui| {
                             ui.label("");
@@ -129,21 +153,30 @@ impl InstrList {
     }
 }
 
-fn highlight_col(row: &mut TableRow<'_, '_>, s1: &str, s2: &str, s3: &str, s4: &str) {
-    // TODO: change for white background
-    let color = Color32::YELLOW;
-    row.col(|ui| {
-        ui.colored_label(color, s1);
-    });
-    row.col(|ui| {
-        ui.colored_label(color, s2);
-    });
-    row.col(|ui| {
-        ui.colored_label(color, s3);
-    });
-    row.col(|ui| {
-        ui.colored_label(color, s4);
-    });
+// TODO: change for white background
+const UNCOVERED_COLOR: Color32 = Color32::from_rgb(255, 100, 100);
+const PARTIAL_BRANCH_COLOR: Color32 = Color32::from_rgb(255, 165, 0);
+
+/// Color of a never executed instruction or a conditional branch taken only one way
+fn coverage_color(coverage: &HashMap<u64, PcCount>, instr_addr: u64) -> Option<Color32> {
+    match coverage.get(&instr_addr) {
+        None => Some(UNCOVERED_COLOR),
+        Some(c)
+            if instr_class(c.instr) == InstrClass::Branch
+                && (c.taken == 0 || c.taken == c.count) =>
+        {
+            Some(PARTIAL_BRANCH_COLOR)
+        }
+        Some(_) => None,
+    }
+}
+
+fn highlight_col(row: &mut TableRow<'_, '_>, color: Color32, cols: [&str; 4]) {
+    for s in cols {
+        row.col(|ui| {
+            ui.colored_label(color, s);
+        });
+    }
 }
 
 /// Instruction Cache to optimizing rendering the instruction list
@@ -3,10 +3,15 @@
 mod app;
 pub use app::KompusimApp;
 mod base_uregs;
+mod breakpoints;
+mod call_stack;
 pub mod cmdline;
 mod console;
 mod instr_decoder;
 mod instr_list;
 mod load_demo;
+mod profiler;
 mod sim;
+mod snapshots;
 mod status_control;
+mod watchpoints;
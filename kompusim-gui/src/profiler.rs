@@ -0,0 +1,205 @@
+use egui_extras::{Column, TableBuilder};
+use kompusim::profile::{Profile, ProfileEntry};
+
+pub enum ProfilerCmd {
+    /// Start or stop (the counts are dropped) profiling
+    Enable(bool),
+    /// Request the current profile
+    Refresh,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum View {
+    Classes,
+    Pcs,
+    Symbols,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortBy {
+    Name,
+    Count,
+    Taken,
+    NotTaken,
+}
+
+pub struct Profiler {
+    /// Is window open or not
+    window_open: bool,
+    enabled: bool,
+    profile: Profile,
+    view: View,
+    sort_by: SortBy,
+    descending: bool,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            window_open: false,
+            enabled: false,
+            profile: Profile::default(),
+            view: View::Classes,
+            sort_by: SortBy::Count,
+            descending: true,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn open(&mut self) {
+        self.window_open = true;
+    }
+
+    /// New profile from the simulator
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        self.sort();
+    }
+
+    fn entries(&mut self) -> &mut Vec<ProfileEntry> {
+        match self.view {
+            View::Classes => &mut self.profile.classes,
+            View::Pcs => &mut self.profile.pcs,
+            View::Symbols => &mut self.profile.symbols,
+        }
+    }
+
+    fn sort(&mut self) {
+        let (sort_by, descending) = (self.sort_by, self.descending);
+        self.entries().sort_by(|a, b| {
+            let order = match sort_by {
+                SortBy::Name => a.name.cmp(&b.name),
+                SortBy::Count => a.count.cmp(&b.count),
+                SortBy::Taken => a.taken.cmp(&b.taken),
+                SortBy::NotTaken => a.not_taken.cmp(&b.not_taken),
+            };
+            if descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+    }
+
+    /// Column header, a click sorts by the column or reverses the order
+    fn sort_header(&mut self, ui: &mut egui::Ui, title: &str, sort_by: SortBy) {
+        let title = match (self.sort_by == sort_by, self.descending) {
+            (true, true) => format!("{title} ⏷"),
+            (true, false) => format!("{title} ⏶"),
+            (false, _) => title.to_string(),
+        };
+        if ui
+            .selectable_label(self.sort_by == sort_by, title)
+            .clicked()
+        {
+            if self.sort_by == sort_by {
+                self.descending = !self.descending;
+            } else {
+                self.sort_by = sort_by;
+                self.descending = sort_by != SortBy::Name;
+            }
+            self.sort();
+        }
+    }
+
+    pub fn show_if_opened(&mut self, ui_ctx: &egui::Context) -> Option<ProfilerCmd> {
+        if !self.window_open {
+            return None;
+        }
+        let mut command: Option<ProfilerCmd> = None;
+        let mut window_opened = self.window_open;
+        egui::Window::new("Profiler")
+            .open(&mut window_opened)
+            .resizable(true)
+            .default_width(600.0)
+            .show(ui_ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui
+                        .checkbox(&mut self.enabled, "Profile")
+                        .on_hover_text("Also highlights the not executed instructions")
+                        .changed()
+                    {
+                        command = Some(ProfilerCmd::Enable(self.enabled));
+                    }
+                    if ui.button("Refresh").clicked() {
+                        command = Some(ProfilerCmd::Refresh);
+                    }
+                    ui.label(format!("{} instructions", self.profile.total));
+                });
+                ui.horizontal(|ui| {
+                    let view = self.view;
+                    ui.selectable_value(&mut self.view, View::Classes, "Opcode classes");
+                    ui.selectable_value(&mut self.view, View::Pcs, "PCs");
+                    ui.add_enabled_ui(!self.profile.symbols.is_empty(), |ui| {
+                        ui.selectable_value(&mut self.view, View::Symbols, "Symbols")
+                            .on_disabled_hover_text("start with --symbols <ELF>");
+                    });
+                    if self.view != view {
+                        self.sort();
+                    }
+                });
+                ui.separator();
+                self.show_table(ui);
+            });
+        self.window_open = window_opened;
+        command
+    }
+
+    fn show_table(&mut self, ui: &mut egui::Ui) {
+        let text_height = egui::TextStyle::Body.resolve(ui.style()).size;
+        ui.style_mut().override_text_style = Some(egui::TextStyle::Monospace);
+        TableBuilder::new(ui)
+            .striped(true)
+            .resizable(true)
+            .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+            .column(Column::initial(160.0).at_least(60.0).clip(true))
+            .column(Column::auto())
+            .column(Column::auto())
+            .column(Column::auto())
+            .column(Column::auto())
+            .column(Column::remainder())
+            .min_scrolled_height(1.0)
+            .header(20.0, |mut header| {
+                header.col(|ui| self.sort_header(ui, "Name", SortBy::Name));
+                header.col(|ui| self.sort_header(ui, "Count", SortBy::Count));
+                header.col(|ui| {
+                    ui.strong("%");
+                });
+                header.col(|ui| self.sort_header(ui, "Taken", SortBy::Taken));
+                header.col(|ui| self.sort_header(ui, "Not taken", SortBy::NotTaken));
+                header.col(|ui| {
+                    ui.strong("Instruction");
+                });
+            })
+            .body(|body| {
+                let profile = &self.profile;
+                let entries = match self.view {
+                    View::Classes => &profile.classes,
+                    View::Pcs => &profile.pcs,
+                    View::Symbols => &profile.symbols,
+                };
+                body.rows(text_height, entries.len(), |mut row| {
+                    let e = &entries[row.index()];
+                    row.col(|ui| {
+                        ui.label(&e.name);
+                    });
+                    row.col(|ui| {
+                        ui.label(e.count.to_string());
+                    });
+                    row.col(|ui| {
+                        ui.label(format!("{:.2}", profile.percent(e.count)));
+                    });
+                    row.col(|ui| {
+                        ui.label(e.taken.to_string());
+                    });
+                    row.col(|ui| {
+                        ui.label(e.not_taken.to_string());
+                    });
+                    row.col(|ui| {
+                        ui.label(&e.detail);
+                    });
+                })
+            });
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     path::PathBuf,
     sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
     thread,
@@ -6,11 +7,13 @@ use std::{
 };
 
 use kompusim::{
-    bus,
-    device::Device,
-    ram,
-    rv64i_cpu::{ExecEvent, RV64ICpu, RV64IURegs},
-    uart::Uart,
+    breakpoint::Breakpoint,
+    elf::SymbolMap,
+    machine::{DeviceKind, MachineBuilder, MachineConfig},
+    profile::{PcCount, Profile, Profiler},
+    rv64i_cpu::{ExecEvent, RV64ICpu, RV64IURegs, WatchHit, Watchpoint},
+    snapshot::Snapshot,
+    undo::DEFAULT_UNDO_BUDGET,
 };
 
 // TODO: setting
@@ -19,6 +22,18 @@ const EXE_INSTRUCTIONS_THEN_POLL: u64 = 102400;
 pub const DEFAULT_MEM_SZ: u64 = 1024 * 1024;
 pub const DEFAULT_START_ADDRESS: u64 = 0x8000_0000;
 
+/// The machine used unless --machine is given: RAM, UART, CLINT, virtio-console and virtio-rng
+pub fn default_machine() -> MachineConfig {
+    MachineBuilder::new()
+        .ram(DEFAULT_START_ADDRESS, DEFAULT_MEM_SZ)
+        .device(DeviceKind::Uart, 0x1001_0000, Some(10))
+        .device(DeviceKind::VirtioConsole, 0x1000_2000, Some(2))
+        .device(DeviceKind::VirtioRng { seed: None }, 0x1000_3000, Some(3))
+        .device(DeviceKind::Clint, 0x200_0000, None)
+        .config()
+        .clone()
+}
+
 pub struct Simulator {
     sim_thread: Option<thread::JoinHandle<()>>,
     cmd_channel: Sender<SimCommand>,
@@ -37,6 +52,16 @@ pub struct Simulator {
     // instruction cache size in bytes
     instr_cache_sz: u64,
     instr_cache_start: u64,
+    /// Execution counts of the cached instructions, if the profiler is enabled
+    instr_coverage: Option<HashMap<u64, PcCount>>,
+    /// The last access that hit a watchpoint
+    watch_hit: Option<WatchHit>,
+    /// lock-less mirrored breakpoints with their hit counters
+    breakpoints: Vec<Breakpoint>,
+    /// The last received profile, taken by the profiler window
+    profile: Option<Box<Profile>>,
+    /// The last received backtrace and how it was unwound, taken by the call stack window
+    backtrace: Option<(Vec<(u64, String)>, String)>,
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -45,10 +70,21 @@ pub enum SimState {
     Initializing,
     InitializedReady,
     StoppedBreakpoint,
+    StoppedWatchpoint,
     Stopped,
     Running,
 }
 
+/// State after exec_continue() returned the event, None - keep running
+fn stopped_state(event: &ExecEvent) -> Option<SimState> {
+    match event {
+        ExecEvent::MaxInstructions(_) => None,
+        ExecEvent::Breakpoint(_) => Some(SimState::StoppedBreakpoint),
+        ExecEvent::Watchpoint(_) => Some(SimState::StoppedWatchpoint),
+        ExecEvent::Exit(_) => Some(SimState::Stopped),
+    }
+}
+
 #[derive(Debug, Clone)]
 enum LoadImageType {
     File(PathBuf),
@@ -63,6 +99,10 @@ enum SimCommand {
     NoCmd,
     Continue,
     Step,
+    /// Undo the last executed instruction
+    StepBack,
+    /// Step back to the previous breakpoint or watchpoint hit
+    ReverseContinue,
     Stop,
     LoadImage((u64, LoadImageType)),
     /// Disasm(starting_address, number_of_bytes)
@@ -71,13 +111,39 @@ enum SimCommand {
     SetRamSz(u64),
     // Add new breakpoint
     AddBreakpoint(u64),
+    // Add or replace the breakpoint at the same address
+    SetBreakpoint(Box<Breakpoint>),
+    EnableBreakpoint(u64, bool),
+    RemoveBreakpoint(u64),
+    ClearBreakpoints,
+    AddWatchpoint(Watchpoint),
+    RemoveWatchpoint(Watchpoint),
+    // Replace the machine
+    SetMachine(Box<MachineConfig>),
+    // Save the machine snapshot to the file
+    SaveSnapshot(PathBuf),
+    // Restore the machine from the snapshot file
+    RestoreSnapshot(PathBuf),
+    // Load the symbols of the program from the ELF file
+    LoadSymbols(PathBuf),
+    // Start or stop profiling
+    EnableProfiler(bool),
+    // Send the current profile
+    GetProfile,
+    // Send the backtrace of the current instruction
+    GetBacktrace,
 }
 
 #[derive(Clone)]
 enum SimEvent {
     /// SimState, registers, number of executed instructions
     StateChanged(SimState, Box<RV64IURegs>, u64),
-    Instructions(Option<Vec<u8>>),
+    /// Memory of the instructions and their execution counts
+    Instructions(Option<Vec<u8>>, Option<HashMap<u64, PcCount>>),
+    WatchpointHit(WatchHit),
+    Breakpoints(Vec<Breakpoint>),
+    Profile(Box<Profile>),
+    Backtrace(Vec<(u64, String)>, String),
 }
 
 impl Simulator {
@@ -94,22 +160,37 @@ impl Simulator {
                     eprintln!("Simulator: failed to send event: {}", err);
                 }
             };
-            let addr = DEFAULT_START_ADDRESS;
-            let ram_sz = DEFAULT_MEM_SZ;
-            let ram = ram::Ram::new(addr, ram_sz);
-            let mut bus = bus::Bus::new();
-            bus.attach_ram(ram);
-
-            let mut uart0 = Box::new(Uart::new("0".to_string()));
-            uart0.register_out_callback(Box::new(move |b: u8| {
-                if let Err(err) = uart_tx_send.send(b) {
-                    println!("Simulator: failed to send command: {}", err);
+            // All console devices share the UART console window
+            let build_machine = |config: MachineConfig| -> Result<RV64ICpu, String> {
+                let uart_tx_send = uart_tx_send.clone();
+                MachineBuilder::from_config(config)
+                    .console_out(Box::new(move |b: u8| {
+                        if let Err(err) = uart_tx_send.send(b) {
+                            println!("Simulator: failed to send command: {}", err);
+                        }
+                    }))
+                    .build()
+                    .map(|mut cpu| {
+                        cpu.set_undo_budget(DEFAULT_UNDO_BUDGET);
+                        cpu.enable_shadow_stack(true);
+                        cpu
+                    })
+            };
+            let mut cpu0 = build_machine(default_machine()).unwrap();
+            let mut symbols: Option<SymbolMap> = None;
+            // runs max_instr instructions, returns the new state if the CPU stopped
+            let exec = |cpu: &mut RV64ICpu, max_instr| {
+                let event = cpu.exec_continue(max_instr);
+                if let ExecEvent::Watchpoint(hit) = event {
+                    send_event(SimEvent::WatchpointHit(hit));
                 }
-            }));
-            bus.attach_device(Device::new(uart0, 0x1001_0000, 0x20));
-
-            let mut cpu0 = RV64ICpu::new(bus);
-            cpu0.pc_jump(addr);
+                let state = stopped_state(&event);
+                if state.is_some() && !cpu.breakpoints().is_empty() {
+                    // hit counters changed
+                    send_event(SimEvent::Breakpoints(cpu.breakpoints().to_vec()));
+                }
+                state
+            };
 
             let mut sim_state = SimState::InitializedReady;
             send_event(SimEvent::StateChanged(
@@ -148,10 +229,8 @@ impl Simulator {
                     SimCommand::NoCmd => {
                         if sim_state == SimState::Running {
                             // TODO: move to settings
-                            if let ExecEvent::Breakpoint(_) =
-                                cpu0.exec_continue(EXE_INSTRUCTIONS_THEN_POLL)
-                            {
-                                sim_state = SimState::StoppedBreakpoint;
+                            if let Some(state) = exec(&mut cpu0, EXE_INSTRUCTIONS_THEN_POLL) {
+                                sim_state = state;
                                 send_event(SimEvent::StateChanged(
                                     sim_state,
                                     Box::new(cpu0.get_regs().clone()),
@@ -162,10 +241,8 @@ impl Simulator {
                     }
                     SimCommand::Continue => {
                         sim_state = SimState::Running;
-                        if let ExecEvent::Breakpoint(_) =
-                            cpu0.exec_continue(EXE_INSTRUCTIONS_THEN_POLL)
-                        {
-                            sim_state = SimState::StoppedBreakpoint;
+                        if let Some(state) = exec(&mut cpu0, EXE_INSTRUCTIONS_THEN_POLL) {
+                            sim_state = state;
                             send_event(SimEvent::StateChanged(
                                 sim_state,
                                 Box::new(cpu0.get_regs().clone()),
@@ -174,7 +251,18 @@ impl Simulator {
                         }
                     }
                     SimCommand::Step => {
-                        let _ = cpu0.exec_continue(1);
+                        sim_state = match exec(&mut cpu0, 1) {
+                            Some(SimState::StoppedWatchpoint) => SimState::StoppedWatchpoint,
+                            _ => SimState::Stopped,
+                        };
+                        send_event(SimEvent::StateChanged(
+                            sim_state,
+                            Box::new(cpu0.get_regs().clone()),
+                            cpu0.get_num_exec_instr(),
+                        ));
+                    }
+                    SimCommand::StepBack => {
+                        cpu0.step_back();
                         sim_state = SimState::Stopped;
                         send_event(SimEvent::StateChanged(
                             sim_state,
@@ -182,6 +270,22 @@ impl Simulator {
                             cpu0.get_num_exec_instr(),
                         ));
                     }
+                    SimCommand::ReverseContinue => {
+                        // None - reached the start of the recorded history
+                        let event = cpu0.reverse_continue();
+                        if let Some(ExecEvent::Watchpoint(hit)) = event {
+                            send_event(SimEvent::WatchpointHit(hit));
+                        }
+                        sim_state = event
+                            .as_ref()
+                            .and_then(stopped_state)
+                            .unwrap_or(SimState::Stopped);
+                        send_event(SimEvent::StateChanged(
+                            sim_state,
+                            Box::new(cpu0.get_regs().clone()),
+                            cpu0.get_num_exec_instr(),
+                        ));
+                    }
                     // SimCommand::Reset => {
                     //     println!("Simulator: reset command")
                     // }
@@ -195,6 +299,8 @@ impl Simulator {
                                 cpu0.bus.load_image(load_addr, mem_buf).unwrap();
                             }
                         }
+                        // the calls of the previous program
+                        cpu0.enable_shadow_stack(true);
                         sim_state = SimState::Stopped;
                         send_event(SimEvent::StateChanged(
                             sim_state,
@@ -207,13 +313,101 @@ impl Simulator {
                         let instructions = cpu0
                             .get_ram(addr, n_bytes)
                             .map(|mem_area| mem_area.to_owned());
-                        send_event(SimEvent::Instructions(instructions));
+                        let coverage = cpu0.profiler().map(|profiler| {
+                            profiler
+                                .pc_counts()
+                                .iter()
+                                .filter(|(&pc, _)| pc >= addr && pc < addr + n_bytes)
+                                .map(|(&pc, &count)| (pc, count))
+                                .collect()
+                        });
+                        send_event(SimEvent::Instructions(instructions, coverage));
                     }
                     SimCommand::SetRamSz(ram_sz) => {
                         cpu0.set_ram_sz(ram_sz);
                     }
                     SimCommand::AddBreakpoint(breakpoint) => {
                         cpu0.add_breakpoint(breakpoint);
+                        send_event(SimEvent::Breakpoints(cpu0.breakpoints().to_vec()));
+                    }
+                    SimCommand::SetBreakpoint(breakpoint) => {
+                        cpu0.set_breakpoint(*breakpoint);
+                        send_event(SimEvent::Breakpoints(cpu0.breakpoints().to_vec()));
+                    }
+                    SimCommand::EnableBreakpoint(addr, enabled) => {
+                        if let Some(breakpoint) = cpu0.breakpoint_mut(addr) {
+                            breakpoint.enabled = enabled;
+                        }
+                        send_event(SimEvent::Breakpoints(cpu0.breakpoints().to_vec()));
+                    }
+                    SimCommand::RemoveBreakpoint(addr) => {
+                        cpu0.remove_breakpoint(addr);
+                        send_event(SimEvent::Breakpoints(cpu0.breakpoints().to_vec()));
+                    }
+                    SimCommand::ClearBreakpoints => {
+                        cpu0.clear_breakpoints();
+                        send_event(SimEvent::Breakpoints(cpu0.breakpoints().to_vec()));
+                    }
+                    SimCommand::AddWatchpoint(watchpoint) => {
+                        cpu0.add_watchpoint(watchpoint);
+                    }
+                    SimCommand::RemoveWatchpoint(watchpoint) => {
+                        cpu0.remove_watchpoint(&watchpoint);
+                    }
+                    SimCommand::SetMachine(config) => match build_machine(*config) {
+                        Ok(cpu) => {
+                            cpu0 = cpu;
+                            sim_state = SimState::InitializedReady;
+                            send_event(SimEvent::StateChanged(
+                                sim_state,
+                                Box::new(cpu0.get_regs().clone()),
+                                0,
+                            ));
+                        }
+                        Err(e) => eprintln!("Simulator: failed to build the machine: {e}"),
+                    },
+                    SimCommand::SaveSnapshot(path) => match cpu0.save_snapshot().save(&path) {
+                        Ok(()) => println!("Simulator: snapshot saved to {path:?}"),
+                        Err(e) => eprintln!("Simulator: failed to save the snapshot: {e}"),
+                    },
+                    SimCommand::RestoreSnapshot(path) => {
+                        match Snapshot::load(&path).and_then(|s| cpu0.restore_snapshot(&s)) {
+                            Ok(()) => {
+                                sim_state = SimState::Stopped;
+                                send_event(SimEvent::StateChanged(
+                                    sim_state,
+                                    Box::new(cpu0.get_regs().clone()),
+                                    cpu0.get_num_exec_instr(),
+                                ));
+                                println!("Simulator: snapshot {path:?} restored");
+                            }
+                            Err(e) => eprintln!("Simulator: failed to restore the snapshot: {e}"),
+                        }
+                    }
+                    SimCommand::LoadSymbols(path) => match SymbolMap::from_file(&path) {
+                        Ok(map) => symbols = Some(map),
+                        Err(e) => eprintln!("Simulator: failed to load the symbols: {e}"),
+                    },
+                    SimCommand::EnableProfiler(enabled) => {
+                        if enabled != cpu0.profiler().is_some() {
+                            cpu0.set_profiler(enabled.then(Profiler::new));
+                        }
+                    }
+                    SimCommand::GetProfile => {
+                        let profile = cpu0
+                            .profiler()
+                            .map(|p| p.profile(symbols.as_ref()))
+                            .unwrap_or_default();
+                        send_event(SimEvent::Profile(Box::new(profile)));
+                    }
+                    SimCommand::GetBacktrace => {
+                        let backtrace = cpu0.backtrace();
+                        let frames = backtrace
+                            .frames
+                            .iter()
+                            .map(|f| (f.pc, f.describe(symbols.as_ref())))
+                            .collect();
+                        send_event(SimEvent::Backtrace(frames, backtrace.unwind.to_string()));
                     }
                     SimCommand::Stop => break,
                 }
@@ -230,12 +424,60 @@ impl Simulator {
             regs: Box::<kompusim::rv64i_cpu::RV64IURegs>::default(),
             num_exec_instr: 0,
             instr_cache: None,
+            instr_coverage: None,
             instr_cache_start: 0,
             instr_cache_sz: 0,
             event_queue: event_recv,
+            watch_hit: None,
+            breakpoints: Vec::new(),
+            profile: None,
+            backtrace: None,
         }
     }
 
+    pub fn set_machine(&mut self, config: MachineConfig) {
+        self.send_cmd(SimCommand::SetMachine(Box::new(config)));
+        self.instr_cache.take();
+    }
+
+    pub fn save_snapshot(&self, path: PathBuf) {
+        self.send_cmd(SimCommand::SaveSnapshot(path));
+    }
+
+    pub fn restore_snapshot(&mut self, path: PathBuf) {
+        self.send_cmd(SimCommand::RestoreSnapshot(path));
+        // clear disassembler cache - RAM is replaced
+        self.instr_cache.take();
+    }
+
+    pub fn load_symbols(&self, path: PathBuf) {
+        self.send_cmd(SimCommand::LoadSymbols(path));
+    }
+
+    pub fn enable_profiler(&self, enabled: bool) {
+        self.send_cmd(SimCommand::EnableProfiler(enabled));
+    }
+
+    /// The profile arrives later, see take_profile()
+    pub fn request_profile(&self) {
+        self.send_cmd(SimCommand::GetProfile);
+    }
+
+    pub fn take_profile(&mut self) -> Option<Box<Profile>> {
+        self.drain_event_queue(); // will update self.profile
+        self.profile.take()
+    }
+
+    /// The backtrace arrives later, see take_backtrace()
+    pub fn request_backtrace(&self) {
+        self.send_cmd(SimCommand::GetBacktrace);
+    }
+
+    pub fn take_backtrace(&mut self) -> Option<(Vec<(u64, String)>, String)> {
+        self.drain_event_queue(); // will update self.backtrace
+        self.backtrace.take()
+    }
+
     pub fn set_ram_sz(&mut self, ram_sz: u64) {
         self.send_cmd(SimCommand::SetRamSz(ram_sz));
     }
@@ -244,6 +486,40 @@ impl Simulator {
         self.send_cmd(SimCommand::AddBreakpoint(breakpoint))
     }
 
+    pub fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.send_cmd(SimCommand::SetBreakpoint(Box::new(breakpoint)))
+    }
+
+    pub fn enable_breakpoint(&mut self, addr: u64, enabled: bool) {
+        self.send_cmd(SimCommand::EnableBreakpoint(addr, enabled))
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.send_cmd(SimCommand::RemoveBreakpoint(addr))
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.send_cmd(SimCommand::ClearBreakpoints)
+    }
+
+    pub fn get_breakpoints(&mut self) -> &[Breakpoint] {
+        self.drain_event_queue(); // will update self.breakpoints
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.send_cmd(SimCommand::AddWatchpoint(watchpoint))
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.send_cmd(SimCommand::RemoveWatchpoint(watchpoint))
+    }
+
+    pub fn get_watch_hit(&mut self) -> Option<WatchHit> {
+        self.drain_event_queue(); // will update self.watch_hit
+        self.watch_hit
+    }
+
     pub fn stop(&mut self) {
         if self.sim_thread.is_some() {
             self.send_cmd(SimCommand::Stop);
@@ -283,6 +559,14 @@ impl Simulator {
         self.send_cmd(SimCommand::Step);
     }
 
+    pub fn step_back(&self) {
+        self.send_cmd(SimCommand::StepBack);
+    }
+
+    pub fn reverse_continue(&self) {
+        self.send_cmd(SimCommand::ReverseContinue);
+    }
+
     fn process_event(&mut self, event: SimEvent) {
         match event {
             SimEvent::StateChanged(new_state, new_regs, num_exec_instr) => {
@@ -293,8 +577,21 @@ impl Simulator {
                 // TODO: optimize - use memory watchpoints
                 self.instr_cache.take();
             }
-            SimEvent::Instructions(instructions) => {
+            SimEvent::Instructions(instructions, coverage) => {
                 self.instr_cache = instructions;
+                self.instr_coverage = coverage;
+            }
+            SimEvent::WatchpointHit(hit) => {
+                self.watch_hit = Some(hit);
+            }
+            SimEvent::Breakpoints(breakpoints) => {
+                self.breakpoints = breakpoints;
+            }
+            SimEvent::Profile(profile) => {
+                self.profile = Some(profile);
+            }
+            SimEvent::Backtrace(frames, unwind) => {
+                self.backtrace = Some((frames, unwind));
             }
         }
     }
@@ -362,24 +659,32 @@ impl Simulator {
         (instr0 as u32) | (instr1 as u32) << 8 | (instr2 as u32) << 16 | (instr3 as u32) << 24
     }
 
-    /// Returns memory area state (bytes_array, start_address)
+    // Returns memory area state (bytes_array, start_address)
     // pub fn get_mem_area(&mut self, start_addr: u64, size: usize) -> (&Vec<u32>, u64) {
     // }
 
     // TODO: remove and replace with get_mem_area()
-    /// Returns (bytes_array, start_address)
-    pub fn get_instructions(&mut self, start_addr: u64, size: u64) -> (&Vec<u8>, u64) {
+    /// Returns (bytes_array, start_address, execution_counts)
+    pub fn get_instructions(
+        &mut self,
+        start_addr: u64,
+        size: u64,
+    ) -> (&Vec<u8>, u64, Option<&HashMap<u64, PcCount>>) {
         if self.instr_cache.is_none()
             || start_addr < self.instr_cache_start
             || start_addr + size > self.instr_cache_start + self.instr_cache_sz
         {
             println!("sim: Updating instruction cache"); // keep it for debuggin unnecessary cache updates
             self.send_cmd(SimCommand::Disasm(start_addr, size));
-            self.wait_for_event(SimEvent::Instructions(Some(Vec::default())));
+            self.wait_for_event(SimEvent::Instructions(Some(Vec::default()), None));
             self.instr_cache_start = start_addr;
             self.instr_cache_sz = size;
         }
-        (self.instr_cache.as_ref().unwrap(), self.instr_cache_start)
+        (
+            self.instr_cache.as_ref().unwrap(),
+            self.instr_cache_start,
+            self.instr_coverage.as_ref(),
+        )
     }
 
     pub fn console_recv(&self) -> Option<String> {
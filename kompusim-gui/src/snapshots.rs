@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+pub enum SnapshotsCmd {
+    Save(PathBuf),
+    Restore(PathBuf),
+}
+
+pub struct Snapshots {
+    /// Is window open or not
+    window_open: bool,
+    /// Path of the snapshot file
+    path: String,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Snapshots {
+            window_open: false,
+            path: "kompusim.snap".to_string(),
+        }
+    }
+}
+
+impl Snapshots {
+    pub fn open(&mut self) {
+        self.window_open = true;
+    }
+
+    pub fn show_if_opened(&mut self, ui_ctx: &egui::Context) -> Option<SnapshotsCmd> {
+        if !self.window_open {
+            return None;
+        }
+        let mut command: Option<SnapshotsCmd> = None;
+        let mut window_opened = self.window_open;
+        egui::Window::new("Snapshots")
+            .open(&mut window_opened)
+            .resizable(true)
+            .default_width(400.0)
+            .show(ui_ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("File:");
+                    ui.text_edit_singleline(&mut self.path);
+                });
+                ui.add_enabled_ui(!self.path.trim().is_empty(), |ui| {
+                    ui.horizontal(|ui| {
+                        let path = PathBuf::from(self.path.trim());
+                        if ui
+                            .button("Save")
+                            .on_hover_text("Save the whole machine to the file")
+                            .clicked()
+                        {
+                            command = Some(SnapshotsCmd::Save(path.clone()));
+                        }
+                        if ui
+                            .button("Restore")
+                            .on_hover_text("Restore the machine from the file")
+                            .clicked()
+                        {
+                            command = Some(SnapshotsCmd::Restore(path));
+                        }
+                    });
+                });
+            });
+        self.window_open = window_opened;
+        command
+    }
+}
@@ -4,6 +4,8 @@ pub enum StatusControlCmd {
     Run,
     Stop,
     Step,
+    StepBack,
+    ReverseContinue,
     //Autostep
 }
 
@@ -46,6 +48,7 @@ impl StatusControl {
                         SimState::Running => (false, true, true),
                         SimState::Stopped => (true, false, true),
                         SimState::StoppedBreakpoint => (true, false, true),
+                        SimState::StoppedWatchpoint => (true, false, true),
                     };
                     ui.add_enabled_ui(run_btn_en, |ui| {
                         if ui.button("Run").clicked() {
@@ -62,6 +65,24 @@ impl StatusControl {
                             command = Some(StatusControlCmd::Step);
                         }
                     });
+                    // the history is recorded only while executing
+                    ui.add_enabled_ui(
+                        run_btn_en && sim_state != SimState::InitializedReady,
+                        |ui| {
+                            if ui.button("Step back").clicked() {
+                                command = Some(StatusControlCmd::StepBack);
+                            }
+                            if ui
+                                .button("Reverse")
+                                .on_hover_text(
+                                    "Step back to the previous breakpoint or watchpoint hit",
+                                )
+                                .clicked()
+                            {
+                                command = Some(StatusControlCmd::ReverseContinue);
+                            }
+                        },
+                    );
                 });
                 egui::Grid::new("load_demo_grid")
                     .num_columns(2)
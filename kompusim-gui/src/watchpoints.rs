@@ -0,0 +1,116 @@
+use kompusim::rv64i_cpu::{WatchHit, WatchKind, Watchpoint};
+
+pub enum WatchpointsCmd {
+    Add(Watchpoint),
+    Remove(Watchpoint),
+}
+
+pub struct Watchpoints {
+    /// Is window open or not
+    window_open: bool,
+    /// Address in hex of the new watchpoint
+    addr: String,
+    /// Number of watched bytes of the new watchpoint
+    len: String,
+    kind: WatchKind,
+    /// Watchpoints set in the simulator
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Watchpoints {
+            window_open: false,
+            addr: String::new(),
+            len: "8".to_string(),
+            kind: WatchKind::Write,
+            watchpoints: Vec::new(),
+        }
+    }
+}
+
+impl Watchpoints {
+    pub fn open(&mut self) {
+        self.window_open = true;
+    }
+
+    fn parse_new(&self) -> Option<Watchpoint> {
+        let addr = u64::from_str_radix(self.addr.trim().trim_start_matches("0x"), 16).ok()?;
+        let len = self.len.trim().parse().ok().filter(|&len| len > 0)?;
+        Some(Watchpoint {
+            addr,
+            len,
+            kind: self.kind,
+        })
+    }
+
+    pub fn show_if_opened(
+        &mut self,
+        ui_ctx: &egui::Context,
+        last_hit: Option<WatchHit>,
+    ) -> Option<WatchpointsCmd> {
+        if !self.window_open {
+            return None;
+        }
+        let mut command: Option<WatchpointsCmd> = None;
+        let mut window_opened = self.window_open;
+        egui::Window::new("Watchpoints")
+            .open(&mut window_opened)
+            .resizable(true)
+            .default_width(500.0)
+            .show(ui_ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Address:");
+                    ui.add(egui::TextEdit::singleline(&mut self.addr).desired_width(150.0));
+                    ui.label("Bytes:");
+                    ui.add(egui::TextEdit::singleline(&mut self.len).desired_width(40.0));
+                    egui::ComboBox::from_id_source("watch_kind")
+                        .selected_text(format!("{:?}", self.kind))
+                        .show_ui(ui, |ui| {
+                            for kind in [WatchKind::Write, WatchKind::Read, WatchKind::Access] {
+                                ui.selectable_value(&mut self.kind, kind, format!("{kind:?}"));
+                            }
+                        });
+                    let new = self.parse_new();
+                    ui.add_enabled_ui(new.is_some(), |ui| {
+                        if ui.button("Add").clicked() {
+                            let watchpoint = new.unwrap();
+                            if !self.watchpoints.contains(&watchpoint) {
+                                self.watchpoints.push(watchpoint);
+                                command = Some(WatchpointsCmd::Add(watchpoint));
+                            }
+                        }
+                    });
+                });
+                egui::Grid::new("watchpoints_grid")
+                    .num_columns(4)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        let mut removed = None;
+                        for (i, w) in self.watchpoints.iter().enumerate() {
+                            ui.label(format!("0x{:016x}", w.addr));
+                            ui.label(format!("{} bytes", w.len));
+                            ui.label(format!("{:?}", w.kind));
+                            if ui.button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                            ui.end_row();
+                        }
+                        if let Some(i) = removed {
+                            command = Some(WatchpointsCmd::Remove(self.watchpoints.remove(i)));
+                        }
+                    });
+                if let Some(hit) = last_hit {
+                    ui.separator();
+                    let access = if hit.write { "Write" } else { "Read" };
+                    ui.label(format!(
+                        "Last hit: {access} of {} bytes at 0x{:x} by PC 0x{:x}",
+                        hit.size, hit.addr, hit.pc
+                    ));
+                    ui.label(format!("Old: 0x{:x}, new: 0x{:x}", hit.old, hit.new));
+                }
+            });
+        self.window_open = window_opened;
+        command
+    }
+}
@@ -0,0 +1,218 @@
+// Backtrace reconstruction. The shadow call stack follows the calls and returns of the executed
+// instructions (see callgraph::instr_flow), it's exact as long as the program uses the calling
+// convention. Without it (not enabled, or dropped when the execution was rewound or the machine
+// restored) the frames are walked through the frame pointer: the GCC/LLVM frame record keeps the
+// return address at fp - 8 and the caller's fp at fp - 16, which needs -fno-omit-frame-pointer.
+
+use std::fmt;
+
+use crate::callgraph::{instr_flow, Flow};
+use crate::elf::SymbolMap;
+use crate::rvc_dec::instr_is_rvc;
+
+/// Deeper stacks are most likely returns the shadow stack doesn't recognize
+pub const MAX_FRAMES: usize = 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct ShadowStack {
+    /// Entry of the function executed first (the bottom of the stack)
+    root: Option<u64>,
+    /// Entries of the called functions and their return addresses
+    frames: Vec<(u64, u64)>,
+    /// The calls before the execution was rewound or the machine restored aren't known
+    lost: bool,
+}
+
+impl ShadowStack {
+    pub fn new() -> ShadowStack {
+        ShadowStack::default()
+    }
+
+    /// Follows the instruction executed at PC, `next_pc` is the PC after it
+    pub fn record(&mut self, pc: u64, instr: u32, next_pc: u64) {
+        self.root.get_or_insert(pc);
+        match instr_flow(instr) {
+            Flow::Call => {
+                if self.frames.len() == MAX_FRAMES {
+                    self.frames.remove(0);
+                }
+                let len = if instr_is_rvc(instr) { 2 } else { 4 };
+                self.frames.push((next_pc, pc.wrapping_add(len)));
+            }
+            Flow::Return => match self.frames.iter().rposition(|&(_, ret)| ret == next_pc) {
+                Some(i) => self.frames.truncate(i),
+                None => {
+                    self.frames.pop();
+                }
+            },
+            Flow::Other => {}
+        }
+    }
+
+    /// Forgets the frames, they don't match the state after rewinding or restoring the machine
+    pub fn lose(&mut self) {
+        *self = ShadowStack {
+            lost: true,
+            ..ShadowStack::default()
+        };
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Frames from the innermost one executing PC
+    pub fn backtrace(&self, pc: u64) -> Backtrace {
+        let mut frames = Vec::with_capacity(self.frames.len() + 1);
+        let mut pc = pc;
+        for &(func, ret) in self.frames.iter().rev() {
+            frames.push(Frame {
+                pc,
+                func: Some(func),
+            });
+            pc = ret;
+        }
+        frames.push(Frame {
+            pc,
+            func: self.root,
+        });
+        Backtrace {
+            frames,
+            unwind: Unwind::ShadowStack,
+        }
+    }
+}
+
+/// Frames walked through the frame pointer, `read64` reads the stack
+pub fn frame_pointer_walk(pc: u64, fp: u64, read64: impl Fn(u64) -> Option<u64>) -> Backtrace {
+    let mut frames = vec![Frame { pc, func: None }];
+    let mut fp = fp;
+    while fp != 0 && fp.is_multiple_of(8) && frames.len() < MAX_FRAMES {
+        let (Some(ra), Some(prev_fp)) = (read64(fp.wrapping_sub(8)), read64(fp.wrapping_sub(16)))
+        else {
+            break;
+        };
+        if ra == 0 {
+            break;
+        }
+        frames.push(Frame { pc: ra, func: None });
+        // the caller's frame is above, a lower one is garbage
+        if prev_fp <= fp {
+            break;
+        }
+        fp = prev_fp;
+    }
+    Backtrace {
+        frames,
+        unwind: Unwind::FramePointer,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unwind {
+    ShadowStack,
+    FramePointer,
+}
+
+impl fmt::Display for Unwind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unwind::ShadowStack => write!(f, "shadow call stack"),
+            Unwind::FramePointer => write!(f, "frame pointers"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// PC of the innermost frame, the return address of the others
+    pub pc: u64,
+    /// Entry of the function, if known
+    pub func: Option<u64>,
+}
+
+impl Frame {
+    /// "foo+0x10" with the symbols, "0x80000000+0x10" with the function entry
+    pub fn describe(&self, symbols: Option<&SymbolMap>) -> String {
+        match (symbols.and_then(|s| s.lookup(self.pc)), self.func) {
+            (Some((name, 0)), _) => name.to_string(),
+            (Some((name, offset)), _) => format!("{name}+0x{offset:x}"),
+            (None, Some(func)) if func <= self.pc => format!("0x{func:x}+0x{:x}", self.pc - func),
+            (None, _) => "??".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backtrace {
+    /// From the innermost frame
+    pub frames: Vec<Frame>,
+    pub unwind: Unwind,
+}
+
+impl Backtrace {
+    /// A line per frame, e.g. "#1  0x0000000080000124 in main+0x24"
+    pub fn lines(&self, symbols: Option<&SymbolMap>) -> Vec<String> {
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(i, f)| format!("#{i:<2} 0x{:016x} in {}", f.pc, f.describe(symbols)))
+            .collect()
+    }
+}
+
+#[test]
+fn test_shadow_stack() {
+    use crate::elf::{ElfSymbol, STT_FUNC};
+
+    let (jal, ret, nop) = (0x008000ef, 0x00008067, 0x00000013);
+    let mut stack = ShadowStack::new();
+    stack.record(0x1000, nop, 0x1004);
+    stack.record(0x1004, jal, 0x2000); // main -> foo
+    stack.record(0x2000, 0x9782, 0x3000); // foo -> bar (c.jalr a5)
+    stack.record(0x3000, nop, 0x3004);
+    let bt = stack.backtrace(0x3004);
+    let pcs: Vec<_> = bt.frames.iter().map(|f| (f.pc, f.func)).collect();
+    assert_eq!(
+        pcs,
+        [
+            (0x3004, Some(0x3000)),
+            (0x2002, Some(0x2000)),
+            (0x1008, Some(0x1000))
+        ]
+    );
+    let symbols = SymbolMap::new(&[ElfSymbol {
+        name: "main".to_string(),
+        value: 0x1000,
+        size: 0x100,
+        sym_type: STT_FUNC,
+    }]);
+    let lines = bt.lines(Some(&symbols));
+    assert_eq!(lines[0], "#0  0x0000000000003004 in 0x3000+0x4");
+    assert_eq!(lines[2], "#2  0x0000000000001008 in main+0x8");
+    stack.record(0x3004, ret, 0x2002);
+    assert_eq!(stack.backtrace(0x2002).frames.len(), 2);
+    // a longjmp-like return drops all the frames above the target
+    stack.record(0x2002, jal, 0x3000);
+    stack.record(0x3000, ret, 0x1008);
+    assert_eq!(stack.backtrace(0x1008).frames.len(), 1);
+}
+
+#[test]
+fn test_frame_pointer_walk() {
+    use std::collections::HashMap;
+
+    // frame records: fp - 8 = ra, fp - 16 = caller's fp
+    let stack = HashMap::from([
+        (0x7ff8, 0x1008),
+        (0x7ff0, 0x8040),
+        (0x8038, 0x2010),
+        (0x8030, 0),
+    ]);
+    let bt = frame_pointer_walk(0x3004, 0x8000, |addr| stack.get(&addr).copied());
+    let pcs: Vec<_> = bt.frames.iter().map(|f| f.pc).collect();
+    assert_eq!(pcs, [0x3004, 0x1008, 0x2010]);
+    assert_eq!(bt.unwind, Unwind::FramePointer);
+    // unreadable stack
+    assert_eq!(frame_pointer_walk(0x3004, 0x100, |_| None).frames.len(), 1);
+}
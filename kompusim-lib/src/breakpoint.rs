@@ -0,0 +1,365 @@
+// PC breakpoints with a condition, an ignore count, a hit counter, enable/disable state and
+// one-shot (temporary) behaviour.
+// Conditions are expressions over registers and memory, e.g. "a0 == 0x42 && [sp+8] != 0":
+//   registers: x0 - x31, ABI names (zero, ra, sp, ..., fp) and pc
+//   [<expr>]: 8 bytes of memory at the virtual address
+//   numbers: decimal or hex with 0x
+//   operators with Rust precedence: unary - ! ~, * / %, + -, << >>, &, ^, |,
+//   == != < <= > >= (unsigned), && ||
+// The result is 64-bit, non-zero is true.
+
+use std::fmt;
+
+use crate::rv64i_cpu::RV64ICpu;
+use crate::rv64i_disasm::reg_idx2abi;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u64,
+    /// The breakpoint stops the CPU only if the condition is true
+    pub condition: Option<Condition>,
+    /// Number of the next hits which don't stop the CPU
+    pub ignore_count: u64,
+    /// Number of times the breakpoint was reached with the condition true
+    pub hit_count: u64,
+    pub enabled: bool,
+    /// Deleted when it stops the CPU
+    pub temporary: bool,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u64) -> Breakpoint {
+        Breakpoint {
+            addr,
+            condition: None,
+            ignore_count: 0,
+            hit_count: 0,
+            enabled: true,
+            temporary: false,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}", self.addr)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {condition}")?;
+        }
+        write!(f, ", hits: {}", self.hit_count)?;
+        if self.ignore_count > 0 {
+            write!(f, ", ignore next {}", self.ignore_count)?;
+        }
+        if self.temporary {
+            write!(f, ", temporary")?;
+        }
+        if !self.enabled {
+            write!(f, ", disabled")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expr(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {token:?}"));
+        }
+        Ok(Condition {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Evaluates the expression, fails on unmapped memory and division by zero
+    pub fn eval(&self, cpu: &RV64ICpu) -> Result<u64, String> {
+        self.expr.eval(cpu)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    BitAnd,
+    BitXor,
+    BitOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+/// Binary operators with their precedence, higher binds tighter
+const BIN_OPS: &[(&str, BinOp, u8)] = &[
+    ("*", BinOp::Mul, 9),
+    ("/", BinOp::Div, 9),
+    ("%", BinOp::Rem, 9),
+    ("+", BinOp::Add, 8),
+    ("-", BinOp::Sub, 8),
+    ("<<", BinOp::Shl, 7),
+    (">>", BinOp::Shr, 7),
+    ("&", BinOp::BitAnd, 6),
+    ("^", BinOp::BitXor, 5),
+    ("|", BinOp::BitOr, 4),
+    ("==", BinOp::Eq, 3),
+    ("!=", BinOp::Ne, 3),
+    ("<", BinOp::Lt, 3),
+    ("<=", BinOp::Le, 3),
+    (">", BinOp::Gt, 3),
+    (">=", BinOp::Ge, 3),
+    ("&&", BinOp::And, 2),
+    ("||", BinOp::Or, 1),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Num(u64),
+    Reg(u8),
+    Pc,
+    /// 8 bytes of memory at the address
+    Mem(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, cpu: &RV64ICpu) -> Result<u64, String> {
+        Ok(match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(i) => cpu.regs_r64(*i),
+            Expr::Pc => cpu.get_pc(),
+            Expr::Mem(addr) => {
+                let addr = addr.eval(cpu)?;
+                cpu.read_virt_u64(addr)
+                    .ok_or(format!("memory at 0x{addr:x} isn't accessible"))?
+            }
+            Expr::Unary(op, e) => {
+                let v = e.eval(cpu)?;
+                match op {
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::Not => (v == 0) as u64,
+                    UnOp::BitNot => !v,
+                }
+            }
+            // short-circuit evaluation, e.g. "sp != 0 && [sp] == 1"
+            Expr::Binary(BinOp::And, a, b) => (a.eval(cpu)? != 0 && b.eval(cpu)? != 0) as u64,
+            Expr::Binary(BinOp::Or, a, b) => (a.eval(cpu)? != 0 || b.eval(cpu)? != 0) as u64,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(cpu)?, b.eval(cpu)?);
+                match op {
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => a.checked_div(b).ok_or("division by zero")?,
+                    BinOp::Rem => a.checked_rem(b).ok_or("division by zero")?,
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+                    BinOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+                    BinOp::BitAnd => a & b,
+                    BinOp::BitXor => a ^ b,
+                    BinOp::BitOr => a | b,
+                    BinOp::Eq => (a == b) as u64,
+                    BinOp::Ne => (a != b) as u64,
+                    BinOp::Lt => (a < b) as u64,
+                    BinOp::Le => (a <= b) as u64,
+                    BinOp::Gt => (a > b) as u64,
+                    BinOp::Ge => (a >= b) as u64,
+                    BinOp::And | BinOp::Or => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(u64),
+    Ident(String),
+    /// Operator or bracket
+    Punct(&'static str),
+}
+
+/// Punctuation, longer first
+const PUNCTS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*", "/", "%", "+", "-", "&", "^", "|", "<",
+    ">", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let word_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if word_len > 0 {
+            let word = &rest[..word_len];
+            let num = if let Some(hex) = word.strip_prefix("0x") {
+                Some(u64::from_str_radix(hex, 16))
+            } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+                Some(word.parse())
+            } else {
+                None
+            };
+            tokens.push(match num {
+                Some(num) => Token::Num(num.map_err(|_| format!("wrong number {word}"))?),
+                None => Token::Ident(word.to_string()),
+            });
+            rest = &rest[word_len..];
+        } else if let Some(punct) = PUNCTS.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len()..];
+        } else {
+            return Err(format!("unexpected character in {rest}"));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn reg_index(name: &str) -> Option<u8> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(i) = name.strip_prefix('x').and_then(|i| i.parse::<u8>().ok()) {
+        return (i < 32).then_some(i);
+    }
+    (0..32).find(|&i| reg_idx2abi(i) == name)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == punct => Ok(()),
+            _ => Err(format!("expected {punct}")),
+        }
+    }
+
+    /// Binary operators with precedence above min_prec
+    fn expr(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Punct(p)) = self.tokens.get(self.pos) {
+            let Some(&(_, op, prec)) = BIN_OPS.iter().find(|(s, _, _)| s == p) else {
+                break;
+            };
+            if prec <= min_prec {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(prec)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.next().ok_or("unexpected end of the expression")?;
+        Ok(match token {
+            Token::Num(n) => Expr::Num(n),
+            Token::Ident(name) if name == "pc" => Expr::Pc,
+            Token::Ident(name) => {
+                Expr::Reg(reg_index(&name).ok_or(format!("unknown register {name}"))?)
+            }
+            Token::Punct("(") => {
+                let e = self.expr(0)?;
+                self.expect(")")?;
+                e
+            }
+            Token::Punct("[") => {
+                let e = self.expr(0)?;
+                self.expect("]")?;
+                Expr::Mem(Box::new(e))
+            }
+            Token::Punct("-") => Expr::Unary(UnOp::Neg, Box::new(self.unary()?)),
+            Token::Punct("!") => Expr::Unary(UnOp::Not, Box::new(self.unary()?)),
+            Token::Punct("~") => Expr::Unary(UnOp::BitNot, Box::new(self.unary()?)),
+            Token::Punct(p) => return Err(format!("unexpected {p}")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::ram::Ram;
+
+    fn eval(cpu: &RV64ICpu, s: &str) -> Result<u64, String> {
+        Condition::parse(s)?.eval(cpu)
+    }
+
+    #[test]
+    fn test_breakpoint_conditions() {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x8000_0000, 0x1000));
+        bus.write64(0x8000_0108, 7).unwrap();
+        let mut cpu = RV64ICpu::new(bus);
+        cpu.regs_w64(10, 0x42); // a0
+        cpu.regs_w64(2, 0x8000_0100); // sp
+        cpu.pc_jump(0x8000_0000);
+
+        assert_eq!(eval(&cpu, "a0 == 0x42 && [sp+8] != 0"), Ok(1));
+        assert_eq!(eval(&cpu, "x10 == 66 && [sp + 8] == 8"), Ok(0));
+        assert_eq!(eval(&cpu, "1 + 2 * 3 << 1"), Ok(14));
+        assert_eq!(
+            eval(&cpu, "(1 + 2) * 3 - pc"),
+            Ok(9u64.wrapping_sub(0x8000_0000))
+        );
+        assert_eq!(eval(&cpu, "a0 & 0xf0 == 0x40 || !sp"), Ok(1));
+        assert_eq!(eval(&cpu, "-1 > 0 && ~0 == -1"), Ok(1));
+        // short-circuit: the memory isn't read
+        assert_eq!(eval(&cpu, "zero && [0]"), Ok(0));
+        assert!(eval(&cpu, "[0]").is_err());
+        assert!(eval(&cpu, "a0 / zero").is_err());
+        assert!(Condition::parse("a0 ==").is_err());
+        assert!(Condition::parse("x32").is_err());
+        assert!(Condition::parse("(a0").is_err());
+        assert!(Condition::parse("a0 a1").is_err());
+        assert_eq!(
+            Condition::parse(" a0 == 1 ").unwrap().to_string(),
+            "a0 == 1"
+        );
+    }
+}
@@ -1,6 +1,8 @@
-use crate::device::Device;
+use crate::device::{Clock, Device, DmaMem};
 use crate::ram::Ram;
+use crate::snapshot::{DeviceState, RamState, StateReader, StateWriter};
 use core::fmt;
+use std::cell::Cell;
 use std::error::Error;
 
 #[derive(Debug)]
@@ -34,6 +36,13 @@ impl BusAgent {
         }
     }
 
+    pub fn read16(&self, addr: u64) -> u16 {
+        match self {
+            BusAgent::Ram(ram) => ram.read16(addr),
+            BusAgent::Device(dev) => dev.read16(addr),
+        }
+    }
+
     pub fn read32(&self, addr: u64) -> u32 {
         match self {
             BusAgent::Ram(ram) => ram.read32(addr),
@@ -55,6 +64,13 @@ impl BusAgent {
         }
     }
 
+    pub fn write16(&mut self, addr: u64, val: u16) {
+        match self {
+            BusAgent::Ram(ram) => ram.write16(addr, val),
+            BusAgent::Device(dev) => dev.write16(addr, val),
+        }
+    }
+
     pub fn write32(&mut self, addr: u64, val: u32) {
         match self {
             BusAgent::Ram(ram) => ram.write32(addr, val),
@@ -77,6 +93,41 @@ impl BusAgent {
     }
 }
 
+/// RAM regions of the bus visible to a device doing DMA
+struct RamDma<'a> {
+    rams: Vec<&'a mut Ram>,
+}
+
+impl DmaMem for RamDma<'_> {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), String> {
+        match self
+            .rams
+            .iter()
+            .find(|r| r.contains(addr, buf.len() as u64))
+        {
+            Some(ram) => {
+                ram.read_bytes(addr, buf);
+                Ok(())
+            }
+            None => Err(format!("DMA read fault @ 0x{addr:x}")),
+        }
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), String> {
+        match self
+            .rams
+            .iter_mut()
+            .find(|r| r.contains(addr, buf.len() as u64))
+        {
+            Some(ram) => {
+                ram.write_bytes(addr, buf);
+                Ok(())
+            }
+            None => Err(format!("DMA write fault @ 0x{addr:x}")),
+        }
+    }
+}
+
 struct AddrRegion {
     start: u64,
     end: u64,
@@ -87,6 +138,11 @@ struct AddrRegion {
 pub struct Bus {
     regions: Vec<AddrRegion>, // TODO: should be sorted
     ram_start: u64,
+    clock: Clock,
+    /// Hart local interrupts asserted by devices, updated after device writes and polls
+    hart_irqs: u64,
+    /// A device register was read, it could change interrupt state (e.g. PLIC claim)
+    irqs_dirty: Cell<bool>,
 }
 
 impl Bus {
@@ -96,16 +152,23 @@ impl Bus {
 
     #[allow(dead_code)]
     pub fn new_with_ram(start: u64, size: u64) -> Bus {
-        let ram = Ram::new(start, start + size);
+        let ram = Ram::new(start, size);
         let mut bus = Bus::new();
         bus.attach_ram(ram);
         bus
     }
 
     pub fn attach_ram(&mut self, ram: Ram) {
-        // for now and for simplicyt let's support only one RAM region
-        assert!(self.ram_start == 0);
-        self.ram_start = ram.start;
+        if self
+            .find_addr_region(ram.start, ram.end - ram.start)
+            .is_some()
+        {
+            panic!("address region is occupied")
+        }
+        // set_ram_sz() resizes the first RAM region
+        if self.ram_regions().is_empty() {
+            self.ram_start = ram.start;
+        }
         self.regions.push(AddrRegion {
             start: ram.start,
             end: ram.end,
@@ -129,7 +192,7 @@ impl Bus {
     }
 
     fn find_addr_region(&self, start: u64, size: u64) -> Option<&AddrRegion> {
-        let end = start + size;
+        let end = start.checked_add(size)?;
         // TODO: fast binary search
         // TODO: what if it crosses two regions?
         self.regions
@@ -137,6 +200,13 @@ impl Bus {
             .find(|&r| start >= r.start && end <= r.end)
     }
 
+    fn find_addr_region_idx(&self, start: u64, size: u64) -> Option<usize> {
+        let end = start.checked_add(size)?;
+        self.regions
+            .iter()
+            .position(|r| start >= r.start && end <= r.end)
+    }
+
     fn find_addr_region_mut(&mut self, start: u64, end: u64) -> Option<&mut AddrRegion> {
         // TODO: fast binary search
         // TODO: what if it crosses two regions?
@@ -145,56 +215,230 @@ impl Bus {
             .find(|r| start >= r.start && end <= r.end)
     }
 
-    /// Read byte
-    /// TODO: implement exception logic - return Error
-    pub fn read8(&self, addr: u64) -> u8 {
-        if let Some(ar) = self.find_addr_region(addr, 1) {
-            ar.agent.read8(addr)
-        } else {
-            panic!("ERR: read8 bus fault @ 0x{addr:x}");
+    /// Reads byte, None - nothing is mapped at the address (access fault)
+    pub fn read8(&self, addr: u64) -> Option<u8> {
+        let ar = self.find_addr_region(addr, 1)?;
+        self.note_device_access(ar);
+        Some(ar.agent.read8(addr))
+    }
+
+    /// Writes byte, None - nothing is mapped at the address (access fault)
+    pub fn write8(&mut self, addr: u64, val: u8) -> Option<()> {
+        let i = self.find_addr_region_idx(addr, 1)?;
+        self.regions[i].agent.write8(addr, val);
+        self.device_dma(i);
+        Some(())
+    }
+
+    // Little Endian 16 bit read
+    pub fn read16(&self, addr: u64) -> Option<u16> {
+        let ar = self.find_addr_region(addr, 2)?;
+        self.note_device_access(ar);
+        Some(ar.agent.read16(addr))
+    }
+
+    pub fn write16(&mut self, addr: u64, val: u16) -> Option<()> {
+        let i = self.find_addr_region_idx(addr, 2)?;
+        self.regions[i].agent.write16(addr, val);
+        self.device_dma(i);
+        Some(())
+    }
+
+    // Little Endian 32 bit read
+    pub fn read32(&self, addr: u64) -> Option<u32> {
+        let ar = self.find_addr_region(addr, 4)?;
+        self.note_device_access(ar);
+        Some(ar.agent.read32(addr))
+    }
+
+    pub fn read64(&self, addr: u64) -> Option<u64> {
+        let ar = self.find_addr_region(addr, 8)?;
+        self.note_device_access(ar);
+        Some(ar.agent.read64(addr))
+    }
+
+    pub fn write32(&mut self, addr: u64, val: u32) -> Option<()> {
+        let i = self.find_addr_region_idx(addr, 4)?;
+        self.regions[i].agent.write32(addr, val);
+        self.device_dma(i);
+        Some(())
+    }
+
+    pub fn write64(&mut self, addr: u64, val: u64) -> Option<()> {
+        let i = self.find_addr_region_idx(addr, 8)?;
+        self.regions[i].agent.write64(addr, val);
+        self.device_dma(i);
+        Some(())
+    }
+
+    /// Lets the device in region i access all RAM regions
+    fn device_dma(&mut self, i: usize) {
+        let (before, rest) = self.regions.split_at_mut(i);
+        let Some((region, after)) = rest.split_first_mut() else {
+            return;
+        };
+        if let BusAgent::Device(dev) = &mut region.agent {
+            let mut mem = RamDma {
+                rams: before
+                    .iter_mut()
+                    .chain(after.iter_mut())
+                    .filter_map(|r| match &mut r.agent {
+                        BusAgent::Ram(ram) => Some(ram),
+                        BusAgent::Device(_) => None,
+                    })
+                    .collect(),
+            };
+            dev.dev.dma(&mut mem);
+            self.update_irqs();
         }
     }
 
-    #[allow(dead_code)]
-    pub fn write8(&mut self, addr: u64, val: u8) {
-        if let Some(ar) = self.find_addr_region_mut(addr, 1) {
-            ar.agent.write8(addr, val)
-        } else {
-            panic!("ERR: write8 bus fault: 0x{addr:x}");
+    fn note_device_access(&self, region: &AddrRegion) {
+        if let BusAgent::Device(_) = region.agent {
+            self.irqs_dirty.set(true);
         }
     }
 
-    // Little Endian 32 bit read
-    pub fn read32(&self, addr: u64) -> u32 {
-        if let Some(ar) = self.find_addr_region(addr, 4) {
-            ar.agent.read32(addr)
-        } else {
-            panic!("ERR: read32 bus fault @ 0x{addr:x}");
+    fn update_irqs(&mut self) {
+        // levels of the device interrupt lines routed to interrupt controllers
+        let lines = self
+            .devices()
+            .filter(|dev| dev.dev.irq_pending())
+            .filter_map(|dev| dev.irq)
+            .filter(|irq| *irq < 64)
+            .fold(0_u64, |lines, irq| lines | 1 << irq);
+        for region in &mut self.regions {
+            if let BusAgent::Device(dev) = &mut region.agent {
+                dev.dev.set_irq_lines(lines);
+            }
         }
+        self.hart_irqs = self
+            .devices()
+            .fold(0, |irqs, dev| irqs | dev.dev.hart_irqs());
+        self.irqs_dirty.set(false);
     }
 
-    pub fn read64(&self, addr: u64) -> u64 {
-        if let Some(ar) = self.find_addr_region(addr, 8) {
-            ar.agent.read64(addr)
-        } else {
-            panic!("ERR: read64 bus fault @ 0x{addr:x}");
+    /// Re-evaluates interrupts if a device register was read since the last update
+    pub fn refresh_irqs(&mut self) {
+        if self.irqs_dirty.get() {
+            self.update_irqs();
         }
     }
 
-    #[allow(dead_code)]
-    pub fn write32(&mut self, addr: u64, val: u32) {
-        if let Some(ar) = self.find_addr_region_mut(addr, 4) {
-            ar.agent.write32(addr, val)
-        } else {
-            panic!("ERR: write32 bus fault @ 0x{addr:x}");
+    /// Hart local interrupts (mip layout) asserted by the devices
+    pub fn pending_irqs(&self) -> u64 {
+        self.hart_irqs
+    }
+
+    /// Platform time base shared with the timer devices
+    pub fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    /// Current platform time (mtime)
+    pub fn time(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Advances the platform time by one tick
+    pub fn tick(&self) {
+        self.clock.advance(1)
+    }
+
+    /// Returns (start, size) of every RAM region
+    pub fn ram_regions(&self) -> Vec<(u64, u64)> {
+        self.regions
+            .iter()
+            .filter(|r| matches!(r.agent, BusAgent::Ram(_)))
+            .map(|r| (r.start, r.end - r.start))
+            .collect()
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.regions.iter().filter_map(|r| match &r.agent {
+            BusAgent::Device(dev) => Some(dev),
+            BusAgent::Ram(_) => None,
+        })
+    }
+
+    /// Contents of the RAM regions for a snapshot
+    pub fn save_rams(&self) -> Vec<RamState> {
+        self.regions
+            .iter()
+            .filter_map(|r| match &r.agent {
+                BusAgent::Ram(ram) => Some(RamState {
+                    start: ram.start,
+                    data: ram.m.clone(),
+                }),
+                BusAgent::Device(_) => None,
+            })
+            .collect()
+    }
+
+    /// Device states for a snapshot
+    pub fn save_devices(&self) -> Vec<DeviceState> {
+        self.devices()
+            .map(|dev| {
+                let mut w = StateWriter::new();
+                dev.dev.save_state(&mut w);
+                DeviceState {
+                    start: dev.start,
+                    end: dev.end,
+                    state: w.into_bytes(),
+                }
+            })
+            .collect()
+    }
+
+    /// Restores RAM and devices saved by save_rams() and save_devices(), the layout (RAM
+    /// regions and device addresses) must be the same
+    pub fn restore_state(
+        &mut self,
+        rams: &[RamState],
+        devices: &[DeviceState],
+    ) -> Result<(), String> {
+        let layout: Vec<(u64, u64)> = rams
+            .iter()
+            .map(|ram| (ram.start, ram.data.len() as u64))
+            .collect();
+        if layout != self.ram_regions() {
+            return Err(format!(
+                "RAM regions {layout:x?} differ from the machine's {:x?}",
+                self.ram_regions()
+            ));
+        }
+        let layout: Vec<(u64, u64)> = devices.iter().map(|d| (d.start, d.end)).collect();
+        let machine: Vec<(u64, u64)> = self.devices().map(|d| (d.start, d.end)).collect();
+        if layout != machine {
+            return Err(format!(
+                "devices at {layout:x?} differ from the machine's {machine:x?}"
+            ));
         }
+        let mut rams = rams.iter();
+        let mut devices = devices.iter();
+        for region in &mut self.regions {
+            match &mut region.agent {
+                BusAgent::Ram(ram) => ram.m.copy_from_slice(&rams.next().unwrap().data),
+                BusAgent::Device(dev) => {
+                    let saved = devices.next().unwrap();
+                    let mut r = StateReader::new(&saved.state);
+                    dev.dev
+                        .restore_state(&mut r)
+                        .map_err(|e| format!("device at 0x{:x}: {e}", saved.start))?;
+                    if !r.is_empty() {
+                        return Err(format!("device at 0x{:x}: wrong state size", saved.start));
+                    }
+                }
+            }
+        }
+        self.update_irqs();
+        Ok(())
     }
 
-    pub fn write64(&mut self, addr: u64, val: u64) {
-        if let Some(ar) = self.find_addr_region_mut(addr, 8) {
-            ar.agent.write64(addr, val)
-        } else {
-            panic!("ERR: write64 bus fault @ 0x{addr:x}");
+    /// Gives all devices a chance to do DMA, e.g. to receive input
+    pub fn poll_devices(&mut self) {
+        for i in 0..self.regions.len() {
+            self.device_dma(i);
         }
     }
 
@@ -228,6 +472,20 @@ impl Bus {
         }))
     }
 
+    /// Copies data into RAM
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let end = addr.checked_add(data.len() as u64);
+        if let Some(ar) = end.and_then(|end| self.find_addr_region_mut(addr, end)) {
+            if let BusAgent::Ram(ram) = &mut ar.agent {
+                ram.write_bytes(addr, data);
+                return Ok(());
+            }
+        }
+        Err(Box::new(BusError {
+            details: format!("No RAM region for 0x{addr:x}..+0x{:x}", data.len()),
+        }))
+    }
+
     /// Loads a binary file image into ram
     pub fn load_file(
         &mut self,
@@ -248,30 +506,30 @@ impl Bus {
 #[test]
 pub fn test_ram_read_write() {
     let mut bus = Bus::new_with_ram(0, 4 * 1024);
-    assert!(bus.read8(0) == 0);
-    bus.write8(1, 0x55);
-    assert!(bus.read8(1) == 0x55)
+    assert!(bus.read8(0).unwrap() == 0);
+    bus.write8(1, 0x55).unwrap();
+    assert!(bus.read8(1).unwrap() == 0x55)
 }
 
 #[test]
 pub fn test_read32_le() {
     let mut bus = Bus::new_with_ram(0, 4 * 1024);
-    bus.write8(0, 0xef);
-    bus.write8(1, 0xbe);
-    bus.write8(2, 0xad);
-    bus.write8(3, 0xde);
-    let v: u32 = bus.read32(0);
+    bus.write8(0, 0xef).unwrap();
+    bus.write8(1, 0xbe).unwrap();
+    bus.write8(2, 0xad).unwrap();
+    bus.write8(3, 0xde).unwrap();
+    let v: u32 = bus.read32(0).unwrap();
     assert!(v == 0xdeadbeef);
 }
 
 #[test]
 pub fn test_write32_le() {
     let mut bus = Bus::new_with_ram(0, 4 * 1024);
-    bus.write32(0, 0x_dead_beef);
-    assert!(bus.read8(0) == 0xef);
-    assert!(bus.read8(1) == 0xbe);
-    assert!(bus.read8(2) == 0xad);
-    assert!(bus.read8(3) == 0xde);
+    bus.write32(0, 0x_dead_beef).unwrap();
+    assert!(bus.read8(0).unwrap() == 0xef);
+    assert!(bus.read8(1).unwrap() == 0xbe);
+    assert!(bus.read8(2).unwrap() == 0xad);
+    assert!(bus.read8(3).unwrap() == 0xde);
 }
 
 #[test]
@@ -279,6 +537,20 @@ fn test_load_image_from_static() {
     static BIN: &[u8] = &[0x55; 1024];
     let mut bus = Bus::new_with_ram(0, 4 * 1024);
     bus.load_image(0x4, BIN).unwrap();
-    assert!(bus.read32(0x4) == 0x5555_5555);
-    assert!(bus.read32(0x0) == 0x0000_0000);
+    assert!(bus.read32(0x4).unwrap() == 0x5555_5555);
+    assert!(bus.read32(0x0).unwrap() == 0x0000_0000);
+}
+
+#[test]
+fn test_dma_wrapping_range() {
+    let mut ram = Ram::new(0x8000_0000, 4 * 1024);
+    let mut dma = RamDma {
+        rams: vec![&mut ram],
+    };
+    let mut buf = [0; 16];
+    assert!(dma.read(0x8000_0ff0, &mut buf).is_ok());
+    // a descriptor address near the top wraps past the end of the address space
+    assert!(dma.read(u64::MAX - 7, &mut buf).is_err());
+    assert!(dma.write(u64::MAX - 7, &buf).is_err());
+    assert!(dma.read(0x8000_0ff8, &mut buf).is_err());
 }
@@ -0,0 +1,332 @@
+// Call-graph profiler. Calls and returns are recognized by the calling convention: JAL/JALR
+// writing the link register (ra or t0) is a call, JALR x0 through the link register is a return.
+// The executed instructions are counted per node of the call tree (a function reached through a
+// path of calls), from the tree come the inclusive/exclusive counts per function, the folded
+// stacks of the flame graph tools (flamegraph.pl, inferno, speedscope) and a callgrind file for
+// KCachegrind. Tail calls and trap handlers are counted in the function they're entered from.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::bits::BitOps;
+use crate::elf::SymbolMap;
+use crate::rvc_dec::instr_is_rvc;
+
+const RA: u32 = 1;
+const T0: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Call,
+    Return,
+    Other,
+}
+
+fn is_link(reg: u32) -> bool {
+    reg == RA || reg == T0
+}
+
+/// Is the instruction a call or a return per the calling convention (JAL/JALR hints)
+pub fn instr_flow(instr: u32) -> Flow {
+    if instr_is_rvc(instr) {
+        // c.jr / c.jalr (rs2 = 0 and rs1 != 0)
+        let rs1 = instr.bits(11, 7);
+        if instr.bits(1, 0) == 0b10
+            && instr.bits(15, 13) == 0b100
+            && instr.bits(6, 2) == 0
+            && rs1 != 0
+        {
+            if instr.bit(12) {
+                return Flow::Call;
+            } else if is_link(rs1) {
+                return Flow::Return;
+            }
+        }
+        return Flow::Other;
+    }
+    let (rd, rs1) = (instr.bits(11, 7), instr.bits(19, 15));
+    match instr.bits(6, 0) {
+        0b110_1111 if is_link(rd) => Flow::Call,
+        0b110_0111 if is_link(rd) => Flow::Call,
+        0b110_0111 if rd == 0 && is_link(rs1) => Flow::Return,
+        _ => Flow::Other,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    func: u64,
+    parent: usize,
+    children: HashMap<u64, usize>,
+    /// Instructions executed in the function itself
+    count: u64,
+    calls: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    /// Call tree, the first node is the root (the function of the first instruction)
+    nodes: Vec<Node>,
+    /// Nodes of the called functions and their return addresses
+    stack: Vec<(usize, u64)>,
+}
+
+impl CallGraph {
+    pub fn new() -> CallGraph {
+        CallGraph::default()
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |&(node, _)| node)
+    }
+
+    /// Counts the instruction executed at PC, `next_pc` is the PC after it
+    pub fn record(&mut self, pc: u64, instr: u32, next_pc: u64) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                func: pc,
+                parent: 0,
+                children: HashMap::new(),
+                count: 0,
+                calls: 1,
+            });
+        }
+        let cur = self.current();
+        self.nodes[cur].count += 1;
+        match instr_flow(instr) {
+            Flow::Call => {
+                let len = if instr_is_rvc(instr) { 2 } else { 4 };
+                let next = self.nodes.len();
+                let node = *self.nodes[cur].children.entry(next_pc).or_insert(next);
+                if node == next {
+                    self.nodes.push(Node {
+                        func: next_pc,
+                        parent: cur,
+                        children: HashMap::new(),
+                        count: 0,
+                        calls: 0,
+                    });
+                }
+                self.nodes[node].calls += 1;
+                self.stack.push((node, pc.wrapping_add(len)));
+            }
+            Flow::Return => {
+                // longjmp and the like skip frames, a return to an unknown address drops one
+                match self.stack.iter().rposition(|&(_, ret)| ret == next_pc) {
+                    Some(i) => self.stack.truncate(i),
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            }
+            Flow::Other => {}
+        }
+    }
+
+    /// Number of the counted instructions
+    pub fn total(&self) -> u64 {
+        self.nodes.iter().map(|n| n.count).sum()
+    }
+
+    /// Inclusive counts of the subtrees of the nodes
+    fn inclusive(&self) -> Vec<u64> {
+        let mut inclusive: Vec<u64> = self.nodes.iter().map(|n| n.count).collect();
+        // children are always created after their parents
+        for i in (1..self.nodes.len()).rev() {
+            inclusive[self.nodes[i].parent] += inclusive[i];
+        }
+        inclusive
+    }
+
+    fn name(func: u64, symbols: Option<&SymbolMap>) -> String {
+        symbols.map_or_else(|| format!("0x{func:x}"), |s| s.describe(func))
+    }
+
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Inclusive and exclusive counts per function, sorted by the inclusive counts
+    pub fn functions(&self, symbols: Option<&SymbolMap>) -> Vec<FunctionCount> {
+        let inclusive = self.inclusive();
+        let mut funcs: HashMap<u64, FunctionCount> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let f = funcs.entry(node.func).or_insert_with(|| FunctionCount {
+                name: CallGraph::name(node.func, symbols),
+                ..Default::default()
+            });
+            f.exclusive += node.count;
+            f.calls += node.calls;
+            // recursive calls are already in the inclusive count of the outer call
+            let path = self.path(i);
+            if !path[..path.len() - 1]
+                .iter()
+                .any(|&n| self.nodes[n].func == node.func)
+            {
+                f.inclusive += inclusive[i];
+            }
+        }
+        let mut funcs: Vec<_> = funcs.into_values().collect();
+        funcs.sort_by(|a, b| {
+            b.inclusive
+                .cmp(&a.inclusive)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        funcs
+    }
+
+    /// Folded stacks, a line per call path: "main;foo;bar 42"
+    pub fn folded(&self, symbols: Option<&SymbolMap>) -> String {
+        let mut lines: Vec<String> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].count != 0)
+            .map(|i| {
+                let names: Vec<_> = self
+                    .path(i)
+                    .iter()
+                    .map(|&n| CallGraph::name(self.nodes[n].func, symbols))
+                    .collect();
+                format!("{} {}\n", names.join(";"), self.nodes[i].count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    /// Callgrind file (positions are instruction addresses, the cost is at the function start)
+    pub fn callgrind(&self, symbols: Option<&SymbolMap>) -> String {
+        let inclusive = self.inclusive();
+        // callee -> (calls, inclusive count)
+        type Callees = HashMap<u64, (u64, u64)>;
+        // function -> (exclusive count, callees)
+        let mut funcs: HashMap<u64, (u64, Callees)> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            funcs.entry(node.func).or_default().0 += node.count;
+            if i != 0 {
+                let caller = self.nodes[node.parent].func;
+                let call = funcs
+                    .entry(caller)
+                    .or_default()
+                    .1
+                    .entry(node.func)
+                    .or_default();
+                call.0 += node.calls;
+                call.1 += inclusive[i];
+            }
+        }
+        let mut funcs: Vec<_> = funcs.into_iter().collect();
+        funcs.sort_by_key(|f| f.0);
+        let mut out = String::new();
+        out += "# callgrind format\nversion: 1\ncreator: kompusim\npositions: instr\n";
+        out += "events: Instructions\n";
+        let _ = writeln!(out, "summary: {}", self.total());
+        for (func, (count, callees)) in funcs {
+            let _ = writeln!(out, "\nfn={}", CallGraph::name(func, symbols));
+            let _ = writeln!(out, "0x{func:x} {count}");
+            let mut callees: Vec<_> = callees.into_iter().collect();
+            callees.sort_by_key(|c| c.0);
+            for (callee, (calls, inclusive)) in callees {
+                let _ = writeln!(out, "cfn={}", CallGraph::name(callee, symbols));
+                let _ = writeln!(out, "calls={calls} 0x{callee:x}");
+                let _ = writeln!(out, "0x{func:x} {inclusive}");
+            }
+        }
+        out
+    }
+}
+
+/// Counts of a function
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionCount {
+    pub name: String,
+    /// Instructions executed in the function and the functions called from it
+    pub inclusive: u64,
+    /// Instructions executed in the function itself
+    pub exclusive: u64,
+    pub calls: u64,
+}
+
+/// Text report with the top N functions
+pub fn report(functions: &[FunctionCount], total: u64, top: usize) -> String {
+    let mut report = format!(
+        "Top {top} functions:\n{:>12} {:>7} {:>12} {:>7} {:>10}\n",
+        "inclusive", "%", "exclusive", "%", "calls"
+    );
+    let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+    for f in &functions[..top.min(functions.len())] {
+        let _ = writeln!(
+            report,
+            "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>10}  {}",
+            f.inclusive,
+            percent(f.inclusive),
+            f.exclusive,
+            percent(f.exclusive),
+            f.calls,
+            f.name
+        );
+    }
+    report
+}
+
+#[test]
+fn test_instr_flow() {
+    assert_eq!(instr_flow(0x008000ef), Flow::Call); // jal ra,8
+    assert_eq!(instr_flow(0x000780e7), Flow::Call); // jalr ra,0(a5)
+    assert_eq!(instr_flow(0x00008067), Flow::Return); // ret
+    assert_eq!(instr_flow(0x0080006f), Flow::Other); // j 8
+    assert_eq!(instr_flow(0x00078067), Flow::Other); // jr a5
+    assert_eq!(instr_flow(0x9782), Flow::Call); // c.jalr a5
+    assert_eq!(instr_flow(0x8082), Flow::Return); // c.jr ra
+    assert_eq!(instr_flow(0x8782), Flow::Other); // c.jr a5
+    assert_eq!(instr_flow(0x9002), Flow::Other); // c.ebreak
+}
+
+#[test]
+fn test_call_graph() {
+    use crate::elf::{ElfSymbol, STT_FUNC};
+
+    let sym = |name: &str, value| ElfSymbol {
+        name: name.to_string(),
+        value,
+        size: 0x100,
+        sym_type: STT_FUNC,
+    };
+    let symbols = SymbolMap::new(&[sym("main", 0x1000), sym("foo", 0x2000), sym("bar", 0x3000)]);
+    let (jal, ret, nop) = (0x008000ef, 0x00008067, 0x00000013);
+    let mut cg = CallGraph::new();
+    // main calls foo twice, foo calls bar
+    for _ in 0..2 {
+        cg.record(0x1000, nop, 0x1004);
+        cg.record(0x1004, jal, 0x2000);
+        cg.record(0x2000, jal, 0x3000);
+        cg.record(0x3000, nop, 0x3004);
+        cg.record(0x3004, ret, 0x2004);
+        cg.record(0x2004, ret, 0x1008);
+    }
+    cg.record(0x1008, nop, 0x100c);
+    assert_eq!(cg.total(), 13);
+    let funcs = cg.functions(Some(&symbols));
+    let counts: Vec<_> = funcs
+        .iter()
+        .map(|f| (f.name.as_str(), f.inclusive, f.exclusive, f.calls))
+        .collect();
+    assert_eq!(
+        counts,
+        [("main", 13, 5, 1), ("foo", 8, 4, 2), ("bar", 4, 4, 2)]
+    );
+    assert_eq!(
+        cg.folded(Some(&symbols)),
+        "main 5\nmain;foo 4\nmain;foo;bar 4\n"
+    );
+    let callgrind = cg.callgrind(Some(&symbols));
+    assert!(callgrind.contains("\nfn=main\n0x1000 5\ncfn=foo\ncalls=2 0x2000\n0x1000 8\n"));
+    assert!(callgrind.contains("\nfn=bar\n0x3000 4\n"));
+    let report = report(&funcs, cg.total(), 1);
+    assert!(report.starts_with("Top 1 functions:\n"));
+    assert!(report.ends_with(" 38.46%          1  main\n"));
+}
@@ -0,0 +1,135 @@
+// Core Local Interruptor (SiFive CLINT compatible): machine software interrupt and timer of
+// a single hart. The timer counts ticks of the bus Clock.
+
+use crate::csr::{MIP_MSIP, MIP_MTIP};
+use crate::device::{Clock, Dev};
+use crate::fdt::{DtNode, DtNodeKind};
+use crate::snapshot::{StateReader, StateWriter};
+
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+const MSIP: u64 = 0x0000;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+pub struct Clint {
+    clock: Clock,
+    msip: u32,
+    mtimecmp: u64,
+}
+
+impl Clint {
+    pub fn new(clock: Clock) -> Clint {
+        Clint {
+            clock,
+            msip: 0,
+            // don't fire the timer until it's programmed
+            mtimecmp: u64::MAX,
+        }
+    }
+
+    fn read_reg(&self, addr: u64) -> u64 {
+        match addr & !0x7 {
+            MSIP => self.msip as u64,
+            MTIMECMP => self.mtimecmp,
+            MTIME => self.clock.now(),
+            _ => {
+                eprintln!("WARN: CLINT: read of unknown register 0x{addr:x}");
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, addr: u64, val: u64) {
+        match addr & !0x7 {
+            MSIP => self.msip = val as u32 & 1,
+            MTIMECMP => self.mtimecmp = val,
+            MTIME => self.clock.set(val),
+            _ => eprintln!("WARN: CLINT: write to unknown register 0x{addr:x}"),
+        }
+    }
+}
+
+// addr is local to the device, i.e bus_address - base_address
+impl Dev for Clint {
+    fn read8(&self, addr: u64) -> u8 {
+        (self.read_reg(addr) >> ((addr & 0x7) * 8)) as u8
+    }
+
+    fn write8(&mut self, addr: u64, _val: u8) {
+        eprintln!("WARN: CLINT: write8 to 0x{addr:x} ignored")
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        // 32-bit halves of 64-bit registers
+        (self.read_reg(addr) >> ((addr & 0x4) * 8)) as u32
+    }
+
+    fn read64(&self, addr: u64) -> u64 {
+        self.read_reg(addr)
+    }
+
+    fn write32(&mut self, addr: u64, val: u32) {
+        let shift = (addr & 0x4) * 8;
+        let old = self.read_reg(addr);
+        let new = (old & !(0xffff_ffff << shift)) | (val as u64) << shift;
+        self.write_reg(addr, new)
+    }
+
+    fn write64(&mut self, addr: u64, val: u64) {
+        self.write_reg(addr, val)
+    }
+
+    fn hart_irqs(&self) -> u64 {
+        let mut irqs = 0;
+        if self.msip & 1 != 0 {
+            irqs |= MIP_MSIP;
+        }
+        if self.clock.now() >= self.mtimecmp {
+            irqs |= MIP_MTIP;
+        }
+        irqs
+    }
+
+    fn dt_node(&self) -> Option<DtNode> {
+        Some(DtNode {
+            compatible: vec!["sifive,clint0", "riscv,clint0"],
+            kind: DtNodeKind::Clint,
+            ..DtNode::new("clint", "sifive,clint0")
+        })
+    }
+
+    // mtime is the bus Clock saved with the machine
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.msip);
+        w.u64(self.mtimecmp);
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.msip = r.u32()?;
+        self.mtimecmp = r.u64()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_clint_timer_and_soft_irq() {
+    let clock = Clock::default();
+    let mut clint = Clint::new(clock.clone());
+    assert_eq!(clint.hart_irqs(), 0);
+    clint.write32(MSIP, 1);
+    assert_eq!(clint.hart_irqs(), MIP_MSIP);
+    clint.write32(MSIP, 0);
+    // program mtimecmp with two 32-bit writes
+    clint.write32(MTIMECMP, 100);
+    clint.write32(MTIMECMP + 4, 0);
+    assert_eq!(clint.read64(MTIMECMP), 100);
+    clock.advance(99);
+    assert_eq!(clint.hart_irqs(), 0);
+    clock.advance(1);
+    assert_eq!(clint.hart_irqs(), MIP_MTIP);
+    assert_eq!(clint.read64(MTIME), 100);
+    clint.write64(MTIME, 5);
+    assert_eq!(clock.now(), 5);
+    assert_eq!(clint.hart_irqs(), 0);
+}
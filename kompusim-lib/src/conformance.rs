@@ -0,0 +1,215 @@
+// Conformance runner for precompiled riscv-tests and riscv-arch-test ELFs. Every test runs on a
+// fresh machine (RAM at the ELF segments and a CLINT) until it reports its status through HTIF
+// tohost. riscv-arch-test programs also have a signature region (begin_signature ..
+// end_signature) which is dumped as 32-bit hex words, one per line, and compared with the
+// reference signature file <test>.reference_output.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::elf::Elf;
+use crate::htif::{Htif, FROMHOST_OFFSET};
+use crate::machine::{DeviceKind, MachineBuilder};
+use crate::rv64i_cpu::{ExecEvent, RV64ICpu, WatchHit};
+
+const CLINT_BASE: u64 = 0x200_0000;
+/// RAM after the ELF segments, e.g. for the stack
+const EXTRA_RAM: u64 = 1 << 20;
+const REFERENCE_EXT: &str = "reference_output";
+
+pub enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+pub struct TestResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+    /// Number of executed instructions
+    pub instructions: u64,
+    /// The signature was compared with the reference
+    pub signature_checked: bool,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, Outcome::Pass)
+    }
+}
+
+/// Runs every ELF file found in dir and its subdirectories, sorted by path. Reference signatures
+/// are looked up in ref_dir, next to the ELF and in its references/ subdirectory.
+pub fn run_dir(
+    dir: &Path,
+    ref_dir: Option<&Path>,
+    max_instr: u64,
+) -> Result<Vec<TestResult>, String> {
+    let mut elfs = Vec::new();
+    find_elfs(dir, &mut elfs)?;
+    elfs.sort();
+    Ok(elfs
+        .into_iter()
+        .map(|path| run_test(&path, ref_dir, max_instr))
+        .collect())
+}
+
+fn find_elfs(dir: &Path, elfs: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("failed to read {dir:?}: {e}"))?;
+    for entry in entries {
+        let path = entry.map_err(|e| format!("{dir:?}: {e}"))?.path();
+        if path.is_dir() {
+            find_elfs(&path, elfs)?;
+        } else if is_elf(&path) {
+            elfs.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_elf(path: &Path) -> bool {
+    use std::io::Read;
+    let mut magic = [0; 4];
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == b"\x7fELF"
+}
+
+/// Runs one test, all errors and panics are reported as a failure of the test so a broken ELF
+/// doesn't stop the run
+pub fn run_test(path: &Path, ref_dir: Option<&Path>, max_instr: u64) -> TestResult {
+    let mut result = TestResult {
+        path: path.to_path_buf(),
+        outcome: Outcome::Pass,
+        instructions: 0,
+        signature_checked: false,
+    };
+    let run = panic::catch_unwind(AssertUnwindSafe(|| run(&mut result, ref_dir, max_instr)));
+    match run {
+        Ok(Ok(())) => {}
+        Ok(Err(reason)) => result.outcome = Outcome::Fail(reason),
+        Err(payload) => result.outcome = Outcome::Fail(panic_reason(payload.as_ref())),
+    }
+    result
+}
+
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown");
+    format!("panic: {msg}")
+}
+
+fn run(result: &mut TestResult, ref_dir: Option<&Path>, max_instr: u64) -> Result<(), String> {
+    let elf = Elf::from_file(&result.path)?;
+    let (start, end) = elf.vaddr_range().ok_or("no loadable segments")?;
+    let tohost = elf.symbol("tohost").ok_or("no tohost symbol")?;
+    let fromhost = elf.symbol("fromhost").unwrap_or(tohost + FROMHOST_OFFSET);
+    let ram_start = start & !0xfff;
+    let ram_size = (end - ram_start).next_multiple_of(0x1000) + EXTRA_RAM;
+    let mut cpu = MachineBuilder::new()
+        .ram(ram_start, ram_size)
+        .device(DeviceKind::Clint, CLINT_BASE, None)
+        .build()?;
+    elf.load(&mut cpu.bus)?;
+    cpu.pc_jump(elf.entry);
+    let htif = Htif::new(tohost, fromhost);
+    htif.check(&cpu.bus)?;
+    cpu.enable_htif(htif);
+
+    let event = cpu.exec_continue(max_instr);
+    result.instructions = cpu.get_num_exec_instr();
+    match event {
+        ExecEvent::Exit(0) => {}
+        ExecEvent::Exit(n) => return Err(format!("test {n} failed")),
+        ExecEvent::MaxInstructions(pc) => {
+            return Err(format!(
+                "no result after {max_instr} instructions, PC 0x{pc:x}"
+            ))
+        }
+        ExecEvent::Breakpoint(pc) | ExecEvent::Watchpoint(WatchHit { pc, .. }) => {
+            return Err(format!("stopped at 0x{pc:x}"))
+        }
+    }
+
+    let (Some(begin), Some(end)) = (elf.symbol("begin_signature"), elf.symbol("end_signature"))
+    else {
+        return Ok(());
+    };
+    let Some(reference) = find_reference(&result.path, ref_dir) else {
+        return Ok(());
+    };
+    let expected = std::fs::read_to_string(&reference)
+        .map_err(|e| format!("failed to read {reference:?}: {e}"))?;
+    result.signature_checked = true;
+    compare_signature(&signature(&cpu, begin, end)?, &expected)
+}
+
+/// <ref_dir>/<stem>.reference_output, <elf_dir>/<stem>.reference_output or
+/// <elf_dir>/references/<stem>.reference_output, stem is the file name without .elf
+fn find_reference(elf: &Path, ref_dir: Option<&Path>) -> Option<PathBuf> {
+    let name = elf.file_name()?.to_string_lossy();
+    let stem = name.strip_suffix(".elf").unwrap_or(&name);
+    let file = format!("{stem}.{REFERENCE_EXT}");
+    let elf_dir = elf.parent()?;
+    let dirs = [
+        ref_dir.map(Path::to_path_buf),
+        Some(elf_dir.to_path_buf()),
+        Some(elf_dir.join("references")),
+    ];
+    dirs.into_iter()
+        .flatten()
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+}
+
+/// 32-bit words of the signature region as lowercase hex, one per line
+pub fn signature(cpu: &RV64ICpu, begin: u64, end: u64) -> Result<Vec<String>, String> {
+    let mem = end
+        .checked_sub(begin)
+        .and_then(|size| cpu.bus.get_ram(begin, size))
+        .ok_or(format!("signature 0x{begin:x}..0x{end:x} isn't in RAM"))?;
+    Ok(mem
+        .chunks(4)
+        .map(|word| {
+            let mut bytes = [0; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            format!("{:08x}", u32::from_le_bytes(bytes))
+        })
+        .collect())
+}
+
+fn compare_signature(signature: &[String], expected: &str) -> Result<(), String> {
+    let expected: Vec<&str> = expected
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    for (i, (word, exp)) in signature.iter().zip(&expected).enumerate() {
+        if !word.eq_ignore_ascii_case(exp) {
+            return Err(format!(
+                "signature mismatch at word {i}: 0x{word}, expected 0x{exp}"
+            ));
+        }
+    }
+    if signature.len() != expected.len() {
+        return Err(format!(
+            "signature has {} words, expected {}",
+            signature.len(),
+            expected.len()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_compare_signature() {
+    let sig = vec!["00000001".to_string(), "deadbeef".to_string()];
+    assert!(compare_signature(&sig, "00000001\nDEADBEEF\n").is_ok());
+    let err = compare_signature(&sig, "00000001\ndeadbeee\n").unwrap_err();
+    assert!(err.contains("word 1"));
+    assert!(compare_signature(&sig, "00000001\n").is_err());
+}
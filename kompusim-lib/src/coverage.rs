@@ -0,0 +1,294 @@
+// Code coverage: which instructions were executed and which directions of the conditional
+// branches were taken. The counts come from the profiler (profile::Profiler), the instructions
+// never executed from the code of the ELF. Exported as a raw per-address listing and as lcov
+// tracefiles (genhtml, IDE plugins) with the lines from the DWARF line table.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::dwarf::LineTable;
+use crate::elf::{Elf, PF_X, STT_FUNC};
+use crate::profile::{instr_class, InstrClass, PcCount};
+use crate::rvc_dec::instr_is_rvc;
+
+/// Instructions of the executable segments: of the functions if the symbols have sizes,
+/// otherwise of the whole segments (data in them is decoded as instructions too)
+pub fn code_instructions(elf: &Elf) -> Vec<(u64, u32)> {
+    let funcs: Vec<_> = elf
+        .symbols
+        .iter()
+        .filter(|s| s.sym_type == STT_FUNC && s.size != 0)
+        .map(|s| (s.value, s.value + s.size))
+        .collect();
+    let mut instrs = Vec::new();
+    for seg in elf.load_segments().filter(|s| s.flags & PF_X != 0) {
+        let seg_end = seg.vaddr + seg.data.len() as u64;
+        let ranges: Vec<_> = if funcs.is_empty() {
+            vec![(seg.vaddr, seg_end)]
+        } else {
+            funcs
+                .iter()
+                .filter(|&&(start, end)| start >= seg.vaddr && end <= seg_end)
+                .copied()
+                .collect()
+        };
+        for (start, end) in ranges {
+            let mut addr = start;
+            while addr + 2 <= end {
+                let offs = (addr - seg.vaddr) as usize;
+                let mut instr = u16::from_le_bytes([seg.data[offs], seg.data[offs + 1]]) as u32;
+                let len = if instr_is_rvc(instr) { 2 } else { 4 };
+                if addr + len > end {
+                    break;
+                }
+                if len == 4 {
+                    instr |=
+                        (u16::from_le_bytes([seg.data[offs + 2], seg.data[offs + 3]]) as u32) << 16;
+                }
+                instrs.push((addr, instr));
+                addr += len;
+            }
+        }
+    }
+    instrs
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CoverageSummary {
+    pub instrs: usize,
+    pub covered_instrs: usize,
+    /// Two directions per conditional branch
+    pub branch_dirs: usize,
+    pub covered_branch_dirs: usize,
+}
+
+impl CoverageSummary {
+    /// e.g. "Coverage: 10/12 instructions (83.33%), 3/4 branch directions (75.00%)"
+    pub fn report(&self) -> String {
+        let percent = |n: usize, total: usize| n as f64 * 100.0 / total.max(1) as f64;
+        format!(
+            "Coverage: {}/{} instructions ({:.2}%), {}/{} branch directions ({:.2}%)",
+            self.covered_instrs,
+            self.instrs,
+            percent(self.covered_instrs, self.instrs),
+            self.covered_branch_dirs,
+            self.branch_dirs,
+            percent(self.covered_branch_dirs, self.branch_dirs)
+        )
+    }
+}
+
+/// Execution counts per instruction address
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pcs: BTreeMap<u64, PcCount>,
+}
+
+impl Coverage {
+    /// Coverage of the executed instructions, see profile::Profiler::pc_counts()
+    pub fn new(counts: &HashMap<u64, PcCount>) -> Coverage {
+        Coverage {
+            pcs: counts.iter().map(|(&pc, &count)| (pc, count)).collect(),
+        }
+    }
+
+    /// Adds the never executed instructions of the code
+    pub fn add_code(&mut self, instrs: &[(u64, u32)]) {
+        for &(addr, instr) in instrs {
+            self.pcs.entry(addr).or_insert(PcCount {
+                instr,
+                ..Default::default()
+            });
+        }
+    }
+
+    pub fn pcs(&self) -> &BTreeMap<u64, PcCount> {
+        &self.pcs
+    }
+
+    pub fn summary(&self) -> CoverageSummary {
+        let mut summary = CoverageSummary::default();
+        for count in self.pcs.values() {
+            summary.instrs += 1;
+            summary.covered_instrs += (count.count != 0) as usize;
+            if instr_class(count.instr) == InstrClass::Branch {
+                summary.branch_dirs += 2;
+                summary.covered_branch_dirs +=
+                    (count.taken != 0) as usize + (count.count > count.taken) as usize;
+            }
+        }
+        summary
+    }
+
+    /// A line per instruction: address, instruction, executions and, for the conditional
+    /// branches, the times taken and not taken
+    pub fn to_raw(&self) -> String {
+        let mut raw =
+            "# kompusim coverage\n# address instruction count [taken not-taken]\n".to_string();
+        for (pc, count) in &self.pcs {
+            let _ = write!(raw, "0x{pc:016x} 0x{:08x} {}", count.instr, count.count);
+            if instr_class(count.instr) == InstrClass::Branch {
+                let _ = write!(raw, " {} {}", count.taken, count.count - count.taken);
+            }
+            raw.push('\n');
+        }
+        raw
+    }
+
+    /// lcov tracefile: per source line the maximum executions of its instructions, the branch
+    /// directions (BRDA, a block per branch instruction of the line) and the function entries
+    pub fn to_lcov(&self, test_name: &str, lines: &LineTable, elf: &Elf) -> String {
+        #[derive(Default)]
+        struct Line {
+            count: u64,
+            /// Executions, taken count of the branches
+            branches: Vec<(u64, u64)>,
+        }
+        #[derive(Default)]
+        struct SourceFile<'a> {
+            lines: BTreeMap<u32, Line>,
+            /// Line, name and executions of the entry of the functions
+            funcs: Vec<(u32, &'a str, u64)>,
+        }
+        let mut files: BTreeMap<&str, SourceFile<'_>> = BTreeMap::new();
+        for (&pc, count) in &self.pcs {
+            let Some((file, line)) = lines.lookup(pc) else {
+                continue;
+            };
+            let l = files
+                .entry(file)
+                .or_default()
+                .lines
+                .entry(line)
+                .or_default();
+            l.count = l.count.max(count.count);
+            if instr_class(count.instr) == InstrClass::Branch {
+                l.branches.push((count.count, count.taken));
+            }
+        }
+        for sym in elf.symbols.iter().filter(|s| s.sym_type == STT_FUNC) {
+            if let Some((file, line)) = lines.lookup(sym.value) {
+                let calls = self.pcs.get(&sym.value).map_or(0, |c| c.count);
+                files
+                    .entry(file)
+                    .or_default()
+                    .funcs
+                    .push((line, &sym.name, calls));
+            }
+        }
+        let mut lcov = String::new();
+        for (file, SourceFile { lines, mut funcs }) in files {
+            let _ = writeln!(lcov, "TN:{test_name}\nSF:{file}");
+            funcs.sort();
+            for (line, name, _) in &funcs {
+                let _ = writeln!(lcov, "FN:{line},{name}");
+            }
+            for (_, name, calls) in &funcs {
+                let _ = writeln!(lcov, "FNDA:{calls},{name}");
+            }
+            let hit = funcs.iter().filter(|f| f.2 != 0).count();
+            let _ = writeln!(lcov, "FNF:{}\nFNH:{hit}", funcs.len());
+            let (mut brf, mut brh) = (0, 0);
+            for (line, l) in &lines {
+                for (block, &(count, taken)) in l.branches.iter().enumerate() {
+                    for (branch, n) in [taken, count - taken].into_iter().enumerate() {
+                        brf += 1;
+                        brh += (n != 0) as usize;
+                        // "-" - the branch was never executed
+                        let n = if count == 0 {
+                            "-".to_string()
+                        } else {
+                            n.to_string()
+                        };
+                        let _ = writeln!(lcov, "BRDA:{line},{block},{branch},{n}");
+                    }
+                }
+            }
+            let _ = writeln!(lcov, "BRF:{brf}\nBRH:{brh}");
+            for (line, l) in &lines {
+                let _ = writeln!(lcov, "DA:{line},{}", l.count);
+            }
+            let lh = lines.values().filter(|l| l.count != 0).count();
+            let _ = writeln!(lcov, "LF:{}\nLH:{lh}\nend_of_record", lines.len());
+        }
+        lcov
+    }
+}
+
+#[test]
+fn test_coverage() {
+    use crate::dwarf::LineRow;
+    use crate::elf::{ElfSegment, ElfSymbol, ET_EXEC, PF_R, PT_LOAD};
+    use crate::profile::Profiler;
+
+    // li t0,0x42; bnez t0,0x1000; ecall; c.nop
+    let code = [0x04200293u32, 0xfe029ee3, 0x00000073];
+    let mut data: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+    data.extend_from_slice(&[0x01, 0x00]);
+    let elf = Elf {
+        elf_type: ET_EXEC,
+        entry: 0x1000,
+        phoff: 0,
+        phentsize: 0,
+        phnum: 1,
+        segments: vec![ElfSegment {
+            p_type: PT_LOAD,
+            flags: PF_R | PF_X,
+            offset: 0,
+            vaddr: 0x1000,
+            paddr: 0x1000,
+            memsz: data.len() as u64,
+            data,
+        }],
+        symbols: vec![ElfSymbol {
+            name: "main".to_string(),
+            value: 0x1000,
+            size: 0xe,
+            sym_type: STT_FUNC,
+        }],
+        debug_sections: Vec::new(),
+    };
+    let instrs = code_instructions(&elf);
+    assert_eq!(instrs.len(), 4);
+    assert_eq!(instrs[3], (0x100c, 0x0001));
+
+    let mut profiler = Profiler::new();
+    for i in 0..10 {
+        profiler.record(0x1000, code[0], 0x1004);
+        profiler.record(0x1004, code[1], if i < 3 { 0x1000 } else { 0x1008 });
+    }
+    let mut coverage = Coverage::new(profiler.pc_counts());
+    coverage.add_code(&instrs);
+    let summary = coverage.summary();
+    assert_eq!((summary.instrs, summary.covered_instrs), (4, 2));
+    assert_eq!((summary.branch_dirs, summary.covered_branch_dirs), (2, 2));
+    assert!(summary
+        .report()
+        .starts_with("Coverage: 2/4 instructions (50.00%)"));
+
+    let raw = coverage.to_raw();
+    assert!(raw.contains("\n0x0000000000001004 0xfe029ee3 10 3 7\n"));
+    assert!(raw.ends_with("\n0x000000000000100c 0x00000001 0\n"));
+
+    let row = |addr, line, end_sequence| LineRow {
+        addr,
+        file: 0,
+        line,
+        end_sequence,
+    };
+    let lines = LineTable {
+        files: vec!["a.S".to_string()],
+        rows: vec![
+            row(0x1000, 1, false),
+            row(0x1004, 2, false),
+            row(0x1008, 3, false),
+            row(0x100e, 3, true),
+        ],
+    };
+    assert_eq!(
+        coverage.to_lcov("test", &lines, &elf),
+        "TN:test\nSF:a.S\nFN:1,main\nFNDA:10,main\nFNF:1\nFNH:1\n\
+         BRDA:2,0,0,3\nBRDA:2,0,1,7\nBRF:2\nBRH:2\n\
+         DA:1,10\nDA:2,10\nDA:3,0\nLF:3\nLH:2\nend_of_record\n"
+    );
+}
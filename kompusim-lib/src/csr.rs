@@ -1,43 +1,660 @@
+use crate::bits::BitOps;
+
 // trick with mod and use to disable rustfmt for the following defines
 #[rustfmt::skip]
 mod csr_defines {
-pub const MTVEC: u16    = 0x305; // Machine trap-handler base address.
-pub const MSCRATCH: u16 = 0x340; // Machine Scratch register for machine trap handlers.
-pub const MHARTID:u16   = 0xf14; // Machine Hardware Thread ID
+// Unprivileged Counter/Timers
+pub const CYCLE: u16         = 0xc00; // Cycle counter for RDCYCLE instruction.
+pub const TIME: u16          = 0xc01; // Timer for RDTIME instruction.
+pub const INSTRET: u16       = 0xc02; // Instructions-retired counter for RDINSTRET instruction.
+pub const HPMCOUNTER31: u16  = 0xc1f; // Last performance-monitoring counter.
+
+// Supervisor-level CSRs
+pub const SSTATUS: u16       = 0x100; // Supervisor status register.
+pub const SIE: u16           = 0x104; // Supervisor interrupt-enable register.
+pub const STVEC: u16         = 0x105; // Supervisor trap handler base address.
+pub const SCOUNTEREN: u16    = 0x106; // Supervisor counter enable.
+pub const SENVCFG: u16       = 0x10a; // Supervisor environment configuration register.
+pub const SSCRATCH: u16      = 0x140; // Scratch register for supervisor trap handlers.
+pub const SEPC: u16          = 0x141; // Supervisor exception program counter.
+pub const SCAUSE: u16        = 0x142; // Supervisor trap cause.
+pub const STVAL: u16         = 0x143; // Supervisor bad address or instruction.
+pub const SIP: u16           = 0x144; // Supervisor interrupt pending.
+pub const SATP: u16          = 0x180; // Supervisor address translation and protection.
+
+// Machine-level CSRs
+pub const MVENDORID: u16     = 0xf11; // Vendor ID.
+pub const MARCHID: u16       = 0xf12; // Architecture ID.
+pub const MIMPID: u16        = 0xf13; // Implementation ID.
+pub const MHARTID:u16        = 0xf14; // Machine Hardware Thread ID
+pub const MCONFIGPTR: u16    = 0xf15; // Pointer to configuration data structure.
+pub const MSTATUS: u16       = 0x300; // Machine status register.
+pub const MISA: u16          = 0x301; // ISA and extensions.
+pub const MEDELEG: u16       = 0x302; // Machine exception delegation register.
+pub const MIDELEG: u16       = 0x303; // Machine interrupt delegation register.
+pub const MIE: u16           = 0x304; // Machine interrupt-enable register.
+pub const MTVEC: u16         = 0x305; // Machine trap-handler base address.
+pub const MCOUNTEREN: u16    = 0x306; // Machine counter enable.
+pub const MENVCFG: u16       = 0x30a; // Machine environment configuration register.
+pub const MCOUNTINHIBIT: u16 = 0x320; // Machine counter-inhibit register.
+pub const MHPMEVENT3: u16    = 0x323; // Machine performance-monitoring event selector.
+pub const MHPMEVENT31: u16   = 0x33f; // Machine performance-monitoring event selector.
+pub const MSCRATCH: u16      = 0x340; // Machine Scratch register for machine trap handlers.
+pub const MEPC: u16          = 0x341; // Machine exception program counter.
+pub const MCAUSE: u16        = 0x342; // Machine trap cause.
+pub const MTVAL: u16         = 0x343; // Machine bad address or instruction.
+pub const MIP: u16           = 0x344; // Machine interrupt pending.
+pub const PMPCFG0: u16       = 0x3a0; // Physical memory protection configuration.
+pub const PMPCFG15: u16      = 0x3af; // Physical memory protection configuration.
+pub const PMPADDR0: u16      = 0x3b0; // Physical memory protection address register.
+pub const PMPADDR63: u16     = 0x3ef; // Physical memory protection address register.
+pub const MCYCLE: u16        = 0xb00; // Machine cycle counter.
+pub const MINSTRET: u16      = 0xb02; // Machine instructions-retired counter.
+pub const MHPMCOUNTER3: u16  = 0xb03; // Machine performance-monitoring counter.
+pub const MHPMCOUNTER31: u16 = 0xb1f; // Machine performance-monitoring counter.
+
+// mstatus/sstatus fields
+pub const MSTATUS_SIE: u64  = 1 << 1;
+pub const MSTATUS_MIE: u64  = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64  = 1 << 8;
+pub const MSTATUS_MPP: u64  = 0b11 << 11;
+pub const MSTATUS_FS: u64   = 0b11 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64  = 1 << 18;
+pub const MSTATUS_MXR: u64  = 1 << 19;
+pub const MSTATUS_TVM: u64  = 1 << 20;
+pub const MSTATUS_TW: u64   = 1 << 21;
+pub const MSTATUS_TSR: u64  = 1 << 22;
+pub const MSTATUS_UXL: u64  = 0b11 << 32;
+pub const MSTATUS_SXL: u64  = 0b11 << 34;
+
+// mip/mie bits
+pub const MIP_SSIP: u64 = 1 << IRQ_S_SOFT;
+pub const MIP_MSIP: u64 = 1 << IRQ_M_SOFT;
+pub const MIP_STIP: u64 = 1 << IRQ_S_TIMER;
+pub const MIP_MTIP: u64 = 1 << IRQ_M_TIMER;
+pub const MIP_SEIP: u64 = 1 << IRQ_S_EXT;
+pub const MIP_MEIP: u64 = 1 << IRQ_M_EXT;
+
+// Interrupt codes (mcause with MCAUSE_INTERRUPT set)
+pub const IRQ_S_SOFT: u64  = 1;
+pub const IRQ_M_SOFT: u64  = 3;
+pub const IRQ_S_TIMER: u64 = 5;
+pub const IRQ_M_TIMER: u64 = 7;
+pub const IRQ_S_EXT: u64   = 9;
+pub const IRQ_M_EXT: u64   = 11;
+
+// Exception codes (mcause)
+pub const EXC_INSTR_MISALIGNED: u64  = 0;
+pub const EXC_INSTR_ACCESS: u64      = 1;
+pub const EXC_ILLEGAL_INSTR: u64     = 2;
+pub const EXC_BREAKPOINT: u64        = 3;
+pub const EXC_LOAD_MISALIGNED: u64   = 4;
+pub const EXC_LOAD_ACCESS: u64       = 5;
+pub const EXC_STORE_MISALIGNED: u64  = 6;
+pub const EXC_STORE_ACCESS: u64      = 7;
+pub const EXC_ECALL_U: u64           = 8;
+pub const EXC_ECALL_S: u64           = 9;
+pub const EXC_ECALL_M: u64           = 11;
+pub const EXC_INSTR_PAGE_FAULT: u64  = 12;
+pub const EXC_LOAD_PAGE_FAULT: u64   = 13;
+pub const EXC_STORE_PAGE_FAULT: u64  = 15;
+
+pub const MCAUSE_INTERRUPT: u64 = 1 << 63;
 }
 pub use csr_defines::*;
 
-#[derive(Default)]
+/// Privilege levels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PrivMode {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl PrivMode {
+    fn from_bits(bits: u64) -> PrivMode {
+        match bits {
+            0 => PrivMode::User,
+            1 => PrivMode::Supervisor,
+            _ => PrivMode::Machine,
+        }
+    }
+}
+
+/// misa: MXL = 64 bit, extensions A, C, I, M, S, U
+const MISA_VAL: u64 = 2 << 62 | 1 << 0 | 1 << 2 | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+/// UXL and SXL are fixed to 64 bit
+const MSTATUS_XLEN64: u64 = 2 << 32 | 2 << 34;
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+const SSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL;
+const SSTATUS_WRITABLE: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+const SUPPORTED_IRQS: u64 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+/// Interrupts which can be delegated to S-mode and pending bits writable by software
+const S_IRQS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// All exceptions except ECALL from M-mode
+const DELEGABLE_EXCEPTIONS: u64 = 0xb3ff;
+/// Interrupts in the order of priority
+const IRQ_PRIORITY: [u64; 6] = [
+    IRQ_M_EXT,
+    IRQ_M_SOFT,
+    IRQ_M_TIMER,
+    IRQ_S_EXT,
+    IRQ_S_SOFT,
+    IRQ_S_TIMER,
+];
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+/// Values of CSRs provided by the rest of the hart and the platform
+#[derive(Clone, Copy, Default)]
+pub struct CsrInputs {
+    /// Number of retired instructions (also used as the cycle counter)
+    pub instret: u64,
+    /// Platform timer (mtime)
+    pub time: u64,
+    /// Interrupts pending from the platform devices (mip layout)
+    pub hw_irqs: u64,
+}
+
+#[derive(Clone)]
 pub struct Csrs {
+    /// Current privilege level of the hart
+    mode: PrivMode,
+    mstatus: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mie: u64,
+    /// Software writable part of mip, hardware interrupts come from CsrInputs
+    mip: u64,
     /// Machine trap-handler base address.
     mtvec: u64,
+    mcounteren: u64,
+    menvcfg: u64,
+    mcountinhibit: u64,
     /// Machine Scratch register for machine trap handlers.
     mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    pmpcfg: [u64; 8],
+    pmpaddr: [u64; 64],
+    stvec: u64,
+    scounteren: u64,
+    senvcfg: u64,
+    sscratch: u64,
+    sepc: u64,
+    scause: u64,
+    stval: u64,
+    satp: u64,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Csrs {
     pub fn new() -> Csrs {
         Csrs {
-            mscratch: 0,
+            mode: PrivMode::Machine,
+            mstatus: MSTATUS_XLEN64,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
             mtvec: 0,
+            mcounteren: 0,
+            menvcfg: 0,
+            mcountinhibit: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            pmpcfg: [0; 8],
+            pmpaddr: [0; 64],
+            stvec: 0,
+            scounteren: 0,
+            senvcfg: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
         }
     }
-    /// Read 64 bit
-    pub fn r64(&self, csr_a: u16) -> u64 {
-        match csr_a {
-            MTVEC => self.mtvec,
+
+    pub fn mode(&self) -> PrivMode {
+        self.mode
+    }
+
+    pub fn mstatus(&self) -> u64 {
+        self.mstatus
+    }
+
+    pub fn satp(&self) -> u64 {
+        self.satp
+    }
+
+    /// Privilege level of loads and stores: with mstatus.MPRV set M-mode accesses memory as
+    /// the mode in mstatus.MPP
+    pub fn data_mode(&self) -> PrivMode {
+        if self.mode == PrivMode::Machine && self.mstatus & MSTATUS_MPRV != 0 {
+            PrivMode::from_bits(self.mstatus.bits(12, 11))
+        } else {
+            self.mode
+        }
+    }
+
+    /// Checks privilege level, counter enables and trapping of satp accesses
+    fn accessible(&self, csr_a: u16) -> bool {
+        if (csr_a.bits(9, 8) as u8) > self.mode as u8 {
+            return false;
+        }
+        if (CYCLE..=HPMCOUNTER31).contains(&csr_a) {
+            let bit = (csr_a - CYCLE) as u32;
+            if self.mode < PrivMode::Machine && !self.mcounteren.bit(bit) {
+                return false;
+            }
+            if self.mode == PrivMode::User && !self.scounteren.bit(bit) {
+                return false;
+            }
+        }
+        if csr_a == SATP && self.mode == PrivMode::Supervisor && self.mstatus & MSTATUS_TVM != 0 {
+            return false;
+        }
+        true
+    }
+
+    /// Debugger access: reads the CSR with M-mode privilege
+    pub fn debug_r64(&self, csr_a: u16, inputs: CsrInputs) -> Option<u64> {
+        let mut csrs = self.clone();
+        csrs.mode = PrivMode::Machine;
+        csrs.r64(csr_a, inputs)
+    }
+
+    /// Raw state for snapshots: the privilege level ("mode") and the CSR storage by name
+    pub fn state(&self) -> Vec<(String, u64)> {
+        let mut csrs = self.clone();
+        let mut state = vec![("mode".to_string(), self.mode as u64)];
+        state.extend(
+            csrs.storage_mut()
+                .into_iter()
+                .map(|(name, val)| (name, *val)),
+        );
+        state
+    }
+
+    /// Restores a value of state(), false - unknown name
+    pub fn set_state(&mut self, name: &str, val: u64) -> bool {
+        if name == "mode" {
+            self.mode = PrivMode::from_bits(val);
+            return true;
+        }
+        match self.storage_mut().into_iter().find(|(n, _)| n == name) {
+            Some((_, storage)) => {
+                *storage = val;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn storage_mut(&mut self) -> Vec<(String, &mut u64)> {
+        let mut storage: Vec<(String, &mut u64)> = vec![
+            ("mstatus".to_string(), &mut self.mstatus),
+            ("medeleg".to_string(), &mut self.medeleg),
+            ("mideleg".to_string(), &mut self.mideleg),
+            ("mie".to_string(), &mut self.mie),
+            ("mip".to_string(), &mut self.mip),
+            ("mtvec".to_string(), &mut self.mtvec),
+            ("mcounteren".to_string(), &mut self.mcounteren),
+            ("menvcfg".to_string(), &mut self.menvcfg),
+            ("mcountinhibit".to_string(), &mut self.mcountinhibit),
+            ("mscratch".to_string(), &mut self.mscratch),
+            ("mepc".to_string(), &mut self.mepc),
+            ("mcause".to_string(), &mut self.mcause),
+            ("mtval".to_string(), &mut self.mtval),
+            ("stvec".to_string(), &mut self.stvec),
+            ("scounteren".to_string(), &mut self.scounteren),
+            ("senvcfg".to_string(), &mut self.senvcfg),
+            ("sscratch".to_string(), &mut self.sscratch),
+            ("sepc".to_string(), &mut self.sepc),
+            ("scause".to_string(), &mut self.scause),
+            ("stval".to_string(), &mut self.stval),
+            ("satp".to_string(), &mut self.satp),
+        ];
+        for (i, cfg) in self.pmpcfg.iter_mut().enumerate() {
+            storage.push((format!("pmpcfg{}", 2 * i), cfg));
+        }
+        for (i, addr) in self.pmpaddr.iter_mut().enumerate() {
+            storage.push((format!("pmpaddr{i}"), addr));
+        }
+        storage
+    }
+
+    /// Debugger access: writes the CSR with M-mode privilege
+    pub fn debug_w64(&mut self, csr_a: u16, val: u64) -> bool {
+        let mode = self.mode;
+        self.mode = PrivMode::Machine;
+        let written = self.w64(csr_a, val);
+        self.mode = mode;
+        written
+    }
+
+    /// Read 64 bit, None - CSR doesn't exist or isn't accessible in the current mode
+    pub fn r64(&self, csr_a: u16, inputs: CsrInputs) -> Option<u64> {
+        if !self.accessible(csr_a) {
+            return None;
+        }
+        Some(match csr_a {
+            CYCLE | INSTRET | MCYCLE | MINSTRET => inputs.instret,
+            TIME => inputs.time,
+            0xc03..=HPMCOUNTER31 | MHPMCOUNTER3..=MHPMCOUNTER31 | MHPMEVENT3..=MHPMEVENT31 => 0,
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SENVCFG => self.senvcfg,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => (self.mip | inputs.hw_irqs) & self.mideleg,
+            SATP => self.satp,
+            MVENDORID | MARCHID | MIMPID | MCONFIGPTR => 0,
             MHARTID => 0, // current cpu id
+            MSTATUS => self.mstatus,
+            MISA => MISA_VAL,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MENVCFG => self.menvcfg,
+            MCOUNTINHIBIT => self.mcountinhibit,
             MSCRATCH => self.mscratch,
-            _ => panic!("CSR: 0x{csr_a:x} is not implemented"),
-        }
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip | inputs.hw_irqs,
+            // only even pmpcfg registers exist in RV64
+            PMPCFG0..=PMPCFG15 if csr_a.is_multiple_of(2) => {
+                self.pmpcfg[(csr_a - PMPCFG0) as usize / 2]
+            }
+            PMPADDR0..=PMPADDR63 => self.pmpaddr[(csr_a - PMPADDR0) as usize],
+            _ => return None,
+        })
     }
-    /// Write 64 bit
-    pub fn w64(&mut self, csr_a: u16, val: u64) {
+
+    /// Write 64 bit, returns false if the CSR doesn't exist, is read-only or isn't accessible in
+    /// the current mode. Unsupported bits (WARL fields) are ignored.
+    pub fn w64(&mut self, csr_a: u16, val: u64) -> bool {
+        // csr[11:10] == 0b11 - read-only CSRs
+        if csr_a.bits(11, 10) == 0b11 || !self.accessible(csr_a) {
+            return false;
+        }
         match csr_a {
-            MTVEC => self.mtvec = val,
-            MHARTID => (), // ignore
+            // counters are not writable for now
+            MCYCLE | MINSTRET => (),
+            MHPMCOUNTER3..=MHPMCOUNTER31 | MHPMEVENT3..=MHPMEVENT31 => (),
+            SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_WRITABLE) | (val & SSTATUS_WRITABLE),
+            SIE => self.mie = (self.mie & !self.mideleg) | (val & self.mideleg),
+            STVEC => self.stvec = val, // reserved modes are treated as direct
+            SCOUNTEREN => self.scounteren = val & 0xffff_ffff,
+            SENVCFG => self.senvcfg = val & 1,
+            SSCRATCH => self.sscratch = val,
+            SEPC => self.sepc = val & !1,
+            SCAUSE => self.scause = val,
+            STVAL => self.stval = val,
+            SIP => {
+                let mask = MIP_SSIP & self.mideleg;
+                self.mip = (self.mip & !mask) | (val & mask)
+            }
+            SATP => {
+                // only Bare and Sv39 translation modes are supported, writes of other modes
+                // have no effect
+                if matches!(val >> 60, SATP_MODE_BARE | SATP_MODE_SV39) {
+                    self.satp = val
+                }
+            }
+            MSTATUS => {
+                let mut val = val;
+                // MPP = 2 is reserved, keep the previous value
+                if val & MSTATUS_MPP == 2 << 11 {
+                    val = (val & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP);
+                }
+                self.mstatus = (self.mstatus & !MSTATUS_WRITABLE) | (val & MSTATUS_WRITABLE);
+            }
+            MISA => (), // extensions can't be disabled
+            MEDELEG => self.medeleg = val & DELEGABLE_EXCEPTIONS,
+            MIDELEG => self.mideleg = val & S_IRQS,
+            MIE => self.mie = val & SUPPORTED_IRQS,
+            MTVEC => self.mtvec = val, // reserved modes are treated as direct
+            MCOUNTEREN => self.mcounteren = val & 0xffff_ffff,
+            MENVCFG => self.menvcfg = val & 1,
+            MCOUNTINHIBIT => self.mcountinhibit = val & 0xffff_fffd,
             MSCRATCH => self.mscratch = val,
-            _ => panic!("CSR: 0x{csr_a:x} is not implemented"),
+            MEPC => self.mepc = val & !1,
+            MCAUSE => self.mcause = val,
+            MTVAL => self.mtval = val,
+            MIP => self.mip = (self.mip & !S_IRQS) | (val & S_IRQS),
+            PMPCFG0..=PMPCFG15 if csr_a.is_multiple_of(2) => {
+                self.pmpcfg[(csr_a - PMPCFG0) as usize / 2] = val
+            }
+            PMPADDR0..=PMPADDR63 => self.pmpaddr[(csr_a - PMPADDR0) as usize] = val & PMPADDR_MASK,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sets or clears the software writable pending bits of S-mode interrupts, e.g. SSIP raised
+    /// by the built-in SBI
+    pub fn set_pending(&mut self, irqs: u64, pending: bool) {
+        if pending {
+            self.mip |= irqs & S_IRQS;
+        } else {
+            self.mip &= !(irqs & S_IRQS);
+        }
+    }
+
+    /// Returns the code of the interrupt to be taken now
+    pub fn pending_interrupt(&self, hw_irqs: u64) -> Option<u64> {
+        let pending = (self.mip | hw_irqs) & self.mie;
+        if pending == 0 {
+            return None;
+        }
+        let m_enabled = self.mode < PrivMode::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let s_enabled = self.mode < PrivMode::Supervisor
+            || (self.mode == PrivMode::Supervisor && self.mstatus & MSTATUS_SIE != 0);
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !self.mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & self.mideleg;
         }
+        IRQ_PRIORITY
+            .into_iter()
+            .find(|&irq| enabled.bit(irq as u32))
+    }
+
+    /// WFI completes when any enabled interrupt is pending (regardless of global enables)
+    pub fn wfi_wakeup(&self, hw_irqs: u64) -> bool {
+        (self.mip | hw_irqs) & self.mie != 0
+    }
+
+    pub fn wfi_is_legal(&self) -> bool {
+        match self.mode {
+            PrivMode::Machine => true,
+            PrivMode::Supervisor => self.mstatus & MSTATUS_TW == 0,
+            PrivMode::User => false,
+        }
+    }
+
+    pub fn sfence_vma_is_legal(&self) -> bool {
+        match self.mode {
+            PrivMode::Machine => true,
+            PrivMode::Supervisor => self.mstatus & MSTATUS_TVM == 0,
+            PrivMode::User => false,
+        }
+    }
+
+    /// Takes a trap (exception or interrupt if MCAUSE_INTERRUPT is set in cause) at pc. Returns
+    /// address of the trap handler.
+    pub fn trap(&mut self, pc: u64, cause: u64, tval: u64) -> u64 {
+        let interrupt = cause & MCAUSE_INTERRUPT != 0;
+        let code = cause & !MCAUSE_INTERRUPT;
+        let deleg = if interrupt {
+            self.mideleg
+        } else {
+            self.medeleg
+        };
+        if self.mode <= PrivMode::Supervisor && deleg.bit(code as u32) {
+            self.sepc = pc;
+            self.scause = cause;
+            self.stval = tval;
+            let mut status = self.mstatus & !(MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_SPP);
+            if self.mstatus & MSTATUS_SIE != 0 {
+                status |= MSTATUS_SPIE;
+            }
+            if self.mode == PrivMode::Supervisor {
+                status |= MSTATUS_SPP;
+            }
+            self.mstatus = status;
+            self.mode = PrivMode::Supervisor;
+            trap_vector(self.stvec, interrupt, code)
+        } else {
+            self.mepc = pc;
+            self.mcause = cause;
+            self.mtval = tval;
+            let mut status = self.mstatus & !(MSTATUS_MPIE | MSTATUS_MIE | MSTATUS_MPP);
+            if self.mstatus & MSTATUS_MIE != 0 {
+                status |= MSTATUS_MPIE;
+            }
+            status |= (self.mode as u64) << 11;
+            self.mstatus = status;
+            self.mode = PrivMode::Machine;
+            trap_vector(self.mtvec, interrupt, code)
+        }
+    }
+
+    /// Returns from M-mode trap, None - MRET is illegal in the current mode
+    pub fn mret(&mut self) -> Option<u64> {
+        if self.mode != PrivMode::Machine {
+            return None;
+        }
+        let mpp = PrivMode::from_bits(self.mstatus.bits(12, 11));
+        let mut status = self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP);
+        if self.mstatus & MSTATUS_MPIE != 0 {
+            status |= MSTATUS_MIE;
+        }
+        status |= MSTATUS_MPIE;
+        if mpp != PrivMode::Machine {
+            status &= !MSTATUS_MPRV;
+        }
+        self.mstatus = status;
+        self.mode = mpp;
+        Some(self.mepc)
+    }
+
+    /// Returns from S-mode trap, None - SRET is illegal in the current mode
+    pub fn sret(&mut self) -> Option<u64> {
+        if self.mode == PrivMode::User
+            || (self.mode == PrivMode::Supervisor && self.mstatus & MSTATUS_TSR != 0)
+        {
+            return None;
+        }
+        let spp = if self.mstatus & MSTATUS_SPP != 0 {
+            PrivMode::Supervisor
+        } else {
+            PrivMode::User
+        };
+        let mut status = self.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        if self.mstatus & MSTATUS_SPIE != 0 {
+            status |= MSTATUS_SIE;
+        }
+        status |= MSTATUS_SPIE;
+        self.mstatus = status;
+        self.mode = spp;
+        Some(self.sepc)
+    }
+}
+
+/// In vectored mode (tvec[1:0] = 1) interrupts jump to base + 4 * cause
+fn trap_vector(tvec: u64, interrupt: bool, code: u64) -> u64 {
+    let base = tvec & !0b11;
+    if interrupt && tvec & 0b11 == 1 {
+        base + 4 * code
+    } else {
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trap_delegation_and_return() {
+        let mut csrs = Csrs::new();
+        let inputs = CsrInputs::default();
+        assert!(csrs.w64(MTVEC, 0x8000_0000));
+        assert!(csrs.w64(STVEC, 0x8020_0001));
+        assert!(csrs.w64(MEDELEG, 1 << EXC_ECALL_U));
+        assert!(csrs.w64(MIDELEG, MIP_STIP));
+        // MPP = S, MPIE = 1
+        assert!(csrs.w64(MSTATUS, 1 << 11 | MSTATUS_MPIE));
+        assert!(!csrs.w64(MHARTID, 1));
+        assert_eq!(csrs.mret(), Some(0));
+        assert_eq!(csrs.mode(), PrivMode::Supervisor);
+        // M-mode CSRs aren't accessible in S-mode
+        assert_eq!(csrs.r64(MSTATUS, inputs), None);
+        assert_eq!(csrs.r64(SSTATUS, inputs), Some(2 << 32));
+        // counters are disabled by mcounteren
+        assert_eq!(csrs.r64(TIME, inputs), None);
+
+        // ECALL from S-mode isn't delegated
+        assert_eq!(csrs.trap(0x8020_0010, EXC_ECALL_S, 0), 0x8000_0000);
+        assert_eq!(csrs.mode(), PrivMode::Machine);
+        assert_eq!(csrs.r64(MEPC, inputs), Some(0x8020_0010));
+        assert_eq!(csrs.r64(MSTATUS, inputs).unwrap() & MSTATUS_MPP, 1 << 11);
+        assert!(csrs.w64(MCOUNTEREN, 0b111));
+        // M-mode firmware raises the supervisor timer interrupt
+        assert!(csrs.w64(MIP, MIP_STIP));
+        assert_eq!(csrs.mret(), Some(0x8020_0010));
+
+        // delegated timer interrupt goes to vectored stvec when SIE is set
+        assert!(csrs.w64(SIE, MIP_STIP));
+        assert_eq!(csrs.pending_interrupt(0), None);
+        assert!(csrs.w64(SSTATUS, MSTATUS_SIE));
+        assert_eq!(csrs.pending_interrupt(0), Some(IRQ_S_TIMER));
+        let cause = MCAUSE_INTERRUPT | IRQ_S_TIMER;
+        assert_eq!(csrs.trap(0x8020_0020, cause, 0), 0x8020_0000 + 4 * 5);
+        assert_eq!(csrs.r64(SCAUSE, inputs), Some(cause));
+        assert_eq!(
+            csrs.r64(SSTATUS, inputs).unwrap() & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SPIE | MSTATUS_SPP
+        );
+        assert_eq!(csrs.sret(), Some(0x8020_0020));
+        assert_eq!(csrs.mode(), PrivMode::Supervisor);
+        assert_eq!(csrs.r64(TIME, CsrInputs { time: 42, ..inputs }), Some(42));
     }
 }
@@ -1,3 +1,9 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::fdt::DtNode;
+use crate::snapshot::{StateReader, StateWriter};
+
 pub trait Dev {
     // addr is local to the device, i.e = PA - Device.start
     fn read8(&self, addr: u64) -> u8;
@@ -6,6 +12,103 @@ pub trait Dev {
     fn read64(&self, addr: u64) -> u64;
     fn write32(&mut self, addr: u64, val: u32);
     fn write64(&mut self, addr: u64, val: u64);
+
+    fn read16(&self, addr: u64) -> u16 {
+        u16::from_le_bytes([self.read8(addr), self.read8(addr + 1)])
+    }
+
+    fn write16(&mut self, addr: u64, val: u16) {
+        let [b0, b1] = val.to_le_bytes();
+        self.write8(addr, b0);
+        self.write8(addr + 1, b1);
+    }
+
+    /// Lets a device access the system memory (DMA). The bus calls it after every register write
+    /// to the device and on every device poll.
+    fn dma(&mut self, _mem: &mut dyn DmaMem) {}
+
+    /// Returns true if the device asserts its interrupt line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Hart local interrupts (mip layout, e.g. MTIP, MSIP) asserted by the device, e.g. a timer
+    fn hart_irqs(&self) -> u64 {
+        0
+    }
+
+    /// Interrupt controllers get levels of all device interrupt lines: bit N - line N
+    fn set_irq_lines(&mut self, _lines: u64) {}
+
+    /// Describes the device in the generated device tree, None - the device is not described
+    fn dt_node(&self) -> Option<DtNode> {
+        None
+    }
+
+    /// Saves the device state (registers, FIFOs) for a snapshot
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    /// Restores the state written by save_state()
+    fn restore_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// System memory as seen by a DMA capable device (e.g. virtio devices)
+pub trait DmaMem {
+    /// Reads buf.len() bytes at physical address addr
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), String>;
+    /// Writes buf at physical address addr
+    fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), String>;
+
+    // Little Endian 16-bit read
+    fn read16(&self, addr: u64) -> Result<u16, String> {
+        let mut b = [0; 2];
+        self.read(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    // Little Endian 32-bit read
+    fn read32(&self, addr: u64) -> Result<u32, String> {
+        let mut b = [0; 4];
+        self.read(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    // Little Endian 64-bit read
+    fn read64(&self, addr: u64) -> Result<u64, String> {
+        let mut b = [0; 8];
+        self.read(addr, &mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    // Little Endian 16-bit write
+    fn write16(&mut self, addr: u64, val: u16) -> Result<(), String> {
+        self.write(addr, &val.to_le_bytes())
+    }
+
+    // Little Endian 32-bit write
+    fn write32(&mut self, addr: u64, val: u32) -> Result<(), String> {
+        self.write(addr, &val.to_le_bytes())
+    }
+}
+
+/// Platform time base (mtime) shared by the CPU which advances it and the timer devices
+#[derive(Clone, Default)]
+pub struct Clock(Rc<Cell<u64>>);
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        self.0.get()
+    }
+
+    pub fn set(&self, time: u64) {
+        self.0.set(time)
+    }
+
+    pub fn advance(&self, ticks: u64) {
+        self.0.set(self.0.get().wrapping_add(ticks))
+    }
 }
 
 /// Device maintains absolute physical address.
@@ -13,6 +116,8 @@ pub struct Device {
     pub start: u64,
     pub end: u64,
     pub dev: Box<dyn Dev>,
+    /// Interrupt line number on the platform interrupt controller
+    pub irq: Option<u32>,
 }
 
 impl Device {
@@ -21,9 +126,15 @@ impl Device {
             start,
             end: start + size,
             dev: d,
+            irq: None,
         }
     }
 
+    pub fn with_irq(mut self, irq: u32) -> Device {
+        self.irq = Some(irq);
+        self
+    }
+
     pub fn read8(&self, addr: u64) -> u8 {
         self.dev.read8(addr - self.start)
     }
@@ -32,6 +143,14 @@ impl Device {
         self.dev.write8(addr - self.start, val)
     }
 
+    pub fn read16(&self, addr: u64) -> u16 {
+        self.dev.read16(addr - self.start)
+    }
+
+    pub fn write16(&mut self, addr: u64, val: u16) {
+        self.dev.write16(addr - self.start, val)
+    }
+
     pub fn read32(&self, addr: u64) -> u32 {
         self.dev.read32(addr - self.start)
     }
@@ -0,0 +1,471 @@
+// Minimal DWARF reader: the line number program (.debug_line) mapping addresses to source lines,
+// versions 2-5, 32- and 64-bit DWARF. Format: DWARF Debugging Information Format, section 6.2.
+
+use crate::elf::Elf;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// Little-endian reader of a section
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or("DWARF: unexpected end of .debug_line")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, String> {
+        let mut val = [0u8; 8];
+        val[..n].copy_from_slice(self.bytes(n)?);
+        Ok(u64::from_le_bytes(val))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let (mut val, mut shift) = (0u64, 0);
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let (mut val, mut shift) = (0i64, 0);
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Position len bytes after the current one, e.g. the end of a header
+    fn after(&self, len: u64, what: &str) -> Result<usize, String> {
+        usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|&end| end <= self.data.len())
+            .ok_or(format!("DWARF: {what} is out of the line table unit"))
+    }
+
+    fn cstr(&mut self) -> Result<String, String> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("DWARF: unterminated string")?;
+        self.pos += len + 1;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+/// NUL-terminated string at offset of a string section
+fn str_at(section: Option<&[u8]>, offset: u64) -> Result<String, String> {
+    let mut r = Reader {
+        data: section.ok_or("DWARF: no string section")?,
+        pos: offset as usize,
+    };
+    r.cstr()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineRow {
+    pub addr: u64,
+    /// Index in LineTable::files
+    pub file: usize,
+    pub line: u32,
+    /// The first address after a sequence of instructions
+    pub end_sequence: bool,
+}
+
+/// Address to source line mapping of all compilation units
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    /// Sorted by address
+    pub rows: Vec<LineRow>,
+}
+
+/// Header fields the line number program depends on
+struct Header {
+    min_instr_len: u64,
+    line_base: i64,
+    line_range: u8,
+    opcode_base: u8,
+    std_opcode_lengths: Vec<u8>,
+    /// Indices in LineTable::files of the file entries
+    files: Vec<usize>,
+}
+
+impl LineTable {
+    /// Line table of .debug_line of the ELF, None - there is no line info
+    pub fn from_elf(elf: &Elf) -> Result<Option<LineTable>, String> {
+        let Some(debug_line) = elf.debug_section(".debug_line") else {
+            return Ok(None);
+        };
+        let table = LineTable::parse(
+            debug_line,
+            elf.debug_section(".debug_line_str"),
+            elf.debug_section(".debug_str"),
+        )?;
+        Ok((!table.rows.is_empty()).then_some(table))
+    }
+
+    /// `line_str` and `str` are .debug_line_str and .debug_str for the strings of DWARF 5
+    pub fn parse(
+        debug_line: &[u8],
+        line_str: Option<&[u8]>,
+        str: Option<&[u8]>,
+    ) -> Result<LineTable, String> {
+        let mut table = LineTable::default();
+        let mut r = Reader {
+            data: debug_line,
+            pos: 0,
+        };
+        while r.pos < debug_line.len() {
+            let mut unit_len = r.uint(4)?;
+            let mut offset_size = 4;
+            if unit_len == 0xffff_ffff {
+                unit_len = r.uint(8)?;
+                offset_size = 8;
+            }
+            let unit_end = r
+                .pos
+                .checked_add(unit_len as usize)
+                .filter(|&end| end <= debug_line.len())
+                .ok_or("DWARF: line table unit is out of .debug_line")?;
+            let mut unit = Reader {
+                data: &debug_line[..unit_end],
+                pos: r.pos,
+            };
+            let header = table.parse_header(&mut unit, offset_size, line_str, str)?;
+            table.run_program(&mut unit, &header)?;
+            r.pos = unit_end;
+        }
+        // the end of a sequence goes before the start of the next one at the same address
+        table.rows.sort_by_key(|row| (row.addr, !row.end_sequence));
+        Ok(table)
+    }
+
+    fn file_index(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    fn parse_header(
+        &mut self,
+        r: &mut Reader<'_>,
+        offset_size: usize,
+        line_str: Option<&[u8]>,
+        str: Option<&[u8]>,
+    ) -> Result<Header, String> {
+        let version = r.uint(2)? as u16;
+        if !(2..=5).contains(&version) {
+            return Err(format!("DWARF: unsupported line table version {version}"));
+        }
+        if version >= 5 {
+            r.bytes(2)?; // address_size, segment_selector_size
+        }
+        let header_len = r.uint(offset_size)?;
+        let program = r.after(header_len, "header")?;
+        let min_instr_len = r.u8()? as u64;
+        if version >= 4 {
+            r.u8()?; // maximum_operations_per_instruction
+        }
+        r.u8()?; // default_is_stmt
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        if line_range == 0 {
+            return Err("DWARF: line_range is 0".to_string());
+        }
+        let std_opcode_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?.to_vec();
+        let mut files = Vec::new();
+        if version >= 5 {
+            let dirs = self.entries(r, offset_size, line_str, str)?;
+            let dirs: Vec<String> = dirs.into_iter().map(|(path, _)| path).collect();
+            for (path, dir) in self.entries(r, offset_size, line_str, str)? {
+                let dir = dirs.get(dir as usize).map_or("", |d| d.as_str());
+                files.push(self.file_index(join(dir, &path)));
+            }
+        } else {
+            let mut dirs = vec![String::new()];
+            loop {
+                let dir = r.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            // file numbers start at 1
+            files.push(usize::MAX);
+            loop {
+                let path = r.cstr()?;
+                if path.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                r.uleb()?; // modification time
+                r.uleb()?; // length
+                let dir = dirs.get(dir).map_or("", |d| d.as_str());
+                files.push(self.file_index(join(dir, &path)));
+            }
+        }
+        r.pos = program;
+        Ok(Header {
+            min_instr_len,
+            line_base,
+            line_range,
+            opcode_base,
+            std_opcode_lengths,
+            files,
+        })
+    }
+
+    /// DWARF 5 directory or file name entries: path and directory index
+    fn entries(
+        &mut self,
+        r: &mut Reader<'_>,
+        offset_size: usize,
+        line_str: Option<&[u8]>,
+        str: Option<&[u8]>,
+    ) -> Result<Vec<(String, u64)>, String> {
+        let n_formats = r.u8()?;
+        let mut formats = Vec::with_capacity(n_formats as usize);
+        for _ in 0..n_formats {
+            formats.push((r.uleb()?, r.uleb()?));
+        }
+        let count = r.uleb()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (mut path, mut dir) = (String::new(), 0);
+            for &(content, form) in &formats {
+                let (string, val) = match form {
+                    DW_FORM_STRING => (Some(r.cstr()?), 0),
+                    DW_FORM_LINE_STRP => (Some(str_at(line_str, r.uint(offset_size)?)?), 0),
+                    DW_FORM_STRP => (Some(str_at(str, r.uint(offset_size)?)?), 0),
+                    DW_FORM_UDATA => (None, r.uleb()?),
+                    DW_FORM_DATA1 => (None, r.uint(1)?),
+                    DW_FORM_DATA2 => (None, r.uint(2)?),
+                    DW_FORM_DATA4 => (None, r.uint(4)?),
+                    DW_FORM_DATA8 => (None, r.uint(8)?),
+                    DW_FORM_DATA16 => (None, r.bytes(16).map(|_| 0)?),
+                    DW_FORM_BLOCK => {
+                        let len = r.uleb()? as usize;
+                        (None, r.bytes(len).map(|_| 0)?)
+                    }
+                    _ => return Err(format!("DWARF: unsupported form 0x{form:x} in line table")),
+                };
+                match content {
+                    DW_LNCT_PATH => path = string.unwrap_or_default(),
+                    DW_LNCT_DIRECTORY_INDEX => dir = val,
+                    _ => {}
+                }
+            }
+            entries.push((path, dir));
+        }
+        Ok(entries)
+    }
+
+    /// Runs the line number state machine and appends the rows
+    fn run_program(&mut self, r: &mut Reader<'_>, h: &Header) -> Result<(), String> {
+        let (mut addr, mut file, mut line) = (0u64, 1, 1i64);
+        let mut files = h.files.clone();
+        while r.pos < r.data.len() {
+            let mut row = false;
+            let opcode = r.u8()?;
+            if opcode >= h.opcode_base {
+                let adj = (opcode - h.opcode_base) as u64;
+                addr = addr.wrapping_add(adj / h.line_range as u64 * h.min_instr_len);
+                line = line.wrapping_add(h.line_base + (adj % h.line_range as u64) as i64);
+                row = true;
+            } else if opcode == 0 {
+                let len = r.uleb()?;
+                let end = r.after(len, "extended opcode")?;
+                let len = len as usize;
+                match r.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        self.rows.push(LineRow {
+                            addr,
+                            file: files.get(file).copied().unwrap_or(usize::MAX),
+                            line: line as u32,
+                            end_sequence: true,
+                        });
+                        (addr, file, line) = (0, 1, 1);
+                    }
+                    DW_LNE_SET_ADDRESS => addr = r.uint(len.saturating_sub(1).min(8))?,
+                    DW_LNE_DEFINE_FILE => {
+                        let path = r.cstr()?;
+                        files.push(self.file_index(path));
+                    }
+                    _ => {}
+                }
+                r.pos = end;
+            } else {
+                match opcode {
+                    DW_LNS_COPY => row = true,
+                    DW_LNS_ADVANCE_PC => {
+                        let advance = r.uleb()?.checked_mul(h.min_instr_len);
+                        addr = addr.wrapping_add(advance.ok_or("DWARF: PC advance overflows")?);
+                    }
+                    DW_LNS_ADVANCE_LINE => line = line.wrapping_add(r.sleb()?),
+                    DW_LNS_SET_FILE => file = r.uleb()? as usize,
+                    DW_LNS_CONST_ADD_PC => {
+                        let adj = (255 - h.opcode_base) as u64;
+                        addr = addr.wrapping_add(adj / h.line_range as u64 * h.min_instr_len);
+                    }
+                    DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(r.uint(2)?),
+                    _ => {
+                        // the operands of the other standard opcodes are ULEB128s
+                        for _ in 0..h.std_opcode_lengths[opcode as usize - 1] {
+                            r.uleb()?;
+                        }
+                    }
+                }
+            }
+            if row {
+                self.rows.push(LineRow {
+                    addr,
+                    file: files.get(file).copied().unwrap_or(usize::MAX),
+                    line: line as u32,
+                    end_sequence: false,
+                });
+            }
+        }
+        // rows with unknown files
+        self.rows.retain(|row| row.file != usize::MAX);
+        Ok(())
+    }
+
+    /// Source file and line of the instruction at the address
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u32)> {
+        let i = self
+            .rows
+            .partition_point(|row| row.addr <= addr)
+            .checked_sub(1)?;
+        let row = &self.rows[i];
+        (!row.end_sequence).then(|| (self.files[row.file].as_str(), row.line))
+    }
+}
+
+fn join(dir: &str, path: &str) -> String {
+    if dir.is_empty() || path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{path}", dir.trim_end_matches('/'))
+    }
+}
+
+#[test]
+fn test_line_table() {
+    // DWARF 4 line table of two lines of "src/a.S" at 0x80000000 (4 bytes) and 0x80000004
+    let mut unit = vec![];
+    unit.extend_from_slice(&4u16.to_le_bytes()); // version
+    let header = [
+        &[1u8, 1, 1, (-5i8) as u8, 14, 13][..], // min_instr_len .. opcode_base
+        &[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1],  // standard_opcode_lengths
+        b"src\0\0",                             // include_directories
+        b"a.S\0\x01\0\0\0",                     // file_names
+    ]
+    .concat();
+    unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    unit.extend_from_slice(&header);
+    unit.extend_from_slice(&[0, 9, DW_LNE_SET_ADDRESS]);
+    unit.extend_from_slice(&0x8000_0000u64.to_le_bytes());
+    unit.extend_from_slice(&[DW_LNS_ADVANCE_LINE, 9, DW_LNS_COPY]); // line 10
+    unit.push(13 + 6 + 14 * 4); // special: addr += 4, line += 1
+    unit.extend_from_slice(&[DW_LNS_ADVANCE_PC, 4, 0, 1, DW_LNE_END_SEQUENCE]);
+    let mut debug_line = (unit.len() as u32).to_le_bytes().to_vec();
+    debug_line.extend_from_slice(&unit);
+
+    let table = LineTable::parse(&debug_line, None, None).unwrap();
+    assert_eq!(table.files, ["src/a.S"]);
+    assert_eq!(table.rows.len(), 3);
+    assert_eq!(table.lookup(0x8000_0000), Some(("src/a.S", 10)));
+    assert_eq!(table.lookup(0x8000_0006), Some(("src/a.S", 11)));
+    assert_eq!(table.lookup(0x8000_0008), None);
+    assert_eq!(table.lookup(0x7fff_fffc), None);
+    assert!(LineTable::parse(&debug_line[..debug_line.len() - 1], None, None).is_err());
+
+    let corrupt = |pos: usize, bytes: &[u8]| {
+        let mut data = debug_line.clone();
+        data[pos..pos + bytes.len()].copy_from_slice(bytes);
+        LineTable::parse(&data, None, None)
+    };
+    // header_len past the end of the unit
+    assert!(corrupt(6, &u32::MAX.to_le_bytes())
+        .is_err_and(|e| e.contains("header is out of the line table unit")));
+    // a truncated header
+    let truncated = corrupt(0, &(6 + 4u32).to_le_bytes());
+    assert!(truncated.is_err_and(|e| e.starts_with("DWARF: ")));
+    // the length of DW_LNE_set_address
+    let ext = 4 + 6 + header.len();
+    assert!(corrupt(
+        ext + 1,
+        &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+    )
+    .is_err_and(|e| e.contains("extended opcode")));
+    // min_instr_len 0xff times a huge advance
+    let advance_pc = debug_line.len() - 5;
+    let mut huge = debug_line.clone();
+    huge[10] = 0xff;
+    huge.splice(
+        advance_pc + 1..advance_pc + 2,
+        [0xff; 9].into_iter().chain([0x01]),
+    );
+    let unit_len = huge.len() as u32 - 4;
+    huge[..4].copy_from_slice(&unit_len.to_le_bytes());
+    assert!(LineTable::parse(&huge, None, None).is_err_and(|e| e.contains("PC advance")));
+}
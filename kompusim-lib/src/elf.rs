@@ -0,0 +1,442 @@
+// Minimal ELF64 little-endian reader for RISC-V executables: the header and loadable segments.
+// Format: System V ABI, "ELF-64 Object File Format" and the RISC-V ELF psABI.
+
+use std::path::Path;
+
+use crate::bus::Bus;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+/// Segment permissions (p_flags)
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHF_COMPRESSED: u64 = 0x800;
+
+/// Symbol types (low 4 bits of st_info)
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+
+pub struct ElfSegment {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    /// Size in memory, the part after data is zero-filled (.bss)
+    pub memsz: u64,
+    /// Contents from the file (filesz bytes)
+    pub data: Vec<u8>,
+}
+
+pub struct ElfSymbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub sym_type: u8,
+}
+
+pub struct Elf {
+    pub elf_type: u16,
+    pub entry: u64,
+    /// File offset, size and number of the program headers
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub segments: Vec<ElfSegment>,
+    /// Symbol table (.symtab), empty if the file is stripped
+    pub symbols: Vec<ElfSymbol>,
+    /// Uncompressed DWARF sections (.debug_line, ...) by name
+    pub debug_sections: Vec<(String, Vec<u8>)>,
+}
+
+fn u16_at(b: &[u8], offs: usize) -> u16 {
+    u16::from_le_bytes(b[offs..offs + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], offs: usize) -> u32 {
+    u32::from_le_bytes(b[offs..offs + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], offs: usize) -> u64 {
+    u64::from_le_bytes(b[offs..offs + 8].try_into().unwrap())
+}
+
+/// Slice of the file at offset, Err if it's out of the file
+fn file_range<'a>(image: &'a [u8], offset: u64, size: u64, what: &str) -> Result<&'a [u8], String> {
+    offset
+        .checked_add(size)
+        .filter(|&end| end <= image.len() as u64)
+        .map(|end| &image[offset as usize..end as usize])
+        .ok_or_else(|| format!("ELF: {what} at 0x{offset:x}+0x{size:x} is out of the file"))
+}
+
+/// NUL-terminated string at offset of the string table
+fn str_at(strtab: &[u8], offs: usize) -> String {
+    let bytes = strtab.get(offs..).unwrap_or_default();
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Section headers, empty if there are none
+fn section_headers(image: &[u8]) -> Result<Vec<&[u8]>, String> {
+    let shoff = u64_at(image, 40);
+    let shentsize = u16_at(image, 58) as u64;
+    let shnum = u16_at(image, 60) as u64;
+    if shoff == 0 || shnum == 0 {
+        return Ok(Vec::new());
+    }
+    if (shentsize as usize) < SHDR_SIZE {
+        return Err(format!("ELF: wrong section header size {shentsize}"));
+    }
+    let shdrs = file_range(image, shoff, shentsize * shnum, "section headers")?;
+    Ok(shdrs.chunks_exact(shentsize as usize).collect())
+}
+
+/// Symbols of the SHT_SYMTAB section with names from its string table (sh_link)
+fn parse_symbols(image: &[u8]) -> Result<Vec<ElfSymbol>, String> {
+    let shdrs = section_headers(image)?;
+    let Some(symtab) = shdrs.iter().find(|sh| u32_at(sh, 4) == SHT_SYMTAB) else {
+        return Ok(Vec::new());
+    };
+    let strtab_idx = u32_at(symtab, 40) as usize;
+    let Some(strtab_sh) = shdrs.get(strtab_idx) else {
+        return Err(format!("ELF: wrong string table index {strtab_idx}"));
+    };
+    let strtab = file_range(
+        image,
+        u64_at(strtab_sh, 24),
+        u64_at(strtab_sh, 32),
+        "strtab",
+    )?;
+    let syms = file_range(image, u64_at(symtab, 24), u64_at(symtab, 32), "symtab")?;
+    Ok(syms
+        .chunks_exact(SYM_SIZE)
+        .skip(1) // the undefined symbol
+        .map(|sym| ElfSymbol {
+            name: str_at(strtab, u32_at(sym, 0) as usize),
+            value: u64_at(sym, 8),
+            size: u64_at(sym, 16),
+            sym_type: sym[4] & 0xf,
+        })
+        .collect())
+}
+
+/// .debug_* sections with names from the section name string table (e_shstrndx)
+fn parse_debug_sections(image: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let shdrs = section_headers(image)?;
+    let Some(shstrtab_sh) = shdrs.get(u16_at(image, 62) as usize) else {
+        return Ok(Vec::new());
+    };
+    let shstrtab = file_range(
+        image,
+        u64_at(shstrtab_sh, 24),
+        u64_at(shstrtab_sh, 32),
+        "shstrtab",
+    )?;
+    let mut sections = Vec::new();
+    for sh in shdrs {
+        let name = str_at(shstrtab, u32_at(sh, 0) as usize);
+        if !name.starts_with(".debug_")
+            || u32_at(sh, 4) == SHT_NOBITS
+            || u64_at(sh, 8) & SHF_COMPRESSED != 0
+        {
+            continue;
+        }
+        let data = file_range(image, u64_at(sh, 24), u64_at(sh, 32), &name)?;
+        sections.push((name, data.to_vec()));
+    }
+    Ok(sections)
+}
+
+impl Elf {
+    pub fn parse(image: &[u8]) -> Result<Elf, String> {
+        if image.len() < EHDR_SIZE || &image[0..4] != ELF_MAGIC {
+            return Err("not an ELF file".to_string());
+        }
+        if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB {
+            return Err("ELF: only 64-bit little-endian files are supported".to_string());
+        }
+        let machine = u16_at(image, 18);
+        if machine != EM_RISCV {
+            return Err(format!("ELF: machine {machine} isn't RISC-V"));
+        }
+        let phoff = u64_at(image, 32);
+        let phentsize = u16_at(image, 54);
+        let phnum = u16_at(image, 56);
+        if phnum != 0 && (phentsize as usize) < PHDR_SIZE {
+            return Err(format!("ELF: wrong program header size {phentsize}"));
+        }
+        let phdrs = file_range(
+            image,
+            phoff,
+            phentsize as u64 * phnum as u64,
+            "program headers",
+        )?;
+        let mut segments = Vec::with_capacity(phnum as usize);
+        for phdr in phdrs.chunks_exact(phentsize.max(1) as usize) {
+            let offset = u64_at(phdr, 8);
+            let filesz = u64_at(phdr, 32);
+            let memsz = u64_at(phdr, 40);
+            if filesz > memsz {
+                return Err(format!("ELF: segment at 0x{offset:x} filesz > memsz"));
+            }
+            segments.push(ElfSegment {
+                p_type: u32_at(phdr, 0),
+                flags: u32_at(phdr, 4),
+                offset,
+                vaddr: u64_at(phdr, 16),
+                paddr: u64_at(phdr, 24),
+                memsz,
+                data: file_range(image, offset, filesz, "segment")?.to_vec(),
+            });
+        }
+        Ok(Elf {
+            elf_type: u16_at(image, 16),
+            entry: u64_at(image, 24),
+            phoff,
+            phentsize,
+            phnum,
+            segments,
+            symbols: parse_symbols(image)?,
+            debug_sections: parse_debug_sections(image)?,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Elf, String> {
+        let image = std::fs::read(path).map_err(|e| format!("failed to read {path:?}: {e}"))?;
+        Elf::parse(&image).map_err(|e| format!("{path:?}: {e}"))
+    }
+
+    /// PT_LOAD segments
+    pub fn load_segments(&self) -> impl Iterator<Item = &ElfSegment> {
+        self.segments.iter().filter(|s| s.p_type == PT_LOAD)
+    }
+
+    /// Dynamically linked executables have an interpreter (the dynamic linker)
+    pub fn is_dynamic(&self) -> bool {
+        self.segments.iter().any(|s| s.p_type == PT_INTERP)
+    }
+
+    /// Virtual address range [start, end) covered by the loadable segments
+    pub fn vaddr_range(&self) -> Option<(u64, u64)> {
+        let start = self.load_segments().map(|s| s.vaddr).min()?;
+        let end = self.load_segments().map(|s| s.vaddr + s.memsz).max()?;
+        Some((start, end))
+    }
+
+    /// Virtual address of the program headers (for AT_PHDR of the auxiliary vector)
+    pub fn phdr_vaddr(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments.iter().find(|s| s.p_type == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.load_segments()
+            .find(|s| (s.offset..s.offset + s.data.len() as u64).contains(&self.phoff))
+            .map(|s| s.vaddr + (self.phoff - s.offset))
+    }
+
+    /// Contents of the DWARF section, e.g. ".debug_line"
+    pub fn debug_section(&self, name: &str) -> Option<&[u8]> {
+        self.debug_sections
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// Address of the symbol
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.value)
+    }
+
+    /// Copies the loadable segments to memory at their virtual addresses and zero-fills .bss
+    pub fn load(&self, bus: &mut Bus) -> Result<(), String> {
+        for seg in self.load_segments() {
+            let mut contents = seg.data.clone();
+            contents.resize(seg.memsz as usize, 0);
+            bus.write_bytes(seg.vaddr, &contents)
+                .map_err(|e| format!("ELF segment at 0x{:x}: {e}", seg.vaddr))?;
+        }
+        Ok(())
+    }
+}
+
+/// Code symbols (functions and assembly labels) sorted by address to find the symbol of a PC
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolMap {
+    /// Address, size (0 - up to the next symbol) and name
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl SymbolMap {
+    pub fn new(symbols: &[ElfSymbol]) -> SymbolMap {
+        let mut symbols: Vec<_> = symbols
+            .iter()
+            .filter(|s| matches!(s.sym_type, STT_FUNC | STT_NOTYPE))
+            // local labels and mapping symbols ($x, $d)
+            .filter(|s| !s.name.is_empty() && !s.name.starts_with(['.', '$']))
+            .map(|s| (s.value, s.size, s.name.clone()))
+            .collect();
+        symbols.sort();
+        SymbolMap { symbols }
+    }
+
+    pub fn from_file(path: &Path) -> Result<SymbolMap, String> {
+        Ok(SymbolMap::new(&Elf::from_file(path)?.symbols))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Symbol containing the address and the offset in it
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let i = self
+            .symbols
+            .partition_point(|s| s.0 <= addr)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.symbols[i];
+        (*size == 0 || addr - start < *size).then_some((name.as_str(), addr - start))
+    }
+
+    /// "main+0x10" or the address in hex
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{name}+0x{offset:x}"),
+            None => format!("0x{addr:x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+
+    /// ELF with one PT_LOAD segment (the code is followed by 0x10 bytes of .bss) and a symbol
+    /// table with "tohost"
+    fn test_elf() -> Vec<u8> {
+        let code = [0x13u8, 0, 0, 0, 0x73, 0, 0, 0];
+        let mut elf = vec![0u8; EHDR_SIZE + PHDR_SIZE];
+        elf[0..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        elf[24..32].copy_from_slice(&0x1_0078u64.to_le_bytes());
+        elf[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        elf[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let ph = EHDR_SIZE;
+        let filesz = (EHDR_SIZE + PHDR_SIZE + code.len()) as u64;
+        elf[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        elf[ph + 4..ph + 8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        elf[ph + 16..ph + 24].copy_from_slice(&0x1_0000u64.to_le_bytes());
+        elf[ph + 32..ph + 40].copy_from_slice(&filesz.to_le_bytes());
+        elf[ph + 40..ph + 48].copy_from_slice(&(filesz + 0x10).to_le_bytes());
+        elf.extend_from_slice(&code);
+
+        // .strtab, .symtab with "tohost" and the section headers: NULL, .symtab, .strtab
+        let strtab = b"\0tohost\0";
+        let strtab_off = elf.len() as u64;
+        elf.extend_from_slice(strtab);
+        let symtab_off = elf.len() as u64;
+        elf.extend_from_slice(&[0u8; SYM_SIZE]);
+        let mut sym = [0u8; SYM_SIZE];
+        sym[0..4].copy_from_slice(&1u32.to_le_bytes());
+        sym[4] = STT_OBJECT;
+        sym[8..16].copy_from_slice(&0x1_0080u64.to_le_bytes());
+        sym[16..24].copy_from_slice(&8u64.to_le_bytes());
+        elf.extend_from_slice(&sym);
+        let shoff = elf.len() as u64;
+        elf.extend_from_slice(&[0u8; SHDR_SIZE * 3]);
+        let sh = shoff as usize + SHDR_SIZE;
+        elf[sh + 4..sh + 8].copy_from_slice(&SHT_SYMTAB.to_le_bytes());
+        elf[sh + 24..sh + 32].copy_from_slice(&symtab_off.to_le_bytes());
+        elf[sh + 32..sh + 40].copy_from_slice(&(2 * SYM_SIZE as u64).to_le_bytes());
+        elf[sh + 40..sh + 44].copy_from_slice(&2u32.to_le_bytes());
+        let sh = sh + SHDR_SIZE;
+        elf[sh + 4..sh + 8].copy_from_slice(&3u32.to_le_bytes()); // SHT_STRTAB
+        elf[sh + 24..sh + 32].copy_from_slice(&strtab_off.to_le_bytes());
+        elf[sh + 32..sh + 40].copy_from_slice(&(strtab.len() as u64).to_le_bytes());
+        elf[40..48].copy_from_slice(&shoff.to_le_bytes());
+        elf[58..60].copy_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        elf[60..62].copy_from_slice(&3u16.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn test_elf_parse_and_load() {
+        let image = test_elf();
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.elf_type, ET_EXEC);
+        assert_eq!(elf.entry, 0x1_0078);
+        assert!(!elf.is_dynamic());
+        assert_eq!(elf.vaddr_range(), Some((0x1_0000, 0x1_0090)));
+        assert_eq!(elf.phdr_vaddr(), Some(0x1_0040));
+        assert_eq!(elf.symbol("tohost"), Some(0x1_0080));
+        assert_eq!(elf.symbols[0].sym_type, STT_OBJECT);
+        assert_eq!(elf.symbol("fromhost"), None);
+
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x1_0000, 0x1000));
+        bus.write64(0x1_0080, u64::MAX).unwrap();
+        elf.load(&mut bus).unwrap();
+        assert_eq!(bus.read32(0x1_0078).unwrap(), 0x13);
+        assert_eq!(bus.read64(0x1_0080).unwrap(), 0);
+
+        assert!(Elf::parse(b"#!/bin/sh").is_err());
+        let mut x86 = image.clone();
+        x86[18] = 62;
+        assert!(Elf::parse(&x86).is_err_and(|e| e.contains("isn't RISC-V")));
+        assert!(Elf::parse(&image[..image.len() - 4]).is_err());
+        let mut stripped = image.clone();
+        stripped[40..48].fill(0);
+        assert!(Elf::parse(&stripped).unwrap().symbols.is_empty());
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let symbol = |name: &str, value, size, sym_type| ElfSymbol {
+            name: name.to_string(),
+            value,
+            size,
+            sym_type,
+        };
+        let symbols = SymbolMap::new(&[
+            symbol("main", 0x1000, 0x20, STT_FUNC),
+            symbol("_start", 0x800, 0, STT_NOTYPE),
+            symbol("data", 0x1010, 8, STT_OBJECT),
+            symbol("$x", 0x1000, 0, STT_NOTYPE),
+            symbol("memcpy", 0x2000, 0x10, STT_FUNC),
+        ]);
+        assert_eq!(symbols.lookup(0x700), None);
+        assert_eq!(symbols.lookup(0x900), Some(("_start", 0x100)));
+        assert_eq!(symbols.lookup(0x1010), Some(("main", 0x10)));
+        assert_eq!(symbols.lookup(0x1020), None);
+        assert_eq!(symbols.describe(0x2000), "memcpy");
+        assert_eq!(symbols.describe(0x2004), "memcpy+0x4");
+        assert_eq!(symbols.describe(0x3000), "0x3000");
+    }
+}
@@ -0,0 +1,563 @@
+// Flattened Device Tree (DTB) generation from the bus layout.
+// Format: Devicetree Specification v0.4, chapter 5 "Flattened Devicetree (DTB) Format".
+
+use crate::bus::Bus;
+use crate::machine::DEFAULT_ISA;
+use crate::rv64i_cpu::RV64ICpu;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// Size of the memory reservation block with the only (terminating) entry
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// RISC-V local interrupt numbers used in interrupts-extended
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// DTB is placed at the top of RAM aligned down to this value
+const DTB_ALIGN: u64 = 0x1000;
+/// Space at the top of RAM left for DTB when initramfs is placed
+const DTB_MAX_SIZE: u64 = 0x1_0000;
+
+/// What role a device plays in the interrupt topology
+pub enum DtNodeKind {
+    Generic,
+    /// Core Local Interruptor - connected to the software and timer interrupts of every hart
+    Clint,
+    /// Platform-Level Interrupt Controller - parent of the device interrupts
+    Plic {
+        ndev: u32,
+    },
+}
+
+pub enum DtProp {
+    U32(u32),
+    Str(&'static str),
+}
+
+/// Device tree description of a device attached to the bus
+pub struct DtNode {
+    /// Node name without the unit address, e.g. "serial"
+    pub name: &'static str,
+    pub compatible: Vec<&'static str>,
+    pub kind: DtNodeKind,
+    /// Device specific properties, e.g. clock-frequency
+    pub props: Vec<(&'static str, DtProp)>,
+}
+
+impl DtNode {
+    pub fn new(name: &'static str, compatible: &'static str) -> DtNode {
+        DtNode {
+            name,
+            compatible: vec![compatible],
+            kind: DtNodeKind::Generic,
+            props: Vec::new(),
+        }
+    }
+}
+
+/// Machine properties which are not visible from the bus layout
+pub struct DtConfig {
+    pub num_harts: u32,
+    /// ISA string, e.g. "rv64imac"
+    pub isa: String,
+    pub timebase_freq: u32,
+    /// Kernel command line
+    pub bootargs: Option<String>,
+    /// Start and end addresses of the initramfs loaded to RAM
+    pub initrd: Option<(u64, u64)>,
+}
+
+impl Default for DtConfig {
+    fn default() -> Self {
+        DtConfig {
+            num_harts: 1,
+            isa: DEFAULT_ISA.to_string(),
+            timebase_freq: 10_000_000,
+            bootargs: None,
+            initrd: None,
+        }
+    }
+}
+
+/// Low level DTB serializer
+#[derive(Default)]
+pub struct FdtWriter {
+    dt_struct: Vec<u8>,
+    dt_strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    pub fn new() -> FdtWriter {
+        Default::default()
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.dt_struct.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn pad_struct(&mut self) {
+        while !self.dt_struct.len().is_multiple_of(4) {
+            self.dt_struct.push(0);
+        }
+    }
+
+    /// Returns offset of the name in the strings block, names are deduplicated
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut off = 0;
+        for s in self.dt_strings.split(|&b| b == 0) {
+            if s == name.as_bytes() {
+                return off as u32;
+            }
+            off += s.len() + 1;
+        }
+        let off = self.dt_strings.len();
+        self.dt_strings.extend_from_slice(name.as_bytes());
+        self.dt_strings.push(0);
+        off as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.dt_struct.extend_from_slice(name.as_bytes());
+        self.dt_struct.push(0);
+        self.pad_struct();
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    pub fn prop(&mut self, name: &str, val: &[u8]) {
+        let name_off = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(val.len() as u32);
+        self.push_u32(name_off);
+        self.dt_struct.extend_from_slice(val);
+        self.pad_struct();
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, val: u32) {
+        self.prop(name, &val.to_be_bytes());
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let val: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &val);
+    }
+
+    /// reg property of (address, size) pairs with #address-cells = #size-cells = 2
+    pub fn prop_reg(&mut self, name: &str, regs: &[(u64, u64)]) {
+        let val: Vec<u8> = regs
+            .iter()
+            .flat_map(|(addr, size)| [addr.to_be_bytes(), size.to_be_bytes()])
+            .flatten()
+            .collect();
+        self.prop(name, &val);
+    }
+
+    pub fn prop_str(&mut self, name: &str, val: &str) {
+        self.prop_strs(name, &[val]);
+    }
+
+    pub fn prop_strs(&mut self, name: &str, vals: &[&str]) {
+        let mut val = Vec::new();
+        for s in vals {
+            val.extend_from_slice(s.as_bytes());
+            val.push(0);
+        }
+        self.prop(name, &val);
+    }
+
+    /// Assembles the blob: header, memory reservation block, structure block, strings block
+    pub fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.dt_struct.len();
+        let total_size = off_dt_strings + self.dt_strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.dt_strings.len() as u32,
+            self.dt_struct.len() as u32,
+        ];
+        let mut dtb: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+        dtb.resize(off_dt_struct, 0);
+        dtb.append(&mut self.dt_struct);
+        dtb.append(&mut self.dt_strings);
+        dtb
+    }
+}
+
+/// Generates DTB describing RAM, harts and devices attached to the bus
+pub fn generate_dtb(bus: &Bus, cfg: &DtConfig) -> Vec<u8> {
+    // phandles: 1..=num_harts - hart local interrupt controllers, then PLIC
+    let intc_phandle = |hart: u32| hart + 1;
+    let plic_phandle = cfg.num_harts + 1;
+    let has_plic = bus.devices().any(|d| {
+        matches!(
+            d.dev.dt_node(),
+            Some(DtNode {
+                kind: DtNodeKind::Plic { .. },
+                ..
+            })
+        )
+    });
+
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "kompusim");
+    fdt.prop_str("model", "kompusim");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &cfg.bootargs {
+        fdt.prop_str("bootargs", bootargs);
+    }
+    if let Some((start, end)) = cfg.initrd {
+        fdt.prop_cells("linux,initrd-start", &[(start >> 32) as u32, start as u32]);
+        fdt.prop_cells("linux,initrd-end", &[(end >> 32) as u32, end as u32]);
+    }
+    let stdout = bus.devices().find_map(|d| match d.dev.dt_node() {
+        Some(node) if node.name == "serial" => Some(format!("/soc/serial@{:x}", d.start)),
+        _ => None,
+    });
+    if let Some(stdout) = stdout {
+        fdt.prop_str("stdout-path", &stdout);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", cfg.timebase_freq);
+    for hart in 0..cfg.num_harts {
+        fdt.begin_node(&format!("cpu@{hart:x}"));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", hart);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", &cfg.isa);
+        fdt.prop_str("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    for (start, size) in bus.ram_regions() {
+        fdt.begin_node(&format!("memory@{start:x}"));
+        fdt.prop_str("device_type", "memory");
+        fdt.prop_reg("reg", &[(start, size)]);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+    let mut devices: Vec<_> = bus.devices().collect();
+    devices.sort_by_key(|d| d.start);
+    for d in devices {
+        let Some(node) = d.dev.dt_node() else {
+            continue;
+        };
+        fdt.begin_node(&format!("{}@{:x}", node.name, d.start));
+        fdt.prop_strs("compatible", &node.compatible);
+        fdt.prop_reg("reg", &[(d.start, d.end - d.start)]);
+        let hart_irqs = |irqs: [u32; 2]| -> Vec<u32> {
+            (0..cfg.num_harts)
+                .flat_map(|h| irqs.map(|irq| [intc_phandle(h), irq]))
+                .flatten()
+                .collect()
+        };
+        match node.kind {
+            DtNodeKind::Generic => {
+                if let (Some(irq), true) = (d.irq, has_plic) {
+                    fdt.prop_u32("interrupt-parent", plic_phandle);
+                    fdt.prop_u32("interrupts", irq);
+                }
+            }
+            DtNodeKind::Clint => {
+                fdt.prop_cells("interrupts-extended", &hart_irqs([IRQ_M_SOFT, IRQ_M_TIMER]));
+            }
+            DtNodeKind::Plic { ndev } => {
+                fdt.prop_cells("interrupts-extended", &hart_irqs([IRQ_M_EXT, IRQ_S_EXT]));
+                fdt.prop_u32("#interrupt-cells", 1);
+                fdt.prop_u32("#address-cells", 0);
+                fdt.prop_empty("interrupt-controller");
+                fdt.prop_u32("riscv,ndev", ndev);
+                fdt.prop_u32("phandle", plic_phandle);
+            }
+        }
+        for (name, val) in node.props {
+            match val {
+                DtProp::U32(v) => fdt.prop_u32(name, v),
+                DtProp::Str(s) => fdt.prop_str(name, s),
+            }
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Places the DTB at the top of RAM and sets up boot registers as firmware expects:
+/// a0 - hart id, a1 - DTB address. Returns the DTB address.
+pub fn place_dtb(cpu: &mut RV64ICpu, dtb: &[u8]) -> Result<u64, String> {
+    let (ram_start, ram_size) = cpu
+        .bus
+        .ram_regions()
+        .into_iter()
+        .next()
+        .ok_or("no RAM to place DTB")?;
+    let dtb_size = dtb.len() as u64;
+    if dtb_size > ram_size {
+        return Err(format!(
+            "DTB ({dtb_size} bytes) doesn't fit into RAM ({ram_size} bytes)"
+        ));
+    }
+    let dtb_addr = (ram_start + ram_size - dtb_size) & !(DTB_ALIGN - 1);
+    let dtb_addr = dtb_addr.max(ram_start);
+    cpu.bus
+        .write_bytes(dtb_addr, dtb)
+        .map_err(|e| e.to_string())?;
+    cpu.regs_w64(10, 0);
+    cpu.regs_w64(11, dtb_addr);
+    Ok(dtb_addr)
+}
+
+/// Places initramfs below the DTB at the top of RAM. Returns its start and end addresses to
+/// be passed in DtConfig::initrd.
+pub fn place_initrd(cpu: &mut RV64ICpu, initrd: &[u8]) -> Result<(u64, u64), String> {
+    let (ram_start, ram_size) = cpu
+        .bus
+        .ram_regions()
+        .into_iter()
+        .next()
+        .ok_or("no RAM to place initramfs")?;
+    let size = initrd.len() as u64;
+    let start = (ram_start + ram_size)
+        .checked_sub(DTB_MAX_SIZE + size)
+        .filter(|start| *start >= ram_start)
+        .ok_or(format!(
+            "initramfs ({size} bytes) doesn't fit into RAM ({ram_size} bytes)"
+        ))?
+        & !(DTB_ALIGN - 1);
+    cpu.bus
+        .write_bytes(start, initrd)
+        .map_err(|e| e.to_string())?;
+    Ok((start, start + size))
+}
+
+/// Writes DTB to a .dtb file, e.g. to inspect it with `dtc -I dtb -O dts`
+pub fn dump_dtb(dtb: &[u8], path: &std::path::Path) -> Result<(), String> {
+    std::fs::write(path, dtb).map_err(|e| format!("failed to write {path:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Dev, Device};
+    use crate::ram::Ram;
+    use crate::uart::Uart;
+
+    struct FakeIntc(DtNodeKind);
+
+    impl Dev for FakeIntc {
+        fn read8(&self, _addr: u64) -> u8 {
+            0
+        }
+        fn write8(&mut self, _addr: u64, _val: u8) {}
+        fn read32(&self, _addr: u64) -> u32 {
+            0
+        }
+        fn read64(&self, _addr: u64) -> u64 {
+            0
+        }
+        fn write32(&mut self, _addr: u64, _val: u32) {}
+        fn write64(&mut self, _addr: u64, _val: u64) {}
+
+        fn dt_node(&self) -> Option<DtNode> {
+            let (name, compatible, kind) = match self.0 {
+                DtNodeKind::Clint => ("clint", "riscv,clint0", DtNodeKind::Clint),
+                _ => ("plic", "riscv,plic0", DtNodeKind::Plic { ndev: 31 }),
+            };
+            Some(DtNode {
+                kind,
+                ..DtNode::new(name, compatible)
+            })
+        }
+    }
+
+    fn be32(dtb: &[u8], off: usize) -> u32 {
+        u32::from_be_bytes(dtb[off..off + 4].try_into().unwrap())
+    }
+
+    /// Walks the structure block and returns (node path, property name, value) triples
+    fn parse(dtb: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let off_struct = be32(dtb, 8) as usize;
+        let off_strings = be32(dtb, 12) as usize;
+        let mut path: Vec<String> = Vec::new();
+        let mut props = Vec::new();
+        let mut off = off_struct;
+        loop {
+            let token = be32(dtb, off);
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let len = dtb[off..].iter().position(|&b| b == 0).unwrap();
+                    path.push(String::from_utf8(dtb[off..off + len].to_vec()).unwrap());
+                    off = (off + len + 1 + 3) & !3;
+                }
+                FDT_END_NODE => {
+                    path.pop().unwrap();
+                }
+                FDT_PROP => {
+                    let len = be32(dtb, off) as usize;
+                    let name_off = off_strings + be32(dtb, off + 4) as usize;
+                    let name_len = dtb[name_off..].iter().position(|&b| b == 0).unwrap();
+                    let name = String::from_utf8(dtb[name_off..name_off + name_len].to_vec());
+                    let val = dtb[off + 8..off + 8 + len].to_vec();
+                    props.push((path.join("/"), name.unwrap(), val));
+                    off = (off + 8 + len + 3) & !3;
+                }
+                FDT_END => break,
+                _ => panic!("bad token {token:x}"),
+            }
+        }
+        assert!(path.is_empty());
+        props
+    }
+
+    fn prop<'a>(props: &'a [(String, String, Vec<u8>)], path: &str, name: &str) -> &'a [u8] {
+        &props
+            .iter()
+            .find(|(p, n, _)| p == path && n == name)
+            .unwrap_or_else(|| panic!("no {path}:{name}"))
+            .2
+    }
+
+    #[test]
+    fn test_generate_dtb() {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x8000_0000, 0x10_0000));
+        let uart = Box::new(Uart::new("0".to_string()));
+        bus.attach_device(Device::new(uart, 0x1001_0000, 0x20).with_irq(3));
+        let plic = Box::new(FakeIntc(DtNodeKind::Plic { ndev: 0 }));
+        bus.attach_device(Device::new(plic, 0xc00_0000, 0x400_0000));
+        let clint = Box::new(FakeIntc(DtNodeKind::Clint));
+        bus.attach_device(Device::new(clint, 0x200_0000, 0x1_0000));
+        let cfg = DtConfig {
+            bootargs: Some("console=ttyS0".to_string()),
+            initrd: Some((0x8f00_0000, 0x8f10_0000)),
+            ..Default::default()
+        };
+        let dtb = generate_dtb(&bus, &cfg);
+
+        assert_eq!(be32(&dtb, 0), FDT_MAGIC);
+        assert_eq!(be32(&dtb, 4) as usize, dtb.len());
+        let props = parse(&dtb);
+        assert_eq!(prop(&props, "/chosen", "bootargs"), b"console=ttyS0\0");
+        assert_eq!(
+            prop(&props, "/chosen", "linux,initrd-end"),
+            0x8f10_0000_u64.to_be_bytes()
+        );
+        assert_eq!(prop(&props, "/cpus/cpu@0", "mmu-type"), b"riscv,sv39\0");
+        assert_eq!(
+            prop(&props, "/chosen", "stdout-path"),
+            b"/soc/serial@10010000\0"
+        );
+        assert_eq!(
+            prop(&props, "/cpus/cpu@0", "riscv,isa"),
+            b"rv64imac_zicsr_zifencei\0"
+        );
+        assert_eq!(
+            prop(&props, "/memory@80000000", "reg"),
+            [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0]
+        );
+        assert_eq!(
+            prop(&props, "/soc/serial@10010000", "interrupts"),
+            3u32.to_be_bytes()
+        );
+        // PLIC phandle follows the hart interrupt controller phandle
+        assert_eq!(
+            prop(&props, "/soc/plic@c000000", "phandle"),
+            2u32.to_be_bytes()
+        );
+        assert_eq!(
+            prop(&props, "/soc/serial@10010000", "interrupt-parent"),
+            2u32.to_be_bytes()
+        );
+        assert_eq!(
+            prop(&props, "/soc/clint@2000000", "interrupts-extended"),
+            [1, IRQ_M_SOFT, 1, IRQ_M_TIMER]
+                .iter()
+                .flat_map(|c| c.to_be_bytes())
+                .collect::<Vec<u8>>()
+        );
+        // devices are sorted by address
+        let soc_nodes: Vec<&str> = props
+            .iter()
+            .filter(|(_, n, _)| n == "compatible")
+            .map(|(p, _, _)| p.as_str())
+            .filter(|p| p.starts_with("/soc/"))
+            .collect();
+        assert_eq!(
+            soc_nodes,
+            [
+                "/soc/clint@2000000",
+                "/soc/plic@c000000",
+                "/soc/serial@10010000"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_place_dtb() {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x8000_0000, 0x10_0000));
+        let mut cpu = RV64ICpu::new(bus);
+        let dtb = generate_dtb(&cpu.bus, &DtConfig::default());
+        let addr = place_dtb(&mut cpu, &dtb).unwrap();
+        assert_eq!(addr % DTB_ALIGN, 0);
+        assert!(addr + dtb.len() as u64 <= 0x8010_0000);
+        assert_eq!(cpu.regs_r64(11), addr);
+        assert_eq!(cpu.bus.read32(addr).unwrap(), FDT_MAGIC.to_be());
+
+        let (start, end) = place_initrd(&mut cpu, &[0x07; 0x1800]).unwrap();
+        assert_eq!(start % DTB_ALIGN, 0);
+        assert_eq!(end - start, 0x1800);
+        assert!(end <= addr);
+        assert_eq!(cpu.bus.read8(end - 1).unwrap(), 0x07);
+        assert!(place_initrd(&mut cpu, &[0; 0x10_0000]).is_err());
+    }
+}
@@ -0,0 +1,743 @@
+// GDB Remote Serial Protocol (RSP) stub: gdb connects over a TCP or Unix socket
+// (target remote :1234 or target remote /tmp/kompusim.sock) and controls the hart.
+// Registers are described with the target description XML: x0-x31 and pc (regnums 0-32), f0-f31
+// (33-64), CSRs (65 + CSR address) and the privilege level (4161). Memory accesses go through the
+// MMU of the hart without side effects, only RAM is accessible. Software and hardware
+// breakpoints use RV64ICpu::add_breakpoint(), write/read/access watchpoints
+// RV64ICpu::add_watchpoint().
+// Reference: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::csr;
+use crate::rv64i_cpu::{ExecEvent, RV64ICpu, WatchKind, Watchpoint};
+use crate::rv64i_disasm::reg_idx2abi;
+
+/// Instructions executed between checks for Ctrl-C
+const CONTINUE_CHUNK: u64 = 10_000;
+const PACKET_SIZE: usize = 0x4000;
+const INTERRUPT: u8 = 0x03;
+
+const REG_PC: usize = 32;
+const REG_F0: usize = 33;
+const REG_CSR0: usize = 65;
+const REG_FFLAGS: usize = REG_CSR0 + 0x001;
+const REG_FRM: usize = REG_CSR0 + 0x002;
+const REG_FCSR: usize = REG_CSR0 + 0x003;
+const REG_PRIV: usize = REG_CSR0 + 0x1000;
+
+/// CSRs described in the target description
+const CSR_NAMES: &[(u16, &str)] = &[
+    (csr::CYCLE, "cycle"),
+    (csr::TIME, "time"),
+    (csr::INSTRET, "instret"),
+    (csr::SSTATUS, "sstatus"),
+    (csr::SIE, "sie"),
+    (csr::STVEC, "stvec"),
+    (csr::SCOUNTEREN, "scounteren"),
+    (csr::SENVCFG, "senvcfg"),
+    (csr::SSCRATCH, "sscratch"),
+    (csr::SEPC, "sepc"),
+    (csr::SCAUSE, "scause"),
+    (csr::STVAL, "stval"),
+    (csr::SIP, "sip"),
+    (csr::SATP, "satp"),
+    (csr::MVENDORID, "mvendorid"),
+    (csr::MARCHID, "marchid"),
+    (csr::MIMPID, "mimpid"),
+    (csr::MHARTID, "mhartid"),
+    (csr::MCONFIGPTR, "mconfigptr"),
+    (csr::MSTATUS, "mstatus"),
+    (csr::MISA, "misa"),
+    (csr::MEDELEG, "medeleg"),
+    (csr::MIDELEG, "mideleg"),
+    (csr::MIE, "mie"),
+    (csr::MTVEC, "mtvec"),
+    (csr::MCOUNTEREN, "mcounteren"),
+    (csr::MENVCFG, "menvcfg"),
+    (csr::MCOUNTINHIBIT, "mcountinhibit"),
+    (csr::MSCRATCH, "mscratch"),
+    (csr::MEPC, "mepc"),
+    (csr::MCAUSE, "mcause"),
+    (csr::MTVAL, "mtval"),
+    (csr::MIP, "mip"),
+    (csr::MCYCLE, "mcycle"),
+    (csr::MINSTRET, "minstret"),
+];
+
+/// Stream to gdb
+pub trait Connection: Read + Write {
+    /// In the non-blocking mode reads fail with WouldBlock when there is no data
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Waits for gdb on "unix:<path>", "<host>:<port>" or "<port>" (localhost)
+pub fn listen(addr: &str) -> Result<Box<dyn Connection>, String> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        crate::virtio_net::remove_stale_socket(std::path::Path::new(path))
+            .map_err(|e| format!("{path}: {e}"))?;
+        let listener = UnixListener::bind(path).map_err(|e| format!("{path}: {e}"))?;
+        println!("Waiting for gdb on {path}");
+        let (stream, _) = listener.accept().map_err(|e| format!("{path}: {e}"))?;
+        return Ok(Box::new(stream));
+    }
+    let addr = if addr.contains(':') {
+        addr.to_string()
+    } else {
+        format!("127.0.0.1:{addr}")
+    };
+    let listener = TcpListener::bind(&addr).map_err(|e| format!("{addr}: {e}"))?;
+    println!("Waiting for gdb on {addr}");
+    let (stream, _) = listener.accept().map_err(|e| format!("{addr}: {e}"))?;
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    Ok(Box::new(stream))
+}
+
+/// Serves gdb until it detaches, kills the program or the connection is closed. Returns the exit
+/// code if the program exited.
+pub fn serve<C: Connection + ?Sized>(
+    cpu: &mut RV64ICpu,
+    conn: &mut C,
+) -> Result<Option<i32>, String> {
+    GdbStub {
+        cpu,
+        conn,
+        no_ack: false,
+        hw_breakpoints: Vec::new(),
+    }
+    .run()
+}
+
+struct GdbStub<'a, C: ?Sized> {
+    cpu: &'a mut RV64ICpu,
+    conn: &'a mut C,
+    /// QStartNoAckMode: packets aren't acknowledged with +/-
+    no_ack: bool,
+    /// Breakpoints set with Z1, reported as hwbreak
+    hw_breakpoints: Vec<u64>,
+}
+
+/// What to do after a packet is handled
+enum Action {
+    Reply(String),
+    /// The program exited with the code
+    Exit(i32),
+    /// Detach or kill: the session is over
+    End,
+}
+
+impl<C: Connection + ?Sized> GdbStub<'_, C> {
+    fn run(&mut self) -> Result<Option<i32>, String> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet)? {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Exit(code) => {
+                    self.send(&format!("W{:02x}", code as u8))?;
+                    return Ok(Some(code));
+                }
+                Action::End => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    /// None - connection closed
+    fn read_byte(&mut self) -> Result<Option<u8>, String> {
+        let mut byte = [0];
+        loop {
+            match self.conn.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(format!("gdb connection: {e}")),
+            }
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), String> {
+        self.conn
+            .write_all(data)
+            .and_then(|_| self.conn.flush())
+            .map_err(|e| format!("gdb connection: {e}"))
+    }
+
+    /// Reads the next packet ($<data>#<checksum>), acks and Ctrl-C outside packets are skipped.
+    /// Packets longer than the advertised PACKET_SIZE are dropped (and NAKed).
+    fn read_packet(&mut self) -> Result<Option<String>, String> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            let mut too_long = false;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) if data.len() < PACKET_SIZE => data.push(byte),
+                    Some(_) => too_long = true,
+                }
+            }
+            if too_long {
+                eprintln!("WARN: gdb: packet longer than {PACKET_SIZE} bytes dropped");
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    Some(byte) => *digit = byte,
+                    None => return Ok(None),
+                }
+            }
+            let valid = !too_long
+                && std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|cs| u8::from_str_radix(cs, 16).ok())
+                    == Some(checksum_of(&data));
+            if !self.no_ack {
+                self.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    /// Sends the packet and waits for the ack, retransmits on '-'
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        loop {
+            self.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Result<Action, String> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match cmd {
+            "?" => "S05".to_string(),
+            "g" => (0..=REG_PC).map(|i| self.reg_hex(i).unwrap()).collect(),
+            "G" => self.write_regs(args),
+            "p" => match usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|i| self.reg_hex(i))
+            {
+                Some(val) => val,
+                None => "E01".to_string(),
+            },
+            "P" => self.write_reg(args),
+            "m" => self.read_mem(args),
+            "M" => self.write_mem(args),
+            "c" | "s" => {
+                if let Ok(addr) = u64::from_str_radix(args, 16) {
+                    self.cpu.pc_jump(addr);
+                }
+                let event = if cmd == "s" {
+                    self.cpu.exec_continue(1)
+                } else {
+                    match self.resume()? {
+                        Some(event) => event,
+                        None => return Ok(Action::Reply("T02".to_string())),
+                    }
+                };
+                match event {
+                    ExecEvent::Exit(code) => return Ok(Action::Exit(code)),
+                    ExecEvent::Breakpoint(pc) if self.hw_breakpoints.contains(&pc) => {
+                        "T05hwbreak:;".to_string()
+                    }
+                    ExecEvent::Breakpoint(_) => "T05swbreak:;".to_string(),
+                    ExecEvent::MaxInstructions(_) => "T05".to_string(),
+                    ExecEvent::Watchpoint(hit) => {
+                        let reason = match hit.watchpoint.kind {
+                            WatchKind::Write => "watch",
+                            WatchKind::Read => "rwatch",
+                            WatchKind::Access => "awatch",
+                        };
+                        // the first watched byte of the access
+                        let addr = hit.addr.max(hit.watchpoint.addr);
+                        format!("T05{reason}:{addr:x};")
+                    }
+                }
+            }
+            "Z" | "z" => self.breakpoint(cmd == "Z", args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "k" => return Ok(Action::End),
+            "D" => {
+                self.send("OK")?;
+                return Ok(Action::End);
+            }
+            "q" | "Q" => self.query(packet),
+            // e.g. vCont and vMustReplyEmpty: gdb falls back to c and s
+            _ => String::new(),
+        };
+        Ok(Action::Reply(reply))
+    }
+
+    /// Runs until a breakpoint, the exit or Ctrl-C (None)
+    fn resume(&mut self) -> Result<Option<ExecEvent>, String> {
+        let set_nonblocking = |conn: &C, nonblocking| {
+            conn.set_nonblocking(nonblocking)
+                .map_err(|e| format!("gdb connection: {e}"))
+        };
+        set_nonblocking(self.conn, true)?;
+        let event = loop {
+            match self.cpu.exec_continue(CONTINUE_CHUNK) {
+                ExecEvent::MaxInstructions(_) => {}
+                event => break Some(event),
+            }
+            let mut byte = [0];
+            match self.conn.read(&mut byte) {
+                Ok(1) if byte[0] == INTERRUPT => break None,
+                Ok(0) => break Some(ExecEvent::MaxInstructions(self.cpu.get_pc())),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(format!("gdb connection: {e}")),
+            }
+        };
+        set_nonblocking(self.conn, false)?;
+        Ok(event)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                 QStartNoAckMode+"
+            );
+        }
+        if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            return "OK".to_string();
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(args, ',') {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{more}{}", &xml[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    /// Register value as little-endian hex, None - the register doesn't exist
+    fn reg_hex(&self, reg: usize) -> Option<String> {
+        let fcsr = self.cpu.get_fregs().fcsr;
+        let val = match reg {
+            0..=31 => self.cpu.regs_r64(reg as u8),
+            REG_PC => self.cpu.get_pc(),
+            REG_F0..=64 => self.cpu.get_fregs().f[reg - REG_F0],
+            REG_FFLAGS => fcsr & 0x1f,
+            REG_FRM => fcsr >> 5 & 0x7,
+            REG_FCSR => fcsr,
+            REG_PRIV => self.cpu.get_priv_mode() as u64,
+            REG_CSR0..REG_PRIV => self.cpu.csr_r64_debug((reg - REG_CSR0) as u16)?,
+            _ => return None,
+        };
+        Some(hex_le(val))
+    }
+
+    /// false - the register doesn't exist or isn't writable
+    fn set_reg(&mut self, reg: usize, val: u64) -> bool {
+        let fcsr = self.cpu.get_fregs().fcsr;
+        match reg {
+            0..=31 => self.cpu.regs_w64(reg as u8, val),
+            REG_PC => self.cpu.pc_jump(val),
+            REG_F0..=64 => self.cpu.get_fregs_mut().f[reg - REG_F0] = val,
+            REG_FFLAGS => self.cpu.get_fregs_mut().fcsr = fcsr & !0x1f | val & 0x1f,
+            REG_FRM => self.cpu.get_fregs_mut().fcsr = fcsr & !0xe0 | (val & 0x7) << 5,
+            REG_FCSR => self.cpu.get_fregs_mut().fcsr = val & 0xff,
+            REG_CSR0..REG_PRIV => return self.cpu.csr_w64_debug((reg - REG_CSR0) as u16, val),
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_regs(&mut self, args: &str) -> String {
+        let vals: Vec<Option<u64>> = args
+            .as_bytes()
+            .chunks(16)
+            .map(|chunk| parse_hex_le(std::str::from_utf8(chunk).ok()?))
+            .collect();
+        for (reg, val) in vals.into_iter().enumerate().take(REG_PC + 1) {
+            match val {
+                Some(val) => {
+                    self.set_reg(reg, val);
+                }
+                None => return "E01".to_string(),
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn write_reg(&mut self, args: &str) -> String {
+        let written = args.split_once('=').is_some_and(|(reg, val)| {
+            match (usize::from_str_radix(reg, 16), parse_hex_le(val)) {
+                (Ok(reg), Some(val)) => self.set_reg(reg, val),
+                _ => false,
+            }
+        });
+        if written { "OK" } else { "E01" }.to_string()
+    }
+
+    /// m<addr>,<length>
+    fn read_mem(&self, args: &str) -> String {
+        let data = parse_pair(args, ',')
+            .filter(|&(_, len)| len as usize <= PACKET_SIZE / 2)
+            .and_then(|(addr, len)| self.cpu.read_virt_bytes(addr, len));
+        match data {
+            Some(data) => data.iter().map(|b| format!("{b:02x}")).collect(),
+            None => "E14".to_string(),
+        }
+    }
+
+    /// M<addr>,<length>:<data>
+    fn write_mem(&mut self, args: &str) -> String {
+        let Some((range, hex)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let data: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+        match (parse_pair(range, ','), data) {
+            (Some((addr, len)), Some(data)) if len as usize == data.len() => {
+                if self.cpu.write_virt_bytes(addr, &data) {
+                    "OK".to_string()
+                } else {
+                    "E14".to_string()
+                }
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Z<type>,<addr>,<kind> and z<type>,<addr>,<kind>: software (0) and hardware (1)
+    /// breakpoints, write (2), read (3) and access (4) watchpoints of kind bytes
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (u64::from_str_radix(addr, 16), u64::from_str_radix(len, 16))
+        else {
+            return "E01".to_string();
+        };
+        let watch_kind = match kind {
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => None,
+        };
+        if let Some(kind) = watch_kind {
+            let watchpoint = Watchpoint { addr, len, kind };
+            if insert {
+                self.cpu.add_watchpoint(watchpoint);
+            } else {
+                self.cpu.remove_watchpoint(&watchpoint);
+            }
+            return "OK".to_string();
+        }
+        match (kind, insert) {
+            ("0" | "1", true) => {
+                if kind == "1" {
+                    self.hw_breakpoints.push(addr);
+                }
+                self.cpu.add_breakpoint(addr);
+            }
+            ("0" | "1", false) => {
+                self.hw_breakpoints.retain(|&a| a != addr);
+                self.cpu.remove_breakpoint(addr);
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex_le(val: u64) -> String {
+    val.to_le_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Register value in target byte order, shorter values are zero extended
+fn parse_hex_le(hex: &str) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate().take(hex.len() / 2) {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// "<hex>,<hex>" pair, e.g. address and length
+fn parse_pair(args: &str, sep: char) -> Option<(u64, u64)> {
+    let (a, b) = args.split_once(sep)?;
+    Some((
+        u64::from_str_radix(a, 16).ok()?,
+        u64::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+fn target_xml() -> String {
+    let reg = |name: &str, regnum: usize, reg_type: &str| {
+        format!("<reg name=\"{name}\" bitsize=\"64\" regnum=\"{regnum}\" type=\"{reg_type}\"/>")
+    };
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target>\
+         <architecture>riscv:rv64</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for i in 0..32 {
+        let reg_type = if i == 1 { "code_ptr" } else { "int" };
+        xml += &reg(reg_idx2abi(i as u8), i, reg_type);
+    }
+    xml += &reg("pc", REG_PC, "code_ptr");
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for i in 0..32 {
+        xml += &reg(&format!("f{i}"), REG_F0 + i, "ieee_double");
+    }
+    xml += &reg("fflags", REG_FFLAGS, "int");
+    xml += &reg("frm", REG_FRM, "int");
+    xml += &reg("fcsr", REG_FCSR, "int");
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for &(csr_a, name) in CSR_NAMES {
+        xml += &reg(name, REG_CSR0 + csr_a as usize, "int");
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.virtual\">";
+    xml += &reg("priv", REG_PRIV, "int");
+    xml += "</feature></target>";
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rv64i_cpu::cpu_with_program;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    const BASE: u64 = 0x8000_0000;
+
+    /// Scripted gdb: reads return the input, reads in the non-blocking mode fail when the input
+    /// is consumed
+    #[derive(Default)]
+    struct MockConn {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+        nonblocking: Cell<bool>,
+    }
+
+    impl Read for MockConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None if self.nonblocking.get() => Err(ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Write for MockConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConn {
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.nonblocking.set(nonblocking);
+            Ok(())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+    }
+
+    /// Runs the session in no-ack mode and returns the replies
+    fn session(cpu: &mut RV64ICpu, packets: &[&str]) -> Vec<String> {
+        let mut input = packet("QStartNoAckMode");
+        input += "+";
+        packets.iter().for_each(|p| input += &packet(p));
+        let mut conn = MockConn {
+            input: input.bytes().collect(),
+            ..Default::default()
+        };
+        serve(cpu, &mut conn).unwrap();
+        let output = String::from_utf8(conn.output).unwrap();
+        // the first reply is acked, the rest are not
+        assert!(output.starts_with(&format!("+{}", packet("OK"))));
+        output
+            .split('$')
+            .skip(2)
+            .map(|p| p.split_once('#').unwrap().0.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_gdb_registers_memory_breakpoints() {
+        let mut cpu = cpu_with_program(
+            BASE,
+            &[
+                0x00100093, // addi ra,zero,1
+                0x00108093, // addi ra,ra,1
+                0x00108093, // addi ra,ra,1
+                0x0000006f, // j .
+            ],
+        );
+        let replies = session(
+            &mut cpu,
+            &[
+                "qSupported:swbreak+",
+                "?",
+                &format!("Z0,{:x},4", BASE + 8),
+                "c",
+                "p1",
+                "p20",
+                "s",
+                "p20",
+                &format!("z0,{:x},4", BASE + 8),
+                "P2=0010000000000000",
+                "p2",
+                &format!("m{BASE:x},4"),
+                &format!("M{:x},2:abcd", BASE + 0x100),
+                &format!("m{:x},2", BASE + 0x100),
+                "m0,4",
+                &format!("p{:x}", REG_CSR0 + csr::MISA as usize),
+                &format!("p{REG_PRIV:x}"),
+                "Z5,0,4",
+                "vMustReplyEmpty",
+                "k",
+            ],
+        );
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+",
+                "S05",
+                "OK",
+                "T05swbreak:;",
+                "0200000000000000",
+                "0800008000000000",
+                "T05",
+                "0c00008000000000",
+                "OK",
+                "OK",
+                "0010000000000000",
+                "93001000",
+                "OK",
+                "abcd",
+                "E14",
+                &hex_le(cpu.csr_r64(csr::MISA).unwrap()),
+                "0300000000000000",
+                "",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gdb_packet_too_long() {
+        let mut cpu = cpu_with_program(BASE, &[0x0000006f]); // j .
+        let long = format!("X{BASE:x},1:{}", "0".repeat(PACKET_SIZE));
+        let replies = session(&mut cpu, &[&long, "?", "k"]);
+        assert_eq!(replies, ["S05"]);
+
+        // NAKed with acks on
+        let mut conn = MockConn {
+            input: packet(&long).bytes().collect(),
+            ..Default::default()
+        };
+        serve(&mut cpu, &mut conn).unwrap();
+        assert_eq!(conn.output, b"-");
+    }
+
+    #[test]
+    fn test_gdb_watchpoint() {
+        let mut cpu = cpu_with_program(
+            BASE,
+            &[
+                0x00000297, // auipc t0,0
+                0x1062b023, // sd t1,0x100(t0)
+                0x0000006f, // j .
+            ],
+        );
+        let replies = session(
+            &mut cpu,
+            &[&format!("Z2,{:x},8", BASE + 0x100), "c", "p20", "k"],
+        );
+        assert_eq!(replies, ["OK", "T05watch:80000100;", "0800008000000000"]);
+    }
+
+    #[test]
+    fn test_gdb_interrupt_and_target_xml() {
+        let mut cpu = cpu_with_program(BASE, &[0x0000006f]); // j .
+        let mut input = packet("c");
+        input.push(INTERRUPT as char);
+        input.push('+');
+        input += &packet("qXfer:features:read:target.xml:0,8000");
+        input.push('+');
+        let mut conn = MockConn {
+            input: input.bytes().collect(),
+            ..Default::default()
+        };
+        assert_eq!(serve(&mut cpu, &mut conn).unwrap(), None);
+        let output = String::from_utf8(conn.output).unwrap();
+        assert!(output.starts_with(&format!("+{}+", packet("T02"))));
+        assert!(output.contains("<reg name=\"pc\" bitsize=\"64\" regnum=\"32\""));
+        assert!(output.contains("<reg name=\"mstatus\" bitsize=\"64\" regnum=\"833\""));
+    }
+}
@@ -0,0 +1,189 @@
+// Host-Target Interface (HTIF) of Spike used by riscv-tests and many benchmarks. The program
+// writes a command to the tohost doubleword in RAM, the simulator polls it, clears it and answers
+// through fromhost. A command is device (63:56), command (55:48) and payload (47:0):
+//   device 0 (syscall proxy), command 0: payload bit 0 set - exit with status payload >> 1,
+//     otherwise payload points to magic_mem: syscall number and arguments, the result is
+//     returned in magic_mem[0]
+//   device 1 (console), command 1: putchar(payload & 0xff)
+// Reference: riscv-isa-sim fesvr (htif.cc, syscall.cc, device.cc).
+
+use crate::bus::Bus;
+
+/// Offset of fromhost from tohost in the .tohost section of riscv-tests
+pub const FROMHOST_OFFSET: u64 = 64;
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CMD_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+/// Maximum size of a single write, longer writes are partial
+const MAX_IO: u64 = 1 << 20;
+
+pub struct Htif {
+    tohost: u64,
+    fromhost: u64,
+    console_out: Option<Box<dyn Fn(u8)>>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: u64) -> Htif {
+        Htif {
+            tohost,
+            fromhost,
+            console_out: None,
+        }
+    }
+
+    /// Output of the console device and of write() to stdout and stderr
+    pub fn register_out_callback(&mut self, cb: Box<dyn Fn(u8)>) {
+        self.console_out = Some(cb);
+    }
+
+    /// Checks that tohost and fromhost are in RAM
+    pub fn check(&self, bus: &Bus) -> Result<(), String> {
+        for (name, addr) in [("tohost", self.tohost), ("fromhost", self.fromhost)] {
+            if bus.get_ram(addr, 8).is_none() {
+                return Err(format!("HTIF {name} 0x{addr:x} isn't in RAM"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a pending command. Returns the exit status when the program exits.
+    pub fn poll(&mut self, bus: &mut Bus) -> Option<i32> {
+        let cmd = read_u64(bus, self.tohost)?;
+        if cmd == 0 {
+            return None;
+        }
+        write_u64(bus, self.tohost, 0);
+        let (dev, command, payload) = (cmd >> 56, cmd >> 48 & 0xff, cmd & 0xffff_ffff_ffff);
+        match (dev, command) {
+            (DEV_SYSCALL, 0) if payload & 1 == 1 => return Some((payload >> 1) as i32),
+            (DEV_SYSCALL, 0) => {
+                let (ret, exit) = self.syscall(bus, payload);
+                write_u64(bus, payload, ret as u64);
+                if exit.is_some() {
+                    return exit;
+                }
+            }
+            (DEV_CONSOLE, CMD_PUTCHAR) => self.putchar(payload as u8),
+            _ => {
+                eprintln!("WARN: htif: unsupported device {dev} command {command}");
+                return None;
+            }
+        }
+        write_u64(bus, self.fromhost, dev << 56 | command << 48 | 1);
+        None
+    }
+
+    /// magic_mem: syscall number followed by the arguments. Returns the result (or -errno) and
+    /// the exit status if the program exits.
+    fn syscall(&mut self, bus: &Bus, magic_mem: u64) -> (i64, Option<i32>) {
+        let arg = |i: u64| read_u64(bus, magic_mem + i * 8).unwrap_or(0);
+        match arg(0) {
+            SYS_WRITE => {
+                let (fd, buf, len) = (arg(1), arg(2), arg(3));
+                if fd != 1 && fd != 2 {
+                    return (-EBADF, None);
+                }
+                if buf.checked_add(len).is_none() {
+                    return (-EFAULT, None);
+                }
+                let len = len.min(MAX_IO);
+                match bus.get_ram(buf, len) {
+                    Some(data) => {
+                        data.iter().for_each(|&octet| self.putchar(octet));
+                        (len as i64, None)
+                    }
+                    None => (-EFAULT, None),
+                }
+            }
+            SYS_EXIT => (0, Some(arg(1) as i32)),
+            nr => {
+                eprintln!("WARN: htif: unsupported syscall {nr}");
+                (-ENOSYS, None)
+            }
+        }
+    }
+
+    fn putchar(&self, octet: u8) {
+        match &self.console_out {
+            Some(cb) => cb(octet),
+            None => print!("{}", octet as char),
+        }
+    }
+}
+
+fn read_u64(bus: &Bus, addr: u64) -> Option<u64> {
+    Some(u64::from_le_bytes(bus.get_ram(addr, 8)?.try_into().ok()?))
+}
+
+fn write_u64(bus: &mut Bus, addr: u64, val: u64) {
+    let _ = bus.write_bytes(addr, &val.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const TOHOST: u64 = 0x8000_1000;
+    const FROMHOST: u64 = TOHOST + FROMHOST_OFFSET;
+
+    #[test]
+    fn test_htif_commands() {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x8000_0000, 0x1_0000));
+        let out = Rc::new(RefCell::new(String::new()));
+        let out_cb = out.clone();
+        let mut htif = Htif::new(TOHOST, FROMHOST);
+        htif.register_out_callback(Box::new(move |b| out_cb.borrow_mut().push(b as char)));
+        assert!(htif.check(&bus).is_ok());
+        assert!(Htif::new(0x1000, FROMHOST).check(&bus).is_err());
+        assert_eq!(htif.poll(&mut bus), None);
+
+        bus.write64(TOHOST, DEV_CONSOLE << 56 | CMD_PUTCHAR << 48 | b'k' as u64)
+            .unwrap();
+        assert_eq!(htif.poll(&mut bus), None);
+        assert_eq!(bus.read64(TOHOST).unwrap(), 0);
+        assert_eq!(
+            bus.read64(FROMHOST).unwrap(),
+            DEV_CONSOLE << 56 | CMD_PUTCHAR << 48 | 1
+        );
+
+        // write(1, "ok", 2) through magic_mem
+        let magic_mem = 0x8000_2000;
+        bus.write_bytes(0x8000_3000, b"ok").unwrap();
+        for (i, val) in [SYS_WRITE, 1, 0x8000_3000, 2].iter().enumerate() {
+            bus.write64(magic_mem + i as u64 * 8, *val).unwrap();
+        }
+        bus.write64(TOHOST, magic_mem).unwrap();
+        assert_eq!(htif.poll(&mut bus), None);
+        assert_eq!(bus.read64(magic_mem).unwrap(), 2);
+        assert_eq!(out.borrow().as_str(), "kok");
+
+        // the length wraps the address space, a long write runs past the end of RAM
+        for len in [u64::MAX, 1 << 32] {
+            for (i, val) in [SYS_WRITE, 1, 0x8000_3000, len].iter().enumerate() {
+                bus.write64(magic_mem + i as u64 * 8, *val).unwrap();
+            }
+            bus.write64(TOHOST, magic_mem).unwrap();
+            assert_eq!(htif.poll(&mut bus), None);
+            assert_eq!(bus.read64(magic_mem).unwrap(), -EFAULT as u64);
+        }
+        assert_eq!(out.borrow().as_str(), "kok");
+
+        // riscv-tests: pass is 1, failure of test N is N << 1 | 1
+        bus.write64(TOHOST, 1).unwrap();
+        assert_eq!(htif.poll(&mut bus), Some(0));
+        bus.write64(TOHOST, 5 << 1 | 1).unwrap();
+        assert_eq!(htif.poll(&mut bus), Some(5));
+    }
+}
@@ -1,10 +1,30 @@
 mod alu;
+pub mod backtrace;
 pub mod bits;
+pub mod breakpoint;
 pub mod bus;
-mod csr;
+pub mod callgraph;
+pub mod clint;
+pub mod conformance;
+pub mod coverage;
+pub mod csr;
 pub mod device;
+pub mod dwarf;
+pub mod elf;
+pub mod fdt;
+pub mod gdb_stub;
+pub mod htif;
+// the syscalls are serviced with the unix file API
+#[cfg(unix)]
+pub mod linux_user;
+pub mod machine;
+pub mod mmu;
+pub mod plic;
+pub mod profile;
 pub mod ram;
-mod rv64fd;
+pub mod replay;
+pub mod rom;
+pub mod rv64fd;
 pub mod rv64i_cpu;
 /// RV64I decoder
 #[allow(clippy::unusual_byte_groupings)]
@@ -14,4 +34,15 @@ pub mod rv64i_disasm;
 #[allow(clippy::unusual_byte_groupings)]
 pub mod rvc_dec;
 pub mod rvc_disasm;
+pub mod sbi;
+pub mod semihosting;
+pub mod snapshot;
+pub mod trace;
+pub mod trace_diff;
 pub mod uart;
+pub mod uart16550;
+pub mod undo;
+pub mod virtio;
+pub mod virtio_console;
+pub mod virtio_net;
+pub mod virtio_rng;
@@ -0,0 +1,841 @@
+// Linux user-mode emulation: a statically linked RV64 Linux program runs in U-mode without a
+// kernel and its ECALLs (syscalls) are serviced against the host, like qemu-user does.
+// Memory is identity mapped: the ELF segments followed by the heap (brk), the mmap area and the
+// stack are RAM regions at the program's virtual addresses.
+// Syscall numbers and structures: Linux asm-generic ABI (include/uapi/asm-generic/unistd.h).
+
+use std::ffi::OsString;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::Bus;
+use crate::csr::{self, PrivMode};
+use crate::elf::{Elf, ET_EXEC};
+use crate::mmu::PAGE_SIZE;
+use crate::ram::Ram;
+use crate::rv64i_cpu::{EcallHandler, ExecEvent, RV64ICpu};
+
+pub const STACK_TOP: u64 = 0x3f_ffff_f000;
+const STACK_SIZE: u64 = 8 << 20;
+/// Maximum size of the heap grown by brk
+const BRK_SIZE: u64 = 64 << 20;
+pub const MMAP_BASE: u64 = 0x20_0000_0000;
+const MMAP_SIZE: u64 = 256 << 20;
+/// Exceptions of the program trap to M-mode here. There is a breakpoint at the address, so
+/// run_program() gets control and reports the fault.
+const TRAP_VECTOR: u64 = 0;
+/// Maximum size of a single read/write transfer
+const MAX_IO: u64 = 1 << 20;
+const PATH_MAX: u64 = 4096;
+/// Maximum number of buffers of writev
+const IOV_MAX: u64 = 1024;
+/// Process and thread ID reported to the program
+const PID: u64 = 1;
+
+// Registers of the calling convention
+const SP: u8 = 2;
+const A0: u8 = 10;
+const A7: u8 = 17;
+
+// Syscall numbers
+const SYS_IOCTL: u64 = 29;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_WRITEV: u64 = 66;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SIGALTSTACK: u64 = 132;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_GETRANDOM: u64 = 278;
+
+// errno
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const CLOCK_BOOTTIME: u64 = 7;
+const S_IFCHR: u32 = 0o020000;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+/// Single letter extensions I, M, A and C: bit (letter - 'A')
+const HWCAP: u64 = 1 << (b'I' - b'A') | 1 << (b'M' - b'A') | 1 | 1 << (b'C' - b'A');
+
+/// Ok(value) or Err(-errno) returned in a0
+type SysResult = Result<u64, i64>;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Services syscalls of a user-mode program
+pub struct LinuxUser {
+    /// Index is the file descriptor
+    fds: Vec<Option<Fd>>,
+    brk_start: u64,
+    brk: u64,
+    mmap_next: u64,
+    /// Output of stdout and stderr, the host stdout and stderr if None
+    console_out: Option<Box<dyn Fn(u8)>>,
+    start: Instant,
+    /// State of the (not cryptographic) generator of getrandom and AT_RANDOM bytes
+    random: u64,
+}
+
+/// None - the aligned address doesn't fit into 64 bits
+fn page_align_up(addr: u64) -> Option<u64> {
+    Some(addr.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+fn errno(e: &io::Error) -> i64 {
+    -(e.raw_os_error().map_or(EINVAL, |e| e as i64))
+}
+
+fn read_guest(cpu: &RV64ICpu, addr: u64, len: u64) -> Result<Vec<u8>, i64> {
+    if addr.checked_add(len).is_none() {
+        return Err(-EFAULT);
+    }
+    match cpu.bus.get_ram(addr, len) {
+        Some(mem) => Ok(mem.to_vec()),
+        None if len == 0 => Ok(Vec::new()),
+        None => Err(-EFAULT),
+    }
+}
+
+fn write_guest(cpu: &mut RV64ICpu, addr: u64, data: &[u8]) -> Result<(), i64> {
+    if addr.checked_add(data.len() as u64).is_none() {
+        return Err(-EFAULT);
+    }
+    cpu.bus.write_bytes(addr, data).map_err(|_| -EFAULT)
+}
+
+fn read_guest_u64(cpu: &RV64ICpu, addr: u64) -> Result<u64, i64> {
+    Ok(u64::from_le_bytes(
+        read_guest(cpu, addr, 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Reads a NUL-terminated path
+fn read_path(cpu: &RV64ICpu, addr: u64) -> Result<PathBuf, i64> {
+    let mut path = Vec::new();
+    for i in 0..PATH_MAX {
+        match read_guest(cpu, addr.wrapping_add(i), 1)?[0] {
+            0 => return Ok(PathBuf::from(OsString::from_vec(path))),
+            c => path.push(c),
+        }
+    }
+    Err(-EINVAL)
+}
+
+/// struct stat of the asm-generic ABI (128 bytes)
+fn stat_bytes(st: &Metadata) -> [u8; 128] {
+    let mut b = [0u8; 128];
+    let fields: [(usize, u64, usize); 17] = [
+        (0, st.dev(), 8),
+        (8, st.ino(), 8),
+        (16, st.mode() as u64, 4),
+        (20, st.nlink(), 4),
+        (24, st.uid() as u64, 4),
+        (28, st.gid() as u64, 4),
+        (32, st.rdev(), 8),
+        (48, st.size(), 8),
+        (56, st.blksize(), 4),
+        (64, st.blocks(), 8),
+        (72, st.atime() as u64, 8),
+        (80, st.atime_nsec() as u64, 8),
+        (88, st.mtime() as u64, 8),
+        (96, st.mtime_nsec() as u64, 8),
+        (104, st.ctime() as u64, 8),
+        (112, st.ctime_nsec() as u64, 8),
+        (120, 0, 8),
+    ];
+    for (offs, val, size) in fields {
+        b[offs..offs + size].copy_from_slice(&val.to_le_bytes()[..size]);
+    }
+    b
+}
+
+/// stat of the console (stdin, stdout, stderr): a character device
+fn console_stat_bytes() -> [u8; 128] {
+    let mut b = [0u8; 128];
+    b[16..20].copy_from_slice(&(S_IFCHR | 0o620).to_le_bytes());
+    b[20..24].copy_from_slice(&1u32.to_le_bytes());
+    b[56..60].copy_from_slice(&1024u32.to_le_bytes());
+    b
+}
+
+/// struct timespec or struct timeval (with microseconds)
+fn time_bytes(secs: u64, subsec: u64) -> [u8; 16] {
+    let mut b = [0u8; 16];
+    b[0..8].copy_from_slice(&secs.to_le_bytes());
+    b[8..16].copy_from_slice(&subsec.to_le_bytes());
+    b
+}
+
+impl LinuxUser {
+    fn new(brk_start: u64, console_out: Option<Box<dyn Fn(u8)>>) -> LinuxUser {
+        LinuxUser {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start,
+            brk: brk_start,
+            mmap_next: MMAP_BASE,
+            console_out,
+            start: Instant::now(),
+            random: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    fn random_bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_random() as u8).collect()
+    }
+
+    fn fd(&mut self, fd: u64) -> Result<&mut Fd, i64> {
+        match self.fds.get_mut(fd as usize) {
+            Some(Some(fd)) => Ok(fd),
+            _ => Err(-EBADF),
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, i64> {
+        match self.fd(fd)? {
+            Fd::File(file) => Ok(file),
+            _ => Err(-ESPIPE),
+        }
+    }
+
+    /// The lowest free file descriptor
+    fn install_fd(&mut self, fd: Fd) -> u64 {
+        match self.fds.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.fds[i] = Some(fd);
+                i as u64
+            }
+            None => {
+                self.fds.push(Some(fd));
+                (self.fds.len() - 1) as u64
+            }
+        }
+    }
+
+    /// Puts argument and environment strings, AT_RANDOM bytes, the auxiliary vector, envp,
+    /// argv and argc on the stack. Returns the initial SP pointing to argc.
+    fn setup_stack(
+        &mut self,
+        cpu: &mut RV64ICpu,
+        elf: &Elf,
+        argv: &[String],
+        envp: &[String],
+    ) -> Result<u64, String> {
+        let mut sp = STACK_TOP;
+        let mut push_bytes = |cpu: &mut RV64ICpu, bytes: &[u8]| -> Result<u64, String> {
+            sp -= bytes.len() as u64;
+            write_guest(cpu, sp, bytes).map_err(|_| "arguments don't fit on the stack")?;
+            Ok(sp)
+        };
+        let mut push_str = |cpu: &mut RV64ICpu, s: &String| {
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.push(0);
+            push_bytes(cpu, &bytes)
+        };
+        let env_ptrs = envp
+            .iter()
+            .map(|s| push_str(cpu, s))
+            .collect::<Result<Vec<u64>, String>>()?;
+        let arg_ptrs = argv
+            .iter()
+            .map(|s| push_str(cpu, s))
+            .collect::<Result<Vec<u64>, String>>()?;
+        let random = self.random_bytes(16);
+        let random_ptr = push_bytes(cpu, &random)?;
+
+        let auxv = [
+            (AT_PHDR, elf.phdr_vaddr().unwrap_or(0)),
+            (AT_PHENT, elf.phentsize as u64),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random_ptr),
+            (AT_EXECFN, arg_ptrs.first().copied().unwrap_or(0)),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend(&arg_ptrs);
+        words.push(0);
+        words.extend(&env_ptrs);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|&(key, val)| [key, val]));
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        // the ABI requires 16-byte aligned SP
+        let sp = (sp - bytes.len() as u64) & !0xf;
+        write_guest(cpu, sp, &bytes).map_err(|_| "arguments don't fit on the stack")?;
+        Ok(sp)
+    }
+
+    fn syscall(&mut self, cpu: &mut RV64ICpu, nr: u64, a: [u64; 6]) -> SysResult {
+        match nr {
+            SYS_READ => self.read(cpu, a[0], a[1], a[2]),
+            SYS_WRITE => {
+                let data = read_guest(cpu, a[1], a[2].min(MAX_IO))?;
+                self.write(a[0], &data)
+            }
+            SYS_WRITEV => self.writev(cpu, a[0], a[1], a[2]),
+            SYS_OPENAT => self.openat(cpu, a[0] as i64, a[1], a[2], a[3]),
+            SYS_CLOSE => match self.fds.get_mut(a[0] as usize) {
+                Some(fd @ Some(_)) => {
+                    *fd = None;
+                    Ok(0)
+                }
+                _ => Err(-EBADF),
+            },
+            SYS_LSEEK => {
+                let pos = match a[2] {
+                    0 => SeekFrom::Start(a[1]),
+                    1 => SeekFrom::Current(a[1] as i64),
+                    2 => SeekFrom::End(a[1] as i64),
+                    _ => return Err(-EINVAL),
+                };
+                self.file(a[0])?.seek(pos).map_err(|e| errno(&e))
+            }
+            SYS_FSTAT => {
+                let stat = self.fstat(a[0])?;
+                write_guest(cpu, a[1], &stat)?;
+                Ok(0)
+            }
+            SYS_NEWFSTATAT => {
+                let path = read_path(cpu, a[1])?;
+                let stat = if path.as_os_str().is_empty() && a[3] & AT_EMPTY_PATH != 0 {
+                    self.fstat(a[0])?
+                } else {
+                    if path.is_relative() && a[0] as i64 != AT_FDCWD {
+                        return Err(-ENOSYS);
+                    }
+                    let metadata = if a[3] & AT_SYMLINK_NOFOLLOW != 0 {
+                        std::fs::symlink_metadata(path)
+                    } else {
+                        std::fs::metadata(path)
+                    };
+                    stat_bytes(&metadata.map_err(|e| errno(&e))?)
+                };
+                write_guest(cpu, a[2], &stat)?;
+                Ok(0)
+            }
+            SYS_BRK => Ok(self.brk(cpu, a[0])),
+            SYS_MMAP => self.mmap(cpu, a[0], a[1], a[3], a[4], a[5]),
+            SYS_MUNMAP => {
+                let end = page_align_up(a[1])
+                    .and_then(|len| a[0].checked_add(len))
+                    .ok_or(-EINVAL)?;
+                // only the last mapping is reclaimed
+                if a[0] >= MMAP_BASE && end == self.mmap_next {
+                    self.mmap_next = a[0];
+                }
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                cpu.exit(a[0] as i32);
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME => {
+                let (secs, nsecs) = match a[0] {
+                    CLOCK_REALTIME | CLOCK_REALTIME_COARSE => {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                        (now.as_secs(), now.subsec_nanos())
+                    }
+                    // monotonic and CPU time clocks start with the program
+                    1..=CLOCK_BOOTTIME => {
+                        let elapsed = self.start.elapsed();
+                        (elapsed.as_secs(), elapsed.subsec_nanos())
+                    }
+                    _ => return Err(-EINVAL),
+                };
+                write_guest(cpu, a[1], &time_bytes(secs, nsecs as u64))?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                if a[0] != 0 {
+                    let tv = time_bytes(now.as_secs(), now.subsec_micros() as u64);
+                    write_guest(cpu, a[0], &tv)?;
+                }
+                Ok(0)
+            }
+            SYS_UNAME => {
+                // struct utsname: 6 fields of 65 bytes
+                let mut uts = [0u8; 6 * 65];
+                let fields = ["Linux", "kompusim", "6.6.0", "#1", "riscv64", "(none)"];
+                for (i, field) in fields.iter().enumerate() {
+                    uts[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_guest(cpu, a[0], &uts)?;
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let len = a[1].min(MAX_IO);
+                let random = self.random_bytes(len as usize);
+                write_guest(cpu, a[0], &random)?;
+                Ok(len)
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(PID),
+            SYS_GETPPID => Ok(0),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            // the terminal isn't emulated, so libc treats the console as a file
+            SYS_IOCTL => Err(-ENOTTY),
+            // signals aren't delivered, there is a single thread and memory isn't protected
+            SYS_SET_ROBUST_LIST | SYS_SIGALTSTACK | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK
+            | SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            _ => {
+                eprintln!("WARN: linux-user: unsupported syscall {nr}");
+                Err(-ENOSYS)
+            }
+        }
+    }
+
+    fn read(&mut self, cpu: &mut RV64ICpu, fd: u64, buf: u64, count: u64) -> SysResult {
+        let mut data = vec![0u8; count.min(MAX_IO) as usize];
+        let n = match self.fd(fd)? {
+            Fd::Stdin => io::stdin().read(&mut data),
+            Fd::File(file) => file.read(&mut data),
+            Fd::Stdout | Fd::Stderr => return Err(-EBADF),
+        }
+        .map_err(|e| errno(&e))?;
+        write_guest(cpu, buf, &data[..n])?;
+        Ok(n as u64)
+    }
+
+    fn write(&mut self, fd: u64, data: &[u8]) -> SysResult {
+        let is_stderr = match self.fd(fd)? {
+            Fd::Stdin => return Err(-EBADF),
+            Fd::File(file) => return file.write(data).map(|n| n as u64).map_err(|e| errno(&e)),
+            console => matches!(console, Fd::Stderr),
+        };
+        if let Some(cb) = &self.console_out {
+            data.iter().for_each(|&octet| cb(octet));
+            return Ok(data.len() as u64);
+        }
+        let result = if is_stderr {
+            io::stderr().write_all(data)
+        } else {
+            io::stdout()
+                .write_all(data)
+                .and_then(|_| io::stdout().flush())
+        };
+        result.map(|_| data.len() as u64).map_err(|e| errno(&e))
+    }
+
+    fn writev(&mut self, cpu: &mut RV64ICpu, fd: u64, iov: u64, iovcnt: u64) -> SysResult {
+        if iovcnt > IOV_MAX {
+            return Err(-EINVAL);
+        }
+        let mut total = 0;
+        for i in 0..iovcnt {
+            // struct iovec { void *iov_base; size_t iov_len; }
+            let entry = iov.checked_add(i * 16).ok_or(-EFAULT)?;
+            let base = read_guest_u64(cpu, entry)?;
+            let len = read_guest_u64(cpu, entry.checked_add(8).ok_or(-EFAULT)?)?;
+            let data = read_guest(cpu, base, len.min(MAX_IO))?;
+            match self.write(fd, &data) {
+                Ok(n) => total += n,
+                Err(e) if total == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(total)
+    }
+
+    fn openat(
+        &mut self,
+        cpu: &mut RV64ICpu,
+        dirfd: i64,
+        path: u64,
+        flags: u64,
+        mode: u64,
+    ) -> SysResult {
+        let path = read_path(cpu, path)?;
+        if path.is_relative() && dirfd != AT_FDCWD {
+            eprintln!("WARN: linux-user: openat relative to a directory isn't supported");
+            return Err(-ENOSYS);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        let file = options.open(path).map_err(|e| errno(&e))?;
+        Ok(self.install_fd(Fd::File(file)))
+    }
+
+    fn fstat(&mut self, fd: u64) -> Result<[u8; 128], i64> {
+        match self.fd(fd)? {
+            Fd::File(file) => Ok(stat_bytes(&file.metadata().map_err(|e| errno(&e))?)),
+            _ => Ok(console_stat_bytes()),
+        }
+    }
+
+    /// brk(0) returns the current break, a failed request returns it unchanged
+    fn brk(&mut self, cpu: &mut RV64ICpu, addr: u64) -> u64 {
+        if (self.brk_start..=self.brk_start + BRK_SIZE).contains(&addr) {
+            // memory returned to the program is zeroed
+            if addr > self.brk {
+                let zeros = vec![0u8; (addr - self.brk) as usize];
+                let _ = write_guest(cpu, self.brk, &zeros);
+            }
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    fn mmap(
+        &mut self,
+        cpu: &mut RV64ICpu,
+        addr: u64,
+        len: u64,
+        flags: u64,
+        fd: u64,
+        offset: u64,
+    ) -> SysResult {
+        if len == 0 || len > MMAP_SIZE {
+            return Err(-EINVAL);
+        }
+        // len <= MMAP_SIZE, it can't overflow
+        let len = page_align_up(len).unwrap();
+        let start = if flags & MAP_FIXED != 0 {
+            if addr & (PAGE_SIZE - 1) != 0 {
+                return Err(-EINVAL);
+            }
+            addr
+        } else {
+            if self.mmap_next + len > MMAP_BASE + MMAP_SIZE {
+                return Err(-ENOMEM);
+            }
+            self.mmap_next += len;
+            self.mmap_next - len
+        };
+        let mut contents = vec![0u8; len as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            // private copy of the file
+            let file = self.file(fd).map_err(|_| -EBADF)?;
+            if offset.checked_add(len).is_none() {
+                return Err(-EINVAL);
+            }
+            let mut done = 0;
+            while done < contents.len() {
+                match file.read_at(&mut contents[done..], offset + done as u64) {
+                    Ok(0) => break,
+                    Ok(n) => done += n,
+                    Err(e) => return Err(errno(&e)),
+                }
+            }
+        }
+        write_guest(cpu, start, &contents).map_err(|_| -ENOMEM)?;
+        Ok(start)
+    }
+}
+
+impl EcallHandler for LinuxUser {
+    /// Syscall number in a7, arguments in a0-a5, the result or -errno is returned in a0
+    fn ecall(&mut self, cpu: &mut RV64ICpu) {
+        let nr = cpu.regs_r64(A7);
+        let args: [u64; 6] = std::array::from_fn(|i| cpu.regs_r64(A0 + i as u8));
+        let ret = match self.syscall(cpu, nr, args) {
+            Ok(val) => val,
+            Err(errno) => errno as u64,
+        };
+        cpu.regs_w64(A0, ret);
+    }
+}
+
+/// Creates a hart in U-mode running a statically linked program: the segments are loaded, the
+/// stack is set up with argv, envp and the auxiliary vector, syscalls are serviced by the host.
+/// stdout and stderr go to console_out if it's given.
+pub fn load_program(
+    elf: &Elf,
+    argv: &[String],
+    envp: &[String],
+    console_out: Option<Box<dyn Fn(u8)>>,
+) -> Result<RV64ICpu, String> {
+    if elf.is_dynamic() {
+        return Err("dynamically linked programs aren't supported, link with -static".to_string());
+    }
+    if elf.elf_type != ET_EXEC {
+        return Err("only static non-PIE executables (ET_EXEC) are supported".to_string());
+    }
+    let (start, end) = elf.vaddr_range().ok_or("no loadable segments")?;
+    let image_start = start & !(PAGE_SIZE - 1);
+    let brk_start = page_align_up(end)
+        .filter(|brk_start| *brk_start <= MMAP_BASE - BRK_SIZE)
+        .ok_or(format!("the program at 0x{start:x}..0x{end:x} is too big"))?;
+    let mut bus = Bus::new();
+    bus.attach_ram(Ram::new(image_start, brk_start + BRK_SIZE - image_start));
+    bus.attach_ram(Ram::new(MMAP_BASE, MMAP_SIZE));
+    bus.attach_ram(Ram::new(STACK_TOP - STACK_SIZE, STACK_SIZE));
+    elf.load(&mut bus)?;
+
+    let mut cpu = RV64ICpu::new(bus);
+    let mut user = LinuxUser::new(brk_start, console_out);
+    let sp = user.setup_stack(&mut cpu, elf, argv, envp)?;
+    cpu.regs_w64(SP, sp);
+    cpu.pc_jump(elf.entry);
+    // counters (rdtime, rdcycle) are readable in U-mode
+    cpu.csr_w64(csr::MCOUNTEREN, 0xffff_ffff);
+    cpu.csr_w64(csr::SCOUNTEREN, 0xffff_ffff);
+    cpu.csr_w64(csr::MTVEC, TRAP_VECTOR);
+    cpu.add_breakpoint(TRAP_VECTOR);
+    cpu.enter_mode(PrivMode::User);
+    cpu.set_ecall_handler(PrivMode::User, Box::new(user));
+    Ok(cpu)
+}
+
+/// Runs the program until it exits and returns its exit status. A fault (e.g. an illegal
+/// instruction) terminates the program like an uncaught signal: the status is 128 + signal.
+pub fn run_program(cpu: &mut RV64ICpu, max_instr: u64) -> Result<i32, String> {
+    match cpu.exec_continue(max_instr) {
+        ExecEvent::Exit(code) => Ok(code),
+        ExecEvent::Breakpoint(_) => Ok(report_fault(cpu)),
+        ExecEvent::MaxInstructions(pc) => Err(format!(
+            "the program didn't exit after {max_instr} instructions, PC 0x{pc:x}"
+        )),
+        ExecEvent::Watchpoint(hit) => Err(format!("watchpoint hit at PC 0x{:x}", hit.pc)),
+    }
+}
+
+fn report_fault(cpu: &RV64ICpu) -> i32 {
+    const SIGILL: i32 = 4;
+    const SIGTRAP: i32 = 5;
+    const SIGBUS: i32 = 7;
+    const SIGSEGV: i32 = 11;
+    let (signal, pc) = if cpu.get_priv_mode() == PrivMode::Machine {
+        let cause = cpu.csr_r64(csr::MCAUSE).unwrap_or(0);
+        let signal = match cause {
+            csr::EXC_ILLEGAL_INSTR => SIGILL,
+            csr::EXC_BREAKPOINT => SIGTRAP,
+            csr::EXC_INSTR_MISALIGNED | csr::EXC_LOAD_MISALIGNED | csr::EXC_STORE_MISALIGNED => {
+                SIGBUS
+            }
+            // access faults (nothing is mapped at the address, e.g. NULL) and the rest
+            _ => SIGSEGV,
+        };
+        (signal, cpu.csr_r64(csr::MEPC).unwrap_or(0))
+    } else {
+        // jump to NULL
+        (SIGSEGV, cpu.get_pc())
+    };
+    eprintln!("ERROR: linux-user: uncaught signal {signal} at PC 0x{pc:x}");
+    128 + signal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUF: u64 = MMAP_BASE + 0x100;
+
+    fn test_cpu() -> (RV64ICpu, LinuxUser) {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(0x1_0000, 0x1_0000 + BRK_SIZE));
+        bus.attach_ram(Ram::new(MMAP_BASE, MMAP_SIZE));
+        (RV64ICpu::new(bus), LinuxUser::new(0x2_0000, None))
+    }
+
+    #[test]
+    fn test_file_syscalls() {
+        let (mut cpu, mut user) = test_cpu();
+        let path = std::env::temp_dir().join(format!("kompusim-linux-user-{}", std::process::id()));
+        let mut path_bytes = path.to_str().unwrap().as_bytes().to_vec();
+        path_bytes.push(0);
+        cpu.bus.write_bytes(BUF, &path_bytes).unwrap();
+        let data = BUF + 0x1000;
+        cpu.bus.write_bytes(data, b"kompusim").unwrap();
+
+        let flags = O_WRONLY | O_CREAT | O_TRUNC;
+        let fd = user.syscall(
+            &mut cpu,
+            SYS_OPENAT,
+            [AT_FDCWD as u64, BUF, flags, 0o644, 0, 0],
+        );
+        assert_eq!(fd, Ok(3));
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_WRITE, [3, data, 8, 0, 0, 0]),
+            Ok(8)
+        );
+        assert_eq!(user.syscall(&mut cpu, SYS_CLOSE, [3, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_CLOSE, [3, 0, 0, 0, 0, 0]),
+            Err(-EBADF)
+        );
+
+        let fd = user.syscall(&mut cpu, SYS_OPENAT, [AT_FDCWD as u64, BUF, 0, 0, 0, 0]);
+        assert_eq!(fd, Ok(3));
+        let stat = BUF + 0x2000;
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_FSTAT, [3, stat, 0, 0, 0, 0]),
+            Ok(0)
+        );
+        // st_size
+        assert_eq!(cpu.bus.read64(stat + 48).unwrap(), 8);
+        let buf = BUF + 0x3000;
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_READ, [3, buf, 100, 0, 0, 0]),
+            Ok(8)
+        );
+        assert_eq!(cpu.get_ram(buf, 8), Some(&b"kompusim"[..]));
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_READ, [3, buf, 100, 0, 0, 0]),
+            Ok(0)
+        );
+        assert_eq!(user.syscall(&mut cpu, SYS_CLOSE, [3, 0, 0, 0, 0, 0]), Ok(0));
+        std::fs::remove_file(&path).unwrap();
+
+        let fd = user.syscall(&mut cpu, SYS_OPENAT, [AT_FDCWD as u64, BUF, 0, 0, 0, 0]);
+        // ENOENT
+        assert_eq!(fd, Err(-2));
+        // bad buffer address
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_WRITE, [1, 0x10, 8, 0, 0, 0]),
+            Err(-EFAULT)
+        );
+    }
+
+    #[test]
+    fn test_memory_syscalls() {
+        let (mut cpu, mut user) = test_cpu();
+        assert_eq!(user.syscall(&mut cpu, SYS_BRK, [0; 6]), Ok(0x2_0000));
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_BRK, [0x2_1000, 0, 0, 0, 0, 0]),
+            Ok(0x2_1000)
+        );
+        // beyond the heap area
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_BRK, [MMAP_BASE, 0, 0, 0, 0, 0]),
+            Ok(0x2_1000)
+        );
+
+        let anon = [0, 0x1800, 3, MAP_ANONYMOUS | 0x2, u64::MAX, 0];
+        assert_eq!(user.syscall(&mut cpu, SYS_MMAP, anon), Ok(MMAP_BASE));
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_MMAP, anon),
+            Ok(MMAP_BASE + 0x2000)
+        );
+        let unmap = [MMAP_BASE + 0x2000, 0x1800, 0, 0, 0, 0];
+        assert_eq!(user.syscall(&mut cpu, SYS_MUNMAP, unmap), Ok(0));
+        cpu.bus.write64(MMAP_BASE + 0x2000, u64::MAX).unwrap();
+        // the reclaimed mapping is reused and zeroed
+        assert_eq!(
+            user.syscall(&mut cpu, SYS_MMAP, anon),
+            Ok(MMAP_BASE + 0x2000)
+        );
+        assert_eq!(cpu.bus.read64(MMAP_BASE + 0x2000).unwrap(), 0);
+        let wraps = [u64::MAX - 0xfff, u64::MAX, 0, 0, 0, 0];
+        assert_eq!(user.syscall(&mut cpu, SYS_MUNMAP, wraps), Err(-EINVAL));
+        // iovec array wrapping around the address space
+        let iov = [1, u64::MAX - 8, 2, 0, 0, 0];
+        assert_eq!(user.syscall(&mut cpu, SYS_WRITEV, iov), Err(-EFAULT));
+        let iov = [1, MMAP_BASE, u64::MAX, 0, 0, 0];
+        assert_eq!(user.syscall(&mut cpu, SYS_WRITEV, iov), Err(-EINVAL));
+
+        let ts = MMAP_BASE;
+        assert_eq!(
+            user.syscall(
+                &mut cpu,
+                SYS_CLOCK_GETTIME,
+                [CLOCK_REALTIME, ts, 0, 0, 0, 0]
+            ),
+            Ok(0)
+        );
+        // after 2020
+        assert!(cpu.bus.read64(ts).unwrap() > 1_577_836_800);
+        assert_eq!(user.syscall(&mut cpu, 0xffff, [0; 6]), Err(-ENOSYS));
+    }
+}
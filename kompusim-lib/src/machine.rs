@@ -0,0 +1,582 @@
+// Machine (SoC) description: memory regions, devices and harts.
+// It is either loaded from a TOML file or assembled with MachineBuilder:
+//
+//   harts = 1
+//   isa = "rv64imac_zicsr_zifencei"
+//
+//   [[memory]]
+//   base = 0x8000_0000
+//   size = "16M"
+//
+//   [[device]]
+//   type = "uart"
+//   base = 0x1001_0000
+//   irq = 10
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::bus::Bus;
+use crate::clint::{Clint, CLINT_SIZE};
+use crate::device::{Dev, Device};
+use crate::fdt::DtConfig;
+use crate::plic::{Plic, PLIC_SIZE};
+use crate::ram::Ram;
+use crate::replay::InputLog;
+use crate::rom::Rom;
+use crate::rv64i_cpu::RV64ICpu;
+use crate::sbi::Sbi;
+use crate::semihosting::Semihosting;
+use crate::uart::Uart;
+use crate::uart16550::{Uart16550, UART16550_SIZE};
+use crate::virtio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+use crate::virtio_console::VirtioConsole;
+use crate::virtio_net::{parse_mac, parse_net_backend, VirtioNet};
+use crate::virtio_rng::{VirtioRng, DEFAULT_RNG_SEED};
+
+pub const DEFAULT_ISA: &str = "rv64imac_zicsr_zifencei";
+
+const UART_SIZE: u64 = 0x20;
+const DEFAULT_MAC: &str = "52:54:00:12:34:56";
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemKind {
+    #[default]
+    Ram,
+    Rom,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MemConfig {
+    pub base: u64,
+    /// Size in bytes or a string with suffix, e.g. "256M"
+    #[serde(deserialize_with = "deserialize_size")]
+    pub size: u64,
+    #[serde(default)]
+    pub kind: MemKind,
+    /// Binary image loaded at the base address
+    pub image: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DeviceKind {
+    Uart,
+    /// Core local interruptor: machine timer and software interrupts
+    Clint,
+    /// Platform-level interrupt controller: routes device interrupts to the hart
+    Plic,
+    /// NS16550A compatible UART
+    Uart16550,
+    VirtioNet {
+        /// Backend: loopback, pcap:<file> or unix:<local_socket>,<peer_socket>
+        #[serde(default = "default_netdev")]
+        netdev: String,
+        mac: Option<String>,
+    },
+    VirtioConsole,
+    VirtioRng {
+        seed: Option<u64>,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub base: u64,
+    /// Interrupt line number on the platform interrupt controller
+    pub irq: Option<u32>,
+    #[serde(flatten)]
+    pub kind: DeviceKind,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    #[serde(default = "default_harts")]
+    pub harts: u32,
+    /// ISA string with extensions, e.g. "rv64imac_zicsr"
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    /// Kernel command line passed in the generated device tree
+    pub bootargs: Option<String>,
+    /// Handle SBI calls in the simulator, the payload is started in S-mode without M-mode
+    /// firmware
+    #[serde(default)]
+    pub sbi: bool,
+    /// Handle semihosting calls (console, host files, exit) in the simulator
+    #[serde(default)]
+    pub semihosting: bool,
+    #[serde(default)]
+    pub memory: Vec<MemConfig>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+fn default_harts() -> u32 {
+    1
+}
+
+fn default_isa() -> String {
+    DEFAULT_ISA.to_string()
+}
+
+fn default_netdev() -> String {
+    "loopback".to_string()
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        WithSuffix(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(size) => Ok(size),
+        Size::WithSuffix(s) => {
+            parse_size(&s).ok_or_else(|| D::Error::custom(format!("wrong size \"{s}\"")))
+        }
+    }
+}
+
+/// Parses sizes like "4096", "64K", "256MiB", "1G"
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number: u64 = s[..digits_end].parse().ok()?;
+    let multiplier = match s[digits_end..].to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            harts: default_harts(),
+            isa: default_isa(),
+            bootargs: None,
+            sbi: false,
+            semihosting: false,
+            memory: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn from_toml(toml_str: &str) -> Result<MachineConfig, String> {
+        toml::from_str(toml_str).map_err(|e| format!("machine config: {e}"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<MachineConfig, String> {
+        let toml_str =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {path:?}: {e}"))?;
+        MachineConfig::from_toml(&toml_str).map_err(|e| format!("{path:?}: {e}"))
+    }
+
+    pub fn dt_config(&self) -> DtConfig {
+        DtConfig {
+            num_harts: self.harts,
+            isa: self.isa.clone(),
+            bootargs: self.bootargs.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the parts of the config which don't depend on the host (files, sockets, etc.)
+    pub fn validate(&self) -> Result<(), String> {
+        if self.harts != 1 {
+            return Err(format!(
+                "{} harts requested, only 1 is supported",
+                self.harts
+            ));
+        }
+        if !self.isa.starts_with("rv64i") && !self.isa.starts_with("rv64g") {
+            return Err(format!("unsupported ISA \"{}\"", self.isa));
+        }
+        // (base, end)
+        let mut regions = Vec::new();
+        for (base, size) in self
+            .memory
+            .iter()
+            .map(|m| (m.base, m.size))
+            .chain(self.devices.iter().map(|d| (d.base, d.kind.size())))
+        {
+            let end = base.checked_add(size).ok_or(format!(
+                "region at 0x{base:x} of size 0x{size:x} wraps the address space"
+            ))?;
+            regions.push((base, end));
+        }
+        regions.sort();
+        for pair in regions.windows(2) {
+            let ((a_base, a_end), (b_base, _)) = (pair[0], pair[1]);
+            if a_end > b_base {
+                return Err(format!("regions at 0x{a_base:x} and 0x{b_base:x} overlap"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceKind {
+    /// Size of the device address region
+    pub fn size(&self) -> u64 {
+        match self {
+            DeviceKind::Uart => UART_SIZE,
+            DeviceKind::Clint => CLINT_SIZE,
+            DeviceKind::Plic => PLIC_SIZE,
+            DeviceKind::Uart16550 => UART16550_SIZE,
+            DeviceKind::VirtioNet { .. }
+            | DeviceKind::VirtioConsole
+            | DeviceKind::VirtioRng { .. } => VIRTIO_MMIO_SIZE,
+        }
+    }
+}
+
+/// Builds a CPU with the bus populated according to MachineConfig
+#[derive(Default)]
+pub struct MachineBuilder {
+    config: MachineConfig,
+    /// Shared by all console devices (UARTs, virtio-console)
+    console_out: Option<Rc<dyn Fn(u8)>>,
+    console_in: Option<Box<dyn FnMut() -> Option<u8>>>,
+    input_log: Option<InputLog>,
+}
+
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        Default::default()
+    }
+
+    pub fn from_config(config: MachineConfig) -> MachineBuilder {
+        MachineBuilder {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn harts(mut self, harts: u32) -> MachineBuilder {
+        self.config.harts = harts;
+        self
+    }
+
+    pub fn isa(mut self, isa: &str) -> MachineBuilder {
+        self.config.isa = isa.to_string();
+        self
+    }
+
+    pub fn bootargs(mut self, bootargs: &str) -> MachineBuilder {
+        self.config.bootargs = Some(bootargs.to_string());
+        self
+    }
+
+    pub fn sbi(mut self, sbi: bool) -> MachineBuilder {
+        self.config.sbi = sbi;
+        self
+    }
+
+    pub fn semihosting(mut self, semihosting: bool) -> MachineBuilder {
+        self.config.semihosting = semihosting;
+        self
+    }
+
+    pub fn ram(mut self, base: u64, size: u64) -> MachineBuilder {
+        self.config.memory.push(MemConfig {
+            base,
+            size,
+            kind: MemKind::Ram,
+            image: None,
+        });
+        self
+    }
+
+    pub fn rom(mut self, base: u64, size: u64, image: PathBuf) -> MachineBuilder {
+        self.config.memory.push(MemConfig {
+            base,
+            size,
+            kind: MemKind::Rom,
+            image: Some(image),
+        });
+        self
+    }
+
+    pub fn device(mut self, kind: DeviceKind, base: u64, irq: Option<u32>) -> MachineBuilder {
+        self.config.devices.push(DeviceConfig { base, irq, kind });
+        self
+    }
+
+    /// Host console output for UARTs and virtio-console
+    pub fn console_out(mut self, cb: Box<dyn Fn(u8)>) -> MachineBuilder {
+        self.console_out = Some(Rc::from(cb));
+        self
+    }
+
+    /// Host console input, it is connected to the first virtio-console or 16550 UART, otherwise
+    /// to the SBI console
+    pub fn console_in(mut self, source: Box<dyn FnMut() -> Option<u8>>) -> MachineBuilder {
+        self.console_in = Some(source);
+        self
+    }
+
+    /// Inputs from the host (console, network, semihosting time and stdin) are recorded to or
+    /// replayed from the log
+    pub fn input_log(mut self, log: InputLog) -> MachineBuilder {
+        self.input_log = Some(log);
+        self
+    }
+
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+
+    fn console_out_callback(&self) -> Option<Box<dyn Fn(u8)>> {
+        let out = self.console_out.clone()?;
+        Some(Box::new(move |octet| out(octet)))
+    }
+
+    fn build_device(&mut self, kind: &DeviceKind, bus: &Bus) -> Result<Box<dyn Dev>, String> {
+        Ok(match kind {
+            DeviceKind::Uart => {
+                let mut uart = Uart::new("0".to_string());
+                if let Some(cb) = self.console_out_callback() {
+                    uart.register_out_callback(cb);
+                }
+                Box::new(uart)
+            }
+            DeviceKind::Clint => Box::new(Clint::new(bus.clock())),
+            DeviceKind::Plic => Box::new(Plic::new()),
+            DeviceKind::Uart16550 => {
+                let mut uart = Uart16550::new("0".to_string());
+                if let Some(cb) = self.console_out_callback() {
+                    uart.register_out_callback(cb);
+                }
+                if let Some(source) = self.console_in.take() {
+                    uart.register_in_source(source);
+                }
+                Box::new(uart)
+            }
+            DeviceKind::VirtioNet { netdev, mac } => {
+                let mac = parse_mac(mac.as_deref().unwrap_or(DEFAULT_MAC))?;
+                let mut backend = parse_net_backend(netdev)?;
+                if let Some(log) = &self.input_log {
+                    backend = log.net(backend);
+                }
+                Box::new(VirtioMmio::new(VirtioNet::new(mac, backend)))
+            }
+            DeviceKind::VirtioConsole => {
+                let mut console = VirtioConsole::new("0".to_string());
+                if let Some(cb) = self.console_out_callback() {
+                    console.register_out_callback(cb);
+                }
+                if let Some(source) = self.console_in.take() {
+                    console.register_in_source(source);
+                }
+                Box::new(VirtioMmio::new(console))
+            }
+            DeviceKind::VirtioRng { seed } => Box::new(VirtioMmio::new(VirtioRng::new(
+                seed.unwrap_or(DEFAULT_RNG_SEED),
+            ))),
+        })
+    }
+
+    /// Returns the CPU with PC set to the first memory region
+    pub fn build(mut self) -> Result<RV64ICpu, String> {
+        self.config.validate()?;
+        if let Some(log) = &self.input_log {
+            self.console_in = Some(log.console(self.console_in.take()));
+        }
+        let mut bus = Bus::new();
+        for mem in &self.config.memory {
+            match mem.kind {
+                MemKind::Ram => {
+                    bus.attach_ram(Ram::new(mem.base, mem.size));
+                    if let Some(image) = &mem.image {
+                        bus.load_file(mem.base, image)
+                            .map_err(|e| format!("failed to load {image:?}: {e}"))?;
+                    }
+                }
+                MemKind::Rom => {
+                    let image = match &mem.image {
+                        Some(path) => std::fs::read(path)
+                            .map_err(|e| format!("failed to read {path:?}: {e}"))?,
+                        None => Vec::new(),
+                    };
+                    let rom = Box::new(Rom::new(mem.size, &image)?);
+                    bus.attach_device(Device::new(rom, mem.base, mem.size));
+                }
+            }
+        }
+        for dev_cfg in self.config.devices.clone() {
+            let dev = self.build_device(&dev_cfg.kind, &bus)?;
+            let mut device = Device::new(dev, dev_cfg.base, dev_cfg.kind.size());
+            if let Some(irq) = dev_cfg.irq {
+                device = device.with_irq(irq);
+            }
+            bus.attach_device(device);
+        }
+        let mut cpu = RV64ICpu::new(bus);
+        if let Some(mem) = self.config.memory.first() {
+            cpu.pc_jump(mem.base);
+        }
+        if self.config.sbi {
+            let mut sbi = Sbi::new();
+            if let Some(cb) = self.console_out_callback() {
+                sbi.register_out_callback(cb);
+            }
+            if let Some(source) = self.console_in.take() {
+                sbi.register_in_source(source);
+            }
+            cpu.enable_sbi(sbi);
+        }
+        if self.config.semihosting {
+            let mut semihosting = Semihosting::new();
+            if let Some(cb) = self.console_out_callback() {
+                semihosting.register_out_callback(cb);
+            }
+            if let Some(log) = &self.input_log {
+                semihosting.set_input_log(log.clone());
+            }
+            cpu.enable_semihosting(semihosting);
+        }
+        if let Some(log) = self.input_log.take() {
+            cpu.set_input_log(log);
+        }
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const MACHINE_TOML: &str = r#"
+        isa = "rv64ic_zicsr"
+        bootargs = "console=hvc0"
+
+        [[memory]]
+        base = 0x8000_0000
+        size = "64K"
+
+        [[memory]]
+        base = 0x1000
+        size = 0x1000
+        kind = "rom"
+
+        [[device]]
+        type = "uart"
+        base = 0x1001_0000
+        irq = 10
+
+        [[device]]
+        type = "virtio-rng"
+        base = 0x1000_3000
+        irq = 3
+        seed = 42
+    "#;
+
+    #[test]
+    fn test_parse_machine_config() {
+        let cfg = MachineConfig::from_toml(MACHINE_TOML).unwrap();
+        assert_eq!(cfg.harts, 1);
+        assert_eq!(cfg.isa, "rv64ic_zicsr");
+        assert!(!cfg.sbi);
+        assert_eq!(cfg.memory[0].size, 64 * 1024);
+        assert_eq!(cfg.memory[1].kind, MemKind::Rom);
+        assert_eq!(
+            cfg.devices[1],
+            DeviceConfig {
+                base: 0x1000_3000,
+                irq: Some(3),
+                kind: DeviceKind::VirtioRng { seed: Some(42) }
+            }
+        );
+        assert_eq!(cfg.dt_config().bootargs.as_deref(), Some("console=hvc0"));
+        assert!(MachineConfig::from_toml("cpus = 2").is_err());
+        assert!(MachineConfig::from_toml("[[device]]\ntype = \"gpu\"\nbase = 0").is_err());
+        assert_eq!(parse_size("256MiB"), Some(256 << 20));
+        assert_eq!(parse_size("1T"), None);
+
+        let example = concat!(env!("CARGO_MANIFEST_DIR"), "/../machines/kompusim.toml");
+        let cfg = MachineConfig::from_file(Path::new(example)).unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.devices.len(), 5);
+    }
+
+    #[test]
+    fn test_machine_builder() {
+        let out = Rc::new(RefCell::new(String::new()));
+        let out_cb = out.clone();
+        let mut cpu = MachineBuilder::from_config(MachineConfig::from_toml(MACHINE_TOML).unwrap())
+            .console_out(Box::new(move |b| out_cb.borrow_mut().push(b as char)))
+            .build()
+            .unwrap();
+        assert_eq!(cpu.get_pc(), 0x8000_0000);
+        cpu.bus.write32(0x1001_0000, b'K' as u32).unwrap();
+        assert_eq!(out.borrow().as_str(), "K");
+        // virtio-rng: device id
+        assert_eq!(cpu.bus.read32(0x1000_3008).unwrap(), 4);
+        assert_eq!(
+            cpu.bus.devices().map(|d| d.irq).collect::<Vec<_>>(),
+            [None, Some(10), Some(3)]
+        );
+
+        let overlap = MachineBuilder::new().ram(0x8000_0000, 0x1000).device(
+            DeviceKind::Uart,
+            0x8000_0f00,
+            None,
+        );
+        assert!(overlap.build().is_err());
+        let wrap = MachineBuilder::new().ram(u64::MAX - 0xfff, 0x2000);
+        assert!(wrap.build().is_err_and(|e| e.contains("wraps")));
+        let top = MachineBuilder::new()
+            .ram(0xffff_ffff_0000_0000, 0x1000)
+            .device(DeviceKind::Uart, 0xffff_ffff_0000_0800, None);
+        assert!(top.build().is_err_and(|e| e.contains("overlap")));
+        assert!(MachineBuilder::new().harts(2).build().is_err());
+    }
+
+    #[test]
+    fn test_linux_profile_uart_irq_via_plic() {
+        let profile = concat!(env!("CARGO_MANIFEST_DIR"), "/../machines/linux.toml");
+        let cfg = MachineConfig::from_file(Path::new(profile)).unwrap();
+        let mut cpu = MachineBuilder::from_config(cfg).build().unwrap();
+        // PLIC: priority of irq 10, enable it for the S-mode context
+        cpu.bus.write32(0xc00_0000 + 4 * 10, 1).unwrap();
+        cpu.bus.write32(0xc00_2080, 1 << 10).unwrap();
+        assert_eq!(cpu.bus.pending_irqs(), 0);
+        // 16550: enable THR empty interrupt
+        cpu.bus.write8(0x1000_0001, 0x02).unwrap();
+        assert_eq!(cpu.bus.pending_irqs(), crate::csr::MIP_SEIP);
+        // claim
+        assert_eq!(cpu.bus.read32(0xc20_1004).unwrap(), 10);
+        cpu.bus.refresh_irqs();
+        assert_eq!(cpu.bus.pending_irqs(), 0);
+    }
+
+    #[test]
+    fn test_machine_with_clint() {
+        let mut cpu = MachineBuilder::new()
+            .ram(0x8000_0000, 0x1000)
+            .device(DeviceKind::Clint, 0x200_0000, None)
+            .build()
+            .unwrap();
+        // mtime follows the bus clock
+        cpu.bus.tick();
+        cpu.bus.tick();
+        assert_eq!(cpu.bus.read64(0x200_bff8).unwrap(), 2);
+        // mtimecmp
+        cpu.bus.write64(0x200_4000, 2).unwrap();
+        cpu.bus.poll_devices();
+        assert_eq!(cpu.bus.pending_irqs(), crate::csr::MIP_MTIP);
+    }
+}
@@ -1,14 +1,32 @@
 mod tui;
 
-use clap::{arg, Parser, Subcommand};
+use anstream::println;
+use clap::{Parser, Subcommand};
 use kompusim::rv64i_disasm::hex_to_u64;
+use owo_colors::OwoColorize;
+use std::io::Read;
 use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
 
-use kompusim::bus;
-use kompusim::device::Device;
-use kompusim::ram;
-use kompusim::rv64i_cpu::RV64ICpu;
-use kompusim::uart::Uart;
+use kompusim::callgraph::{self, CallGraph};
+use kompusim::conformance::{self, Outcome};
+use kompusim::coverage::{code_instructions, Coverage};
+use kompusim::dwarf::LineTable;
+use kompusim::elf::{Elf, SymbolMap};
+use kompusim::fdt::{dump_dtb, generate_dtb, place_dtb, place_initrd};
+use kompusim::gdb_stub;
+use kompusim::htif::{Htif, FROMHOST_OFFSET};
+#[cfg(unix)]
+use kompusim::linux_user;
+use kompusim::machine::{DeviceKind, MachineBuilder, MachineConfig};
+use kompusim::profile::Profiler;
+use kompusim::replay::InputLog;
+use kompusim::rv64i_cpu::{ExecEvent, RV64ICpu};
+use kompusim::snapshot::Snapshot;
+use kompusim::trace::{parse_range, read_trace_file, TraceFilter, TraceFormat, Tracer};
+use kompusim::trace_diff::{diff_traces, IgnoreRules, NONDETERMINISTIC_CSRS};
+use kompusim::undo::DEFAULT_UNDO_BUDGET;
 use tui::TuiMenuCmd;
 
 #[derive(Parser)]
@@ -25,17 +43,33 @@ struct Args {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     // Disasm {},
     /// Load a binary file and execute it
     Exec {
-        /// Address in hex where to load the binary (e.g, 0x0000000080000000)
-        #[arg(short, long)]
-        load_addr: String,
+        /// Address in hex where to load the binary (e.g, 0x0000000080000000), with --elf the
+        /// start of RAM (default the lowest segment address)
+        #[arg(short, long, required_unless_present = "elf")]
+        load_addr: Option<String>,
 
         /// Path to the binary file
+        #[arg(long, required_unless_present = "elf", conflicts_with = "elf")]
+        bin: Option<PathBuf>,
+
+        /// ELF executable: the segments are loaded at their addresses and execution starts at
+        /// the entry point
+        #[arg(long)]
+        elf: Option<PathBuf>,
+
+        /// Payload (e.g. Linux kernel) loaded next to the firmware given in --bin, like the
+        /// OpenSBI fw_jump firmware expects
+        #[arg(long)]
+        payload: Option<PathBuf>,
+
+        /// Address in hex where to load the payload (default 0x80200000)
         #[arg(long)]
-        bin: PathBuf,
+        payload_addr: Option<String>,
 
         /// RAM size in KiBytes (defult 4)
         #[arg(short, long)]
@@ -52,14 +86,317 @@ enum Commands {
         /// Run in with interactive menu, don't execute
         #[arg(short, long, action=clap::ArgAction::SetTrue)]
         interactive: Option<bool>,
+
+        /// Number of the last executed instructions which can be undone in the interactive mode
+        /// (default 100000), 0 - disables reverse execution
+        #[arg(long)]
+        undo: Option<usize>,
+
+        /// Wait for gdb on a TCP port ("1234", "<host>:<port>") or a Unix socket
+        /// ("unix:<path>") and execute under its control
+        #[arg(long, conflicts_with = "interactive")]
+        gdb: Option<String>,
+
+        /// Attach virtio-net device with backend: loopback, pcap:<file> or
+        /// unix:<local_socket>,<peer_socket>
+        #[arg(long)]
+        netdev: Option<String>,
+
+        /// MAC address of the virtio-net device (default 52:54:00:12:34:56)
+        #[arg(long)]
+        mac: Option<String>,
+
+        /// Attach virtio-console device connected to the host console
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        virtio_console: Option<bool>,
+
+        /// Attach virtio-rng device seeded with the given value
+        #[arg(long)]
+        rng_seed: Option<u64>,
+
+        /// Generate device tree of the machine, place it at the top of RAM and pass its address in
+        /// a1
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        dtb: Option<bool>,
+
+        /// Kernel command line (/chosen/bootargs) of the generated device tree
+        #[arg(long)]
+        bootargs: Option<String>,
+
+        /// initramfs image placed at the top of RAM below the device tree, requires --dtb
+        #[arg(long)]
+        initrd: Option<PathBuf>,
+
+        /// Write the generated device tree to a .dtb file
+        #[arg(long)]
+        dump_dtb: Option<PathBuf>,
+
+        /// Handle SBI calls with the built-in SBI implementation: the binary (e.g. Linux kernel) is
+        /// started in S-mode without M-mode firmware
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        sbi: Option<bool>,
+
+        /// Handle semihosting calls: the program prints to the console, reads host files and
+        /// its exit code becomes the exit code of the simulator
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        semihosting: Option<bool>,
+
+        /// HTIF tohost/fromhost interface (riscv-tests): the program prints and exits with its
+        /// status through tohost. The address is taken from the "tohost" symbol of --elf.
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        htif: Option<bool>,
+
+        /// Address in hex of tohost, implies --htif (fromhost is at tohost + 0x40 unless --elf
+        /// has the "fromhost" symbol)
+        #[arg(long)]
+        tohost: Option<String>,
+
+        /// Machine description file (TOML) with RAM/ROM regions, devices, harts and ISA. The
+        /// default machine is RAM at the load address, a UART at 0x10010000 and a CLINT at
+        /// 0x2000000
+        #[arg(long)]
+        machine: Option<PathBuf>,
+
+        /// Restore the machine from a snapshot file (saved by the interactive mode or the GUI)
+        /// before running, the machine must have the same RAM regions and devices
+        #[arg(long)]
+        restore: Option<PathBuf>,
+
+        /// Record the inputs from the host (console, network frames, semihosting time and stdin)
+        /// with the instruction counts they arrived at to a file
+        #[arg(long)]
+        record: Option<PathBuf>,
+
+        /// Replay the inputs recorded with --record instead of taking them from the host, the
+        /// run is repeated exactly when the other options are the same
+        #[arg(long, conflicts_with = "record")]
+        replay: Option<PathBuf>,
+
+        /// Trace the executed instructions to a file ("-" - stdout)
+        #[arg(long)]
+        trace: Option<PathBuf>,
+
+        /// Trace format: spike (default, like Spike's --log-commits), spike-disasm (also with
+        /// the disassembly lines of Spike's -l) or binary
+        #[arg(long, requires = "trace")]
+        trace_format: Option<String>,
+
+        /// Trace only the instructions with PC in the range <start>:<end> (end excluded), e.g.
+        /// 0x80000000:0x80001000
+        #[arg(long, requires = "trace")]
+        trace_pc: Option<String>,
+
+        /// Trace only the instructions executed after <start> and before <end> instructions,
+        /// e.g. 1000:2000 or 1000:
+        #[arg(long, requires = "trace")]
+        trace_instr: Option<String>,
+
+        /// Profile the executed instructions and print the instruction mix and the hotspots (per
+        /// PC and per symbol of --elf) at exit
+        #[arg(long, action=clap::ArgAction::SetTrue)]
+        profile: Option<bool>,
+
+        /// Number of the hottest PCs, symbols and functions in the profiles (default 20)
+        #[arg(long)]
+        profile_top: Option<usize>,
+
+        /// Track the calls and returns and write the instruction counts per call path in the
+        /// folded stacks format of the flame graph tools (e.g. flamegraph.pl, inferno)
+        #[arg(long)]
+        folded: Option<PathBuf>,
+
+        /// Track the calls and returns and write the call graph in the callgrind format (e.g.
+        /// for KCachegrind)
+        #[arg(long)]
+        callgrind: Option<PathBuf>,
+
+        /// Write the instruction and branch-direction coverage per address to the file at exit,
+        /// the never executed instructions of --elf are listed too
+        #[arg(long)]
+        coverage: Option<PathBuf>,
+
+        /// Write the coverage as an lcov tracefile with the source lines of the DWARF line info
+        /// of --elf
+        #[arg(long, requires = "elf")]
+        lcov: Option<PathBuf>,
+    },
+    /// Run riscv-tests / riscv-arch-test ELFs of a directory and report pass/fail per test
+    Conformance {
+        /// Directory with the test ELFs (searched recursively)
+        dir: PathBuf,
+
+        /// Directory with reference signatures (<test>.reference_output) of riscv-arch-test,
+        /// by default they're looked up next to the ELFs and in references/
+        #[arg(long)]
+        ref_dir: Option<PathBuf>,
+
+        /// Maximum number of instruction per test (default 100000000)
+        #[arg(long)]
+        max_instr: Option<u64>,
+    },
+    /// Run a statically linked RISC-V Linux program, its syscalls are serviced by the host
+    #[cfg(unix)]
+    User {
+        /// Maximum number of instruction before stop
+        #[arg(long)]
+        max_instr: Option<u64>,
+
+        /// Path to the ELF executable
+        elf: PathBuf,
+
+        /// Arguments of the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Report registers and memory ranges which differ between two machine snapshots
+    SnapshotDiff {
+        /// The first snapshot file
+        a: PathBuf,
+
+        /// The second snapshot file
+        b: PathBuf,
+    },
+    /// Compare two instruction traces (Spike commit logs or binary traces of exec --trace) and
+    /// report the first divergence
+    TraceDiff {
+        /// The first trace, e.g. of kompusim
+        a: PathBuf,
+
+        /// The second trace, e.g. of Spike or an RTL simulation
+        b: PathBuf,
+
+        /// Comma separated CSRs (names or numbers) whose read and written values are ignored
+        /// (default cycle,time,instret,mcycle,minstret, "" - none)
+        #[arg(long)]
+        ignore_csr: Option<String>,
+
+        /// Comma separated address ranges <start>:<end> whose loaded and stored data is
+        /// ignored, e.g. 0x200bff8:0x200c000 (CLINT mtime)
+        #[arg(long)]
+        ignore_mem: Option<String>,
+
+        /// Comma separated PC ranges <start>:<end> of instructions whose effects are ignored
+        #[arg(long)]
+        ignore_pc: Option<String>,
+
+        /// Number of records shown before and after the divergence (default 5)
+        #[arg(long)]
+        context: Option<usize>,
     },
 }
 
+const CLINT_BASE: u64 = 0x200_0000;
+const UART0_BASE: u64 = 0x1001_0000;
+const VIRTIO_NET_BASE: u64 = 0x1000_1000;
+const VIRTIO_CONSOLE_BASE: u64 = 0x1000_2000;
+const VIRTIO_RNG_BASE: u64 = 0x1000_3000;
+// Interrupt lines of the platform interrupt controller
+const UART0_IRQ: u32 = 10;
+const VIRTIO_NET_IRQ: u32 = 1;
+const VIRTIO_CONSOLE_IRQ: u32 = 2;
+const VIRTIO_RNG_IRQ: u32 = 3;
+// OpenSBI FW_JUMP_ADDR/FW_PAYLOAD_OFFSET of the generic platform
+const DEFAULT_PAYLOAD_ADDR: u64 = 0x8020_0000;
+const DEFAULT_TEST_MAX_INSTR: u64 = 100_000_000;
+const DEFAULT_PROFILE_TOP: usize = 20;
+
 fn uart_out_to_console(octet: u8) {
     let char_ascii = octet as char;
     print!("{char_ascii}");
 }
 
+// Host stdin is read in a separate thread so the simulation isn't blocked
+fn console_in_from_stdin() -> Box<dyn FnMut() -> Option<u8>> {
+    let (in_send, in_recv) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 64];
+        while let Ok(n @ 1..) = std::io::stdin().read(&mut buf) {
+            if buf[..n].iter().any(|octet| in_send.send(*octet).is_err()) {
+                break;
+            }
+        }
+    });
+    Box::new(move || in_recv.try_recv().ok())
+}
+
+/// What is written when the simulation ends
+struct AtExit {
+    symbols: Option<SymbolMap>,
+    profile: bool,
+    profile_top: usize,
+    folded: Option<PathBuf>,
+    callgrind: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    lcov: Option<PathBuf>,
+    elf: Option<Elf>,
+}
+
+impl AtExit {
+    fn finish(&self, cpu: &mut RV64ICpu) {
+        // flush the trace
+        cpu.set_tracer(None);
+        if let Some(profiler) = cpu.profiler().filter(|_| self.profile) {
+            let profile = profiler.profile(self.symbols.as_ref());
+            eprint!("{}", profile.report(self.profile_top));
+        }
+        if let Some(profiler) = cpu.profiler() {
+            self.write_coverage(profiler);
+        }
+        if let Some(call_graph) = cpu.call_graph() {
+            let symbols = self.symbols.as_ref();
+            let functions = call_graph.functions(symbols);
+            eprint!(
+                "{}",
+                callgraph::report(&functions, call_graph.total(), self.profile_top)
+            );
+            let outputs = [
+                (&self.folded, call_graph.folded(symbols)),
+                (&self.callgrind, call_graph.callgrind(symbols)),
+            ];
+            for (path, output) in outputs {
+                if let Some(path) = path {
+                    if let Err(e) = std::fs::write(path, output) {
+                        eprintln!("ERROR: failed to write {path:?}: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_coverage(&self, profiler: &Profiler) {
+        if self.coverage.is_none() && self.lcov.is_none() {
+            return;
+        }
+        let mut coverage = Coverage::new(profiler.pc_counts());
+        if let Some(elf) = &self.elf {
+            coverage.add_code(&code_instructions(elf));
+        }
+        eprintln!("{}", coverage.summary().report());
+        let write = |path: &PathBuf, contents: String| {
+            if let Err(e) = std::fs::write(path, contents) {
+                eprintln!("ERROR: failed to write {path:?}: {e}");
+            }
+        };
+        if let Some(path) = &self.coverage {
+            write(path, coverage.to_raw());
+        }
+        if let (Some(path), Some(elf)) = (&self.lcov, &self.elf) {
+            match LineTable::from_elf(elf) {
+                Ok(Some(lines)) => write(path, coverage.to_lcov("kompusim", &lines, elf)),
+                Ok(None) => eprintln!("ERROR: no DWARF line info in --elf (build it with -g)"),
+                Err(e) => eprintln!("ERROR: {e}"),
+            }
+        }
+    }
+}
+
+fn trace_filter(pc: &Option<String>, instr: &Option<String>) -> Result<TraceFilter, String> {
+    Ok(TraceFilter {
+        pc: pc.as_deref().map(parse_range).transpose()?,
+        instr: instr.as_deref().map(parse_range).transpose()?,
+    })
+}
+
 fn main() {
     let args = Args::parse();
 
@@ -68,10 +405,41 @@ fn main() {
         Some(Commands::Exec {
             load_addr,
             bin,
+            elf,
+            payload,
+            payload_addr,
             ram,
             breakpoint,
             max_instr,
             interactive,
+            undo,
+            gdb,
+            netdev,
+            mac,
+            virtio_console,
+            rng_seed,
+            dtb,
+            bootargs,
+            initrd,
+            dump_dtb: dump_dtb_path,
+            sbi,
+            semihosting,
+            htif,
+            tohost,
+            machine,
+            restore,
+            record,
+            replay,
+            trace,
+            trace_format,
+            trace_pc,
+            trace_instr,
+            profile,
+            profile_top,
+            folded,
+            callgrind,
+            coverage,
+            lcov,
         }) => {
             let max_instr = max_instr.unwrap_or(u64::MAX);
 
@@ -83,27 +451,206 @@ fn main() {
                 // TODO: handel auto breakpoint case
             }
 
-            let ram_sz = ram.unwrap_or(4) * 1024;
+            let elf_file = elf.as_ref().map(|path| Elf::from_file(path).unwrap());
+            let elf_range = elf_file.as_ref().map(|elf| {
+                elf.vaddr_range()
+                    .expect("no loadable segments in --elf file")
+            });
+            let addr = match (load_addr, elf_range) {
+                (Some(load_addr), _) => hex_to_u64(load_addr).expect("wrong hex in --load_addr"),
+                (None, Some((start, _))) => start & !0xfff,
+                (None, None) => unreachable!("--load-addr is required without --elf"),
+            };
+            // RAM covers the ELF segments plus 1 MiB (e.g. for the stack)
+            let ram_kib = match elf_range {
+                Some((_, end)) => (end - addr).div_ceil(1 << 20) * 1024 + 1024,
+                None => 4,
+            };
+            let mut machine = match machine {
+                Some(machine) => {
+                    if ram.is_some() {
+                        eprintln!("WARN: --ram is ignored, RAM is described in {machine:?}");
+                    }
+                    MachineBuilder::from_config(MachineConfig::from_file(machine).unwrap())
+                }
+                None => MachineBuilder::new()
+                    .ram(addr, ram.unwrap_or(ram_kib) * 1024)
+                    .device(DeviceKind::Uart, UART0_BASE, Some(UART0_IRQ))
+                    .device(DeviceKind::Clint, CLINT_BASE, None),
+            };
+            if sbi.unwrap_or(false) {
+                machine = machine.sbi(true);
+            }
+            if semihosting.unwrap_or(false) {
+                machine = machine.semihosting(true);
+            }
+            machine = machine.console_out(Box::new(uart_out_to_console));
+            if let Some(netdev) = netdev {
+                let kind = DeviceKind::VirtioNet {
+                    netdev: netdev.clone(),
+                    mac: mac.clone(),
+                };
+                machine = machine.device(kind, VIRTIO_NET_BASE, Some(VIRTIO_NET_IRQ));
+            }
+            if virtio_console.unwrap_or(false) {
+                let kind = DeviceKind::VirtioConsole;
+                machine = machine.device(kind, VIRTIO_CONSOLE_BASE, Some(VIRTIO_CONSOLE_IRQ));
+            }
+            let has_console =
+                machine.config().sbi
+                    || machine.config().devices.iter().any(|d| {
+                        matches!(d.kind, DeviceKind::VirtioConsole | DeviceKind::Uart16550)
+                    });
+            // in the interactive mode stdin belongs to the menu
+            if has_console && !interactive.unwrap_or(false) && replay.is_none() {
+                machine = machine.console_in(console_in_from_stdin());
+            }
+            if let Some(seed) = rng_seed {
+                let kind = DeviceKind::VirtioRng { seed: Some(*seed) };
+                machine = machine.device(kind, VIRTIO_RNG_BASE, Some(VIRTIO_RNG_IRQ));
+            }
+            if let Some(bootargs) = bootargs {
+                machine = machine.bootargs(bootargs);
+            }
+            let input_log = match (record, replay) {
+                (Some(path), _) => Some(InputLog::record(path)),
+                (_, Some(path)) => Some(InputLog::replay(path)),
+                _ => None,
+            };
+            if let Some(input_log) = input_log {
+                match input_log {
+                    Ok(input_log) => machine = machine.input_log(input_log),
+                    Err(e) => {
+                        eprintln!("ERROR: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let mut dt_config = machine.config().dt_config();
+            let mut cpu0 = machine.build().unwrap();
+            if let Some(bin) = bin {
+                cpu0.bus.load_file(addr, bin).unwrap();
+                println!("Loaded {bin:?} at 0x{addr:x}");
+            }
+            let mut entry = addr;
+            if let (Some(path), Some(elf_file)) = (elf, &elf_file) {
+                elf_file.load(&mut cpu0.bus).unwrap();
+                entry = elf_file.entry;
+                println!("Loaded {path:?}, entry 0x{entry:x}");
+            }
+            if let Some(payload) = payload {
+                let payload_addr = match payload_addr {
+                    Some(a) => hex_to_u64(a).expect("wrong hex in --payload-addr"),
+                    None => DEFAULT_PAYLOAD_ADDR,
+                };
+                cpu0.bus.load_file(payload_addr, payload).unwrap();
+                println!("Loaded {payload:?} at 0x{payload_addr:x}");
+            }
+            cpu0.pc_jump(entry);
 
-            let addr = hex_to_u64(load_addr).expect("wrong hex in --load_addr");
-            let mut ram = ram::Ram::new(addr, ram_sz);
-            ram.load_bin_file(addr, bin).unwrap();
-            println!("Loaded {bin:?} at 0x{addr:x}");
-            // ram.dump_hex(addr, 80);
+            let tohost = match tohost {
+                Some(tohost) => Some(hex_to_u64(tohost).expect("wrong hex in --tohost")),
+                None if htif.unwrap_or(false) => Some(
+                    elf_file
+                        .as_ref()
+                        .and_then(|elf| elf.symbol("tohost"))
+                        .expect("--htif needs --tohost or the tohost symbol in --elf"),
+                ),
+                None => None,
+            };
+            if let Some(tohost) = tohost {
+                let fromhost = elf_file
+                    .as_ref()
+                    .and_then(|elf| elf.symbol("fromhost"))
+                    .unwrap_or(tohost + FROMHOST_OFFSET);
+                let mut htif = Htif::new(tohost, fromhost);
+                htif.check(&cpu0.bus).unwrap();
+                htif.register_out_callback(Box::new(uart_out_to_console));
+                cpu0.enable_htif(htif);
+            }
 
-            let mut bus = bus::Bus::new();
-            bus.attach_ram(ram);
-            let mut uart0 = Box::new(Uart::new("0".to_string()));
-            uart0.register_out_callback(Box::new(uart_out_to_console));
-            bus.attach_device(Device::new(uart0, 0x1001_0000, 0x20));
-            let mut cpu0 = RV64ICpu::new(bus);
-            cpu0.pc_jump(addr);
+            if let Some(initrd) = initrd {
+                if !dtb.unwrap_or(false) {
+                    eprintln!("WARN: --initrd without --dtb, the kernel won't find initramfs");
+                }
+                let image = std::fs::read(initrd).expect("failed to read --initrd");
+                let (start, end) = place_initrd(&mut cpu0, &image).unwrap();
+                println!("Loaded {initrd:?} at 0x{start:x}");
+                dt_config.initrd = Some((start, end));
+            }
+
+            if dtb.unwrap_or(false) || dump_dtb_path.is_some() {
+                let dtb_blob = generate_dtb(&cpu0.bus, &dt_config);
+                if let Some(path) = dump_dtb_path {
+                    dump_dtb(&dtb_blob, path).unwrap();
+                    println!("Device tree written to {path:?}");
+                }
+                if dtb.unwrap_or(false) {
+                    let dtb_addr = place_dtb(&mut cpu0, &dtb_blob).unwrap();
+                    println!("Device tree placed at 0x{dtb_addr:x}");
+                }
+            }
+
+            if let Some(restore) = restore {
+                if let Err(e) = Snapshot::load(restore).and_then(|s| cpu0.restore_snapshot(&s)) {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+                println!("Restored {restore:?} at 0x{:x}", cpu0.get_pc());
+            }
 
             if let Some(breakpoint) = break_point {
                 cpu0.add_breakpoint(breakpoint)
             }
 
-            if interactive.unwrap_or(false) {
+            if let Some(path) = trace {
+                let tracer = trace_filter(trace_pc, trace_instr).and_then(|filter| {
+                    let format = TraceFormat::parse(trace_format.as_deref().unwrap_or("spike"))?;
+                    Tracer::to_file(path, format, filter)
+                        .map_err(|e| format!("failed to create {path:?}: {e}"))
+                });
+                match tracer {
+                    Ok(tracer) => cpu0.set_tracer(Some(tracer)),
+                    Err(e) => {
+                        eprintln!("ERROR: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            // the coverage comes from the per-PC counts of the profiler
+            let profile = profile.unwrap_or(false);
+            if profile || coverage.is_some() || lcov.is_some() {
+                cpu0.set_profiler(Some(Profiler::new()));
+            }
+            if folded.is_some() || callgrind.is_some() {
+                cpu0.set_call_graph(Some(CallGraph::new()));
+            }
+            let symbols = elf_file.as_ref().map(|elf| SymbolMap::new(&elf.symbols));
+            let at_exit = AtExit {
+                symbols,
+                profile,
+                profile_top: profile_top.unwrap_or(DEFAULT_PROFILE_TOP),
+                folded: folded.clone(),
+                callgrind: callgrind.clone(),
+                coverage: coverage.clone(),
+                lcov: lcov.clone(),
+                elf: elf_file,
+            };
+
+            let mut exit_code = None;
+            if let Some(gdb) = gdb {
+                let mut conn = gdb_stub::listen(gdb).unwrap();
+                match gdb_stub::serve(&mut cpu0, &mut *conn) {
+                    Ok(code) => exit_code = code,
+                    Err(e) => {
+                        eprintln!("ERROR: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            } else if interactive.unwrap_or(false) {
+                cpu0.set_undo_budget(undo.unwrap_or(DEFAULT_UNDO_BUDGET));
+                cpu0.enable_shadow_stack(true);
                 loop {
                     match tui::interactive_menu() {
                         TuiMenuCmd::Quit => break,
@@ -112,13 +659,70 @@ fn main() {
                                 let before_regs = cpu0.get_regs().clone();
                                 let pc = cpu0.get_pc();
                                 tui::print_instr_listing(cpu0.get_n_instr(pc - 4, 3), pc - 4, pc);
-                                let _ = cpu0.exec_continue(1);
+                                let event = cpu0.exec_continue(1);
                                 let after_regs = cpu0.get_regs();
                                 tui::print_changed_regs(&before_regs, after_regs);
+                                if let ExecEvent::Watchpoint(hit) = event {
+                                    tui::print_watch_hit(&hit);
+                                    break;
+                                }
+                            }
+                        }
+                        TuiMenuCmd::Continue => match cpu0.exec_continue(max_instr) {
+                            ExecEvent::Watchpoint(hit) => tui::print_watch_hit(&hit),
+                            ExecEvent::Breakpoint(pc) => println!("Breakpoint 0x{pc:x}"),
+                            _ => {}
+                        },
+                        TuiMenuCmd::StepBack(n_steps) => {
+                            for _ in 0..n_steps {
+                                let before_regs = cpu0.get_regs().clone();
+                                if !cpu0.step_back() {
+                                    println!("no more recorded instructions to undo");
+                                    break;
+                                }
+                                tui::print_changed_regs(&before_regs, cpu0.get_regs());
+                            }
+                            // PC - 4 may be out of RAM at the start of the program
+                            let pc = cpu0.get_pc();
+                            tui::print_instr_listing(cpu0.get_n_instr(pc, 2), pc, pc);
+                        }
+                        TuiMenuCmd::ReverseContinue => match cpu0.reverse_continue() {
+                            Some(ExecEvent::Watchpoint(hit)) => tui::print_watch_hit(&hit),
+                            Some(ExecEvent::Breakpoint(pc)) => println!("Breakpoint 0x{pc:x}"),
+                            _ => println!(
+                                "reached the start of the recorded history at 0x{:x}",
+                                cpu0.get_pc()
+                            ),
+                        },
+                        TuiMenuCmd::AddWatchpoint(watchpoint) => {
+                            cpu0.add_watchpoint(watchpoint);
+                        }
+                        TuiMenuCmd::ListWatchpoints => {
+                            tui::print_watchpoints(cpu0.watchpoints());
+                        }
+                        TuiMenuCmd::SetBreakpoint(breakpoint) => {
+                            cpu0.set_breakpoint(breakpoint);
+                        }
+                        TuiMenuCmd::ListBreakpoints => {
+                            tui::print_breakpoints(cpu0.breakpoints());
+                        }
+                        TuiMenuCmd::EnableBreakpoint(addr, enabled) => {
+                            match cpu0.breakpoint_mut(addr) {
+                                Some(breakpoint) => breakpoint.enabled = enabled,
+                                None => println!("no breakpoint at 0x{addr:x}"),
                             }
                         }
-                        TuiMenuCmd::Continue => {
-                            let _ = cpu0.exec_continue(max_instr);
+                        TuiMenuCmd::DeleteBreakpoint(Some(addr)) => {
+                            if !cpu0.remove_breakpoint(addr) {
+                                println!("no breakpoint at 0x{addr:x}");
+                            }
+                        }
+                        TuiMenuCmd::DeleteBreakpoint(None) => cpu0.clear_breakpoints(),
+                        TuiMenuCmd::IgnoreBreakpoint(addr, count) => {
+                            match cpu0.breakpoint_mut(addr) {
+                                Some(breakpoint) => breakpoint.ignore_count = count,
+                                None => println!("no breakpoint at 0x{addr:x}"),
+                            }
                         }
                         TuiMenuCmd::PrintAllRegisters => {
                             // TODO: highlight changed registers - store old state, calc diff
@@ -127,6 +731,9 @@ fn main() {
                         TuiMenuCmd::PrintRegister(reg_i) => {
                             tui::print_reg(cpu0.get_regs(), reg_i);
                         }
+                        TuiMenuCmd::Backtrace => {
+                            tui::print_backtrace(&cpu0.backtrace(), at_exit.symbols.as_ref())
+                        }
                         TuiMenuCmd::DumpMem(addr, size) => {
                             tui::dump_mem(cpu0.get_ram(addr, size), addr, size)
                         }
@@ -135,10 +742,121 @@ fn main() {
                             let start = (pc as i64 + pc_offset as i64) as u64;
                             tui::print_instr_listing(cpu0.get_n_instr(start, n_instr), start, pc);
                         }
+                        TuiMenuCmd::SaveSnapshot(path) => {
+                            match cpu0.save_snapshot().save(path.as_ref()) {
+                                Ok(()) => println!("Snapshot saved to {path}"),
+                                Err(e) => println!("{e}"),
+                            }
+                        }
+                        TuiMenuCmd::LoadSnapshot(path) => {
+                            match Snapshot::load(path.as_ref())
+                                .and_then(|s| cpu0.restore_snapshot(&s))
+                            {
+                                Ok(()) => println!("Restored {path} at 0x{:x}", cpu0.get_pc()),
+                                Err(e) => println!("{e}"),
+                            }
+                        }
                     }
                 }
-            } else {
-                let _ = cpu0.exec_continue(max_instr);
+            } else if let ExecEvent::Exit(code) = cpu0.exec_continue(max_instr) {
+                exit_code = Some(code);
+            }
+            at_exit.finish(&mut cpu0);
+            if let Some(code) = exit_code {
+                std::process::exit(code);
+            }
+        }
+        Some(Commands::Conformance {
+            dir,
+            ref_dir,
+            max_instr,
+        }) => {
+            let max_instr = max_instr.unwrap_or(DEFAULT_TEST_MAX_INSTR);
+            let results = match conformance::run_dir(dir, ref_dir.as_deref(), max_instr) {
+                Ok(results) => results,
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            };
+            for r in &results {
+                let name = r.path.strip_prefix(dir).unwrap_or(&r.path).display();
+                let signature = if r.signature_checked {
+                    " (signature)"
+                } else {
+                    ""
+                };
+                match &r.outcome {
+                    Outcome::Pass => println!("{} {name}{signature}", "PASS".green()),
+                    Outcome::Fail(reason) => println!("{} {name}: {reason}", "FAIL".red()),
+                }
+            }
+            let passed = results.iter().filter(|r| r.passed()).count();
+            println!("{passed} passed, {} failed", results.len() - passed);
+            if passed != results.len() {
+                std::process::exit(1);
+            }
+        }
+        #[cfg(unix)]
+        Some(Commands::User {
+            max_instr,
+            elf,
+            args,
+        }) => {
+            let argv: Vec<String> = std::iter::once(elf.to_string_lossy().into_owned())
+                .chain(args.iter().cloned())
+                .collect();
+            let envp: Vec<String> = std::env::vars().map(|(k, v)| format!("{k}={v}")).collect();
+            let exit_code = Elf::from_file(elf)
+                .and_then(|elf| linux_user::load_program(&elf, &argv, &envp, None))
+                .and_then(|mut cpu| {
+                    linux_user::run_program(&mut cpu, max_instr.unwrap_or(u64::MAX))
+                });
+            match exit_code {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::SnapshotDiff { a, b }) => {
+            match Snapshot::load(a).and_then(|a| Ok(a.diff(&Snapshot::load(b)?))) {
+                Ok(diff) => print!("{diff}"),
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::TraceDiff {
+            a,
+            b,
+            ignore_csr,
+            ignore_mem,
+            ignore_pc,
+            context,
+        }) => {
+            let diff = || -> Result<bool, String> {
+                let ignore = IgnoreRules {
+                    csrs: IgnoreRules::parse_csrs(
+                        ignore_csr.as_deref().unwrap_or(NONDETERMINISTIC_CSRS),
+                    )?,
+                    mem: IgnoreRules::parse_ranges(ignore_mem.as_deref().unwrap_or(""))?,
+                    pc: IgnoreRules::parse_ranges(ignore_pc.as_deref().unwrap_or(""))?,
+                };
+                let (a, b) = (read_trace_file(a)?, read_trace_file(b)?);
+                let diff = diff_traces(&a, &b, &ignore);
+                print!("{}", diff.report(&a, &b, context.unwrap_or(5)));
+                Ok(diff.matches())
+            };
+            match diff() {
+                Ok(true) => (),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(2);
+                }
             }
         }
         None => {}
@@ -0,0 +1,309 @@
+// Sv39 virtual memory: page table walk with hardware update of the A/D bits and a small
+// direct-mapped TLB. Page tables outside RAM (a PMA violation) raise access faults.
+// Format: RISC-V Privileged Architecture, 4.4 "Sv39: Page-Based 39-bit Virtual-Memory System".
+
+use crate::bus::Bus;
+use crate::csr::{self, Csrs, PrivMode, MSTATUS_MXR, MSTATUS_SUM, SATP_MODE_SV39};
+
+pub const PAGE_SIZE: u64 = 4096;
+const PAGE_SHIFT: u64 = 12;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+const PPN_MASK: u64 = (1 << 44) - 1;
+/// N, PBMT and the reserved bits: Svnapot and Svpbmt aren't supported
+const PTE_RESERVED: u64 = 0x3ff << 54;
+
+const LEVELS: u64 = 3;
+const VPN_BITS: u64 = 9;
+const VA_BITS: u32 = 39;
+
+const TLB_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self) -> u64 {
+        match self {
+            Access::Fetch => csr::EXC_INSTR_PAGE_FAULT,
+            Access::Load => csr::EXC_LOAD_PAGE_FAULT,
+            Access::Store => csr::EXC_STORE_PAGE_FAULT,
+        }
+    }
+
+    /// Cause of the exception raised when nothing is mapped at the physical address
+    pub fn access_fault(self) -> u64 {
+        match self {
+            Access::Fetch => csr::EXC_INSTR_ACCESS,
+            Access::Load => csr::EXC_LOAD_ACCESS,
+            Access::Store => csr::EXC_STORE_ACCESS,
+        }
+    }
+}
+
+/// Why a page table walk failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fault {
+    /// Invalid or missing PTE
+    Page,
+    /// PTE is not in RAM
+    Access,
+}
+
+impl Fault {
+    fn cause(self, access: Access) -> u64 {
+        match self {
+            Fault::Page => access.page_fault(),
+            Fault::Access => access.access_fault(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u64,
+    /// Physical page number of the 4 KiB page (superpages are split)
+    ppn: u64,
+    /// Leaf PTE flags
+    pte: u64,
+}
+
+pub struct Mmu {
+    tlb: [TlbEntry; TLB_SIZE],
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Mmu {
+            tlb: [TlbEntry::default(); TLB_SIZE],
+        }
+    }
+}
+
+impl Mmu {
+    /// SFENCE.VMA and satp writes: all cached translations are dropped
+    pub fn flush(&mut self) {
+        self.tlb = [TlbEntry::default(); TLB_SIZE];
+    }
+
+    /// Translates a virtual address to physical one, Err - exception cause (page or access
+    /// fault)
+    pub fn translate(
+        &mut self,
+        bus: &mut Bus,
+        csrs: &Csrs,
+        vaddr: u64,
+        access: Access,
+    ) -> Result<u64, u64> {
+        let mode = match access {
+            Access::Fetch => csrs.mode(),
+            Access::Load | Access::Store => csrs.data_mode(),
+        };
+        if mode == PrivMode::Machine || csrs.satp() >> 60 != SATP_MODE_SV39 {
+            return Ok(vaddr);
+        }
+        if !is_canonical(vaddr) {
+            return Err(access.page_fault());
+        }
+        let vpn = vpn(vaddr);
+        let offset = vaddr & (PAGE_SIZE - 1);
+        let entry = &self.tlb[vpn as usize % TLB_SIZE];
+        // the dirty bit has to be set by the page walk before the first store
+        if entry.valid && entry.vpn == vpn && (access != Access::Store || entry.pte & PTE_D != 0) {
+            if !permitted(entry.pte, mode, access, csrs.mstatus()) {
+                return Err(access.page_fault());
+            }
+            return Ok(entry.ppn << PAGE_SHIFT | offset);
+        }
+
+        let (pte_addr, pte, ppn) =
+            walk(bus, csrs.satp(), vaddr).map_err(|fault| fault.cause(access))?;
+        if !permitted(pte, mode, access, csrs.mstatus()) {
+            return Err(access.page_fault());
+        }
+        let mut new_pte = pte | PTE_A;
+        if access == Access::Store {
+            new_pte |= PTE_D;
+        }
+        if new_pte != pte {
+            bus.write64(pte_addr, new_pte)
+                .ok_or(access.access_fault())?;
+        }
+        self.tlb[vpn as usize % TLB_SIZE] = TlbEntry {
+            valid: true,
+            vpn,
+            ppn,
+            pte: new_pte,
+        };
+        Ok(ppn << PAGE_SHIFT | offset)
+    }
+
+    /// Translation for debuggers and disassemblers: permissions aren't checked, A/D bits
+    /// aren't updated. None - the address isn't mapped.
+    pub fn translate_debug(&self, bus: &Bus, csrs: &Csrs, vaddr: u64) -> Option<u64> {
+        if csrs.mode() == PrivMode::Machine || csrs.satp() >> 60 != SATP_MODE_SV39 {
+            return Some(vaddr);
+        }
+        if !is_canonical(vaddr) {
+            return None;
+        }
+        let (_, _, ppn) = walk(bus, csrs.satp(), vaddr).ok()?;
+        Some(ppn << PAGE_SHIFT | vaddr & (PAGE_SIZE - 1))
+    }
+}
+
+/// VPN[2:0] of Sv39 virtual address
+fn vpn(vaddr: u64) -> u64 {
+    (vaddr >> PAGE_SHIFT) & ((1 << (LEVELS * VPN_BITS)) - 1)
+}
+
+/// Bits 63:39 must be equal to bit 38
+fn is_canonical(vaddr: u64) -> bool {
+    let shift = 64 - VA_BITS;
+    (((vaddr << shift) as i64) >> shift) as u64 == vaddr
+}
+
+/// Walks the page table. Returns the address of the leaf PTE, the PTE and the physical page
+/// number of the 4 KiB page containing vaddr.
+fn walk(bus: &Bus, satp: u64, vaddr: u64) -> Result<(u64, u64, u64), Fault> {
+    let vpn_all = vpn(vaddr);
+    let mut table = (satp & PPN_MASK) << PAGE_SHIFT;
+    for level in (0..LEVELS).rev() {
+        let vpn_i = (vpn_all >> (level * VPN_BITS)) & ((1 << VPN_BITS) - 1);
+        let pte_addr = table + vpn_i * 8;
+        // page tables can only be in RAM
+        let pte = bus.get_ram(pte_addr, 8).ok_or(Fault::Access)?;
+        let pte = u64::from_le_bytes(pte.try_into().unwrap());
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0 {
+            return Err(Fault::Page);
+        }
+        let pte_ppn = (pte >> PTE_PPN_SHIFT) & PPN_MASK;
+        if pte & (PTE_R | PTE_X) != 0 {
+            let superpage_mask = (1 << (level * VPN_BITS)) - 1;
+            // misaligned superpage
+            if pte_ppn & superpage_mask != 0 {
+                return Err(Fault::Page);
+            }
+            return Ok((pte_addr, pte, pte_ppn | vpn_all & superpage_mask));
+        }
+        table = pte_ppn << PAGE_SHIFT;
+    }
+    Err(Fault::Page)
+}
+
+fn permitted(pte: u64, mode: PrivMode, access: Access, mstatus: u64) -> bool {
+    let access_ok = match access {
+        Access::Fetch => pte & PTE_X != 0,
+        // Make eXecutable Readable
+        Access::Load => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+        Access::Store => pte & PTE_W != 0,
+    };
+    let mode_ok = match mode {
+        PrivMode::User => pte & PTE_U != 0,
+        // permit Supervisor User Memory access
+        PrivMode::Supervisor => {
+            pte & PTE_U == 0 || (access != Access::Fetch && mstatus & MSTATUS_SUM != 0)
+        }
+        PrivMode::Machine => true,
+    };
+    access_ok && mode_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::{MSTATUS, SATP};
+    use crate::ram::Ram;
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const ROOT: u64 = RAM_BASE;
+    const L1: u64 = RAM_BASE + 0x1000;
+    const L0: u64 = RAM_BASE + 0x2000;
+
+    fn pte(pa: u64, flags: u64) -> u64 {
+        (pa >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags | PTE_V
+    }
+
+    #[test]
+    fn test_sv39_translation() {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(RAM_BASE, 0x10_0000));
+        let mut csrs = Csrs::new();
+        // 0x4000_0000: 1 GiB superpage mapped to RAM (kernel)
+        bus.write64(ROOT + 8, pte(RAM_BASE, PTE_R | PTE_W | PTE_X))
+            .unwrap();
+        // 0x1000: user page 0x8000_5000 via L1 and L0 tables
+        bus.write64(ROOT, pte(L1, 0)).unwrap();
+        bus.write64(L1, pte(L0, 0)).unwrap();
+        bus.write64(L0 + 8, pte(RAM_BASE + 0x5000, PTE_R | PTE_U))
+            .unwrap();
+        assert!(csrs.w64(SATP, SATP_MODE_SV39 << 60 | ROOT >> PAGE_SHIFT));
+        // M-mode isn't translated
+        let mut mmu = Mmu::default();
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x1234, Access::Load),
+            Ok(0x1234)
+        );
+
+        // MPP = S, return to S-mode
+        assert!(csrs.w64(MSTATUS, 1 << 11));
+        assert!(csrs.mret().is_some());
+        let va = 0x4000_1234;
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, va, Access::Store),
+            Ok(RAM_BASE + 0x1234)
+        );
+        // accessed and dirty bits are set
+        assert_eq!(
+            bus.read64(ROOT + 8).unwrap() & (PTE_A | PTE_D),
+            PTE_A | PTE_D
+        );
+        // user page isn't accessible from S-mode without SUM
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x1008, Access::Load),
+            Err(csr::EXC_LOAD_PAGE_FAULT)
+        );
+        assert!(csrs.w64(csr::SSTATUS, MSTATUS_SUM));
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x1008, Access::Load),
+            Ok(RAM_BASE + 0x5008)
+        );
+        // read-only page
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x1008, Access::Store),
+            Err(csr::EXC_STORE_PAGE_FAULT)
+        );
+        // not mapped and not canonical addresses
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x3000, Access::Fetch),
+            Err(csr::EXC_INSTR_PAGE_FAULT)
+        );
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 1 << 40, Access::Load),
+            Err(csr::EXC_LOAD_PAGE_FAULT)
+        );
+        assert_eq!(
+            mmu.translate_debug(&bus, &csrs, 0x4000_0010),
+            Some(RAM_BASE + 0x10)
+        );
+        assert_eq!(mmu.translate_debug(&bus, &csrs, 0x3000), None);
+        // page table outside RAM
+        bus.write64(ROOT + 16, pte(0x1000_0000, 0)).unwrap();
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, 0x8000_0000, Access::Store),
+            Err(csr::EXC_STORE_ACCESS)
+        );
+    }
+}
@@ -0,0 +1,238 @@
+// Platform-Level Interrupt Controller (SiFive PLIC compatible) of a single hart with two
+// contexts: 0 - M-mode, 1 - S-mode. Interrupt sources are level triggered device interrupt
+// lines 1..=PLIC_NDEV.
+
+use std::cell::Cell;
+
+use crate::csr::{MIP_MEIP, MIP_SEIP};
+use crate::device::Dev;
+use crate::fdt::{DtNode, DtNodeKind};
+use crate::snapshot::{StateReader, StateWriter};
+
+pub const PLIC_SIZE: u64 = 0x400_0000;
+pub const PLIC_NDEV: u32 = 31;
+
+const NUM_CONTEXTS: usize = 2;
+const PRIORITY_BASE: u64 = 0x0000;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+const MAX_PRIORITY: u32 = 7;
+/// Source 0 doesn't exist
+const SOURCES_MASK: u64 = ((1 << (PLIC_NDEV + 1)) - 1) & !1;
+
+#[derive(Default)]
+pub struct Plic {
+    priority: [u32; PLIC_NDEV as usize + 1],
+    enable: [u64; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS],
+    /// Levels of the interrupt lines
+    lines: u64,
+    /// Interrupts claimed and not completed yet, they aren't pending until completion
+    claimed: Cell<u64>,
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        Default::default()
+    }
+
+    fn pending(&self) -> u64 {
+        self.lines & !self.claimed.get() & SOURCES_MASK
+    }
+
+    /// The highest priority pending interrupt enabled for the context, 0 - none
+    fn best_irq(&self, context: usize) -> u32 {
+        let candidates = self.pending() & self.enable[context];
+        let mut best = (0, 0);
+        for irq in 1..=PLIC_NDEV {
+            let prio = self.priority[irq as usize];
+            // ties are resolved in favour of the lowest id
+            if candidates & 1 << irq != 0 && prio > self.threshold[context] && prio > best.1 {
+                best = (irq, prio);
+            }
+        }
+        best.0
+    }
+
+    fn read_reg(&self, addr: u64) -> u32 {
+        match addr {
+            PRIORITY_BASE..PENDING_BASE => {
+                let irq = ((addr - PRIORITY_BASE) / 4) as usize;
+                self.priority.get(irq).copied().unwrap_or(0)
+            }
+            PENDING_BASE..ENABLE_BASE => match addr - PENDING_BASE {
+                0 => self.pending() as u32,
+                _ => 0,
+            },
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = ((addr - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                match (self.enable.get(context), addr % ENABLE_STRIDE) {
+                    (Some(enable), 0) => *enable as u32,
+                    _ => 0,
+                }
+            }
+            _ => {
+                let context = ((addr - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                if context >= NUM_CONTEXTS {
+                    eprintln!("WARN: PLIC: read of unknown register 0x{addr:x}");
+                    return 0;
+                }
+                match addr % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => self.threshold[context],
+                    CONTEXT_CLAIM => {
+                        let irq = self.best_irq(context);
+                        if irq != 0 {
+                            self.claimed.set(self.claimed.get() | 1 << irq);
+                        }
+                        irq
+                    }
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write_reg(&mut self, addr: u64, val: u32) {
+        match addr {
+            PRIORITY_BASE..PENDING_BASE => {
+                let irq = ((addr - PRIORITY_BASE) / 4) as usize;
+                if let Some(prio) = self.priority.get_mut(irq) {
+                    *prio = val.min(MAX_PRIORITY);
+                }
+            }
+            // pending bits are read-only
+            PENDING_BASE..ENABLE_BASE => (),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = ((addr - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                if let (Some(enable), 0) = (self.enable.get_mut(context), addr % ENABLE_STRIDE) {
+                    *enable = val as u64 & SOURCES_MASK;
+                }
+            }
+            _ => {
+                let context = ((addr - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                if context >= NUM_CONTEXTS {
+                    eprintln!("WARN: PLIC: write to unknown register 0x{addr:x}");
+                    return;
+                }
+                match addr % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => self.threshold[context] = val.min(MAX_PRIORITY),
+                    // completion of an interrupt which isn't enabled is ignored
+                    CONTEXT_CLAIM if self.enable[context] & 1 << val.min(63) != 0 => {
+                        self.claimed.set(self.claimed.get() & !(1 << val));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+// addr is local to the device, i.e bus_address - base_address
+impl Dev for Plic {
+    fn read8(&self, addr: u64) -> u8 {
+        eprintln!("WARN: PLIC: read8 of 0x{addr:x}, only 32-bit access is supported");
+        0
+    }
+
+    fn write8(&mut self, addr: u64, _val: u8) {
+        eprintln!("WARN: PLIC: write8 to 0x{addr:x} ignored, only 32-bit access is supported")
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        self.read_reg(addr)
+    }
+
+    fn read64(&self, addr: u64) -> u64 {
+        self.read_reg(addr) as u64 | (self.read_reg(addr + 4) as u64) << 32
+    }
+
+    fn write32(&mut self, addr: u64, val: u32) {
+        self.write_reg(addr, val)
+    }
+
+    fn write64(&mut self, addr: u64, val: u64) {
+        self.write_reg(addr, val as u32);
+        self.write_reg(addr + 4, (val >> 32) as u32);
+    }
+
+    fn set_irq_lines(&mut self, lines: u64) {
+        self.lines = lines;
+    }
+
+    fn hart_irqs(&self) -> u64 {
+        let mut irqs = 0;
+        if self.best_irq(0) != 0 {
+            irqs |= MIP_MEIP;
+        }
+        if self.best_irq(1) != 0 {
+            irqs |= MIP_SEIP;
+        }
+        irqs
+    }
+
+    fn dt_node(&self) -> Option<DtNode> {
+        Some(DtNode {
+            compatible: vec!["sifive,plic-1.0.0", "riscv,plic0"],
+            kind: DtNodeKind::Plic { ndev: PLIC_NDEV },
+            ..DtNode::new("plic", "sifive,plic-1.0.0")
+        })
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.priority.iter().for_each(|&prio| w.u32(prio));
+        self.enable.iter().for_each(|&enable| w.u64(enable));
+        self.threshold
+            .iter()
+            .for_each(|&threshold| w.u32(threshold));
+        w.u64(self.lines);
+        w.u64(self.claimed.get());
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for prio in self.priority.iter_mut() {
+            *prio = r.u32()?;
+        }
+        for enable in self.enable.iter_mut() {
+            *enable = r.u64()?;
+        }
+        for threshold in self.threshold.iter_mut() {
+            *threshold = r.u32()?;
+        }
+        self.lines = r.u64()?;
+        self.claimed.set(r.u64()?);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_plic_claim_complete() {
+    let s_context = CONTEXT_BASE + CONTEXT_STRIDE;
+    let mut plic = Plic::new();
+    plic.set_irq_lines(1 << 10 | 1 << 3);
+    // nothing is enabled
+    assert_eq!(plic.hart_irqs(), 0);
+    plic.write32(PRIORITY_BASE + 4 * 10, 1);
+    plic.write32(PRIORITY_BASE + 4 * 3, 1);
+    plic.write32(ENABLE_BASE + ENABLE_STRIDE, 1 << 10 | 1 << 3);
+    assert_eq!(plic.read32(PENDING_BASE), 1 << 10 | 1 << 3);
+    assert_eq!(plic.hart_irqs(), MIP_SEIP);
+    // threshold masks priority 1
+    plic.write32(s_context + CONTEXT_THRESHOLD, 1);
+    assert_eq!(plic.hart_irqs(), 0);
+    plic.write32(s_context + CONTEXT_THRESHOLD, 0);
+    // the lowest id wins among equal priorities
+    assert_eq!(plic.read32(s_context + CONTEXT_CLAIM), 3);
+    assert_eq!(plic.read32(s_context + CONTEXT_CLAIM), 10);
+    assert_eq!(plic.read32(s_context + CONTEXT_CLAIM), 0);
+    assert_eq!(plic.hart_irqs(), 0);
+    // the line is still high, the interrupt is pending again after completion
+    plic.write32(s_context + CONTEXT_CLAIM, 10);
+    assert_eq!(plic.hart_irqs(), MIP_SEIP);
+    plic.set_irq_lines(0);
+    assert_eq!(plic.hart_irqs(), 0);
+}
@@ -0,0 +1,303 @@
+// Instruction-mix and hotspot profiler. While enabled it counts the executed instructions per PC
+// and whether the conditional branches were taken. Reports aggregate the counts per opcode class
+// (the instruction at a PC is classified once) and per symbol of the ELF.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bits::BitOps;
+use crate::elf::SymbolMap;
+use crate::rv64i_disasm::disasm;
+use crate::rvc_dec::instr_is_rvc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InstrClass {
+    /// Integer arithmetic, logic, shifts, LUI and AUIPC
+    Alu,
+    MulDiv,
+    Load,
+    Store,
+    /// Conditional branches
+    Branch,
+    /// JAL and JALR
+    Jump,
+    Atomic,
+    Fp,
+    Csr,
+    Fence,
+    /// ECALL, EBREAK, xRET, WFI, SFENCE.VMA
+    System,
+    Unknown,
+}
+
+impl fmt::Display for InstrClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InstrClass::Alu => "alu",
+            InstrClass::MulDiv => "mul/div",
+            InstrClass::Load => "load",
+            InstrClass::Store => "store",
+            InstrClass::Branch => "branch",
+            InstrClass::Jump => "jump",
+            InstrClass::Atomic => "atomic",
+            InstrClass::Fp => "fp",
+            InstrClass::Csr => "csr",
+            InstrClass::Fence => "fence",
+            InstrClass::System => "system",
+            InstrClass::Unknown => "unknown",
+        };
+        f.pad(name)
+    }
+}
+
+/// Opcode class of a 32-bit or compressed instruction
+pub fn instr_class(instr: u32) -> InstrClass {
+    if instr_is_rvc(instr) {
+        return rvc_instr_class(instr);
+    }
+    match instr.bits(6, 0) {
+        0b000_0011 | 0b000_0111 => InstrClass::Load,
+        0b010_0011 | 0b010_0111 => InstrClass::Store,
+        0b001_0011 | 0b001_1011 | 0b011_0111 | 0b001_0111 => InstrClass::Alu,
+        0b011_0011 | 0b011_1011 if instr.bits(31, 25) == 1 => InstrClass::MulDiv,
+        0b011_0011 | 0b011_1011 => InstrClass::Alu,
+        0b110_0011 => InstrClass::Branch,
+        0b110_1111 | 0b110_0111 => InstrClass::Jump,
+        0b010_1111 => InstrClass::Atomic,
+        0b101_0011 | 0b100_0011 | 0b100_0111 | 0b100_1011 | 0b100_1111 => InstrClass::Fp,
+        0b111_0011 if instr.bits(14, 12) == 0 => InstrClass::System,
+        0b111_0011 => InstrClass::Csr,
+        0b000_1111 => InstrClass::Fence,
+        _ => InstrClass::Unknown,
+    }
+}
+
+fn rvc_instr_class(instr: u32) -> InstrClass {
+    let (rs1, rs2) = (instr.bits(11, 7), instr.bits(6, 2));
+    match (instr.bits(1, 0), instr.bits(15, 13)) {
+        (0b00, 0b000) => InstrClass::Alu,
+        (0b00, 0b001..=0b011) => InstrClass::Load,
+        (0b00, 0b101..=0b111) => InstrClass::Store,
+        (0b01, 0b000..=0b100) => InstrClass::Alu,
+        (0b01, 0b101) => InstrClass::Jump,
+        (0b01, 0b110 | 0b111) => InstrClass::Branch,
+        (0b10, 0b000) => InstrClass::Alu,
+        (0b10, 0b001..=0b011) => InstrClass::Load,
+        // c.jr, c.mv, c.ebreak, c.jalr, c.add
+        (0b10, 0b100) if rs2 != 0 => InstrClass::Alu,
+        (0b10, 0b100) if instr.bit(12) && rs1 == 0 => InstrClass::System,
+        (0b10, 0b100) => InstrClass::Jump,
+        (0b10, 0b101..=0b111) => InstrClass::Store,
+        _ => InstrClass::Unknown,
+    }
+}
+
+/// Executions of an instruction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PcCount {
+    pub instr: u32,
+    pub count: u64,
+    /// Times a conditional branch was taken
+    pub taken: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    pcs: HashMap<u64, PcCount>,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Counts the instruction executed at PC, `next_pc` is the PC after it
+    pub fn record(&mut self, pc: u64, instr: u32, next_pc: u64) {
+        self.total += 1;
+        let count = self.pcs.entry(pc).or_default();
+        count.instr = instr;
+        count.count += 1;
+        let len = if instr_is_rvc(instr) { 2 } else { 4 };
+        if next_pc != pc.wrapping_add(len) && instr_class(instr) == InstrClass::Branch {
+            count.taken += 1;
+        }
+    }
+
+    /// Number of the counted instructions
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn pc_counts(&self) -> &HashMap<u64, PcCount> {
+        &self.pcs
+    }
+
+    /// Counts per opcode class, PC and symbol (if there are symbols)
+    pub fn profile(&self, symbols: Option<&SymbolMap>) -> Profile {
+        let mut classes: HashMap<InstrClass, ProfileEntry> = HashMap::new();
+        let mut by_symbol: HashMap<String, ProfileEntry> = HashMap::new();
+        let mut pcs = Vec::with_capacity(self.pcs.len());
+        for (&pc, count) in &self.pcs {
+            let class = instr_class(count.instr);
+            let add = |e: &mut ProfileEntry| {
+                e.count += count.count;
+                if class == InstrClass::Branch {
+                    e.taken += count.taken;
+                    e.not_taken += count.count - count.taken;
+                }
+            };
+            add(classes.entry(class).or_insert_with(|| ProfileEntry {
+                name: class.to_string(),
+                ..Default::default()
+            }));
+            let symbol = symbols.filter(|s| !s.is_empty()).map(|s| {
+                let name = s.lookup(pc).map_or("??", |(name, _)| name);
+                add(by_symbol
+                    .entry(name.to_string())
+                    .or_insert_with(|| ProfileEntry {
+                        name: name.to_string(),
+                        ..Default::default()
+                    }));
+                s.describe(pc)
+            });
+            let mut entry = ProfileEntry {
+                name: format!("0x{pc:016x}"),
+                detail: disasm(count.instr, pc),
+                ..Default::default()
+            };
+            if let Some(symbol) = symbol {
+                entry.detail = format!("<{symbol}> {}", entry.detail);
+            }
+            add(&mut entry);
+            pcs.push(entry);
+        }
+        let sorted = |mut entries: Vec<ProfileEntry>| {
+            entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+            entries
+        };
+        Profile {
+            total: self.total,
+            classes: sorted(classes.into_values().collect()),
+            pcs: sorted(pcs),
+            symbols: sorted(by_symbol.into_values().collect()),
+        }
+    }
+}
+
+/// Counts of an opcode class, PC or symbol
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfileEntry {
+    pub name: String,
+    /// Disassembly of the instruction at the PC
+    pub detail: String,
+    pub count: u64,
+    /// Conditional branches
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Profile sorted by the counts
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub total: u64,
+    pub classes: Vec<ProfileEntry>,
+    pub pcs: Vec<ProfileEntry>,
+    /// Empty without symbols
+    pub symbols: Vec<ProfileEntry>,
+}
+
+impl Profile {
+    /// Percentage of all instructions
+    pub fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+
+    /// Text report with the top N PCs and symbols
+    pub fn report(&self, top: usize) -> String {
+        let mut report = format!("Profile of {} executed instructions\n", self.total);
+        let mut table = |title: String, entries: &[ProfileEntry]| {
+            report += &format!(
+                "{title}:\n{:>12} {:>7} {:>10} {:>10}\n",
+                "count", "%", "taken", "not taken"
+            );
+            for e in entries {
+                let row = format!(
+                    "{:>12} {:>6.2}% {:>10} {:>10}  {} {}",
+                    e.count,
+                    self.percent(e.count),
+                    e.taken,
+                    e.not_taken,
+                    e.name,
+                    e.detail
+                );
+                report += row.trim_end();
+                report.push('\n');
+            }
+        };
+        table("Opcode classes".to_string(), &self.classes);
+        table(
+            format!("Top {top} PCs"),
+            &self.pcs[..top.min(self.pcs.len())],
+        );
+        if !self.symbols.is_empty() {
+            let n = top.min(self.symbols.len());
+            table(format!("Top {top} symbols"), &self.symbols[..n]);
+        }
+        report
+    }
+}
+
+#[test]
+fn test_instr_class() {
+    assert_eq!(instr_class(0x04200293), InstrClass::Alu); // li t0,0x42
+    assert_eq!(instr_class(0x02b50533), InstrClass::MulDiv); // mul a0,a0,a1
+    assert_eq!(instr_class(0x0002b383), InstrClass::Load); // ld t2,0(t0)
+    assert_eq!(instr_class(0x00532023), InstrClass::Store); // sw t0,0(t1)
+    assert_eq!(instr_class(0xfe03cee3), InstrClass::Branch); // blt t2,zero,-4
+    assert_eq!(instr_class(0x008000ef), InstrClass::Jump); // jal 8
+    assert_eq!(instr_class(0x34029073), InstrClass::Csr); // csrw mscratch,t0
+    assert_eq!(instr_class(0x00000073), InstrClass::System); // ecall
+    assert_eq!(instr_class(0x4505), InstrClass::Alu); // c.li a0,1
+    assert_eq!(instr_class(0xc119), InstrClass::Branch); // c.beqz a0,6
+    assert_eq!(instr_class(0x8082), InstrClass::Jump); // c.jr ra (ret)
+    assert_eq!(instr_class(0x852e), InstrClass::Alu); // c.mv a0,a1
+    assert_eq!(instr_class(0x9002), InstrClass::System); // c.ebreak
+    assert_eq!(instr_class(0x6522), InstrClass::Load); // c.ldsp a0,8(sp)
+}
+
+#[test]
+fn test_profiler() {
+    use crate::elf::{ElfSymbol, STT_FUNC};
+
+    let mut profiler = Profiler::new();
+    for i in 0..10 {
+        profiler.record(0x1000, 0x04200293, 0x1004); // li t0,0x42
+        let next = if i < 3 { 0x1000 } else { 0x1008 };
+        profiler.record(0x1004, 0xfe029ee3, next); // bnez t0,0x1000
+    }
+    profiler.record(0x2000, 0x00000073, 0x100); // ecall
+    let symbols = SymbolMap::new(&[ElfSymbol {
+        name: "loop".to_string(),
+        value: 0x1000,
+        size: 8,
+        sym_type: STT_FUNC,
+    }]);
+    let profile = profiler.profile(Some(&symbols));
+    assert_eq!(profile.total, 21);
+    let alu = &profile.classes[0];
+    assert_eq!((alu.name.as_str(), alu.count), ("alu", 10));
+    assert_eq!(profile.classes[1].name, "branch");
+    assert_eq!(
+        (profile.classes[1].taken, profile.classes[1].not_taken),
+        (3, 7)
+    );
+    assert_eq!(profile.pcs[1].name, "0x0000000000001004");
+    assert!(profile.pcs[1].detail.starts_with("<loop+0x4> "));
+    assert_eq!(profile.symbols[0].count, 20);
+    assert_eq!(profile.symbols[1].name, "??");
+    let report = profile.report(1);
+    assert!(report.contains("Top 1 PCs"));
+    assert!(report.contains("          20  95.24%          3          7  loop\n"));
+    assert!(profiler.profile(None).symbols.is_empty());
+}
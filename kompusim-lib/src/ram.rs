@@ -55,6 +55,18 @@ impl Ram {
         self.m[offs] = val
     }
 
+    // Little Endian 16-bit read
+    pub fn read16(&self, addr: u64) -> u16 {
+        let offs = (addr - self.start) as usize;
+        u16::from_le_bytes([self.m[offs], self.m[offs + 1]])
+    }
+
+    // Little Endian 16-bit write
+    pub fn write16(&mut self, addr: u64, val: u16) {
+        let offs = (addr - self.start) as usize;
+        self.m[offs..offs + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
     // Little Endian 32-bit read
     pub fn read32(&self, addr: u64) -> u32 {
         let offs = (addr - self.start) as usize;
@@ -133,6 +145,26 @@ impl Ram {
         Ok(())
     }
 
+    /// Returns true if [addr, addr + size) is backed by this RAM
+    pub fn contains(&self, addr: u64, size: u64) -> bool {
+        addr >= self.start
+            && addr
+                .checked_add(size)
+                .is_some_and(|end| end <= self.start + self.m.len() as u64)
+    }
+
+    /// Copies buf.len() bytes at addr to buf
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) {
+        let offs = (addr - self.start) as usize;
+        buf.copy_from_slice(&self.m[offs..offs + buf.len()]);
+    }
+
+    /// Copies buf to RAM at addr
+    pub fn write_bytes(&mut self, addr: u64, buf: &[u8]) {
+        let offs = (addr - self.start) as usize;
+        self.m[offs..offs + buf.len()].copy_from_slice(buf);
+    }
+
     pub fn get_ram(&self, addr: u64, size: u64) -> Option<&[u8]> {
         if addr < self.start || addr > self.end {
             return None;
@@ -0,0 +1,375 @@
+// Deterministic record/replay of the inputs coming from the host: console bytes, host time and
+// stdin reads of semihosting, received network frames and the network link state. Everything
+// else is deterministic, so recording logs each input with the number of executed instructions
+// when it arrived and replaying feeds the logged inputs back at the same instructions instead of
+// asking the host. The log is a text file:
+//   # kompusim input log v1
+//   <instruction> console <hex byte>
+//   <instruction> time <value>
+//   <instruction> read <hex bytes>
+//   <instruction> net <hex bytes>
+//   <instruction> link <0|1>
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::virtio_net::NetBackend;
+
+const INPUT_LOG_HEADER: &str = "# kompusim input log v1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    /// Byte from the host console
+    Console(u8),
+    /// Host time read by semihosting (wall clock or elapsed)
+    Time(u64),
+    /// Bytes returned by a read of the host stdin by semihosting
+    Read(Vec<u8>),
+    /// Ethernet frame received by virtio-net
+    NetFrame(Vec<u8>),
+    /// virtio-net link went up or down
+    NetLink(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputEvent {
+    /// Number of executed instructions when the input arrived
+    pub instr: u64,
+    pub input: Input,
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.input {
+            Input::Console(octet) => write!(f, "{} console {octet:02x}", self.instr),
+            Input::Time(time) => write!(f, "{} time {time}", self.instr),
+            Input::Read(data) => write!(f, "{} read {}", self.instr, hex(data)),
+            Input::NetFrame(frame) => write!(f, "{} net {}", self.instr, hex(frame)),
+            Input::NetLink(up) => write!(f, "{} link {}", self.instr, *up as u8),
+        }
+    }
+}
+
+impl InputEvent {
+    pub fn parse(line: &str) -> Result<InputEvent, String> {
+        let wrong = || format!("wrong input log line: {line}");
+        let mut fields = line.split_whitespace();
+        let instr = fields
+            .next()
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(wrong)?;
+        let kind = fields.next().ok_or_else(wrong)?;
+        // a read of nothing has no data field
+        let data = fields.next().unwrap_or("");
+        let input = match kind {
+            "console" => Input::Console(u8::from_str_radix(data, 16).map_err(|_| wrong())?),
+            "time" => Input::Time(data.parse().map_err(|_| wrong())?),
+            "read" => Input::Read(parse_hex(data).ok_or_else(wrong)?),
+            "net" => Input::NetFrame(parse_hex(data).ok_or_else(wrong)?),
+            "link" => Input::NetLink(data == "1"),
+            _ => return Err(wrong()),
+        };
+        Ok(InputEvent { instr, input })
+    }
+}
+
+/// Logged inputs not fed to the machine yet, by source
+#[derive(Default)]
+struct Replay {
+    console: VecDeque<InputEvent>,
+    /// Time and stdin reads, they are requested by the guest so they are taken in order
+    host: VecDeque<InputEvent>,
+    net: VecDeque<InputEvent>,
+    link: VecDeque<InputEvent>,
+    /// The guest asked for an input the log doesn't have at that instruction
+    diverged: bool,
+}
+
+enum Mode {
+    Record(BufWriter<File>),
+    Replay(Replay),
+}
+
+struct LogState {
+    /// Number of executed instructions, set by the CPU
+    instr: Cell<u64>,
+    mode: RefCell<Mode>,
+}
+
+/// Shared by the CPU and the input sources it wraps
+#[derive(Clone)]
+pub struct InputLog(Rc<LogState>);
+
+impl InputLog {
+    fn new(mode: Mode) -> InputLog {
+        InputLog(Rc::new(LogState {
+            instr: Cell::new(0),
+            mode: RefCell::new(mode),
+        }))
+    }
+
+    /// Records the inputs to the file
+    pub fn record(path: &Path) -> Result<InputLog, String> {
+        let mut out = BufWriter::new(
+            File::create(path).map_err(|e| format!("failed to create {path:?}: {e}"))?,
+        );
+        writeln!(out, "{INPUT_LOG_HEADER}")
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("failed to write {path:?}: {e}"))?;
+        Ok(InputLog::new(Mode::Record(out)))
+    }
+
+    /// Replays the inputs recorded to the file
+    pub fn replay(path: &Path) -> Result<InputLog, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {path:?}: {e}"))?;
+        let mut lines = text.lines();
+        if lines.next() != Some(INPUT_LOG_HEADER) {
+            return Err(format!("{path:?} is not an input log"));
+        }
+        let events = lines
+            .filter(|l| !l.trim().is_empty())
+            .map(InputEvent::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(InputLog::replaying(events))
+    }
+
+    pub fn replaying(events: Vec<InputEvent>) -> InputLog {
+        let mut replay = Replay::default();
+        for event in events {
+            match event.input {
+                Input::Console(_) => replay.console.push_back(event),
+                Input::Time(_) | Input::Read(_) => replay.host.push_back(event),
+                Input::NetFrame(_) => replay.net.push_back(event),
+                Input::NetLink(_) => replay.link.push_back(event),
+            }
+        }
+        InputLog::new(Mode::Replay(replay))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(*self.0.mode.borrow(), Mode::Replay(_))
+    }
+
+    /// Called by the CPU before every instruction
+    pub fn set_instr(&self, instr: u64) {
+        self.0.instr.set(instr);
+    }
+
+    /// Number of logged inputs not replayed yet
+    pub fn remaining(&self) -> usize {
+        match &*self.0.mode.borrow() {
+            Mode::Record(_) => 0,
+            Mode::Replay(r) => r.console.len() + r.host.len() + r.net.len() + r.link.len(),
+        }
+    }
+
+    fn write(&self, input: Input) {
+        if let Mode::Record(out) = &mut *self.0.mode.borrow_mut() {
+            let event = InputEvent {
+                instr: self.0.instr.get(),
+                input,
+            };
+            // flushed right away: the simulator may exit without dropping the log
+            if let Err(e) = writeln!(out, "{event}").and_then(|_| out.flush()) {
+                eprintln!("ERROR: failed to write the input log: {e}");
+            }
+        }
+    }
+
+    /// Takes the next input which arrived at or before the current instruction
+    fn take_due(&self, queue: fn(&mut Replay) -> &mut VecDeque<InputEvent>) -> Option<Input> {
+        let now = self.0.instr.get();
+        let mut mode = self.0.mode.borrow_mut();
+        let Mode::Replay(replay) = &mut *mode else {
+            return None;
+        };
+        let queue = queue(replay);
+        if queue.front()?.instr > now {
+            return None;
+        }
+        queue.pop_front().map(|event| event.input)
+    }
+
+    /// Takes the next time or stdin read, the guest asks for them so they must be due now
+    fn take_host(&self) -> Option<Input> {
+        let now = self.0.instr.get();
+        let mut mode = self.0.mode.borrow_mut();
+        let Mode::Replay(replay) = &mut *mode else {
+            return None;
+        };
+        let event = replay.host.pop_front();
+        if event.as_ref().map(|e| e.instr) != Some(now) && !replay.diverged {
+            replay.diverged = true;
+            eprintln!("WARN: replay: diverged from the input log at instruction {now}");
+        }
+        event.map(|e| e.input)
+    }
+
+    /// Wraps the host console source: logs its bytes or replays them from the log
+    pub fn console(
+        &self,
+        mut source: Option<Box<dyn FnMut() -> Option<u8>>>,
+    ) -> Box<dyn FnMut() -> Option<u8>> {
+        let log = self.clone();
+        Box::new(move || {
+            if log.is_replaying() {
+                return match log.take_due(|r| &mut r.console) {
+                    Some(Input::Console(octet)) => Some(octet),
+                    _ => None,
+                };
+            }
+            let octet = source.as_mut().and_then(|source| source())?;
+            log.write(Input::Console(octet));
+            Some(octet)
+        })
+    }
+
+    /// Host time read by the guest
+    pub fn host_time(&self, read: impl FnOnce() -> u64) -> u64 {
+        if self.is_replaying() {
+            return match self.take_host() {
+                Some(Input::Time(time)) => time,
+                _ => read(),
+            };
+        }
+        let time = read();
+        self.write(Input::Time(time));
+        time
+    }
+
+    /// Read of the host stdin by the guest, a failed read is replayed as a read of nothing
+    pub fn host_read(&self, read: impl FnOnce() -> io::Result<Vec<u8>>) -> io::Result<Vec<u8>> {
+        if self.is_replaying() {
+            return match self.take_host() {
+                Some(Input::Read(data)) => Ok(data),
+                _ => Ok(Vec::new()),
+            };
+        }
+        let data = read();
+        self.write(Input::Read(data.as_ref().cloned().unwrap_or_default()));
+        data
+    }
+
+    /// Wraps a network backend: logs the received frames and link changes or replays them,
+    /// frames sent while replaying are dropped
+    pub fn net(&self, backend: Box<dyn NetBackend>) -> Box<dyn NetBackend> {
+        Box::new(LoggedNet {
+            log: self.clone(),
+            link_up: Cell::new(None),
+            backend,
+        })
+    }
+}
+
+struct LoggedNet {
+    log: InputLog,
+    /// The last logged link state
+    link_up: Cell<Option<bool>>,
+    backend: Box<dyn NetBackend>,
+}
+
+impl NetBackend for LoggedNet {
+    fn send(&mut self, frame: &[u8]) {
+        if !self.log.is_replaying() {
+            self.backend.send(frame);
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.log.is_replaying() {
+            return match self.log.take_due(|r| &mut r.net) {
+                Some(Input::NetFrame(frame)) => Some(frame),
+                _ => None,
+            };
+        }
+        let frame = self.backend.recv()?;
+        self.log.write(Input::NetFrame(frame.clone()));
+        Some(frame)
+    }
+
+    fn link_up(&self) -> bool {
+        if self.log.is_replaying() {
+            while let Some(Input::NetLink(up)) = self.log.take_due(|r| &mut r.link) {
+                self.link_up.set(Some(up));
+            }
+            return self.link_up.get().unwrap_or(true);
+        }
+        let up = self.backend.link_up();
+        if self.link_up.get() != Some(up) {
+            self.link_up.set(Some(up));
+            self.log.write(Input::NetLink(up));
+        }
+        up
+    }
+}
+
+#[test]
+fn test_input_log_record_replay() {
+    let path = std::env::temp_dir().join(format!("kompusim-replay-{}.log", std::process::id()));
+    let log = InputLog::record(&path).unwrap();
+    let mut typed = VecDeque::from([b'h', b'i']);
+    let mut console = log.console(Some(Box::new(move || typed.pop_front())));
+    log.set_instr(10);
+    assert_eq!(console(), Some(b'h'));
+    log.set_instr(20);
+    assert_eq!(log.host_time(|| 1234), 1234);
+    assert_eq!(console(), Some(b'i'));
+    assert_eq!(console(), None);
+    assert_eq!(log.host_read(|| Ok(vec![0, 0xff])).unwrap(), [0, 0xff]);
+    let mut net = log.net(Box::<crate::virtio_net::Loopback>::default());
+    net.send(&[1, 2, 3]);
+    log.set_instr(30);
+    assert!(net.link_up());
+    assert_eq!(net.recv(), Some(vec![1, 2, 3]));
+    drop((console, net, log));
+
+    let log = InputLog::replay(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(log.is_replaying());
+    assert_eq!(log.remaining(), 6);
+    // the host sources aren't used
+    let mut console = log.console(Some(Box::new(|| Some(b'x'))));
+    let mut net = log.net(Box::<crate::virtio_net::Loopback>::default());
+    log.set_instr(5);
+    assert_eq!(console(), None);
+    log.set_instr(20);
+    assert_eq!(console(), Some(b'h'));
+    assert_eq!(console(), Some(b'i'));
+    assert_eq!(log.host_time(|| 0), 1234);
+    assert_eq!(log.host_read(|| Ok(Vec::new())).unwrap(), [0, 0xff]);
+    net.send(&[4]);
+    assert_eq!(net.recv(), None);
+    log.set_instr(30);
+    assert!(net.link_up());
+    assert_eq!(net.recv(), Some(vec![1, 2, 3]));
+    assert_eq!(log.remaining(), 0);
+
+    assert!(InputEvent::parse("1 console zz").is_err());
+    assert_eq!(
+        InputEvent::parse("7 read").unwrap(),
+        InputEvent {
+            instr: 7,
+            input: Input::Read(Vec::new())
+        }
+    );
+}
@@ -0,0 +1,67 @@
+use crate::device::Dev;
+
+/// Read-only memory, e.g. a boot ROM. Writes are ignored.
+pub struct Rom {
+    m: Vec<u8>,
+}
+
+impl Rom {
+    /// Creates ROM of the given size with the image at its beginning
+    pub fn new(size: u64, image: &[u8]) -> Result<Rom, String> {
+        if image.len() as u64 > size {
+            return Err(format!(
+                "ROM image ({} bytes) is bigger than ROM ({size} bytes)",
+                image.len()
+            ));
+        }
+        let mut m = vec![0; size as usize];
+        m[..image.len()].copy_from_slice(image);
+        Ok(Rom { m })
+    }
+
+    fn read_le(&self, addr: u64, size: usize) -> u64 {
+        let offs = addr as usize;
+        self.m[offs..offs + size]
+            .iter()
+            .rev()
+            .fold(0, |val, &b| (val << 8) | b as u64)
+    }
+}
+
+// addr is local to the device, i.e bus_address - base_address
+impl Dev for Rom {
+    fn read8(&self, addr: u64) -> u8 {
+        self.m[addr as usize]
+    }
+
+    fn write8(&mut self, addr: u64, _val: u8) {
+        eprintln!("WARN: ROM: write to 0x{addr:x} ignored")
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        self.read_le(addr, 4) as u32
+    }
+
+    fn read64(&self, addr: u64) -> u64 {
+        self.read_le(addr, 8)
+    }
+
+    fn write32(&mut self, addr: u64, _val: u32) {
+        eprintln!("WARN: ROM: write to 0x{addr:x} ignored")
+    }
+
+    fn write64(&mut self, addr: u64, _val: u64) {
+        eprintln!("WARN: ROM: write to 0x{addr:x} ignored")
+    }
+}
+
+#[test]
+fn test_rom_read_only() {
+    let mut rom = Rom::new(16, &[0x13, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde]).unwrap();
+    assert_eq!(rom.read32(0), 0x0000_0013);
+    assert_eq!(rom.read64(0), 0xdead_beef_0000_0013);
+    rom.write32(0, 0);
+    assert_eq!(rom.read8(4), 0xef);
+    assert_eq!(rom.read32(0), 0x0000_0013);
+    assert!(Rom::new(4, &[0; 8]).is_err());
+}
@@ -1,10 +1,22 @@
 use crate::alu::{Imm, I12, I13, I21, I6};
+use crate::backtrace::{frame_pointer_walk, Backtrace, ShadowStack};
 use crate::bits::BitOps;
+use crate::breakpoint::Breakpoint;
 use crate::bus::Bus;
-use crate::csr::Csrs;
-// use crate::rv64fd::RV64FDRegs;
+use crate::callgraph::CallGraph;
+use crate::csr::{self, CsrInputs, Csrs, PrivMode};
+use crate::htif::Htif;
+use crate::mmu::{Access, Mmu, PAGE_SIZE};
+use crate::profile::Profiler;
+use crate::replay::InputLog;
+use crate::rv64fd::RV64FDRegs;
 use crate::rv64i_dec::*;
 use crate::rvc_dec::{c_i_opcode, instr_is_rvc, rv64c_decode_instr, COpcode};
+use crate::sbi::{self, Sbi};
+use crate::semihosting::{self, Semihosting};
+use crate::snapshot::{HartState, Snapshot};
+use crate::trace::{Commit, MemAccess, RegWrite, Tracer};
+use crate::undo::{UndoLog, UndoRecord};
 
 /// exec_continue() returns:
 pub enum ExecEvent {
@@ -12,11 +24,77 @@ pub enum ExecEvent {
     MaxInstructions(u64),
     /// Hit a breakpoint at addr
     Breakpoint(u64),
+    /// The guest stopped the machine (e.g. SBI shutdown) with the exit code
+    Exit(i32),
+    /// A load or store hit a watchpoint, CPU stopped after the instruction
+    Watchpoint(WatchHit),
+}
+
+/// Memory accesses a watchpoint triggers on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Read or write
+    Access,
+}
+
+/// Data watchpoint on virtual addresses addr..addr+len
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn hits(&self, addr: u64, size: u64, read: bool, write: bool) -> bool {
+        let kind_hits = match self.kind {
+            WatchKind::Read => read,
+            WatchKind::Write => write,
+            WatchKind::Access => read || write,
+        };
+        kind_hits
+            && addr < self.addr.saturating_add(self.len)
+            && self.addr < addr.saturating_add(size)
+    }
+}
+
+/// The access that hit a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// PC of the instruction that made the access
+    pub pc: u64,
+    /// Virtual address and size in bytes of the access
+    pub addr: u64,
+    pub size: u64,
+    pub write: bool,
+    /// Memory value before and after the access (the same for loads), old is 0 if the store
+    /// went to a device
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Environment calls serviced by the simulator instead of the guest trap handler, e.g. the
+/// built-in SBI or Linux syscalls of user-mode programs
+pub trait EcallHandler {
+    /// Handles ECALL from the privilege mode the handler is installed for, PC is advanced past
+    /// the ECALL afterwards
+    fn ecall(&mut self, cpu: &mut RV64ICpu);
+
+    /// Interrupts raised by the handler (mip layout), e.g. the SBI timer
+    fn pending_irqs(&self, _time: u64) -> u64 {
+        0
+    }
 }
 
 const ILEN_32B: u8 = 4;
 const ILEN_RVC: u8 = 2;
 
+/// Devices are polled (e.g. for input) every DEV_POLL_PERIOD executed instructions
+const DEV_POLL_PERIOD: u64 = 1024;
+
 // RV64I Unprivileged Registers
 #[derive(Clone, Debug, Default)]
 pub struct RV64IURegs {
@@ -29,14 +107,48 @@ pub struct RV64IURegs {
 #[derive(Default)]
 pub struct RV64ICpu {
     regs: RV64IURegs,
-    // fregs: RV64FDRegs,
-    lr_sc_reservation: u64,
+    /// F/D register file, the F and D instructions aren't implemented yet
+    fregs: RV64FDRegs,
+    /// Address reserved by LR, SC succeeds only if the reservation is valid
+    lr_sc_reservation: Option<u64>,
     pub bus: Bus,
     csrs: Csrs,
+    mmu: Mmu,
+    /// Sorted by address, one breakpoint per address
     // TODO: optimize - use hashmap:
-    breakpoints: Vec<u64>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    /// First watchpoint hit by the current instruction
+    watch_hit: Option<WatchHit>,
     /// Number of executed instructions
     num_exec_instr: u64,
+    /// Services ECALLs from ecall_handler_mode
+    ecall_handler: Option<Box<dyn EcallHandler>>,
+    ecall_handler_mode: PrivMode,
+    /// Services semihosting calls (EBREAK between the magic SLLI and SRAI)
+    semihosting: Option<Box<Semihosting>>,
+    /// Polled with the devices for tohost commands
+    htif: Option<Htif>,
+    /// Set when the guest stops the machine
+    exit_code: Option<i32>,
+    /// State overwritten by the last executed instructions for step_back()
+    undo: UndoLog,
+    /// Inputs from the host are recorded to or replayed from the log
+    input_log: Option<InputLog>,
+    /// Writes the executed instructions
+    tracer: Option<Tracer>,
+    /// Effects of the instruction being executed, collected while tracing or by step_commit()
+    commit: Option<Commit>,
+    /// step_commit() is executing an instruction
+    stepping_commit: bool,
+    /// Interrupts driven from outside the machine (mip layout), e.g. by a testbench
+    ext_irqs: u64,
+    /// Counts the executed instructions
+    profiler: Option<Profiler>,
+    /// Counts the executed instructions per call path
+    call_graph: Option<CallGraph>,
+    /// Follows the calls and returns for backtrace()
+    shadow_stack: Option<ShadowStack>,
 }
 
 impl RV64ICpu {
@@ -44,14 +156,196 @@ impl RV64ICpu {
         RV64ICpu {
             bus,
             regs: RV64IURegs::default(),
-            // fregs: RV64FDRegs::default(),
-            lr_sc_reservation: 0,
+            fregs: RV64FDRegs::default(),
+            lr_sc_reservation: None,
             breakpoints: Vec::with_capacity(2),
+            watchpoints: Vec::new(),
+            watch_hit: None,
             csrs: Csrs::new(),
+            mmu: Mmu::default(),
             num_exec_instr: 0,
+            ecall_handler: None,
+            ecall_handler_mode: PrivMode::Supervisor,
+            semihosting: None,
+            htif: None,
+            exit_code: None,
+            undo: UndoLog::default(),
+            input_log: None,
+            tracer: None,
+            commit: None,
+            stepping_commit: false,
+            ext_irqs: 0,
+            profiler: None,
+            call_graph: None,
+            shadow_stack: None,
+        }
+    }
+
+    /// Handles ECALLs from S-mode with the built-in SBI. The hart is switched to S-mode (PC is
+    /// kept) with the delegation M-mode firmware would set up before jumping to the payload.
+    pub fn enable_sbi(&mut self, sbi: Sbi) {
+        let firmware_setup = [
+            (csr::MEDELEG, sbi::DELEGATED_EXCEPTIONS),
+            (csr::MIDELEG, sbi::DELEGATED_IRQS),
+            (csr::MCOUNTEREN, 0xffff_ffff),
+            (csr::MSTATUS, (PrivMode::Supervisor as u64) << 11),
+        ];
+        for (csr_a, val) in firmware_setup {
+            assert!(self.csr_w64(csr_a, val), "SBI must be enabled in M-mode");
+        }
+        self.enter_mode(PrivMode::Supervisor);
+        self.set_ecall_handler(PrivMode::Supervisor, Box::new(sbi));
+    }
+
+    /// ECALLs from the mode are serviced by the handler instead of trapping
+    pub fn set_ecall_handler(&mut self, mode: PrivMode, handler: Box<dyn EcallHandler>) {
+        self.ecall_handler = Some(handler);
+        self.ecall_handler_mode = mode;
+    }
+
+    /// Semihosting calls are serviced by the simulator instead of raising the breakpoint
+    /// exception
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(Box::new(semihosting));
+    }
+
+    /// The log is told the number of executed instructions when the inputs it wraps arrive
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.input_log = Some(log);
+    }
+
+    pub fn input_log(&self) -> Option<&InputLog> {
+        self.input_log.as_ref()
+    }
+
+    /// Traces every executed instruction, None - stops tracing
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Profiles the executed instructions, None - stops profiling
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Builds the call graph of the executed instructions, None - stops
+    pub fn set_call_graph(&mut self, call_graph: Option<CallGraph>) {
+        self.call_graph = call_graph;
+    }
+
+    pub fn call_graph(&self) -> Option<&CallGraph> {
+        self.call_graph.as_ref()
+    }
+
+    /// Keeps the shadow call stack for backtrace(), it starts empty at the current instruction
+    pub fn enable_shadow_stack(&mut self, enabled: bool) {
+        self.shadow_stack = enabled.then(ShadowStack::new);
+    }
+
+    /// Call stack of the current instruction from the shadow call stack or, when it's disabled
+    /// or lost (after step_back(), reverse_continue() and restore_snapshot()), from the frame
+    /// pointer (s0)
+    pub fn backtrace(&self) -> Backtrace {
+        match &self.shadow_stack {
+            Some(stack) if !stack.is_lost() => stack.backtrace(self.regs.pc),
+            _ => frame_pointer_walk(self.regs.pc, self.regs.x[8], |addr| {
+                self.read_virt_u64(addr)
+            }),
+        }
+    }
+
+    fn lose_shadow_stack(&mut self) {
+        if let Some(stack) = &mut self.shadow_stack {
+            stack.lose();
         }
     }
 
+    /// Raises the interrupts (mip layout) and clears the other ones driven from outside the
+    /// machine, they stay pending until cleared like level-triggered interrupt lines
+    pub fn set_external_irqs(&mut self, irqs: u64) {
+        self.ext_irqs = irqs;
+    }
+
+    /// The program talks to the simulator through HTIF tohost/fromhost
+    pub fn enable_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    /// Switches the hart from M-mode to a lower privilege mode keeping PC (like MRET with
+    /// mstatus.MPP = mode)
+    pub fn enter_mode(&mut self, mode: PrivMode) {
+        let mstatus = self.csrs.mstatus() & !csr::MSTATUS_MPP | (mode as u64) << 11;
+        assert!(self.csr_w64(csr::MSTATUS, mstatus), "not in M-mode");
+        let _ = self.csrs.mret();
+    }
+
+    /// Stops the machine, exec_continue() returns ExecEvent::Exit(code)
+    pub(crate) fn exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    /// Drops all cached address translations (SFENCE.VMA)
+    pub(crate) fn flush_tlb(&mut self) {
+        self.mmu.flush();
+    }
+
+    /// Raises or clears the supervisor software interrupt (IPI)
+    pub(crate) fn set_s_soft_irq(&mut self, pending: bool) {
+        self.undo.save_csrs(&self.csrs);
+        self.csrs.set_pending(csr::MIP_SSIP, pending);
+    }
+
+    /// Reads 8 bytes of RAM at virtual address without traps and A/D updates, None - not mapped
+    pub(crate) fn read_virt_u64(&self, vaddr: u64) -> Option<u64> {
+        Some(u64::from_le_bytes(
+            self.read_virt_bytes(vaddr, 8)?.try_into().ok()?,
+        ))
+    }
+
+    /// Reads RAM at virtual address page by page, None - not mapped or not RAM
+    pub(crate) fn read_virt_bytes(&self, vaddr: u64, len: u64) -> Option<Vec<u8>> {
+        let end = vaddr.checked_add(len)?;
+        // the length comes from the guest, the buffer grows only as pages are read
+        let mut data = Vec::with_capacity(len.min(PAGE_SIZE) as usize);
+        let mut addr = vaddr;
+        while addr < end {
+            let chunk = (PAGE_SIZE - (addr & (PAGE_SIZE - 1))).min(end - addr);
+            let paddr = self.mmu.translate_debug(&self.bus, &self.csrs, addr)?;
+            data.extend_from_slice(self.bus.get_ram(paddr, chunk)?);
+            addr += chunk;
+        }
+        Some(data)
+    }
+
+    /// Writes RAM at virtual address page by page, false - not mapped or not RAM
+    pub(crate) fn write_virt_bytes(&mut self, vaddr: u64, data: &[u8]) -> bool {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = vaddr.wrapping_add(done as u64);
+            let chunk = ((PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize).min(data.len() - done);
+            let Some(paddr) = self.mmu.translate_debug(&self.bus, &self.csrs, addr) else {
+                return false;
+            };
+            for off in (0..chunk as u64).step_by(8) {
+                let size = (chunk as u64 - off).min(8);
+                self.save_mem_for_undo(addr + off, paddr + off, size);
+            }
+            if self
+                .bus
+                .write_bytes(paddr, &data[done..done + chunk])
+                .is_err()
+            {
+                return false;
+            }
+            done += chunk;
+        }
+        true
+    }
+
     pub fn get_num_exec_instr(&self) -> u64 {
         self.num_exec_instr
     }
@@ -61,8 +355,122 @@ impl RV64ICpu {
         self.get_instr(self.get_pc())
     }
 
+    /// Reads instruction at virtual address addr without side effects, 0 - not mapped
     pub fn get_instr(&self, addr: u64) -> u32 {
-        self.bus.read32(addr)
+        self.mmu
+            .translate_debug(&self.bus, &self.csrs, addr)
+            .and_then(|paddr| self.bus.read32(paddr))
+            .unwrap_or(0)
+    }
+
+    /// Fetches the instruction at PC, None - fetch trapped (e.g. page fault)
+    fn fetch(&mut self) -> Option<u32> {
+        let pc = self.regs.pc;
+        let paddr = self.translate(pc, Access::Fetch)?;
+        let low = self.bus_access(self.bus.read16(paddr), Access::Fetch, pc)? as u32;
+        if instr_is_rvc(low) {
+            return Some(low);
+        }
+        // 32-bit instruction can cross a page boundary
+        let high_vaddr = pc.wrapping_add(2);
+        let high_paddr = if high_vaddr.is_multiple_of(PAGE_SIZE) {
+            self.translate(high_vaddr, Access::Fetch)?
+        } else {
+            paddr + 2
+        };
+        let high = self.bus_access(self.bus.read16(high_paddr), Access::Fetch, high_vaddr)?;
+        Some((high as u32) << 16 | low)
+    }
+
+    /// Takes the access fault trap if the bus access failed (nothing is mapped at the physical
+    /// address)
+    fn bus_access<T>(&mut self, result: Option<T>, access: Access, vaddr: u64) -> Option<T> {
+        if result.is_none() {
+            self.take_trap(access.access_fault(), vaddr);
+        }
+        result
+    }
+
+    /// Translates virtual address, takes the page fault (or the access fault if the page table
+    /// isn't in RAM) trap on failure
+    fn translate(&mut self, vaddr: u64, access: Access) -> Option<u64> {
+        match self.mmu.translate(&mut self.bus, &self.csrs, vaddr, access) {
+            Ok(paddr) => Some(paddr),
+            Err(cause) => {
+                self.take_trap(cause, vaddr);
+                None
+            }
+        }
+    }
+
+    /// Loads size (1, 2, 4 or 8) bytes from virtual address, None - the load trapped
+    fn mem_read(&mut self, vaddr: u64, size: u64) -> Option<u64> {
+        let val = self.load(vaddr, size)?;
+        self.commit_mem(vaddr, size, val, false);
+        self.check_watchpoints(vaddr, size, (true, false), val, val);
+        Some(val)
+    }
+
+    fn load(&mut self, vaddr: u64, size: u64) -> Option<u64> {
+        if (vaddr & (PAGE_SIZE - 1)) + size > PAGE_SIZE {
+            // misaligned access crossing pages is done byte by byte
+            let mut val = 0;
+            for i in 0..size {
+                val |= self.load(vaddr.wrapping_add(i), 1)? << (8 * i);
+            }
+            return Some(val);
+        }
+        let paddr = self.translate(vaddr, Access::Load)?;
+        let val = match size {
+            1 => self.bus.read8(paddr).map(u64::from),
+            2 => self.bus.read16(paddr).map(u64::from),
+            4 => self.bus.read32(paddr).map(u64::from),
+            _ => self.bus.read64(paddr),
+        };
+        self.bus_access(val, Access::Load, vaddr)
+    }
+
+    /// Stores size (1, 2, 4 or 8) bytes to virtual address, None - the store trapped
+    fn mem_write(&mut self, vaddr: u64, size: u64, val: u64) -> Option<()> {
+        let new = if size == 8 {
+            val
+        } else {
+            val & ((1 << (8 * size)) - 1)
+        };
+        if self.watchpoints.is_empty() {
+            self.store(vaddr, size, val)?;
+            self.commit_mem(vaddr, size, new, true);
+            return Some(());
+        }
+        let old = self.read_virt_bytes(vaddr, size).map_or(0, |bytes| {
+            let mut val = [0; 8];
+            val[..bytes.len()].copy_from_slice(&bytes);
+            u64::from_le_bytes(val)
+        });
+        self.store(vaddr, size, val)?;
+        self.commit_mem(vaddr, size, new, true);
+        self.check_watchpoints(vaddr, size, (false, true), old, new);
+        Some(())
+    }
+
+    fn store(&mut self, vaddr: u64, size: u64, val: u64) -> Option<()> {
+        if (vaddr & (PAGE_SIZE - 1)) + size > PAGE_SIZE {
+            // both pages must be writable before anything is written
+            self.translate(vaddr.wrapping_add(size - 1), Access::Store)?;
+            for i in 0..size {
+                self.store(vaddr.wrapping_add(i), 1, val >> (8 * i))?;
+            }
+            return Some(());
+        }
+        let paddr = self.translate(vaddr, Access::Store)?;
+        self.save_mem_for_undo(vaddr, paddr, size);
+        let done = match size {
+            1 => self.bus.write8(paddr, val as u8),
+            2 => self.bus.write16(paddr, val as u16),
+            4 => self.bus.write32(paddr, val as u32),
+            _ => self.bus.write64(paddr, val),
+        };
+        self.bus_access(done, Access::Store, vaddr)
     }
 
     // TODO: remove it because it doesn't support compressed instructions
@@ -90,23 +498,365 @@ impl RV64ICpu {
         &self.regs
     }
 
+    pub fn get_fregs(&self) -> &RV64FDRegs {
+        &self.fregs
+    }
+
+    pub fn get_fregs_mut(&mut self) -> &mut RV64FDRegs {
+        &mut self.fregs
+    }
+
+    /// Current privilege level
+    pub fn get_priv_mode(&self) -> PrivMode {
+        self.csrs.mode()
+    }
+
+    fn csr_inputs(&self) -> CsrInputs {
+        CsrInputs {
+            instret: self.num_exec_instr,
+            time: self.bus.time(),
+            hw_irqs: self.hw_irqs(),
+        }
+    }
+
+    /// Interrupts pending from the platform devices and the ECALL handler (mip layout)
+    fn hw_irqs(&self) -> u64 {
+        let irqs = self.bus.pending_irqs() | self.ext_irqs;
+        match &self.ecall_handler {
+            Some(handler) => irqs | handler.pending_irqs(self.bus.time()),
+            None => irqs,
+        }
+    }
+
+    /// Reads a CSR as if by the current privilege level, None - the CSR is not accessible
+    pub fn csr_r64(&self, csr_a: u16) -> Option<u64> {
+        self.csrs.r64(csr_a, self.csr_inputs())
+    }
+
+    /// Reads a CSR with M-mode privilege (for debuggers), None - the CSR doesn't exist
+    pub fn csr_r64_debug(&self, csr_a: u16) -> Option<u64> {
+        self.csrs.debug_r64(csr_a, self.csr_inputs())
+    }
+
+    /// Writes a CSR with M-mode privilege (for debuggers), false - the CSR is not writable
+    pub fn csr_w64_debug(&mut self, csr_a: u16, val: u64) -> bool {
+        if !self.csrs.debug_w64(csr_a, val) {
+            return false;
+        }
+        if csr_a == csr::SATP {
+            self.mmu.flush();
+        }
+        true
+    }
+
+    /// Writes a CSR as if by the current privilege level, false - the CSR is not writable
+    pub fn csr_w64(&mut self, csr_a: u16, val: u64) -> bool {
+        self.undo.save_csrs(&self.csrs);
+        if !self.csrs.w64(csr_a, val) {
+            return false;
+        }
+        if csr_a == csr::SATP {
+            self.mmu.flush();
+        }
+        // the value written after masking of the read-only bits
+        let inputs = self.csr_inputs();
+        if let Some(commit) = &mut self.commit {
+            let val = self.csrs.debug_r64(csr_a, inputs).unwrap_or(val);
+            commit.writes.push(RegWrite::Csr(csr_a, val));
+        }
+        true
+    }
+
+    /// Traps to M-mode or S-mode (if delegated) handler. cause is mcause value, i.e. with
+    /// csr::MCAUSE_INTERRUPT set for interrupts.
+    pub fn take_trap(&mut self, cause: u64, tval: u64) {
+        // a trap invalidates the reservation of LR/SC
+        self.lr_sc_reservation = None;
+        self.undo.save_csrs(&self.csrs);
+        if let Some(commit) = &mut self.commit {
+            commit.trap.get_or_insert(cause);
+        }
+        let handler = self.csrs.trap(self.regs.pc, cause, tval);
+        self.pc_jump(handler);
+    }
+
+    fn raise_illegal_instr(&mut self, instr: u32) -> Result<(), String> {
+        self.take_trap(csr::EXC_ILLEGAL_INSTR, instr as u64);
+        Ok(())
+    }
+
     pub fn add_breakpoint(&mut self, breakpoint: u64) {
         // Adding a new breakpoint: O(log(N)) + O(N)
         // Searching: O(log(N))
-        match self.breakpoints.binary_search(&breakpoint) {
+        match self
+            .breakpoints
+            .binary_search_by_key(&breakpoint, |b| b.addr)
+        {
             Ok(_) => {
                 eprint!("WARN: breakpoint 0x{breakpoint:x} already exists")
             }
+            Err(pos) => self.breakpoints.insert(pos, Breakpoint::new(breakpoint)),
+        }
+    }
+
+    /// Adds the breakpoint or replaces the one at the same address
+    pub fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        match self
+            .breakpoints
+            .binary_search_by_key(&breakpoint.addr, |b| b.addr)
+        {
+            Ok(pos) => self.breakpoints[pos] = breakpoint,
             Err(pos) => self.breakpoints.insert(pos, breakpoint),
         }
     }
 
+    /// E.g. to enable/disable the breakpoint or change its ignore count
+    pub fn breakpoint_mut(&mut self, addr: u64) -> Option<&mut Breakpoint> {
+        let pos = self
+            .breakpoints
+            .binary_search_by_key(&addr, |b| b.addr)
+            .ok()?;
+        Some(&mut self.breakpoints[pos])
+    }
+
+    /// Breakpoints sorted by address
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Returns false if there is no breakpoint at the address
+    pub fn remove_breakpoint(&mut self, breakpoint: u64) -> bool {
+        match self
+            .breakpoints
+            .binary_search_by_key(&breakpoint, |b| b.addr)
+        {
+            Ok(pos) => {
+                self.breakpoints.remove(pos);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if self.watchpoints.contains(&watchpoint) {
+            eprintln!("WARN: watchpoint {watchpoint:?} already exists");
+            return;
+        }
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Returns false if there is no such watchpoint
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Saves the whole machine: the hart, the platform time, RAM and devices
+    pub fn save_snapshot(&self) -> Snapshot {
+        Snapshot {
+            hart: HartState {
+                pc: self.regs.pc,
+                x: self.regs.x,
+                f: self.fregs.f,
+                fcsr: self.fregs.fcsr,
+                num_exec_instr: self.num_exec_instr,
+                lr_sc_reservation: self.lr_sc_reservation,
+                csrs: self.csrs.state(),
+            },
+            time: self.bus.time(),
+            rams: self.bus.save_rams(),
+            devices: self.bus.save_devices(),
+        }
+    }
+
+    /// Restores the machine saved by save_snapshot(), the machine must have the same RAM regions
+    /// and devices. The undo log is dropped.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let mut csrs = Csrs::new();
+        for (name, val) in &snapshot.hart.csrs {
+            if !csrs.set_state(name, *val) {
+                return Err(format!("unknown CSR state {name}"));
+            }
+        }
+        self.bus.restore_state(&snapshot.rams, &snapshot.devices)?;
+        self.bus.clock().set(snapshot.time);
+        let hart = &snapshot.hart;
+        self.regs.pc = hart.pc;
+        self.regs.x = hart.x;
+        self.fregs.f = hart.f;
+        self.fregs.fcsr = hart.fcsr;
+        self.num_exec_instr = hart.num_exec_instr;
+        self.lr_sc_reservation = hart.lr_sc_reservation;
+        self.csrs = csrs;
+        self.mmu.flush();
+        self.exit_code = None;
+        self.watch_hit = None;
+        self.set_undo_budget(self.undo.budget());
+        self.lose_shadow_stack();
+        Ok(())
+    }
+
+    /// Records the state overwritten by the last max_instr executed instructions for step_back()
+    /// and reverse_continue(), 0 - disables recording. The recorded history is dropped.
+    pub fn set_undo_budget(&mut self, max_instr: usize) {
+        self.undo = UndoLog::new(max_instr);
+    }
+
+    /// Number of the last executed instructions which can be undone
+    pub fn undo_depth(&self) -> usize {
+        self.undo.depth()
+    }
+
+    /// Logs RAM bytes the current instruction is going to overwrite, other memory (e.g. device
+    /// registers) can't be restored
+    fn save_mem_for_undo(&mut self, vaddr: u64, paddr: u64, size: u64) {
+        if self.undo.budget() == 0 {
+            return;
+        }
+        if let Some(bytes) = self.bus.get_ram(paddr, size) {
+            let mut old = [0; 8];
+            old[..bytes.len()].copy_from_slice(bytes);
+            self.undo.push(UndoRecord::Mem {
+                vaddr,
+                paddr,
+                size: size as u8,
+                old: u64::from_le_bytes(old),
+            });
+        }
+    }
+
+    /// Restores the state before the last executed instruction, false - the undo log is empty.
+    /// A write watchpoint hit by the undone stores is recorded in watch_hit.
+    fn undo_instr(&mut self) -> bool {
+        let Some(records) = self.undo.pop_instr() else {
+            return false;
+        };
+        let mut stores = Vec::new();
+        for record in records {
+            match record {
+                UndoRecord::Instr {
+                    pc,
+                    num_exec_instr,
+                    lr_sc_reservation,
+                } => {
+                    self.regs.pc = pc;
+                    self.num_exec_instr = num_exec_instr;
+                    self.lr_sc_reservation = lr_sc_reservation;
+                }
+                UndoRecord::Reg(reg_i, val) => self.regs.x[reg_i as usize] = val,
+                UndoRecord::Mem {
+                    vaddr,
+                    paddr,
+                    size,
+                    old,
+                } => {
+                    let size = size as usize;
+                    let mut new = [0; 8];
+                    if let Some(bytes) = self.bus.get_ram(paddr, size as u64) {
+                        new[..size].copy_from_slice(bytes);
+                    }
+                    let _ = self.bus.write_bytes(paddr, &old.to_le_bytes()[..size]);
+                    stores.push((vaddr, size as u64, old, u64::from_le_bytes(new)));
+                }
+                UndoRecord::Csrs(csrs) => self.csrs = *csrs,
+            }
+        }
+        // satp or page tables may have changed
+        self.mmu.flush();
+        self.exit_code = None;
+        self.watch_hit = None;
+        // the first store of the instruction was undone last
+        for (vaddr, size, old, new) in stores.into_iter().rev() {
+            self.check_watchpoints(vaddr, size, (false, true), old, new);
+        }
+        true
+    }
+
+    /// Undoes the last executed instruction, false - nothing to undo (the undo budget is 0 or
+    /// the recorded history is exhausted). Devices aren't rewound.
+    pub fn step_back(&mut self) -> bool {
+        let undone = self.undo_instr();
+        self.watch_hit = None;
+        if undone {
+            self.lose_shadow_stack();
+        }
+        undone
+    }
+
+    /// Steps back until an enabled breakpoint with a true condition is at PC or an undone store
+    /// hits a write/access watchpoint (reads aren't recorded). Hit and ignore counters don't
+    /// change. None - reached the start of the recorded history.
+    pub fn reverse_continue(&mut self) -> Option<ExecEvent> {
+        self.lose_shadow_stack();
+        while self.undo_instr() {
+            if let Some(hit) = self.watch_hit.take() {
+                return Some(ExecEvent::Watchpoint(hit));
+            }
+            let pc = self.regs.pc;
+            if let Ok(pos) = self.breakpoints.binary_search_by_key(&pc, |b| b.addr) {
+                if self.breakpoint_triggers(pos) {
+                    return Some(ExecEvent::Breakpoint(pc));
+                }
+            }
+        }
+        None
+    }
+
+    fn commit_mem(&mut self, addr: u64, size: u64, data: u64, write: bool) {
+        if let Some(commit) = &mut self.commit {
+            commit.mem.push(MemAccess {
+                addr,
+                size: size as u8,
+                data,
+                write,
+            });
+        }
+    }
+
+    /// Records the first watchpoint hit by the current instruction. AMOs both read and write.
+    fn check_watchpoints(&mut self, addr: u64, size: u64, rw: (bool, bool), old: u64, new: u64) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let (read, write) = rw;
+        if let Some(&watchpoint) = self
+            .watchpoints
+            .iter()
+            .find(|w| w.hits(addr, size, read, write))
+        {
+            self.watch_hit = Some(WatchHit {
+                watchpoint,
+                pc: self.regs.pc,
+                addr,
+                size,
+                write,
+                old,
+                new,
+            });
+        }
+    }
+
     // reg_i - register index (0 - 31)
     pub fn regs_w64(&mut self, reg_i: u8, val: u64) {
         if reg_i == 0 {
             return; // writes to x0 are ignored
         }
+        self.undo
+            .push(UndoRecord::Reg(reg_i, self.regs.x[reg_i as usize]));
         self.regs.x[reg_i as usize] = val;
+        if let Some(commit) = &mut self.commit {
+            commit.writes.push(RegWrite::X(reg_i, val));
+        }
     }
 
     /// writes sign extended u32 to reg_i register
@@ -145,11 +895,6 @@ impl RV64ICpu {
         self.regs.x[reg_i as usize] as u32
     }
 
-    // reads [7:0] from register x[reg_i]
-    fn regs_r8(&self, reg_i: u8) -> u8 {
-        self.regs.x[reg_i as usize] as u8
-    }
-
     // Treat register as signed 64 bit
     fn regs_ri64(&self, reg_i: u8) -> i64 {
         self.regs.x[reg_i as usize] as i64
@@ -173,39 +918,107 @@ impl RV64ICpu {
         self.regs.pc = self.regs.pc.add_i21(off21);
     }
 
-    // csrrs, csrrwi
+    // CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI
     fn exe_opc_system(&mut self, csr: u16, rs1: u8, funct3: u8, rd: u8) -> Result<(), String> {
-        // TODO: each operation is atomic
-        match funct3 {
-            // csrrs rd, csr, rs1
-            F3_SYSTEM_CSRRS => {
-                let mut csr_v = self.csrs.r64(csr);
-                self.regs_w64(rd, csr_v);
-                csr_v |= self.regs_r64(rs1);
-                self.csrs.w64(csr, csr_v);
-            }
-            // csrrw rd, csr, rs1
-            F3_SYSTEM_CSRRW => {
-                self.regs_w64(rd, self.csrs.r64(csr));
-                self.csrs.w64(csr, self.regs_r64(rs1));
-            }
-            // csrrwi rd, csr, uimm5
-            F3_SYSTEM_CSRRWI => {
-                self.regs_w64(rd, self.csrs.r64(csr));
-                // rs1 is uimm[4:0]
-                self.csrs.w64(csr, rs1 as u64);
-            }
-            F3_SYSTEM_WFI => {
-                // TODO: hint to check interrupts
-            }
+        // rs1 is uimm[4:0] for the immediate variants
+        let src = match funct3 {
+            F3_SYSTEM_CSRRW | F3_SYSTEM_CSRRS | F3_SYSTEM_CSRRC => self.regs_r64(rs1),
+            F3_SYSTEM_CSRRWI | F3_SYSTEM_CSRRSI | F3_SYSTEM_CSRRCI => rs1 as u64,
             _ => {
                 return Err(format!("SYSTEM, funct3: {funct3:x}"));
             }
+        };
+        let instr = (csr as u32) << 20
+            | (rs1 as u32) << 15
+            | (funct3 as u32) << 12
+            | (rd as u32) << 7
+            | OPC_SYSTEM as u32;
+        // each operation is atomic
+        let Some(old) = self.csr_r64(csr) else {
+            return self.raise_illegal_instr(instr);
+        };
+        // CSRRS and CSRRC with rs1 = x0 (or uimm = 0) don't write the CSR
+        let new = match funct3 {
+            F3_SYSTEM_CSRRW | F3_SYSTEM_CSRRWI => Some(src),
+            F3_SYSTEM_CSRRS | F3_SYSTEM_CSRRSI => (rs1 != 0).then_some(old | src),
+            _ => (rs1 != 0).then_some(old & !src),
+        };
+        if let Some(new) = new {
+            if !self.csr_w64(csr, new) {
+                return self.raise_illegal_instr(instr);
+            }
         }
+        self.regs_w64(rd, old);
         self.pc_inc(ILEN_32B);
         Ok(())
     }
 
+    /// EBREAK at PC is surrounded by the semihosting magic instructions
+    fn is_semihosting_call(&self) -> bool {
+        // the neighbours may be out of RAM, e.g. EBREAK at the start of it
+        let instr_at = |addr: u64| {
+            let bytes = self.read_virt_bytes(addr, ILEN_32B as u64)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?))
+        };
+        let pc = self.regs.pc;
+        self.semihosting.is_some()
+            && instr_at(pc.wrapping_sub(ILEN_32B as u64)) == Some(semihosting::SLLI_MAGIC)
+            && instr_at(pc.wrapping_add(ILEN_32B as u64)) == Some(semihosting::SRAI_MAGIC)
+    }
+
+    // ECALL, EBREAK, MRET, SRET, WFI, SFENCE.VMA
+    fn exe_opc_priv(&mut self, opcode: Opcode, instr: u32) -> Result<(), String> {
+        if matches!(opcode, Opcode::Ecall) && self.csrs.mode() == self.ecall_handler_mode {
+            // the handler gets access to the whole hart, so it's taken out for the call
+            if let Some(mut handler) = self.ecall_handler.take() {
+                handler.ecall(self);
+                self.ecall_handler = Some(handler);
+                self.pc_inc(ILEN_32B);
+                return Ok(());
+            }
+        }
+        if matches!(opcode, Opcode::Ebreak) && self.is_semihosting_call() {
+            if let Some(mut semihosting) = self.semihosting.take() {
+                semihosting.call(self);
+                self.semihosting = Some(semihosting);
+                // the SRAI after EBREAK is a NOP
+                self.pc_inc(ILEN_32B);
+                return Ok(());
+            }
+        }
+        if matches!(opcode, Opcode::Mret | Opcode::Sret) {
+            self.undo.save_csrs(&self.csrs);
+        }
+        match opcode {
+            Opcode::Ecall => {
+                let cause = match self.csrs.mode() {
+                    PrivMode::User => csr::EXC_ECALL_U,
+                    PrivMode::Supervisor => csr::EXC_ECALL_S,
+                    PrivMode::Machine => csr::EXC_ECALL_M,
+                };
+                self.take_trap(cause, 0);
+            }
+            Opcode::Ebreak => self.take_trap(csr::EXC_BREAKPOINT, self.regs.pc),
+            Opcode::Mret => match self.csrs.mret() {
+                Some(epc) => self.pc_jump(epc),
+                None => return self.raise_illegal_instr(instr),
+            },
+            Opcode::Sret => match self.csrs.sret() {
+                Some(epc) => self.pc_jump(epc),
+                None => return self.raise_illegal_instr(instr),
+            },
+            // WFI returns immediately, pending interrupts are checked before every instruction
+            Opcode::Wfi if self.csrs.wfi_is_legal() => self.pc_inc(ILEN_32B),
+            // the whole TLB is flushed regardless of the address and ASID
+            Opcode::SfenceVma { .. } if self.csrs.sfence_vma_is_legal() => {
+                self.mmu.flush();
+                self.pc_inc(ILEN_32B)
+            }
+            _ => return self.raise_illegal_instr(instr),
+        }
+        Ok(())
+    }
+
     // BRANCH opcodes: BEQ, BNE, BLT, BLTU, ...
     fn exe_opc_branch(
         &mut self,
@@ -294,6 +1107,11 @@ impl RV64ICpu {
             F3_OP_IMM_ADDI => {
                 self.regs_w64(rd, self.regs_r64(rs1).add_i12(imm12));
             }
+            // Set Less Than Immediate
+            F3_OP_IMM_SLTI => {
+                let lt = self.regs_ri64(rs1) < u64::from(imm12) as i64;
+                self.regs_w64(rd, lt as u64)
+            }
             // Set Less Than Immediate Unsigned
             F3_OP_IMM_SLTIU => {
                 // sign extend imm12
@@ -307,15 +1125,22 @@ impl RV64ICpu {
             F3_OP_IMM_XORI => {
                 self.regs_w64(rd, self.regs_r64(rs1) ^ u64::from(imm12));
             }
+            F3_OP_IMM_ORI => {
+                self.regs_w64(rd, self.regs_r64(rs1) | u64::from(imm12));
+            }
             F3_OP_IMM_ANDI => {
                 self.regs_w64(rd, self.regs_r64(rs1) & u64::from(imm12));
             }
-            F3_OP_IMM_SLLI => {
+            F3_OP_IMM_SLLI if imm12.0.bits(11, 6) == 0 => {
                 self.regs_w64(rd, self.regs_r64(rs1) << imm12.0.bits(5, 0));
             }
-            F3_OP_IMM_SRLI => {
+            F3_OP_IMM_SRLI if imm12.0.bits(11, 6) == 0 => {
                 self.regs_w64(rd, self.regs_r64(rs1) >> imm12.0.bits(5, 0));
             }
+            // SRAI: imm[11:6] = 0b010000
+            F3_OP_IMM_SRLI if imm12.0.bits(11, 6) == 0b01_0000 => {
+                self.regs_w64(rd, (self.regs_ri64(rs1) >> imm12.0.bits(5, 0)) as u64);
+            }
             _ => {
                 return Err(format!("OP_IMM, funct3: 0b{funct3:b}"));
             }
@@ -324,7 +1149,7 @@ impl RV64ICpu {
         Ok(())
     }
 
-    // ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND
+    // ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND and M extension: MUL, DIV, REM, ...
     fn exe_opc_op(
         &mut self,
         funct7: u8,
@@ -334,21 +1159,70 @@ impl RV64ICpu {
         rd: u8,
         isize: u8,
     ) -> Result<(), String> {
-        match (funct7, funct3) {
-            (F7_OP_ADD, F3_OP_ADD_SUB) => {
-                // ignore overflow with wrapping_add()
-                self.regs_w64(rd, self.regs_r64(rs1).wrapping_add(self.regs_r64(rs2)))
-            }
-            (F7_OP_SUB, F3_OP_ADD_SUB) => {
-                // ignore overflow with wrapping_sub()
-                self.regs_w64(rd, self.regs_r64(rs1).wrapping_sub(self.regs_r64(rs2)))
-            }
+        let a = self.regs_r64(rs1);
+        let b = self.regs_r64(rs2);
+        let val = match (funct7, funct3) {
+            // ignore overflow with wrapping_add()
+            (F7_OP_ADD, F3_OP_ADD_SUB) => a.wrapping_add(b),
+            // ignore overflow with wrapping_sub()
+            (F7_OP_SUB, F3_OP_ADD_SUB) => a.wrapping_sub(b),
+            (0, F3_OP_SLL) => a << (b & 0x3f),
+            (0, F3_OP_SLT) => ((a as i64) < (b as i64)) as u64,
+            (0, F3_OP_SLTU) => (a < b) as u64,
+            (0, F3_OP_XOR) => a ^ b,
+            (0, F3_OP_SRL_SRA) => a >> (b & 0x3f),
+            (F7_OP_SRA, F3_OP_SRL_SRA) => ((a as i64) >> (b & 0x3f)) as u64,
+            (0, F3_OP_OR) => a | b,
+            (0, F3_OP_AND) => a & b,
+            (F7_OP_MULDIV, F3_OP_MUL) => a.wrapping_mul(b),
+            (F7_OP_MULDIV, F3_OP_MULH) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+            (F7_OP_MULDIV, F3_OP_MULHSU) => ((a as i64 as i128 * b as i128) >> 64) as u64,
+            (F7_OP_MULDIV, F3_OP_MULHU) => ((a as u128 * b as u128) >> 64) as u64,
+            // division by zero and overflow don't trap
+            (F7_OP_MULDIV, F3_OP_DIV) if b == 0 => u64::MAX,
+            (F7_OP_MULDIV, F3_OP_DIV) => (a as i64).wrapping_div(b as i64) as u64,
+            (F7_OP_MULDIV, F3_OP_DIVU) => a.checked_div(b).unwrap_or(u64::MAX),
+            (F7_OP_MULDIV, F3_OP_REM) if b == 0 => a,
+            (F7_OP_MULDIV, F3_OP_REM) => (a as i64).wrapping_rem(b as i64) as u64,
+            (F7_OP_MULDIV, F3_OP_REMU) => a.checked_rem(b).unwrap_or(a),
             (_, _) => return Err(format!("OP, funct7: {funct7:x}, funct3: {funct3:x}")),
-        }
+        };
+        self.regs_w64(rd, val);
         self.pc_inc(isize);
         Ok(())
     }
 
+    // ADDW, SLLW, SRLW, SRAW, MULW, DIVW, DIVUW, REMW, REMUW (SUBW is decoded separately)
+    fn exe_opc_op32(
+        &mut self,
+        funct7: u8,
+        rs2: u8,
+        rs1: u8,
+        funct3: u8,
+        rd: u8,
+    ) -> Result<(), String> {
+        let a = self.regs_r32(rs1);
+        let b = self.regs_r32(rs2);
+        let val = match (funct7, funct3) {
+            (F7_OP_ADD, F3_OP_ADD_SUB) => a.wrapping_add(b),
+            (0, F3_OP_SLL) => a << (b & 0x1f),
+            (0, F3_OP_SRL_SRA) => a >> (b & 0x1f),
+            (F7_OP_SRA, F3_OP_SRL_SRA) => ((a as i32) >> (b & 0x1f)) as u32,
+            (F7_OP_MULDIV, F3_OP_MUL) => a.wrapping_mul(b),
+            (F7_OP_MULDIV, F3_OP_DIV) if b == 0 => u32::MAX,
+            (F7_OP_MULDIV, F3_OP_DIV) => (a as i32).wrapping_div(b as i32) as u32,
+            (F7_OP_MULDIV, F3_OP_DIVU) => a.checked_div(b).unwrap_or(u32::MAX),
+            (F7_OP_MULDIV, F3_OP_REM) if b == 0 => a,
+            (F7_OP_MULDIV, F3_OP_REM) => (a as i32).wrapping_rem(b as i32) as u32,
+            (F7_OP_MULDIV, F3_OP_REMU) => a.checked_rem(b).unwrap_or(a),
+            (_, _) => return Err(format!("OP32, funct7: {funct7:x}, funct3: {funct3:x}")),
+        };
+        // results are sign extended
+        self.regs_wi32(rd, val);
+        self.pc_inc(ILEN_32B);
+        Ok(())
+    }
+
     // Only one instrucitn JAL - Jump and Link
     fn exe_opc_jal(&mut self, imm21: I21, rd: u8) -> Result<(), String> {
         self.regs_w64(rd, self.regs.pc + 4);
@@ -374,21 +1248,31 @@ impl RV64ICpu {
         isize: u8,
     ) -> Result<(), String> {
         let addr = self.regs_r64(rs1).add_i12(imm12);
-        // TODO raise fault if returns 0xffff_ffff?
+        let size = match funct3 {
+            F3_OP_LOAD_LB | F3_OP_LOAD_LBU => 1,
+            F3_OP_LOAD_LH | F3_OP_LOAD_LHU => 2,
+            F3_OP_LOAD_LW | F3_OP_LOAD_LWU => 4,
+            F3_OP_LOAD_LD => 8,
+            _ => {
+                return Err(format!("LOAD, funct3: 0b{funct3:b}"));
+            }
+        };
+        let Some(val) = self.mem_read(addr, size) else {
+            return Ok(());
+        };
         match funct3 {
             // Load Byte
-            F3_OP_LOAD_LB => self.regs_wi8(rd, self.bus.read8(addr)),
+            F3_OP_LOAD_LB => self.regs_wi8(rd, val as u8),
             // Load Byte Unsigned
-            F3_OP_LOAD_LBU => self.regs_wu8(rd, self.bus.read8(addr)),
+            F3_OP_LOAD_LBU => self.regs_wu8(rd, val as u8),
+            // Load Half-word
+            F3_OP_LOAD_LH => self.regs_w64(rd, val as i16 as i64 as u64),
             // Load Word
-            F3_OP_LOAD_LW => self.regs_wi32(rd, self.bus.read32(addr)),
+            F3_OP_LOAD_LW => self.regs_wi32(rd, val as u32),
             // Load Word Unsigned
-            F3_OP_LOAD_LWU => self.regs_w32(rd, self.bus.read32(addr)),
-            // Load Double Word
-            F3_OP_LOAD_LD => self.regs_w64(rd, self.bus.read64(addr)),
-            _ => {
-                return Err(format!("LOAD, funct3: 0b{funct3:b}"));
-            }
+            F3_OP_LOAD_LWU => self.regs_w32(rd, val as u32),
+            // Load Half-word Unsigned, Load Double Word
+            _ => self.regs_w64(rd, val),
         }
         self.pc_inc(isize);
         Ok(())
@@ -403,13 +1287,17 @@ impl RV64ICpu {
         isize: u8,
     ) -> Result<(), String> {
         let addr = self.regs_r64(rs1).add_i12(imm12);
-        match funct3 {
-            F3_OP_STORE_SB => self.bus.write8(addr, self.regs_r8(rs2)),
-            F3_OP_STORE_SW => self.bus.write32(addr, self.regs_r32(rs2)),
-            F3_OP_STORE_SD => self.bus.write64(addr, self.regs_r64(rs2)),
+        let size = match funct3 {
+            F3_OP_STORE_SB => 1,
+            F3_OP_STORE_SH => 2,
+            F3_OP_STORE_SW => 4,
+            F3_OP_STORE_SD => 8,
             _ => {
                 return Err(format!("STORE, funct3: 0b{funct3:b}"));
             }
+        };
+        if self.mem_write(addr, size, self.regs_r64(rs2)).is_none() {
+            return Ok(());
         }
         self.pc_inc(isize);
         Ok(())
@@ -431,42 +1319,108 @@ impl RV64ICpu {
         // These AMO instructions atomically load a data value from the address in rs1,
         // place the value into register rd, apply a binary operator to the loaded value and
         // the original value in rs2, then store the result back to the address in rs1.
-        // TODO: check whether rs1 value (address) is 8-byte aligned. If not aligned then generate
-        // an address misalgined exception.
-        match (funct5, funct3) {
-            // amoadd.w rd, rs2, rs1 # rd <= mem[rs1]; mem[rs1] <= rd + rs2
-            (F5_OP_AMO_ADD, F3_OP_AMO_WORD) => {
-                // TODO: use native atomic operation
-                let address = self.regs_r64(rs1); // preserve address to avoid problem when rd == rs1
-                self.regs_wi32(rd, self.bus.read32(address));
-                self.bus.write32(
-                    address,
-                    self.regs_r64(rs2).wrapping_add(self.regs_r64(rd)) as u32,
-                );
-            }
-            // amoswap.w rd, rs2, rs1 # rd <= mem[rs1]; mem[rs1] <= rs2
-            (F5_OP_AMO_SWAP, F3_OP_AMO_WORD) => {
-                // TODO: use native atomic swap
-                let address = self.regs_r64(rs1); // preserve address to avoid problem when rd == rs1
-                self.regs_wi32(rd, self.bus.read32(self.regs_r64(rs1)));
-                self.bus.write32(address, self.regs_r64(rs2) as u32);
-            }
-            // lr.w
-            (F5_OP_AMO_LRW, F3_OP_AMO_WORD) if rs2 == 0 => {
-                let addressed_word = self.bus.read32(self.regs_r64(rs1));
-                self.regs_wi32(rd, addressed_word);
-                // registers a reservation set — a set of bytes that subsumes the bytes in the addressed word.
-                self.lr_sc_reservation = addressed_word as u64;
-                // TODO: check lr_sc_reservation in sc.w
+        let dword = match funct3 {
+            F3_OP_AMO_WORD => false,
+            F3_OP_AMO_DWORD => true,
+            _ => return Err(format!("AMO, funct5: {funct5:x}, funct3: {funct3:x}")),
+        };
+        // preserve address and source to avoid problem when rd == rs1 or rd == rs2
+        let address = self.regs_r64(rs1);
+        let src = self.regs_r64(rs2);
+        if !address.is_multiple_of(if dword { 8 } else { 4 }) {
+            let cause = if funct5 == F5_OP_AMO_LRW {
+                csr::EXC_LOAD_MISALIGNED
+            } else {
+                csr::EXC_STORE_MISALIGNED
+            };
+            self.take_trap(cause, address);
+            return Ok(());
+        }
+        let access = if funct5 == F5_OP_AMO_LRW {
+            Access::Load
+        } else {
+            Access::Store
+        };
+        let vaddr = address;
+        let Some(address) = self.translate(address, access) else {
+            return Ok(());
+        };
+        let size = if dword { 8 } else { 4 };
+        let mask = if dword { u64::MAX } else { 0xffff_ffff };
+        // W variants sign extend the loaded word
+        let old = if dword {
+            self.bus.read64(address)
+        } else {
+            self.bus.read32(address).map(|w| w as i32 as i64 as u64)
+        };
+        // AMOs raise store/AMO access faults, LR load access faults
+        let Some(old) = self.bus_access(old, access, vaddr) else {
+            return Ok(());
+        };
+        let new = match funct5 {
+            F5_OP_AMO_LRW if rs2 == 0 => {
+                // registers a reservation set — a set of bytes that subsumes the bytes in the
+                // addressed word.
+                self.lr_sc_reservation = Some(address);
+                self.commit_mem(vaddr, size, old & mask, false);
+                self.check_watchpoints(vaddr, size, (true, false), old & mask, old & mask);
+                None
             }
+            F5_OP_AMO_SC => {
+                let reserved = self.lr_sc_reservation.take() == Some(address);
+                if reserved {
+                    if self.amo_store(vaddr, address, src, dword).is_none() {
+                        return Ok(());
+                    }
+                    self.commit_mem(vaddr, size, src & mask, true);
+                    self.check_watchpoints(vaddr, size, (false, true), old & mask, src & mask);
+                }
+                // rd = 0 on success, 1 on failure
+                self.regs_w64(rd, !reserved as u64);
+                self.pc_inc(ILEN_32B);
+                return Ok(());
+            }
+            F5_OP_AMO_SWAP => Some(src),
+            F5_OP_AMO_ADD => Some(old.wrapping_add(src)),
+            F5_OP_AMO_XOR => Some(old ^ src),
+            F5_OP_AMO_AND => Some(old & src),
+            F5_OP_AMO_OR => Some(old | src),
+            F5_OP_AMO_MIN if dword => Some((old as i64).min(src as i64) as u64),
+            F5_OP_AMO_MIN => Some((old as i32).min(src as i32) as u64),
+            F5_OP_AMO_MAX if dword => Some((old as i64).max(src as i64) as u64),
+            F5_OP_AMO_MAX => Some((old as i32).max(src as i32) as u64),
+            F5_OP_AMO_MINU if dword => Some(old.min(src)),
+            F5_OP_AMO_MINU => Some((old as u32).min(src as u32) as u64),
+            F5_OP_AMO_MAXU if dword => Some(old.max(src)),
+            F5_OP_AMO_MAXU => Some((old as u32).max(src as u32) as u64),
             _ => {
                 return Err(format!("AMO, funct5: {funct5:x}, funct3: {funct3:x}"));
             }
+        };
+        if let Some(new) = new {
+            if self.amo_store(vaddr, address, new, dword).is_none() {
+                return Ok(());
+            }
+            self.commit_mem(vaddr, size, old & mask, false);
+            self.commit_mem(vaddr, size, new & mask, true);
+            self.check_watchpoints(vaddr, size, (true, true), old & mask, new & mask);
         }
+        self.regs_w64(rd, old);
         self.pc_inc(ILEN_32B);
         Ok(())
     }
 
+    /// None - the store trapped
+    fn amo_store(&mut self, vaddr: u64, address: u64, val: u64, dword: bool) -> Option<()> {
+        self.save_mem_for_undo(vaddr, address, if dword { 8 } else { 4 });
+        let done = if dword {
+            self.bus.write64(address, val)
+        } else {
+            self.bus.write32(address, val as u32)
+        };
+        self.bus_access(done, Access::Store, vaddr)
+    }
+
     pub fn execute_instr(&mut self, instr: u32) {
         if let Err(e) = match decode_instr(instr) {
             Opcode::LUI { uimm20, rd } => {
@@ -522,6 +1476,11 @@ impl RV64ICpu {
                 self.pc_inc(ILEN_32B);
                 Ok(())
             }
+            Opcode::SRAIW { shamt, rs1, rd } => {
+                self.regs_wi32(rd, ((self.regs_r32(rs1) as i32) >> shamt) as u32);
+                self.pc_inc(ILEN_32B);
+                Ok(())
+            }
             Opcode::Op {
                 funct7,
                 rs2,
@@ -534,6 +1493,13 @@ impl RV64ICpu {
                 self.pc_inc(ILEN_32B);
                 Ok(())
             }
+            Opcode::Op32 {
+                funct7,
+                rs2,
+                rs1,
+                funct3,
+                rd,
+            } => self.exe_opc_op32(funct7, rs2, rs1, funct3, rd),
             Opcode::Amo {
                 funct5,
                 aq,
@@ -549,12 +1515,18 @@ impl RV64ICpu {
                 funct3,
                 rd,
             } => self.exe_opc_system(csr, rs1, funct3, rd),
+            opcode @ (Opcode::Ecall
+            | Opcode::Ebreak
+            | Opcode::Mret
+            | Opcode::Sret
+            | Opcode::Wfi
+            | Opcode::SfenceVma { .. }) => self.exe_opc_priv(opcode, instr),
             Opcode::Uknown => Err(String::new()),
         } {
             let opc = i_opcode(instr);
             eprintln!("ERROR: Uknown instruction {e}\nPC = 0x{:x}, code: 0x{instr:08x} (0b_{instr:032b}), opcode: 0x{opc:x} (0b_{opc:07b})",
             self.get_pc());
-            // TODO: trigger CPU exception
+            let _ = self.raise_illegal_instr(instr);
         }
         self.num_exec_instr += 1;
     }
@@ -604,12 +1576,31 @@ impl RV64ICpu {
             COpcode::CSRLI { shamt6, rd } => {
                 self.exe_opc_op_imm(I12(shamt6 as i16), rd, F3_OP_IMM_SRLI, rd, ILEN_RVC)
             }
+            // C.SRAI expands into SRAI rd, rd, shamt[5:0]
+            COpcode::CSRAI { shamt6, rd } => {
+                self.exe_opc_op_imm(I12(0x400 | shamt6 as i16), rd, F3_OP_IMM_SRLI, rd, ILEN_RVC)
+            }
             COpcode::CLI { imm6, rd } => self.exe_opc_c_li(imm6, rd),
             // C.JR expands to JALR x0, 0(rs1)
             COpcode::CJR { rs1 } => self.exe_opc_jalr(0_u16.into(), rs1, 0),
+            // C.JALR expands to JALR x1, 0(rs1)
+            COpcode::CJALR { rs1 } => {
+                let target = self.regs_r64(rs1);
+                self.regs_w64(1, self.regs.pc + ILEN_RVC as u64);
+                self.pc_jump(target);
+                Ok(())
+            }
+            COpcode::CEBREAK => {
+                self.take_trap(csr::EXC_BREAKPOINT, self.regs.pc);
+                Ok(())
+            }
             COpcode::CADD { rd, rs2 } => {
-                self.exe_opc_op(F3_OP_ADD_SUB, rs2, rd, F7_OP_ADD, rd, ILEN_RVC)
+                self.exe_opc_op(F7_OP_ADD, rs2, rd, F3_OP_ADD_SUB, rd, ILEN_RVC)
+            }
+            COpcode::CSUB { rd, rs2 } => {
+                self.exe_opc_op(F7_OP_SUB, rs2, rd, F3_OP_ADD_SUB, rd, ILEN_RVC)
             }
+            COpcode::CXOR { rd, rs2 } => self.exe_opc_op(0, rs2, rd, F3_OP_XOR, rd, ILEN_RVC),
             // C.ADDW rd, rs2
             COpcode::CADDW { rd, rs2 } => {
                 self.regs_wi32(rd, self.regs_r32(rd).wrapping_add(self.regs_r32(rs2)));
@@ -631,6 +1622,24 @@ impl RV64ICpu {
                     ILEN_RVC,
                 )
             }
+            COpcode::SWSP { uimm6, rs2 } => {
+                self.exe_opc_store(
+                    ((uimm6 as u16) << 2).into(),
+                    rs2,
+                    /* SP */ 2,
+                    F3_OP_STORE_SW,
+                    ILEN_RVC,
+                )
+            }
+            COpcode::LWSP { uimm6, rd } => {
+                self.exe_opc_load(
+                    ((uimm6 as u16) << 2).into(),
+                    /* SP */ 2,
+                    F3_OP_LOAD_LW,
+                    rd,
+                    ILEN_RVC,
+                )
+            }
             COpcode::LDSP { uimm6, rd } => {
                 self.exe_opc_load(
                     ((uimm6 as u16) << 3).into(),
@@ -666,7 +1675,7 @@ impl RV64ICpu {
             ),
             // c.mv expands to add rd, x0, rs2
             COpcode::MV { rd, rs2 } => {
-                self.exe_opc_op(F3_OP_ADD_SUB, rs2, 0, F7_OP_ADD, rd, ILEN_RVC)
+                self.exe_opc_op(F7_OP_ADD, rs2, 0, F3_OP_ADD_SUB, rd, ILEN_RVC)
             }
             // c.addiw expands to addiw rd, rd, imm[5:0]
             COpcode::ADDIW { rd, uimm6 } => {
@@ -707,25 +1716,112 @@ impl RV64ICpu {
                 self.get_pc()
             );
             eprintln!("(0b_{c_instr:016b}), opcode: 0x{opc:02x} (0b_{opc:05b})");
-            // TODO: trigger CPU exception
+            let _ = self.raise_illegal_instr(c_instr as u32);
         }
 
         self.num_exec_instr += 1;
     }
 
-    fn check_break_points(&self, addr: u64) -> bool {
+    /// Counts the hit of an enabled breakpoint with a true condition, returns true if the CPU
+    /// must stop
+    fn check_break_points(&mut self, addr: u64) -> bool {
         // Search: O(log(N))
-        self.breakpoints.binary_search(&addr).is_ok()
+        let Ok(pos) = self.breakpoints.binary_search_by_key(&addr, |b| b.addr) else {
+            return false;
+        };
+        if !self.breakpoint_triggers(pos) {
+            return false;
+        }
+        let breakpoint = &mut self.breakpoints[pos];
+        breakpoint.hit_count += 1;
+        if breakpoint.ignore_count > 0 {
+            breakpoint.ignore_count -= 1;
+            return false;
+        }
+        if breakpoint.temporary {
+            self.breakpoints.remove(pos);
+        }
+        true
+    }
+
+    /// The breakpoint is enabled and its condition is true
+    fn breakpoint_triggers(&self, pos: usize) -> bool {
+        let breakpoint = &self.breakpoints[pos];
+        if !breakpoint.enabled {
+            return false;
+        }
+        if let Some(condition) = &breakpoint.condition {
+            match condition.eval(self) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                // stop to let the user fix the condition
+                Err(e) => eprintln!("WARN: breakpoint 0x{:x}: {condition}: {e}", breakpoint.addr),
+            }
+        }
+        true
     }
 
     /// Returns PC (i.e. where stopped)
     pub fn exec_continue(&mut self, max_instr: u64) -> ExecEvent {
         for _ in 0..max_instr {
-            let instr = self.fetch_instr();
-            if instr_is_rvc(instr) {
-                self.execute_rvc_instr(instr.bits(15, 0) as u16);
-            } else {
-                self.execute_instr(instr);
+            if let Some(code) = self.exit_code {
+                return ExecEvent::Exit(code);
+            }
+            self.undo
+                .begin_instr(self.regs.pc, self.num_exec_instr, self.lr_sc_reservation);
+            if let Some(log) = &self.input_log {
+                log.set_instr(self.num_exec_instr);
+            }
+            self.bus.refresh_irqs();
+            if let Some(irq) = self.csrs.pending_interrupt(self.hw_irqs()) {
+                self.take_trap(csr::MCAUSE_INTERRUPT | irq, 0);
+            }
+            if self.tracer.is_some() || self.stepping_commit {
+                self.commit = Some(Commit {
+                    index: self.num_exec_instr,
+                    hart: self.csr_r64_debug(csr::MHARTID).unwrap_or(0),
+                    priv_mode: self.get_priv_mode() as u8,
+                    pc: self.regs.pc,
+                    ..Default::default()
+                });
+            }
+            if let Some(instr) = self.fetch() {
+                if let Some(commit) = &mut self.commit {
+                    commit.instr = instr;
+                }
+                let pc = self.regs.pc;
+                if instr_is_rvc(instr) {
+                    self.execute_rvc_instr(instr.bits(15, 0) as u16);
+                } else {
+                    self.execute_instr(instr);
+                }
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record(pc, instr, self.regs.pc);
+                }
+                if let Some(call_graph) = &mut self.call_graph {
+                    call_graph.record(pc, instr, self.regs.pc);
+                }
+                if let Some(stack) = &mut self.shadow_stack {
+                    stack.record(pc, instr, self.regs.pc);
+                }
+            }
+            if let (Some(commit), Some(tracer)) = (&self.commit, &mut self.tracer) {
+                tracer.trace(commit);
+            }
+            if !self.stepping_commit {
+                self.commit = None;
+            }
+            // one timer tick per instruction
+            self.bus.tick();
+            if self.num_exec_instr.is_multiple_of(DEV_POLL_PERIOD) {
+                self.bus.poll_devices();
+                if let Some(code) = self.htif.as_mut().and_then(|h| h.poll(&mut self.bus)) {
+                    self.exit(code);
+                }
+            }
+            self.undo.end_instr();
+            if let Some(hit) = self.watch_hit.take() {
+                return ExecEvent::Watchpoint(hit);
             }
             if self.check_break_points(self.regs.pc) {
                 return ExecEvent::Breakpoint(self.regs.pc);
@@ -733,6 +1829,26 @@ impl RV64ICpu {
         }
         ExecEvent::MaxInstructions(self.regs.pc)
     }
+
+    /// Executes one instruction and returns its effects (with `trap` set if it raised an
+    /// exception), None - the machine has exited
+    pub fn step_commit(&mut self) -> (ExecEvent, Option<Commit>) {
+        self.stepping_commit = true;
+        let event = self.exec_continue(1);
+        self.stepping_commit = false;
+        (event, self.commit.take())
+    }
+}
+
+/// Hart with the program at the start of 4 KiB of RAM at base, PC points to it
+#[cfg(test)]
+pub(crate) fn cpu_with_program(base: u64, program: &[u32]) -> RV64ICpu {
+    let mut bus = Bus::new_with_ram(base, 0x1000);
+    let code: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes()).collect();
+    bus.write_bytes(base, &code).unwrap();
+    let mut cpu = RV64ICpu::new(bus);
+    cpu.pc_jump(base);
+    cpu
 }
 
 #[test]
@@ -747,15 +1863,218 @@ fn test_instr_decode_immidiates() {
     assert!(imm12.0 == 255);
 }
 
+#[test]
+fn test_watchpoint_top_of_address_space() {
+    let watch = Watchpoint {
+        addr: 0xffff_ffff_ffff_fffc,
+        len: 4,
+        kind: WatchKind::Read,
+    };
+    assert!(watch.hits(0xffff_ffff_ffff_fff8, 8, true, false));
+    assert!(!watch.hits(0xffff_ffff_ffff_fff8, 4, true, false));
+    assert!(!watch.hits(0xffff_ffff_ffff_fff8, 8, false, true));
+}
+
 #[test]
 fn test_breakpoints() {
     let mut cpu = RV64ICpu::default();
     cpu.add_breakpoint(100);
     cpu.add_breakpoint(1000);
     cpu.add_breakpoint(0);
-    assert_eq!(cpu.check_break_points(0), true);
-    assert_eq!(cpu.check_break_points(1), false);
-    assert_eq!(cpu.check_break_points(1000), true);
-    assert_eq!(cpu.check_break_points(100), true);
-    assert_eq!(cpu.check_break_points(10000), false);
+    assert!(cpu.check_break_points(0));
+    assert!(!cpu.check_break_points(1));
+    assert!(cpu.check_break_points(1000));
+    assert!(cpu.check_break_points(100));
+    assert!(!cpu.check_break_points(10000));
+}
+
+#[test]
+fn test_breakpoint_remove_clear_list() {
+    let mut cpu = RV64ICpu::default();
+    let addrs = |cpu: &RV64ICpu| cpu.breakpoints().iter().map(|b| b.addr).collect::<Vec<_>>();
+    assert!(cpu.breakpoints().is_empty());
+    assert!(!cpu.remove_breakpoint(0));
+    for addr in [300, 100, 200, 0] {
+        cpu.add_breakpoint(addr);
+    }
+    // listed sorted by address
+    assert_eq!(addrs(&cpu), [0, 100, 200, 300]);
+    assert!(cpu.remove_breakpoint(100));
+    assert!(!cpu.remove_breakpoint(100));
+    assert!(!cpu.check_break_points(100));
+    assert!(cpu.check_break_points(200));
+    assert_eq!(addrs(&cpu), [0, 200, 300]);
+    assert!(cpu.remove_breakpoint(300));
+    assert!(cpu.remove_breakpoint(0));
+    assert_eq!(addrs(&cpu), [200]);
+    cpu.add_breakpoint(100);
+    cpu.add_watchpoint(Watchpoint {
+        addr: 0x1000,
+        len: 8,
+        kind: WatchKind::Write,
+    });
+    cpu.clear_breakpoints();
+    assert!(cpu.breakpoints().is_empty());
+    assert!(!cpu.check_break_points(200));
+    // watchpoints aren't breakpoints
+    assert_eq!(cpu.watchpoints().len(), 1);
+}
+
+#[test]
+fn test_conditional_breakpoints() {
+    use crate::breakpoint::Condition;
+    let mut cpu = RV64ICpu::default();
+    cpu.set_breakpoint(Breakpoint {
+        condition: Some(Condition::parse("a0 == 2").unwrap()),
+        ..Breakpoint::new(0x100)
+    });
+    cpu.set_breakpoint(Breakpoint {
+        ignore_count: 2,
+        ..Breakpoint::new(0x200)
+    });
+    cpu.set_breakpoint(Breakpoint {
+        temporary: true,
+        ..Breakpoint::new(0x300)
+    });
+    assert!(!cpu.check_break_points(0x100));
+    cpu.regs_w64(10, 2);
+    assert!(cpu.check_break_points(0x100));
+    cpu.breakpoint_mut(0x100).unwrap().enabled = false;
+    assert!(!cpu.check_break_points(0x100));
+    assert_eq!(cpu.breakpoints()[0].hit_count, 1);
+
+    assert!(!cpu.check_break_points(0x200));
+    assert!(!cpu.check_break_points(0x200));
+    assert!(cpu.check_break_points(0x200));
+    assert_eq!(cpu.breakpoints()[1].hit_count, 3);
+
+    assert!(cpu.check_break_points(0x300));
+    assert!(!cpu.check_break_points(0x300));
+    assert_eq!(cpu.breakpoints().len(), 2);
+}
+
+#[test]
+fn test_watchpoints() {
+    let mut cpu = cpu_with_program(
+        0x8000_0000,
+        &[
+            0x00000297, // auipc t0,0
+            0x00500313, // li t1,5
+            0x1062b023, // sd t1,0x100(t0)
+            0x1002b383, // ld t2,0x100(t0)
+            0x10028e93, // addi t4,t0,0x100
+            0x006ebe2f, // amoadd.d t3,t1,(t4)
+            0x10628423, // sb t1,0x108(t0)
+        ],
+    );
+    cpu.bus.write64(0x8000_0100, 0x1234).unwrap();
+    let watch = |addr, len, kind| Watchpoint { addr, len, kind };
+    cpu.add_watchpoint(watch(0x8000_0104, 4, WatchKind::Write));
+    cpu.add_watchpoint(watch(0x8000_0100, 1, WatchKind::Read));
+    cpu.add_watchpoint(watch(0x8000_0108, 1, WatchKind::Access));
+    let hit = |cpu: &mut RV64ICpu| match cpu.exec_continue(10) {
+        ExecEvent::Watchpoint(hit) => (hit.pc, hit.addr, hit.size, hit.write, hit.old, hit.new),
+        _ => panic!("no watchpoint hit"),
+    };
+    // the store overlaps the write watchpoint with its upper half
+    assert_eq!(
+        hit(&mut cpu),
+        (0x8000_0008, 0x8000_0100, 8, true, 0x1234, 5)
+    );
+    assert_eq!(cpu.get_pc(), 0x8000_000c);
+    assert_eq!(hit(&mut cpu), (0x8000_000c, 0x8000_0100, 8, false, 5, 5));
+    // AMO reads and writes
+    assert_eq!(hit(&mut cpu), (0x8000_0014, 0x8000_0100, 8, true, 5, 10));
+    assert!(cpu.remove_watchpoint(&watch(0x8000_0100, 1, WatchKind::Read)));
+    assert!(!cpu.remove_watchpoint(&watch(0x8000_0100, 1, WatchKind::Read)));
+    assert_eq!(hit(&mut cpu), (0x8000_0018, 0x8000_0108, 1, true, 0, 5));
+    assert_eq!(cpu.watchpoints().len(), 2);
+}
+
+#[test]
+fn test_access_faults() {
+    let mut cpu = cpu_with_program(
+        0x8000_0000,
+        &[
+            0x00003503, // ld a0,0(zero)
+            0x00a03023, // sd a0,0(zero)
+            0x00b0352f, // amoadd.d a0,a1,(zero)
+        ],
+    );
+    assert!(cpu.csr_w64(csr::MTVEC, 0x8000_0800));
+    let mut trap = |pc: u64| {
+        cpu.pc_jump(pc);
+        cpu.exec_continue(1);
+        assert_eq!(cpu.get_pc(), 0x8000_0800);
+        assert_eq!(cpu.csr_r64(csr::MEPC), Some(pc));
+        (cpu.csr_r64(csr::MCAUSE), cpu.csr_r64(csr::MTVAL))
+    };
+    assert_eq!(trap(0x8000_0000), (Some(csr::EXC_LOAD_ACCESS), Some(0)));
+    assert_eq!(trap(0x8000_0004), (Some(csr::EXC_STORE_ACCESS), Some(0)));
+    assert_eq!(trap(0x8000_0008), (Some(csr::EXC_STORE_ACCESS), Some(0)));
+    assert_eq!(
+        trap(0x4000_0000),
+        (Some(csr::EXC_INSTR_ACCESS), Some(0x4000_0000))
+    );
+}
+
+#[test]
+fn test_reverse_execution() {
+    let mut cpu = cpu_with_program(
+        0x8000_0000,
+        &[
+            0x00000297, // auipc t0,0
+            0x00500313, // li t1,5
+            0x1062b023, // sd t1,0x100(t0)
+            0x34031073, // csrw mscratch,t1
+            0x00130313, // addi t1,t1,1
+            0x00000073, // ecall
+        ],
+    );
+    cpu.bus.write64(0x8000_0100, 0x1234).unwrap();
+    // nothing is recorded by default
+    cpu.exec_continue(1);
+    assert!(!cpu.step_back());
+    cpu.pc_jump(0x8000_0000);
+    cpu.set_undo_budget(5);
+    cpu.exec_continue(6);
+    assert_eq!(cpu.undo_depth(), 5);
+    assert_eq!(cpu.csr_r64(csr::MCAUSE), Some(csr::EXC_ECALL_M));
+    // the trap is undone
+    assert!(cpu.step_back());
+    assert_eq!(cpu.get_pc(), 0x8000_0014);
+    assert_eq!(cpu.csr_r64(csr::MCAUSE), Some(0));
+    assert!(cpu.step_back());
+    assert_eq!(cpu.regs_r64(6), 5);
+    assert!(cpu.step_back());
+    assert_eq!(cpu.csr_r64(csr::MSCRATCH), Some(0));
+    assert_eq!(cpu.get_num_exec_instr(), 4);
+    cpu.add_breakpoint(0x8000_0004);
+    cpu.add_watchpoint(Watchpoint {
+        addr: 0x8000_0100,
+        len: 8,
+        kind: WatchKind::Write,
+    });
+    match cpu.reverse_continue() {
+        Some(ExecEvent::Watchpoint(hit)) => {
+            assert_eq!(
+                (hit.pc, hit.addr, hit.old, hit.new),
+                (0x8000_0008, 0x8000_0100, 0x1234, 5)
+            )
+        }
+        _ => panic!("no watchpoint hit"),
+    }
+    assert_eq!(cpu.read_virt_u64(0x8000_0100), Some(0x1234));
+    assert!(matches!(
+        cpu.reverse_continue(),
+        Some(ExecEvent::Breakpoint(0x8000_0004))
+    ));
+    assert_eq!(cpu.regs_r64(6), 0);
+    assert_eq!(cpu.breakpoints()[0].hit_count, 0);
+    // the first instruction is out of the budget
+    assert!(cpu.reverse_continue().is_none());
+    assert_eq!(cpu.get_pc(), 0x8000_0004);
+    // executes again
+    cpu.exec_continue(1);
+    assert_eq!(cpu.regs_r64(6), 5);
 }
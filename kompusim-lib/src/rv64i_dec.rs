@@ -44,6 +44,13 @@ pub enum Opcode {
         rs1: u8,
         rd: u8,
     },
+    /// Shift Right Arithmetic Immidiate Word
+    SRAIW {
+        /// shift amount
+        shamt: u8,
+        rs1: u8,
+        rd: u8,
+    },
     Op {
         funct7: u8,
         rs2: u8,
@@ -56,6 +63,14 @@ pub enum Opcode {
         rs1: u8,
         rd: u8,
     },
+    /// The rest of R-type word instructions: ADDW, SLLW, SRLW, SRAW, MULW, DIVW, ...
+    Op32 {
+        funct7: u8,
+        rs2: u8,
+        rs1: u8,
+        funct3: u8,
+        rd: u8,
+    },
     Jal {
         imm21: I21,
         rd: u8,
@@ -96,6 +111,21 @@ pub enum Opcode {
         funct3: u8,
         // rs1 and rd fields are reserved
     },
+    /// Environment Call
+    Ecall,
+    /// Environment Breakpoint
+    Ebreak,
+    /// Return from M-mode trap handler
+    Mret,
+    /// Return from S-mode trap handler
+    Sret,
+    /// Wait For Interrupt
+    Wfi,
+    /// Supervisor memory-management fence
+    SfenceVma {
+        rs2: u8,
+        rs1: u8,
+    },
     Uknown,
 }
 
@@ -131,42 +161,85 @@ pub const F3_BRANCH_BLTU: u8 = 0b110; // Branch Less Than Unsigned
 pub const F3_BRANCH_BGE: u8  = 0b101; // Branch Greater or Equal (Signed)
 pub const F3_BRANCH_BGEU: u8 = 0b111; // Branch Greater or Equal (Unsigned)
 
-pub const F3_SYSTEM_WFI: u8    = 0b000; // Wait For Interrupt
+pub const F3_SYSTEM_PRIV: u8   = 0b000; // ECALL, EBREAK, MRET, SRET, WFI, SFENCE.VMA
 pub const F3_SYSTEM_CSRRW: u8  = 0b001; // atomic CSR read, write
 pub const F3_SYSTEM_CSRRS: u8  = 0b010; // atomic CSR read, set bits
+pub const F3_SYSTEM_CSRRC: u8  = 0b011; // atomic CSR read, clear bits
 pub const F3_SYSTEM_CSRRWI: u8 = 0b101; // atomic CSR read, write immidiate
+pub const F3_SYSTEM_CSRRSI: u8 = 0b110; // atomic CSR read, set bits immidiate
+pub const F3_SYSTEM_CSRRCI: u8 = 0b111; // atomic CSR read, clear bits immidiate
+
+// imm12 field of privileged instructions (funct3 = F3_SYSTEM_PRIV)
+pub const SYSTEM_ECALL: u16  = 0x000;
+pub const SYSTEM_EBREAK: u16 = 0x001;
+pub const SYSTEM_SRET: u16   = 0x102;
+pub const SYSTEM_WFI: u16    = 0x105;
+pub const SYSTEM_MRET: u16   = 0x302;
+pub const F7_SYSTEM_SFENCE_VMA: u16 = 0b_000_1001;
 
 pub const F3_OP_IMM_ADDI: u8  = 0b000;
+pub const F3_OP_IMM_SLTI: u8  = 0b010; // Set Less Than Immediate
 pub const F3_OP_IMM_SLTIU: u8 = 0b011; // Set Less Than Immediate Unsigned
 pub const F3_OP_IMM_XORI: u8  = 0b100;
+pub const F3_OP_IMM_ORI: u8   = 0b110;
 pub const F3_OP_IMM_ANDI: u8  = 0b111;
 pub const F3_OP_IMM_SLLI: u8  = 0b001;
-pub const F3_OP_IMM_SRLI: u8  = 0b101;
+pub const F3_OP_IMM_SRLI: u8  = 0b101; // SRLI and SRAI (imm[10] = 1)
 
 pub const F3_OP_IMM32_ADDIW: u8 = 0b000;
 pub const F3_OP_IMM32_SLLIW: u8 = 0b001; // Shift Left Logical Immediate Word
-pub const F3_OP_IMM32_SRLIW: u8 = 0b101; // Shift Right Logical Immediate Word
+pub const F3_OP_IMM32_SRLIW: u8 = 0b101; // Shift Right Logical (Arithmetic) Immediate Word
 
 pub const F3_OP_ADD_SUB: u8 = 0b_000;
+pub const F3_OP_SLL: u8     = 0b_001;
+pub const F3_OP_SLT: u8     = 0b_010;
+pub const F3_OP_SLTU: u8    = 0b_011;
+pub const F3_OP_XOR: u8     = 0b_100;
+pub const F3_OP_SRL_SRA: u8 = 0b_101;
+pub const F3_OP_OR: u8      = 0b_110;
+pub const F3_OP_AND: u8     = 0b_111;
+
+// funct3 field of M extension instructions (funct7 = F7_OP_MULDIV)
+pub const F3_OP_MUL: u8    = 0b_000;
+pub const F3_OP_MULH: u8   = 0b_001;
+pub const F3_OP_MULHSU: u8 = 0b_010;
+pub const F3_OP_MULHU: u8  = 0b_011;
+pub const F3_OP_DIV: u8    = 0b_100;
+pub const F3_OP_DIVU: u8   = 0b_101;
+pub const F3_OP_REM: u8    = 0b_110;
+pub const F3_OP_REMU: u8   = 0b_111;
 
 pub const F3_OP_LOAD_LB:  u8 = 0b000;
+pub const F3_OP_LOAD_LH:  u8 = 0b001;
 pub const F3_OP_LOAD_LBU: u8 = 0b100;
+pub const F3_OP_LOAD_LHU: u8 = 0b101;
 pub const F3_OP_LOAD_LW:  u8 = 0b010;
 pub const F3_OP_LOAD_LD:  u8 = 0b011;
 pub const F3_OP_LOAD_LWU: u8 = 0b110;
 
 pub const F3_OP_STORE_SB: u8 = 0b000;
+pub const F3_OP_STORE_SH: u8 = 0b001;
 pub const F3_OP_STORE_SW: u8 = 0b010;
 pub const F3_OP_STORE_SD: u8 = 0b011;
 
 // funct7 field of R-type instruction
-pub const F7_OP_ADD: u8 = 0b_000_0000;
-pub const F7_OP_SUB: u8 = 0b_010_0000;
+pub const F7_OP_ADD: u8    = 0b_000_0000;
+pub const F7_OP_SUB: u8    = 0b_010_0000;
+pub const F7_OP_SRA: u8    = 0b_010_0000;
+pub const F7_OP_MULDIV: u8 = 0b_000_0001;
 
 // func5 field of AMO instructions
 pub const F5_OP_AMO_ADD: u8   = 0b_00000;
 pub const F5_OP_AMO_SWAP: u8  = 0b_00001;
-pub const F5_OP_AMO_LRW: u8   = 0b_00010;
+pub const F5_OP_AMO_LRW: u8   = 0b_00010; // LR.W and LR.D
+pub const F5_OP_AMO_SC: u8    = 0b_00011;
+pub const F5_OP_AMO_XOR: u8   = 0b_00100;
+pub const F5_OP_AMO_OR: u8    = 0b_01000;
+pub const F5_OP_AMO_AND: u8   = 0b_01100;
+pub const F5_OP_AMO_MIN: u8   = 0b_10000;
+pub const F5_OP_AMO_MAX: u8   = 0b_10100;
+pub const F5_OP_AMO_MINU: u8  = 0b_11000;
+pub const F5_OP_AMO_MAXU: u8  = 0b_11100;
 
 pub const F3_OP_AMO_WORD: u8  = 0b_010;
 pub const F3_OP_AMO_DWORD: u8 = 0b_011;
@@ -249,6 +322,25 @@ pub fn dec_opc_system(ins: u32) -> Opcode {
     let funct3 = i_funct3(ins);
     let rs1 = i_rs1(ins);
     let csr = i_csr(ins);
+    if funct3 == F3_SYSTEM_PRIV {
+        if csr >> 5 == F7_SYSTEM_SFENCE_VMA && rd == 0 {
+            return Opcode::SfenceVma {
+                rs2: i_rs2(ins),
+                rs1,
+            };
+        }
+        if rs1 != 0 || rd != 0 {
+            return Opcode::Uknown;
+        }
+        return match csr {
+            SYSTEM_ECALL => Opcode::Ecall,
+            SYSTEM_EBREAK => Opcode::Ebreak,
+            SYSTEM_SRET => Opcode::Sret,
+            SYSTEM_MRET => Opcode::Mret,
+            SYSTEM_WFI => Opcode::Wfi,
+            _ => Opcode::Uknown,
+        };
+    }
     Opcode::System {
         csr,
         rs1,
@@ -383,6 +475,11 @@ pub fn decode_instr(instr: u32) -> Opcode {
                         rs1,
                         rd,
                     },
+                    (0b0100000, F3_OP_IMM32_SRLIW) => Opcode::SRAIW {
+                        shamt: instr.bits(24, 20) as u8,
+                        rs1,
+                        rd,
+                    },
                     (_, _) => Opcode::Uknown,
                 }
             }
@@ -396,7 +493,13 @@ pub fn decode_instr(instr: u32) -> Opcode {
             let rd = i_rd(instr);
             match (bits31_25, funct3) {
                 (0b_010_0000, 0b_000) => Opcode::SUBW { rs2, rs1, rd },
-                (_, _) => Opcode::Uknown,
+                (funct7, funct3) => Opcode::Op32 {
+                    funct7: funct7 as u8,
+                    rs2,
+                    rs1,
+                    funct3,
+                    rd,
+                },
             }
         }
         OPC_SYSTEM => dec_opc_system(instr),
@@ -39,6 +39,8 @@ pub fn disasm_operation_name(instr: u32) -> String {
         Opcode::Load { funct3, .. } => match funct3 {
             F3_OP_LOAD_LB => "Load Byte (sign extend)".to_string(),
             F3_OP_LOAD_LBU => "Load Byte Unsigned".to_string(),
+            F3_OP_LOAD_LH => "Load Half-word (sign extend)".to_string(),
+            F3_OP_LOAD_LHU => "Load Half-word Unsigned".to_string(),
             F3_OP_LOAD_LW => "Load Word (sign extend)".to_string(),
             F3_OP_LOAD_LWU => "Load Word Unsigned".to_string(),
             F3_OP_LOAD_LD => "Load Double Word".to_string(),
@@ -47,35 +49,44 @@ pub fn disasm_operation_name(instr: u32) -> String {
 
         Opcode::Store { funct3, .. } => match funct3 {
             F3_OP_STORE_SB => "Store Byte".to_string(),
+            F3_OP_STORE_SH => "Store Half-word".to_string(),
             F3_OP_STORE_SW => "Store Word".to_string(),
             F3_OP_STORE_SD => "Store Double Word".to_string(),
             _ => "Unknown STORE opcode".to_string(),
         },
 
-        Opcode::OpImm { funct3, .. } => match funct3 {
+        Opcode::OpImm { imm12, funct3, .. } => match funct3 {
             F3_OP_IMM_ADDI => "ADD Immediate".to_string(),
+            F3_OP_IMM_SLTI => "Set Less Than Immediate".to_string(),
             F3_OP_IMM_SLTIU => "Set Less Than Immediate Unsigned".to_string(),
             F3_OP_IMM_XORI => "XOR Immediate".to_string(),
+            F3_OP_IMM_ORI => "OR Immediate".to_string(),
             F3_OP_IMM_ANDI => "AND Immediate".to_string(),
             F3_OP_IMM_SLLI => "Shift Left Logical Immediate".to_string(),
+            F3_OP_IMM_SRLI if imm12.0.bit(10) => "Shift Right Arithmetic Immediate".to_string(),
+            F3_OP_IMM_SRLI => "Shift Right Logical Immediate".to_string(),
             _ => "Unknown OP-IMM opcode".to_string(),
         },
 
         Opcode::ADDIW { .. } => "ADD Word Immediate".to_string(),
         Opcode::SLLIW { .. } => "Shift Left Logical Immediate Word".to_string(),
         Opcode::SRLIW { .. } => "Shift Right Logical Immediate Word".to_string(),
-        Opcode::Op { funct7, funct3, .. } => match (funct7, funct3) {
-            (F7_OP_ADD, F3_OP_ADD_SUB) => "Add register to register".to_string(),
-            (F7_OP_SUB, F3_OP_ADD_SUB) => "Subtract register from regiser".to_string(),
-            _ => format!("Unknown OP instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
+        Opcode::SRAIW { .. } => "Shift Right Arithmetic Immediate Word".to_string(),
+        Opcode::Op { funct7, funct3, .. } => match op_names(funct7, funct3) {
+            Some((_, name)) => name.to_string(),
+            None => format!("Unknown OP instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
         },
 
         Opcode::SUBW { .. } => "Subtract Word".to_string(),
 
-        Opcode::Amo { funct5, funct3, .. } => match (funct5, funct3) {
-            (F5_OP_AMO_SWAP, F3_OP_AMO_WORD) => "Atomic swap".to_string(),
-            (F5_OP_AMO_ADD, F3_OP_AMO_WORD) => "Atomic Add".to_string(),
-            (F5_OP_AMO_LRW, F3_OP_AMO_WORD) => "Load Reserve Word".to_string(),
+        Opcode::Op32 { funct7, funct3, .. } => match op32_names(funct7, funct3) {
+            Some((_, name)) => format!("{name} Word"),
+            None => format!("Unknown OP-32 instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
+        },
+
+        Opcode::Amo { funct5, funct3, .. } => match (amo_names(funct5), funct3) {
+            (Some((_, name)), F3_OP_AMO_WORD) => format!("{name} Word"),
+            (Some((_, name)), F3_OP_AMO_DWORD) => format!("{name} Double Word"),
             _ => format!("Unknown AMO instruction: funct5: {funct5:x}, funct3: {funct3:x}"),
         },
 
@@ -86,13 +97,24 @@ pub fn disasm_operation_name(instr: u32) -> String {
         },
 
         Opcode::System { funct3, .. } => match funct3 {
-            F3_SYSTEM_WFI => "Wait For Interrupt".to_string(),
             F3_SYSTEM_CSRRS => "Control Status Register - Read, Set bitmask".to_string(),
+            F3_SYSTEM_CSRRC => "Control Status Register - Read, Clear bitmask".to_string(),
             F3_SYSTEM_CSRRWI => "Control Status Register - Read, Write Immediate".to_string(),
+            F3_SYSTEM_CSRRSI => "Control Status Register - Read, Set bitmask Immediate".to_string(),
+            F3_SYSTEM_CSRRCI => {
+                "Control Status Register - Read, Clear bitmask Immediate".to_string()
+            }
             F3_SYSTEM_CSRRW => "Control Status Register - Read, Write".to_string(),
             _ => "Unknown SYSTEM opcode".to_string(),
         },
 
+        Opcode::Ecall => "Environment Call".to_string(),
+        Opcode::Ebreak => "Environment Breakpoint".to_string(),
+        Opcode::Mret => "Return from Machine mode trap".to_string(),
+        Opcode::Sret => "Return from Supervisor mode trap".to_string(),
+        Opcode::Wfi => "Wait For Interrupt".to_string(),
+        Opcode::SfenceVma { .. } => "Supervisor Fence Virtual Memory".to_string(),
+
         Opcode::Uknown => "Unknown Operation".to_string(),
     }
 }
@@ -154,6 +176,12 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
             F3_OP_LOAD_LBU => {
                 format!("x{rd}[7:0] = m8[x{rs1} {:+})]; z-ext", imm12.0)
             }
+            F3_OP_LOAD_LH => {
+                format!("x{rd}[15:0] = m16[x{rs1} {:+}]; s-ext", imm12.0)
+            }
+            F3_OP_LOAD_LHU => {
+                format!("x{rd}[15:0] = m16[x{rs1} {:+}]; z-ext", imm12.0)
+            }
             F3_OP_LOAD_LW => {
                 format!("x{rd}[31:0] = m32[x{rs1} {:+}]; s-ext", imm12.0)
             }
@@ -171,6 +199,7 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
             funct3,
         } => match funct3 {
             F3_OP_STORE_SB => format!("m8[x{rs1} {:+}] = x{rs2}[7:0]", imm12.0),
+            F3_OP_STORE_SH => format!("m16[x{rs1} {:+}] = x{rs2}[15:0]", imm12.0),
             F3_OP_STORE_SW => format!("m32[x{rs1} {:+}] = x{rs2}[31:0]", imm12.0),
             F3_OP_STORE_SD => format!("m64[x{rs1} {:+}] = x{rs2}", imm12.0),
             _ => "Unknown STORE opcode".to_string(),
@@ -183,10 +212,17 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
             rd,
         } => match funct3 {
             F3_OP_IMM_ADDI => format!("x{rd} = x{rs1} {:+}", imm12.0),
-            F3_OP_IMM_SLTIU => format!("If x{rs1} < {:+} then x{rd} = 1 else x{rd} = 0", imm12.0),
+            F3_OP_IMM_SLTI | F3_OP_IMM_SLTIU => {
+                format!("If x{rs1} < {:+} then x{rd} = 1 else x{rd} = 0", imm12.0)
+            }
             F3_OP_IMM_XORI => format!("x{rd} = x{rs1} ^ 0x{imm12:x}"),
+            F3_OP_IMM_ORI => format!("x{rd} = x{rs1} | 0x{imm12:x}"),
             F3_OP_IMM_ANDI => format!("x{rd} = x{rs1} & 0x{imm12:x}"),
             F3_OP_IMM_SLLI => format!("x{rd} = x{rs1} << {imm12}"),
+            F3_OP_IMM_SRLI if imm12.0.bit(10) => {
+                format!("x{rd} = x{rs1} >> {}; arithmetic", imm12.0.bits(5, 0))
+            }
+            F3_OP_IMM_SRLI => format!("x{rd} = x{rs1} >> {imm12}"),
             _ => "Unknown OP-IMM opcode".to_string(),
         },
 
@@ -197,15 +233,17 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
         Opcode::SRLIW { shamt, rs1, rd } => {
             format!("x{rd}[31:0] = x{rs1}[31:0] >> {shamt}; sign extend")
         }
+        Opcode::SRAIW { shamt, rs1, rd } => {
+            format!("x{rd}[31:0] = x{rs1}[31:0] >> {shamt}; arithmetic; sign extend")
+        }
         Opcode::Op {
             funct7,
             rs2,
             rs1,
             funct3,
             rd,
-        } => match (funct7, funct3) {
-            (F7_OP_ADD, F3_OP_ADD_SUB) => format!("x{rd} = x{rs1} + x{rs2}"),
-            (F7_OP_SUB, F3_OP_ADD_SUB) => format!("x{rd} = x{rs1} - x{rs2}"),
+        } => match op_names(funct7, funct3) {
+            Some((mnemonic, _)) => format!("x{rd} = {} x{rs2}", op_expr(mnemonic, rs1)),
             _ => format!("Unknown OP instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
         },
 
@@ -213,6 +251,20 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
             format!("x{rd}[31:0] = x{rs1}[31:0] - x{rs2}[31:0]; s-ext")
         }
 
+        Opcode::Op32 {
+            funct7,
+            rs2,
+            rs1,
+            funct3,
+            rd,
+        } => match op32_names(funct7, funct3) {
+            Some((mnemonic, _)) => format!(
+                "x{rd}[31:0] = {} x{rs2}[31:0]; s-ext",
+                op_expr(mnemonic.trim_end_matches('w'), rs1)
+            ),
+            _ => format!("Unknown OP-32 instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
+        },
+
         Opcode::Amo {
             funct5,
             aq,
@@ -236,7 +288,19 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
                     if rl { "; release" } else { "" }
                 )
             }
-            (F5_OP_AMO_LRW, F3_OP_AMO_WORD) => format!("x{rd} = m32[x{rs1}] ; todo"),
+            (F5_OP_AMO_LRW, F3_OP_AMO_WORD) => format!("x{rd} = m32[x{rs1}]; reserve"),
+            (F5_OP_AMO_LRW, F3_OP_AMO_DWORD) => format!("x{rd} = m64[x{rs1}]; reserve"),
+            (F5_OP_AMO_SC, F3_OP_AMO_WORD | F3_OP_AMO_DWORD) => format!(
+                "if reserved then m{0}[x{rs1}] = x{rs2}; x{rd} = 0 else x{rd} = 1",
+                if funct3 == F3_OP_AMO_WORD { 32 } else { 64 }
+            ),
+            (funct5, F3_OP_AMO_WORD | F3_OP_AMO_DWORD) if amo_names(funct5).is_some() => {
+                format!(
+                    "x{rd} <= m{0}[x{rs1}]; m{0}[x{rs1}] <= {1}(x{rd}, x{rs2})",
+                    if funct3 == F3_OP_AMO_WORD { 32 } else { 64 },
+                    amo_names(funct5).unwrap().0.trim_start_matches("amo")
+                )
+            }
             _ => format!("Unknown AMO instruction: funct5: {funct5:x}, funct3: {funct3:x}"),
         },
 
@@ -246,16 +310,34 @@ pub fn disasm_pseudo_code(instr: u32) -> String {
             funct3,
             rd,
         } => match funct3 {
-            F3_SYSTEM_WFI => "no effect".to_string(),
             F3_SYSTEM_CSRRS => format!(
                 "x{rd} = {csrn}; {csrn} = {csrn} | x{rs1:b}",
                 csrn = csr_name(csr)
             ),
+            F3_SYSTEM_CSRRC => format!(
+                "x{rd} = {csrn}; {csrn} = {csrn} & ~x{rs1}",
+                csrn = csr_name(csr)
+            ),
             F3_SYSTEM_CSRRWI => format!("x{rd} = {csrn}; {csrn} = 0x{rs1:x}", csrn = csr_name(csr)),
+            F3_SYSTEM_CSRRSI => format!(
+                "x{rd} = {csrn}; {csrn} = {csrn} | 0x{rs1:x}",
+                csrn = csr_name(csr)
+            ),
+            F3_SYSTEM_CSRRCI => format!(
+                "x{rd} = {csrn}; {csrn} = {csrn} & ~0x{rs1:x}",
+                csrn = csr_name(csr)
+            ),
             F3_SYSTEM_CSRRW => format!("x{rd} = {csrn}; {csrn} = x{rs1}", csrn = csr_name(csr)),
             _ => "Unknown SYSTEM opcode".to_string(),
         },
 
+        Opcode::Ecall => "raise environment call exception".to_string(),
+        Opcode::Ebreak => "raise breakpoint exception".to_string(),
+        Opcode::Mret => "PC = mepc; mode = mstatus.MPP".to_string(),
+        Opcode::Sret => "PC = sepc; mode = sstatus.SPP".to_string(),
+        Opcode::Wfi => "no effect".to_string(),
+        Opcode::SfenceVma { .. } => "no effect".to_string(),
+
         Opcode::Fence { imm12, funct3 } => match funct3 {
             F3_OP_FENCE => format!(
                 "fence: FM:0b_{:b}, PI:{}, PO:{}, PR:{}, PW:{}, SI:{}, SO:{}, SR:{}, SW:{}",
@@ -294,18 +376,25 @@ pub fn disasm_get_used_regs(instr: u32) -> (Option<u8>, Option<u8>, Option<u8>)
         Opcode::ADDIW { rs1, rd, .. } => (Some(rs1), None, Some(rd)),
         Opcode::SLLIW { rs1, rd, .. } => (Some(rs1), None, Some(rd)),
         Opcode::SRLIW { rs1, rd, .. } => (Some(rs1), None, Some(rd)),
+        Opcode::SRAIW { rs1, rd, .. } => (Some(rs1), None, Some(rd)),
         Opcode::SUBW { rs2, rs1, rd } => (Some(rs1), Some(rs2), Some(rd)),
+        Opcode::Op32 { rs2, rs1, rd, .. } => (Some(rs1), Some(rs2), Some(rd)),
         Opcode::Op { rs2, rs1, rd, .. } => (Some(rs1), Some(rs2), Some(rd)),
         Opcode::Amo { rs2, rs1, rd, .. } => (Some(rs1), Some(rs2), Some(rd)),
         Opcode::System {
             rs1, rd, funct3, ..
         } => {
-            if funct3 == F3_SYSTEM_WFI {
-                (None, None, None)
+            if funct3 >= F3_SYSTEM_CSRRWI {
+                // rs1 field is immediate
+                (None, None, Some(rd))
             } else {
                 (Some(rs1), None, Some(rd))
             }
         }
+        Opcode::SfenceVma { rs2, rs1 } => (Some(rs1), Some(rs2), None),
+        Opcode::Ecall | Opcode::Ebreak | Opcode::Mret | Opcode::Sret | Opcode::Wfi => {
+            (None, None, None)
+        }
         Opcode::Fence { .. } => (None, None, None),
         Opcode::Uknown => (None, None, None),
     }
@@ -360,6 +449,8 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
         } => match funct3 {
             F3_OP_LOAD_LB => format!("lb x{rd}, {imm12}(x{rs1})"),
             F3_OP_LOAD_LBU => format!("lbu x{rd}, {imm12}(x{rs1})"),
+            F3_OP_LOAD_LH => format!("lh x{rd}, {imm12}(x{rs1})"),
+            F3_OP_LOAD_LHU => format!("lhu x{rd}, {imm12}(x{rs1})"),
             F3_OP_LOAD_LW => format!("lw x{rd}, {imm12}(x{rs1})"),
             F3_OP_LOAD_LWU => format!("lwu x{rd}, {imm12}(x{rs1})"),
             F3_OP_LOAD_LD => format!("ld x{rd}, {imm12}(x{rs1})"),
@@ -373,6 +464,7 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
             funct3,
         } => match funct3 {
             F3_OP_STORE_SB => format!("sb x{rs2}, {imm12}(x{rs1})"),
+            F3_OP_STORE_SH => format!("sh x{rs2}, {imm12}(x{rs1})"),
             F3_OP_STORE_SW => format!("sw x{rs2}, {imm12}(x{rs1})"),
             F3_OP_STORE_SD => format!("sd x{rs2}, {imm12}(x{rs1})"),
             _ => "Unknown STORE opcode".to_string(),
@@ -385,10 +477,16 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
             rd,
         } => match funct3 {
             F3_OP_IMM_ADDI => format!("addi x{rd}, x{rs1}, 0x{imm12:x}"),
+            F3_OP_IMM_SLTI => format!("slti x{rd}, x{rs1}, {imm12}"),
             F3_OP_IMM_SLTIU => format!("sltiu x{rd}, x{rs1}, {imm12}"),
             F3_OP_IMM_XORI => format!("xori x{rd}, x{rs1}, {imm12}"),
+            F3_OP_IMM_ORI => format!("ori x{rd}, x{rs1}, {imm12}"),
             F3_OP_IMM_ANDI => format!("andi x{rd}, x{rs1}, {imm12}"),
             F3_OP_IMM_SLLI => format!("slli x{rd}, x{rs1}, 0x{imm12:x}"),
+            F3_OP_IMM_SRLI if imm12.0.bit(10) => {
+                format!("srai x{rd}, x{rs1}, 0x{:x}", imm12.0.bits(5, 0))
+            }
+            F3_OP_IMM_SRLI => format!("srli x{rd}, x{rs1}, 0x{imm12:x}"),
             _ => "Unknown OP-IMM opcode".to_string(),
         },
 
@@ -396,20 +494,31 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
 
         Opcode::SLLIW { shamt, rs1, rd } => format!("slliw x{rd}, x{rs1}, 0x{shamt:x}"),
         Opcode::SRLIW { shamt, rs1, rd } => format!("srliw x{rd}, x{rs1}, 0x{shamt:x}"),
+        Opcode::SRAIW { shamt, rs1, rd } => format!("sraiw x{rd}, x{rs1}, 0x{shamt:x}"),
         Opcode::Op {
             funct7,
             rs2,
             rs1,
             funct3,
             rd,
-        } => match (funct7, funct3) {
-            (F7_OP_ADD, F3_OP_ADD_SUB) => format!("add x{rd}, x{rs1}, x{rs2}"),
-            (F7_OP_SUB, F3_OP_ADD_SUB) => format!("sub x{rd}, x{rs1}, x{rs2}"),
-            _ => format!("Unknown OP instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
+        } => match op_names(funct7, funct3) {
+            Some((mnemonic, _)) => format!("{mnemonic} x{rd}, x{rs1}, x{rs2}"),
+            None => format!("Unknown OP instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
         },
 
         Opcode::SUBW { rs2, rs1, rd } => format!("subw x{rd}, x{rs1}, x{rs2}"),
 
+        Opcode::Op32 {
+            funct7,
+            rs2,
+            rs1,
+            funct3,
+            rd,
+        } => match op32_names(funct7, funct3) {
+            Some((mnemonic, _)) => format!("{mnemonic} x{rd}, x{rs1}, x{rs2}"),
+            None => format!("Unknown OP-32 instruction: funct7: {funct7:x}, funct3: {funct3:x}"),
+        },
+
         Opcode::Amo {
             funct5,
             aq,
@@ -434,6 +543,16 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
                 )
             }
             (F5_OP_AMO_LRW, F3_OP_AMO_WORD) => format!("lr.w x{rd}, (x{rs1})"),
+            (F5_OP_AMO_LRW, F3_OP_AMO_DWORD) => format!("lr.d x{rd}, (x{rs1})"),
+            (funct5, F3_OP_AMO_WORD | F3_OP_AMO_DWORD) if amo_names(funct5).is_some() => {
+                format!(
+                    "{}.{}{}{} x{rd}, x{rs2}, (x{rs1})",
+                    amo_names(funct5).unwrap().0,
+                    if funct3 == F3_OP_AMO_WORD { "w" } else { "d" },
+                    if aq { ".aq" } else { "" },
+                    if rl { ".rl" } else { "" }
+                )
+            }
             _ => format!("Uknown AMO instruction: funct5: {funct5:x}, funct3: {funct3:x}"),
         },
 
@@ -443,13 +562,22 @@ pub fn disasm(instr: u32, instr_addr: u64) -> String {
             funct3,
             rd,
         } => match funct3 {
-            F3_SYSTEM_WFI => "wfi".to_string(),
             F3_SYSTEM_CSRRS => format!("csrrs x{rd}, {}, x{rs1}", csr_name(csr)),
+            F3_SYSTEM_CSRRC => format!("csrrc x{rd}, {}, x{rs1}", csr_name(csr)),
             F3_SYSTEM_CSRRWI => format!("csrrwi x{rd}, {}, {rs1:x}", csr_name(csr)),
+            F3_SYSTEM_CSRRSI => format!("csrrsi x{rd}, {}, {rs1:x}", csr_name(csr)),
+            F3_SYSTEM_CSRRCI => format!("csrrci x{rd}, {}, {rs1:x}", csr_name(csr)),
             F3_SYSTEM_CSRRW => format!("csrrw x{rd}, {}, x{rs1}", csr_name(csr)),
             _ => "Unknown SYSTEM opcode".to_string(),
         },
 
+        Opcode::Ecall => "ecall".to_string(),
+        Opcode::Ebreak => "ebreak".to_string(),
+        Opcode::Mret => "mret".to_string(),
+        Opcode::Sret => "sret".to_string(),
+        Opcode::Wfi => "wfi".to_string(),
+        Opcode::SfenceVma { rs2, rs1 } => format!("sfence.vma x{rs1}, x{rs2}"),
+
         Opcode::Fence { imm12, funct3 } => match funct3 {
             F3_OP_FENCE => format!(
                 "fence{} {}{}{}{}, {}{}{}{}",
@@ -514,11 +642,124 @@ pub fn reg_idx2abi(r: u8) -> &'static str {
     }
 }
 
+/// Mnemonic and operation name of OP (R-type) instructions
+fn op_names(funct7: u8, funct3: u8) -> Option<(&'static str, &'static str)> {
+    Some(match (funct7, funct3) {
+        (F7_OP_ADD, F3_OP_ADD_SUB) => ("add", "Add register to register"),
+        (F7_OP_SUB, F3_OP_ADD_SUB) => ("sub", "Subtract register from regiser"),
+        (0, F3_OP_SLL) => ("sll", "Shift Left Logical"),
+        (0, F3_OP_SLT) => ("slt", "Set Less Than"),
+        (0, F3_OP_SLTU) => ("sltu", "Set Less Than Unsigned"),
+        (0, F3_OP_XOR) => ("xor", "XOR registers"),
+        (0, F3_OP_SRL_SRA) => ("srl", "Shift Right Logical"),
+        (F7_OP_SRA, F3_OP_SRL_SRA) => ("sra", "Shift Right Arithmetic"),
+        (0, F3_OP_OR) => ("or", "OR registers"),
+        (0, F3_OP_AND) => ("and", "AND registers"),
+        (F7_OP_MULDIV, F3_OP_MUL) => ("mul", "Multiply"),
+        (F7_OP_MULDIV, F3_OP_MULH) => ("mulh", "Multiply High (signed x signed)"),
+        (F7_OP_MULDIV, F3_OP_MULHSU) => ("mulhsu", "Multiply High (signed x unsigned)"),
+        (F7_OP_MULDIV, F3_OP_MULHU) => ("mulhu", "Multiply High (unsigned x unsigned)"),
+        (F7_OP_MULDIV, F3_OP_DIV) => ("div", "Divide (signed)"),
+        (F7_OP_MULDIV, F3_OP_DIVU) => ("divu", "Divide Unsigned"),
+        (F7_OP_MULDIV, F3_OP_REM) => ("rem", "Remainder (signed)"),
+        (F7_OP_MULDIV, F3_OP_REMU) => ("remu", "Remainder Unsigned"),
+        _ => return None,
+    })
+}
+
+/// Mnemonic and operation name of OP-32 instructions (except SUBW)
+fn op32_names(funct7: u8, funct3: u8) -> Option<(&'static str, &'static str)> {
+    Some(match (funct7, funct3) {
+        (F7_OP_ADD, F3_OP_ADD_SUB) => ("addw", "Add"),
+        (0, F3_OP_SLL) => ("sllw", "Shift Left Logical"),
+        (0, F3_OP_SRL_SRA) => ("srlw", "Shift Right Logical"),
+        (F7_OP_SRA, F3_OP_SRL_SRA) => ("sraw", "Shift Right Arithmetic"),
+        (F7_OP_MULDIV, F3_OP_MUL) => ("mulw", "Multiply"),
+        (F7_OP_MULDIV, F3_OP_DIV) => ("divw", "Divide (signed)"),
+        (F7_OP_MULDIV, F3_OP_DIVU) => ("divuw", "Divide Unsigned"),
+        (F7_OP_MULDIV, F3_OP_REM) => ("remw", "Remainder (signed)"),
+        (F7_OP_MULDIV, F3_OP_REMU) => ("remuw", "Remainder Unsigned"),
+        _ => return None,
+    })
+}
+
+/// Left part of the pseudo code expression "x{rs1} <operator>" for OP mnemonic
+fn op_expr(mnemonic: &str, rs1: u8) -> String {
+    let operator = match mnemonic {
+        "add" => "+",
+        "sub" => "-",
+        "sll" => "<<",
+        "slt" | "sltu" => "<",
+        "xor" => "^",
+        "srl" | "sra" => ">>",
+        "or" => "|",
+        "and" => "&",
+        "mul" => "*",
+        "div" | "divu" => "/",
+        "rem" | "remu" => "%",
+        // mulh, mulhsu, mulhu
+        _ => return format!("(x{rs1} *"),
+    };
+    format!("x{rs1} {operator}")
+}
+
+/// Mnemonic and operation name of AMO instructions (except LR and SC)
+fn amo_names(funct5: u8) -> Option<(&'static str, &'static str)> {
+    Some(match funct5 {
+        F5_OP_AMO_SWAP => ("amoswap", "Atomic swap"),
+        F5_OP_AMO_ADD => ("amoadd", "Atomic Add"),
+        F5_OP_AMO_XOR => ("amoxor", "Atomic XOR"),
+        F5_OP_AMO_AND => ("amoand", "Atomic AND"),
+        F5_OP_AMO_OR => ("amoor", "Atomic OR"),
+        F5_OP_AMO_MIN => ("amomin", "Atomic Minimum"),
+        F5_OP_AMO_MAX => ("amomax", "Atomic Maximum"),
+        F5_OP_AMO_MINU => ("amominu", "Atomic Minimum Unsigned"),
+        F5_OP_AMO_MAXU => ("amomaxu", "Atomic Maximum Unsigned"),
+        F5_OP_AMO_LRW => ("lr", "Load Reserved"),
+        F5_OP_AMO_SC => ("sc", "Store Conditional"),
+        _ => return None,
+    })
+}
+
 pub fn csr_name(csr: u16) -> &'static str {
     match csr {
-        csr::MTVEC => "mtvec",
+        csr::CYCLE => "cycle",
+        csr::TIME => "time",
+        csr::INSTRET => "instret",
+        csr::SSTATUS => "sstatus",
+        csr::SIE => "sie",
+        csr::STVEC => "stvec",
+        csr::SCOUNTEREN => "scounteren",
+        csr::SENVCFG => "senvcfg",
+        csr::SSCRATCH => "sscratch",
+        csr::SEPC => "sepc",
+        csr::SCAUSE => "scause",
+        csr::STVAL => "stval",
+        csr::SIP => "sip",
+        csr::SATP => "satp",
+        csr::MVENDORID => "mvendorid",
+        csr::MARCHID => "marchid",
+        csr::MIMPID => "mimpid",
         csr::MHARTID => "mhartid",
+        csr::MCONFIGPTR => "mconfigptr",
+        csr::MSTATUS => "mstatus",
+        csr::MISA => "misa",
+        csr::MEDELEG => "medeleg",
+        csr::MIDELEG => "mideleg",
+        csr::MIE => "mie",
+        csr::MTVEC => "mtvec",
+        csr::MCOUNTEREN => "mcounteren",
+        csr::MENVCFG => "menvcfg",
+        csr::MCOUNTINHIBIT => "mcountinhibit",
         csr::MSCRATCH => "mscratch",
+        csr::MEPC => "mepc",
+        csr::MCAUSE => "mcause",
+        csr::MTVAL => "mtval",
+        csr::MIP => "mip",
+        csr::PMPCFG0 => "pmpcfg0",
+        csr::PMPADDR0 => "pmpaddr0",
+        csr::MCYCLE => "mcycle",
+        csr::MINSTRET => "minstret",
         _ => "UKNOWN",
     }
 }
@@ -644,4 +885,15 @@ fn test_disasm() {
     assert_eq!(disasm(0x_f0f6c713, 0x0), "xori x14, x13, -241");
     assert_eq!(disasm(0x_70f6_f713, 0x0), "andi x14, x13, 1807");
     assert_eq!(disasm(0x_1050_0073, 0x0), "wfi");
+    assert_eq!(disasm(0x_02b5_0533, 0x0), "mul x10, x10, x11");
+    assert_eq!(disasm(0x_02b5_453b, 0x0), "divw x10, x10, x11");
+    assert_eq!(disasm(0x_40b5_5533, 0x0), "sra x10, x10, x11");
+    assert_eq!(disasm(0x_4035_5513, 0x0), "srai x10, x10, 0x3");
+    assert_eq!(disasm(0x_4035_551b, 0x0), "sraiw x10, x10, 0x3");
+    assert_eq!(disasm(0x_00a5_9023, 0x0), "sh x10, 0(x11)");
+    assert_eq!(disasm(0x_40b5_b52f, 0x0), "amoor.d x10, x11, (x11)");
+    assert_eq!(disasm(0x_18b5_252f, 0x0), "sc.w x10, x11, (x10)");
+    assert_eq!(disasm(0x_3020_0073, 0x0), "mret");
+    assert_eq!(disasm(0x_0000_0073, 0x0), "ecall");
+    assert_eq!(disasm(0x_3000_b573, 0x0), "csrrc x10, mstatus, x1");
 }
@@ -29,6 +29,11 @@ pub enum COpcode {
         shamt6: u8,
         rd: u8,
     },
+    /// Shift Right Arithmetic Immidiate
+    CSRAI {
+        shamt6: u8,
+        rd: u8,
+    },
     CLI {
         imm6: I6,
         rd: u8,
@@ -36,6 +41,10 @@ pub enum COpcode {
     CJR {
         rs1: u8,
     },
+    CJALR {
+        rs1: u8,
+    },
+    CEBREAK,
     CADD {
         rd: u8,
         rs2: u8,
@@ -52,6 +61,14 @@ pub enum COpcode {
         imm6: I6,
         rd: u8,
     },
+    CSUB {
+        rd: u8,
+        rs2: u8,
+    },
+    CXOR {
+        rd: u8,
+        rs2: u8,
+    },
     COR {
         rd: u8,
         rs2: u8,
@@ -79,6 +96,14 @@ pub enum COpcode {
         uimm6: u8,
         rd: u8,
     },
+    SWSP {
+        uimm6: u8,
+        rs2: u8,
+    },
+    LWSP {
+        uimm6: u8,
+        rd: u8,
+    },
     ADDI4SPN {
         uimm8: u8,
         rd: u8,
@@ -138,6 +163,8 @@ pub const OPC_C_SLLI: u8 =                  0b_000_10; // shift logical left imm
 pub const OPC_C_JR_MV_EBREAK_JALR_ADD: u8 = 0b_100_10;
 pub const OPC_C_SDSP: u8 =                  0b_111_10; // Store (in memory) Dword by Stack Pointer
 pub const OPC_C_LDSP: u8 =                  0b_011_10; // Load (from memory) Dword by Stack Pointer
+pub const OPC_C_SWSP: u8 =                  0b_110_10; // Store (in memory) Word by Stack Pointer
+pub const OPC_C_LWSP: u8 =                  0b_010_10; // Load (from memory) Word by Stack Pointer
 }
 use c_opcodes::*;
 
@@ -246,6 +273,7 @@ pub fn rv64c_decode_instr(c_instr: u16) -> COpcode {
             imm6: c_i_imm6(c_instr),
             rd: c_i_rd(c_instr),
         },
+        OPC_C_LI => COpcode::Hint,
         OPC_C_LUI_ADDI16SP => {
             let imm6 = c_i_imm6(c_instr);
             match (rd, imm6.0) {
@@ -291,7 +319,10 @@ pub fn rv64c_decode_instr(c_instr: u16) -> COpcode {
                 (0, 0, _) => COpcode::Hint,
                 (0, rs1, 0) => COpcode::CJR { rs1 },
                 (0, rd, rs2) => COpcode::MV { rd, rs2 },
-                (1, rd, rs2) if rd != 0 && rs2 != 0 => COpcode::CADD { rd, rs2 },
+                (1, 0, 0) => COpcode::CEBREAK,
+                (1, rs1, 0) => COpcode::CJALR { rs1 },
+                (1, 0, _) => COpcode::Hint,
+                (1, rd, rs2) => COpcode::CADD { rd, rs2 },
                 _ => COpcode::Uknown,
             }
         }
@@ -315,6 +346,26 @@ pub fn rv64c_decode_instr(c_instr: u16) -> COpcode {
                 COpcode::Reserved
             }
         }
+        OPC_C_SWSP => {
+            let uimm6 = c_instr.bits(8, 7) << 4 | c_instr.bits(12, 9);
+            let rs2 = c_i_rs2(c_instr);
+            COpcode::SWSP {
+                uimm6: uimm6 as u8,
+                rs2,
+            }
+        }
+        OPC_C_LWSP => {
+            if rd != 0 {
+                let uimm6 =
+                    c_instr.bits(3, 2) << 4 | c_instr.bits(12, 12) << 3 | c_instr.bits(6, 4);
+                COpcode::LWSP {
+                    uimm6: uimm6 as u8,
+                    rd,
+                }
+            } else {
+                COpcode::Reserved
+            }
+        }
         OPC_C_ADDI4SPN => {
             let nz_uimm8 = c_instr.bits(10, 7) << 4
                 | c_instr.bits(12, 11) << 2
@@ -382,16 +433,17 @@ pub fn rv64c_decode_instr(c_instr: u16) -> COpcode {
             let bits6_5 = c_instr.bits(6, 5);
             let rd = c_instr.bits(9, 7) as u8 + 8;
             let rs2 = c_instr.bits(4, 2) as u8 + 8;
+            let shamt6 = (bit12 << 5 | c_instr.bits(6, 2)) as u8;
             match (bit12, bits11_10, bits6_5) {
-                (0b_0, 0b_00, _) => COpcode::Hint,
-                (bit12, 0b_00, _) => COpcode::CSRLI {
-                    shamt6: (bit12 << 5 | c_instr.bits(6, 2)) as u8,
-                    rd,
-                },
+                (_, 0b_00 | 0b_01, _) if shamt6 == 0 => COpcode::Hint,
+                (_, 0b_00, _) => COpcode::CSRLI { shamt6, rd },
+                (_, 0b_01, _) => COpcode::CSRAI { shamt6, rd },
                 (imm5, 0b_10, _) => COpcode::CANDI {
                     imm6: I6::from(imm5 << 5 | c_instr.bits(6, 2)),
                     rd,
                 },
+                (0b_0, 0b_11, 0b_00) => COpcode::CSUB { rd, rs2 },
+                (0b_0, 0b_11, 0b_01) => COpcode::CXOR { rd, rs2 },
                 (0b_0, 0b_11, 0b_10) => COpcode::COR { rd, rs2 },
                 (0b_0, 0b_11, 0b_11) => COpcode::CAND { rd, rs2 },
                 (0b_1, 0b_11, 0b_00) => COpcode::CSUBW { rd, rs2 },
@@ -11,11 +11,16 @@ pub fn disasm_rvc_operation_name(instr: u16) -> String {
         COpcode::ADDI16SP { .. } => "Add Immediate to Stack Pointer (x2)".to_string(),
         COpcode::CSLLI { .. } => "Compressed Shift Left Logical Immediate".to_string(),
         COpcode::CSRLI { .. } => "Compressed Shift Right Logical Immediate".to_string(),
+        COpcode::CSRAI { .. } => "Compressed Shift Right Arithmetic Immediate".to_string(),
         COpcode::CLI { .. } => "Compressed Load Immediate".to_string(),
         COpcode::CJR { .. } => "Compressed Jump Register".to_string(),
+        COpcode::CJALR { .. } => "Compressed Jump And Link Register".to_string(),
+        COpcode::CEBREAK => "Compressed Environment Breakpoint".to_string(),
         COpcode::CADD { .. } => "Compressed Add".to_string(),
         COpcode::CADDW { .. } => "Compressed Add Word".to_string(),
         COpcode::CSUBW { .. } => "Compressed Subtract Word".to_string(),
+        COpcode::CSUB { .. } => "Compressed Subtract".to_string(),
+        COpcode::CXOR { .. } => "Compressed bitwise Xor".to_string(),
         COpcode::COR { .. } => "Compressed bitwise Or".to_string(),
         COpcode::CAND { .. } => "Compressed bitwise And".to_string(),
         COpcode::CANDI { .. } => "Compressed bitwise AND Immediate".to_string(),
@@ -24,6 +29,8 @@ pub fn disasm_rvc_operation_name(instr: u16) -> String {
         COpcode::BNEZ { .. } => "Compressed Branch Not Equal Zero".to_string(),
         COpcode::SDSP { .. } => "Compressed Store Doubleword at Stack Pointer".to_string(),
         COpcode::LDSP { .. } => "Compressed Load Doubleword at Stack Pointer".to_string(),
+        COpcode::SWSP { .. } => "Compressed Store Word at Stack Pointer".to_string(),
+        COpcode::LWSP { .. } => "Compressed Load Word at Stack Pointer".to_string(),
         COpcode::LD { .. } => "Compressed Load Doubleword from memory".to_string(),
         COpcode::SW { .. } => "Compressed Store Word to memory".to_string(),
         COpcode::SD { .. } => "Compressed Store Double-word to memory".to_string(),
@@ -48,8 +55,11 @@ pub fn disasm_rvc_pseudo_code(instr: u16) -> String {
         COpcode::ADDI16SP { imm6 } => format!("x2 = x2 {:+}", imm6.0 << 4),
         COpcode::CSLLI { uimm6, rd } => format!("x{rd} = x{rd} << {uimm6}"),
         COpcode::CSRLI { shamt6, rd } => format!("x{rd} = x{rd} >> {shamt6}"),
+        COpcode::CSRAI { shamt6, rd } => format!("x{rd} = x{rd} >> {shamt6}; arithmetic"),
         COpcode::CLI { imm6, rd } => format!("x{rd} = {imm6:x}"),
         COpcode::CJR { rs1 } => format!("PC = x{rs1}"),
+        COpcode::CJALR { rs1 } => format!("x1 = PC + 2; PC = x{rs1}"),
+        COpcode::CEBREAK => "raise breakpoint exception".to_string(),
         COpcode::CADD { rd, rs2 } => format!("x{rd} = x{rd} + x{rs2}"),
         COpcode::CADDW { rd, rs2 } => {
             format!("x{rd}[31:0] = x{rd}[31:0] + x{rs2}[31:0]; sign extend")
@@ -57,6 +67,8 @@ pub fn disasm_rvc_pseudo_code(instr: u16) -> String {
         COpcode::CSUBW { rd, rs2 } => {
             format!("x{rd}[31:0] = x{rd}[31:0] - x{rs2}[31:0]; sign extend")
         }
+        COpcode::CSUB { rd, rs2 } => format!("x{rd} = x{rd} - x{rs2}"),
+        COpcode::CXOR { rd, rs2 } => format!("x{rd} = x{rd} ^ x{rs2}"),
         COpcode::COR { rd, rs2 } => format!("x{rd} = x{rd} | x{rs2}"),
         COpcode::CAND { rd, rs2 } => format!("x{rd} = x{rd} & x{rs2}"),
         COpcode::CANDI { imm6, rd } => format!("x{rd} = x{rd} & 0x{imm6:x}"),
@@ -65,6 +77,10 @@ pub fn disasm_rvc_pseudo_code(instr: u16) -> String {
         COpcode::BNEZ { imm9, rs1 } => format!("if x{rs1} != 0 then PC = PC {:+}", imm9.0),
         COpcode::SDSP { uimm6, rs2 } => format!("mem64[x2 {:+}] = x{rs2}", uimm6 << 3),
         COpcode::LDSP { uimm6, rd } => format!("x{rd} = mem64[x2 {:+}]", uimm6 << 3),
+        COpcode::SWSP { uimm6, rs2 } => format!("mem32[x2 {:+}] = x{rs2}", uimm6 << 2),
+        COpcode::LWSP { uimm6, rd } => {
+            format!("x{rd}[31:0] = mem32[x2 {:+}]; sign extend", uimm6 << 2)
+        }
         COpcode::LD { uoff8, rs1, rd } => format!("x{rd} = mem64[x{rs1} + {uoff8}]"),
         COpcode::SW { uoff7, rs1, rs2 } => format!("mem32[x{rs1} + {uoff7}] = x{rs2}"),
         COpcode::SD { uoff8, rs1, rs2 } => format!("mem64[x{rs1} + {uoff8}] = x{rs2}"),
@@ -90,11 +106,16 @@ pub fn disasm_rvc_get_used_regs(instr: u16) -> (Option<u8>, Option<u8>, Option<u
         COpcode::ADDI16SP { .. } => (Some(2), None, Some(2)),
         COpcode::CSLLI { rd, .. } => (Some(rd), None, Some(rd)),
         COpcode::CSRLI { rd, .. } => (Some(rd), None, Some(rd)),
+        COpcode::CSRAI { rd, .. } => (Some(rd), None, Some(rd)),
         COpcode::CLI { rd, .. } => (None, None, Some(rd)),
         COpcode::CJR { rs1 } => (Some(rs1), None, None),
+        COpcode::CJALR { rs1 } => (Some(rs1), None, Some(1)),
+        COpcode::CEBREAK => (None, None, None),
         COpcode::CADD { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
         COpcode::CADDW { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
         COpcode::CSUBW { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
+        COpcode::CSUB { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
+        COpcode::CXOR { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
         COpcode::COR { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
         COpcode::CAND { rd, rs2 } => (Some(rd), Some(rs2), Some(rd)),
         COpcode::CANDI { rd, .. } => (Some(rd), None, Some(rd)),
@@ -103,6 +124,8 @@ pub fn disasm_rvc_get_used_regs(instr: u16) -> (Option<u8>, Option<u8>, Option<u
         COpcode::BNEZ { rs1, .. } => (Some(rs1), None, None),
         COpcode::SDSP { rs2, .. } => (Some(2), Some(rs2), None),
         COpcode::LDSP { rd, .. } => (Some(2), None, Some(rd)),
+        COpcode::SWSP { rs2, .. } => (Some(2), Some(rs2), None),
+        COpcode::LWSP { rd, .. } => (Some(2), None, Some(rd)),
         COpcode::LD { rs1, rd, .. } => (Some(rs1), None, Some(rd)),
         COpcode::SW { rs1, rs2, .. } => (Some(rs1), Some(rs2), None),
         COpcode::SD { rs1, rs2, .. } => (Some(rs1), Some(rs2), None),
@@ -125,11 +148,16 @@ pub fn disasm_rvc(c_instr: u16, instr_addr: u64) -> String {
         COpcode::ADDI16SP { imm6 } => format!("c.addi16sp x2, {:+}", (imm6.0 as i16) << 4),
         COpcode::CSLLI { uimm6, rd } => format!("c.slli x{rd}, 0x{uimm6:x}"),
         COpcode::CSRLI { shamt6, rd } => format!("c.srli x{rd}, 0x{shamt6:x}"),
+        COpcode::CSRAI { shamt6, rd } => format!("c.srai x{rd}, 0x{shamt6:x}"),
         COpcode::CLI { imm6, rd } => format!("c.li x{rd}, {imm6}"),
         COpcode::CJR { rs1 } => format!("c.jr x{rs1}"),
+        COpcode::CJALR { rs1 } => format!("c.jalr x{rs1}"),
+        COpcode::CEBREAK => "c.ebreak".to_string(),
         COpcode::CADD { rd, rs2 } => format!("c.add x{rd}, x{rs2}"),
         COpcode::CADDW { rd, rs2 } => format!("c.addw x{rd}, x{rs2}"),
         COpcode::CSUBW { rd, rs2 } => format!("c.subw x{rd}, x{rs2}"),
+        COpcode::CSUB { rd, rs2 } => format!("c.sub x{rd}, x{rs2}"),
+        COpcode::CXOR { rd, rs2 } => format!("c.xor x{rd}, x{rs2}"),
         COpcode::COR { rd, rs2 } => format!("c.or x{rd}, x{rs2}"),
         COpcode::CAND { rd, rs2 } => format!("c.and x{rd}, x{rs2}"),
         COpcode::CANDI { imm6, rd } => format!("c.andi x{rd}, {imm6}"),
@@ -138,6 +166,8 @@ pub fn disasm_rvc(c_instr: u16, instr_addr: u64) -> String {
         COpcode::BNEZ { imm9, rs1 } => format!("c.bnez x{rs1}, 0x{:x}", instr_addr.add_i9(imm9)),
         COpcode::SDSP { uimm6, rs2 } => format!("c.sdsp x{rs2}, {}(x2)", uimm6 << 3),
         COpcode::LDSP { uimm6, rd } => format!("c.ldsp x{rd}, {}(x2)", uimm6 << 3),
+        COpcode::SWSP { uimm6, rs2 } => format!("c.swsp x{rs2}, {}(x2)", uimm6 << 2),
+        COpcode::LWSP { uimm6, rd } => format!("c.lwsp x{rd}, {}(x2)", uimm6 << 2),
         COpcode::LD { uoff8, rs1, rd } => format!("c.ld x{rd}, {uoff8}(x{rs1})"),
         COpcode::SW { uoff7, rs1, rs2 } => format!("c.sw x{rs2}, {uoff7}(x{rs1})"),
         COpcode::SD { uoff8, rs1, rs2 } => format!("c.sd x{rs2}, {uoff8}(x{rs1})"),
@@ -181,4 +211,12 @@ fn test_disasm_rvc_cli() {
     assert_eq!(disasm_rvc(0x_c7d8, 0x0), "c.sw x14, 12(x15)");
     assert_eq!(disasm_rvc(0x_fff8, 0x0), "c.sd x14, 248(x15)");
     assert_eq!(disasm_rvc(0x_4ffc, 0x0), "c.lw x15, 92(x15)");
+    assert_eq!(disasm_rvc(0x_8d89, 0x0), "c.sub x11, x10");
+    assert_eq!(disasm_rvc(0x_8da9, 0x0), "c.xor x11, x10");
+    assert_eq!(disasm_rvc(0x_8505, 0x0), "c.srai x10, 0x1");
+    assert_eq!(disasm_rvc(0x_8105, 0x0), "c.srli x10, 0x1");
+    assert_eq!(disasm_rvc(0x_9502, 0x0), "c.jalr x10");
+    assert_eq!(disasm_rvc(0x_9002, 0x0), "c.ebreak");
+    assert_eq!(disasm_rvc(0x_c62a, 0x0), "c.swsp x10, 12(x2)");
+    assert_eq!(disasm_rvc(0x_4532, 0x0), "c.lwsp x10, 12(x2)");
 }
@@ -0,0 +1,359 @@
+// Supervisor Binary Interface implemented in the simulator. ECALLs from S-mode are handled
+// natively instead of trapping to M-mode firmware, so kernels and S-mode programs are loaded
+// directly without OpenSBI. Supported: Base, TIME, IPI, RFENCE, HSM, SRST and the legacy
+// extensions (console, timer, IPI, fences, shutdown) of a single hart.
+// Spec: RISC-V Supervisor Binary Interface Specification v2.0.
+
+use crate::csr::{self, MIP_STIP};
+use crate::rv64i_cpu::{EcallHandler, RV64ICpu};
+
+/// Exceptions delegated to S-mode like M-mode firmware does: all except ECALL from S and M-mode
+pub const DELEGATED_EXCEPTIONS: u64 = 0xb1ff;
+pub const DELEGATED_IRQS: u64 = csr::MIP_SSIP | csr::MIP_STIP | csr::MIP_SEIP;
+
+// Registers of the calling convention
+const A0: u8 = 10;
+const A1: u8 = 11;
+const A6: u8 = 16;
+const A7: u8 = 17;
+
+// Extension IDs
+const EID_LEGACY_SET_TIMER: u64 = 0x00;
+const EID_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EID_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EID_LEGACY_CLEAR_IPI: u64 = 0x03;
+const EID_LEGACY_SEND_IPI: u64 = 0x04;
+const EID_LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const EID_LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const EID_LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const EID_LEGACY_SHUTDOWN: u64 = 0x08;
+const EID_BASE: u64 = 0x10;
+const EID_TIME: u64 = 0x5449_4d45;
+const EID_IPI: u64 = 0x0073_5049;
+const EID_RFENCE: u64 = 0x5246_4e43;
+const EID_HSM: u64 = 0x0048_534d;
+const EID_SRST: u64 = 0x5352_5354;
+
+// Standard error codes
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// v2.0
+const SPEC_VERSION: u64 = 2 << 24;
+/// The implementation ID isn't registered, it is "KOMP" in ASCII
+const IMPL_ID: u64 = 0x4b4f_4d50;
+
+const HSM_STATE_STARTED: u64 = 0;
+const HSM_SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+const HSM_SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+const SRST_TYPE_SHUTDOWN: u32 = 0;
+const SRST_TYPE_COLD_REBOOT: u32 = 1;
+const SRST_TYPE_WARM_REBOOT: u32 = 2;
+const SRST_TYPE_VENDOR: u32 = 0xf000_0000;
+
+/// Ok(value) is returned in a1 with a0 = 0, Err(error) is returned in a0
+type SbiRet = Result<u64, i64>;
+
+pub struct Sbi {
+    console_out: Option<Box<dyn Fn(u8)>>,
+    console_in: Option<Box<dyn FnMut() -> Option<u8>>>,
+    /// Supervisor timer interrupt is pending when time >= stimecmp
+    stimecmp: u64,
+}
+
+impl Default for Sbi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sbi {
+    pub fn new() -> Sbi {
+        Sbi {
+            console_out: None,
+            console_in: None,
+            // don't fire the timer until it's programmed
+            stimecmp: u64::MAX,
+        }
+    }
+
+    /// Output of the legacy console
+    pub fn register_out_callback(&mut self, cb: Box<dyn Fn(u8)>) {
+        self.console_out = Some(cb);
+    }
+
+    /// Input of the legacy console, e.g. the host console
+    pub fn register_in_source(&mut self, source: Box<dyn FnMut() -> Option<u8>>) {
+        self.console_in = Some(source);
+    }
+
+    fn base(&self, fid: u64, arg0: u64) -> SbiRet {
+        match fid {
+            // sbi_get_spec_version
+            0 => Ok(SPEC_VERSION),
+            // sbi_get_impl_id
+            1 => Ok(IMPL_ID),
+            // sbi_get_impl_version: major << 16 | minor
+            2 => Ok(impl_version()),
+            // sbi_probe_extension
+            3 => Ok(is_supported(arg0) as u64),
+            // sbi_get_mvendorid, sbi_get_marchid, sbi_get_mimpid
+            4..=6 => Ok(0),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn legacy(&mut self, cpu: &mut RV64ICpu, eid: u64, arg0: u64) -> i64 {
+        match eid {
+            EID_LEGACY_SET_TIMER => self.stimecmp = arg0,
+            EID_LEGACY_CONSOLE_PUTCHAR => {
+                if let Some(cb) = &self.console_out {
+                    cb(arg0 as u8);
+                }
+            }
+            EID_LEGACY_CONSOLE_GETCHAR => {
+                let octet = self.console_in.as_mut().and_then(|source| source());
+                return octet.map_or(-1, |octet| octet as i64);
+            }
+            EID_LEGACY_CLEAR_IPI => cpu.set_s_soft_irq(false),
+            EID_LEGACY_SEND_IPI => {
+                // a0 is the virtual address of the hart mask, 0 - all harts
+                let hart_mask = match arg0 {
+                    0 => 1,
+                    addr => match cpu.read_virt_u64(addr) {
+                        Some(hart_mask) => hart_mask,
+                        None => return SBI_ERR_INVALID_ADDRESS,
+                    },
+                };
+                if hart_mask & 1 != 0 {
+                    cpu.set_s_soft_irq(true);
+                }
+            }
+            EID_LEGACY_REMOTE_FENCE_I => (),
+            EID_LEGACY_REMOTE_SFENCE_VMA | EID_LEGACY_REMOTE_SFENCE_VMA_ASID => cpu.flush_tlb(),
+            EID_LEGACY_SHUTDOWN => cpu.exit(0),
+            _ => return SBI_ERR_NOT_SUPPORTED,
+        }
+        0
+    }
+}
+
+impl EcallHandler for Sbi {
+    /// Handles ECALL from S-mode: extension ID in a7, function ID in a6, arguments in a0-a5
+    fn ecall(&mut self, cpu: &mut RV64ICpu) {
+        let eid = cpu.regs_r64(A7);
+        let fid = cpu.regs_r64(A6);
+        let args: [u64; 6] = std::array::from_fn(|i| cpu.regs_r64(A0 + i as u8));
+        if eid <= EID_LEGACY_SHUTDOWN {
+            // legacy extensions return only a0
+            let ret = self.legacy(cpu, eid, args[0]);
+            cpu.regs_w64(A0, ret as u64);
+            return;
+        }
+        let ret = match eid {
+            EID_BASE => self.base(fid, args[0]),
+            EID_TIME if fid == 0 => {
+                self.stimecmp = args[0];
+                Ok(0)
+            }
+            EID_IPI if fid == 0 => send_ipi(cpu, args[0], args[1]),
+            EID_RFENCE => rfence(cpu, fid, args[0], args[1]),
+            EID_HSM => hsm(fid, args[0]),
+            EID_SRST if fid == 0 => system_reset(cpu, args[0] as u32, args[1] as u32),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        };
+        let (error, value) = match ret {
+            Ok(value) => (0, value),
+            Err(error) => (error, 0),
+        };
+        cpu.regs_w64(A0, error as u64);
+        cpu.regs_w64(A1, value);
+    }
+
+    /// Interrupts raised by the SBI implementation (mip layout): the supervisor timer
+    fn pending_irqs(&self, time: u64) -> u64 {
+        if time >= self.stimecmp {
+            MIP_STIP
+        } else {
+            0
+        }
+    }
+}
+
+fn impl_version() -> u64 {
+    let major: u64 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    let minor: u64 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    major << 16 | minor
+}
+
+fn is_supported(eid: u64) -> bool {
+    eid <= EID_LEGACY_SHUTDOWN
+        || matches!(
+            eid,
+            EID_BASE | EID_TIME | EID_IPI | EID_RFENCE | EID_HSM | EID_SRST
+        )
+}
+
+/// The only hart is 0. Returns whether it's selected by the hart mask, masks with other harts
+/// are invalid.
+fn hart0_selected(hart_mask: u64, hart_mask_base: u64) -> Result<bool, i64> {
+    match (hart_mask_base, hart_mask) {
+        // all harts
+        (u64::MAX, _) => Ok(true),
+        (_, 0) => Ok(false),
+        (0, 1) => Ok(true),
+        _ => Err(SBI_ERR_INVALID_PARAM),
+    }
+}
+
+fn send_ipi(cpu: &mut RV64ICpu, hart_mask: u64, hart_mask_base: u64) -> SbiRet {
+    if hart0_selected(hart_mask, hart_mask_base)? {
+        cpu.set_s_soft_irq(true);
+    }
+    Ok(0)
+}
+
+fn rfence(cpu: &mut RV64ICpu, fid: u64, hart_mask: u64, hart_mask_base: u64) -> SbiRet {
+    match fid {
+        // sbi_remote_fence_i: instruction fetches aren't cached
+        0 => {
+            hart0_selected(hart_mask, hart_mask_base)?;
+        }
+        // sbi_remote_sfence_vma, sbi_remote_sfence_vma_asid: the whole TLB is flushed
+        1 | 2 => {
+            if hart0_selected(hart_mask, hart_mask_base)? {
+                cpu.flush_tlb();
+            }
+        }
+        // hypervisor fences
+        _ => return Err(SBI_ERR_NOT_SUPPORTED),
+    }
+    Ok(0)
+}
+
+fn hsm(fid: u64, arg0: u64) -> SbiRet {
+    match fid {
+        // sbi_hart_start
+        0 if arg0 == 0 => Err(SBI_ERR_ALREADY_AVAILABLE),
+        0 => Err(SBI_ERR_INVALID_PARAM),
+        // sbi_hart_stop: the only hart can't be stopped
+        1 => Err(SBI_ERR_FAILED),
+        // sbi_hart_get_status
+        2 if arg0 == 0 => Ok(HSM_STATE_STARTED),
+        2 => Err(SBI_ERR_INVALID_PARAM),
+        // sbi_hart_suspend: retentive suspend returns at once like WFI, pending interrupts are
+        // taken after the ECALL
+        3 => match arg0 as u32 {
+            HSM_SUSPEND_RETENTIVE => Ok(0),
+            HSM_SUSPEND_NON_RETENTIVE => Err(SBI_ERR_NOT_SUPPORTED),
+            _ => Err(SBI_ERR_INVALID_PARAM),
+        },
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Shutdown and reboot stop the simulation, the exit code is 1 for the system failure reason
+fn system_reset(cpu: &mut RV64ICpu, reset_type: u32, reset_reason: u32) -> SbiRet {
+    match reset_type {
+        SRST_TYPE_SHUTDOWN => (),
+        SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => {
+            eprintln!("WARN: SBI: reboot isn't supported, stopping the machine")
+        }
+        SRST_TYPE_VENDOR.. => return Err(SBI_ERR_NOT_SUPPORTED),
+        _ => return Err(SBI_ERR_INVALID_PARAM),
+    }
+    cpu.exit(if reset_reason == 0 { 0 } else { 1 });
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::{PrivMode, MIP_SSIP, SIP};
+    use crate::machine::MachineBuilder;
+    use crate::rv64i_cpu::ExecEvent;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const RAM_BASE: u64 = 0x8020_0000;
+    const ECALL: u32 = 0x0000_0073;
+
+    /// Executes ECALL with the given extension, function and arguments, returns (a0, a1)
+    fn ecall(cpu: &mut RV64ICpu, eid: u64, fid: u64, args: &[u64]) -> (i64, u64) {
+        cpu.bus.write32(cpu.get_pc(), ECALL).unwrap();
+        cpu.regs_w64(A7, eid);
+        cpu.regs_w64(A6, fid);
+        for (i, arg) in args.iter().enumerate() {
+            cpu.regs_w64(A0 + i as u8, *arg);
+        }
+        cpu.exec_continue(1);
+        (cpu.regs_r64(A0) as i64, cpu.regs_r64(A1))
+    }
+
+    #[test]
+    fn test_sbi_ecalls() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let out_cb = out.clone();
+        let mut cpu = MachineBuilder::new()
+            .ram(RAM_BASE, 0x1000)
+            .sbi(true)
+            .console_out(Box::new(move |b| out_cb.borrow_mut().push(b)))
+            .build()
+            .unwrap();
+        assert_eq!(cpu.get_priv_mode(), PrivMode::Supervisor);
+
+        assert_eq!(ecall(&mut cpu, EID_BASE, 0, &[]), (0, SPEC_VERSION));
+        assert_eq!(cpu.get_pc(), RAM_BASE + 4);
+        assert_eq!(ecall(&mut cpu, EID_BASE, 3, &[EID_HSM]), (0, 1));
+        assert_eq!(ecall(&mut cpu, EID_BASE, 3, &[0x1234_5678]), (0, 0));
+        assert_eq!(
+            ecall(&mut cpu, 0x1234_5678, 0, &[]),
+            (SBI_ERR_NOT_SUPPORTED, 0)
+        );
+        assert_eq!(cpu.get_priv_mode(), PrivMode::Supervisor);
+
+        // legacy console
+        ecall(&mut cpu, EID_LEGACY_CONSOLE_PUTCHAR, 0, &[b'K' as u64]);
+        assert_eq!(out.borrow().as_slice(), b"K");
+        assert_eq!(ecall(&mut cpu, EID_LEGACY_CONSOLE_GETCHAR, 0, &[]).0, -1);
+
+        // timer
+        let time = cpu.bus.time();
+        ecall(&mut cpu, EID_TIME, 0, &[time + 10]);
+        assert_eq!(cpu.csr_r64(SIP).unwrap() & MIP_STIP, 0);
+        cpu.bus.clock().advance(10);
+        assert_eq!(cpu.csr_r64(SIP).unwrap() & MIP_STIP, MIP_STIP);
+        ecall(&mut cpu, EID_LEGACY_SET_TIMER, 0, &[u64::MAX]);
+        assert_eq!(cpu.csr_r64(SIP).unwrap() & MIP_STIP, 0);
+
+        // IPI to self, harts other than 0 don't exist
+        assert_eq!(ecall(&mut cpu, EID_IPI, 0, &[1, 0]), (0, 0));
+        assert_eq!(cpu.csr_r64(SIP).unwrap() & MIP_SSIP, MIP_SSIP);
+        ecall(&mut cpu, EID_LEGACY_CLEAR_IPI, 0, &[]);
+        assert_eq!(cpu.csr_r64(SIP).unwrap() & MIP_SSIP, 0);
+        assert_eq!(
+            ecall(&mut cpu, EID_IPI, 0, &[1, 1]),
+            (SBI_ERR_INVALID_PARAM, 0)
+        );
+        assert_eq!(ecall(&mut cpu, EID_RFENCE, 1, &[0, u64::MAX]), (0, 0));
+
+        // HSM
+        assert_eq!(ecall(&mut cpu, EID_HSM, 2, &[0]), (0, HSM_STATE_STARTED));
+        assert_eq!(
+            ecall(&mut cpu, EID_HSM, 0, &[0, RAM_BASE, 0]),
+            (SBI_ERR_ALREADY_AVAILABLE, 0)
+        );
+
+        // shutdown with the system failure reason
+        cpu.bus.write32(cpu.get_pc(), ECALL).unwrap();
+        cpu.regs_w64(A7, EID_SRST);
+        cpu.regs_w64(A6, 0);
+        cpu.regs_w64(A0, SRST_TYPE_SHUTDOWN as u64);
+        cpu.regs_w64(A1, 1);
+        assert!(matches!(cpu.exec_continue(10), ExecEvent::Exit(1)));
+    }
+}
@@ -0,0 +1,446 @@
+// RISC-V semihosting: bare-metal programs request services of the host (console, files, clock,
+// exit) with EBREAK surrounded by the magic instructions
+//   slli x0, x0, 0x1f
+//   ebreak
+//   srai x0, x0, 7
+// The operation number is in a0, a1 points to the parameter block (or is the parameter), the
+// result is returned in a0.
+// Spec: RISC-V Semihosting v0.2 and Arm "Semihosting for AArch32 and AArch64" (64-bit fields).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::replay::InputLog;
+use crate::rv64i_cpu::RV64ICpu;
+
+/// slli x0, x0, 0x1f
+pub const SLLI_MAGIC: u32 = 0x01f0_1013;
+/// srai x0, x0, 7
+pub const SRAI_MAGIC: u32 = 0x4070_5013;
+
+// Registers of the calling convention
+const A0: u8 = 10;
+const A1: u8 = 11;
+
+// Operation numbers
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+const SYS_ELAPSED: u64 = 0x30;
+const SYS_TICKFREQ: u64 = 0x31;
+
+/// Reason of SYS_EXIT for the normal termination of the program
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+/// The special file name of the console
+const TT_NAME: &[u8] = b":tt";
+/// Ticks of SYS_ELAPSED are the machine timer ticks, the timebase-frequency of the generated
+/// device tree
+const TICK_FREQ: u64 = 10_000_000;
+/// Maximum size of a single read/write transfer
+const MAX_IO: u64 = 1 << 20;
+/// Longest file name accepted by SYS_OPEN
+const PATH_MAX: u64 = 4096;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Returned in a0: Ok(value) or Err(errno), a failed call returns -1 and sets errno
+type ShResult = Result<u64, i64>;
+
+pub struct Semihosting {
+    /// Index is the handle
+    handles: Vec<Option<Handle>>,
+    /// Console output, the host stdout and stderr if None
+    console_out: Option<Box<dyn Fn(u8)>>,
+    /// errno of the last failed call (SYS_ERRNO)
+    errno: i64,
+    start: Instant,
+    /// Host time and stdin reads are recorded to or replayed from the log
+    input_log: Option<InputLog>,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn errno(e: &io::Error) -> i64 {
+    e.raw_os_error().map_or(EINVAL, |e| e as i64)
+}
+
+/// Field i of the parameter block, the fields are 64 bits wide on RV64
+fn param(cpu: &RV64ICpu, block: u64, i: u64) -> Result<u64, i64> {
+    cpu.read_virt_u64(block.wrapping_add(i * 8)).ok_or(EFAULT)
+}
+
+fn read_mem(cpu: &RV64ICpu, addr: u64, len: u64) -> Result<Vec<u8>, i64> {
+    cpu.read_virt_bytes(addr, len).ok_or(EFAULT)
+}
+
+impl Semihosting {
+    pub fn new() -> Semihosting {
+        Semihosting {
+            handles: Vec::new(),
+            console_out: None,
+            errno: 0,
+            start: Instant::now(),
+            input_log: None,
+        }
+    }
+
+    /// Output of SYS_WRITEC, SYS_WRITE0 and writes to ":tt"
+    pub fn register_out_callback(&mut self, cb: Box<dyn Fn(u8)>) {
+        self.console_out = Some(cb);
+    }
+
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.input_log = Some(log);
+    }
+
+    fn host_time(&self, read: impl FnOnce() -> u64) -> u64 {
+        match &self.input_log {
+            Some(log) => log.host_time(read),
+            None => read(),
+        }
+    }
+
+    /// Reads up to len bytes of the host stdin
+    fn stdin_read(&self, len: usize) -> io::Result<Vec<u8>> {
+        let read = || {
+            let mut data = vec![0; len];
+            let n = io::stdin().read(&mut data)?;
+            data.truncate(n);
+            Ok(data)
+        };
+        match &self.input_log {
+            Some(log) => log.host_read(read),
+            None => read(),
+        }
+    }
+
+    /// Services the call: the operation is in a0, the parameter in a1
+    pub fn call(&mut self, cpu: &mut RV64ICpu) {
+        let op = cpu.regs_r64(A0);
+        let arg = cpu.regs_r64(A1);
+        let ret = match self.operation(cpu, op, arg) {
+            Ok(val) => val,
+            Err(errno) => {
+                self.errno = errno;
+                u64::MAX
+            }
+        };
+        cpu.regs_w64(A0, ret);
+    }
+
+    fn operation(&mut self, cpu: &mut RV64ICpu, op: u64, arg: u64) -> ShResult {
+        match op {
+            SYS_OPEN => {
+                let len = param(cpu, arg, 2)?;
+                let name = read_mem(cpu, param(cpu, arg, 0)?, len.min(PATH_MAX))?;
+                self.open(&name, param(cpu, arg, 1)?)
+            }
+            SYS_CLOSE => {
+                let handle = param(cpu, arg, 0)?;
+                match self.handles.get_mut(handle as usize) {
+                    Some(h @ Some(_)) => {
+                        *h = None;
+                        Ok(0)
+                    }
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_WRITEC => {
+                let c = read_mem(cpu, arg, 1)?;
+                self.console_write(false, &c).map(|_| 0)
+            }
+            SYS_WRITE0 => {
+                let mut s = Vec::new();
+                loop {
+                    match read_mem(cpu, arg.wrapping_add(s.len() as u64), 1)?[0] {
+                        0 => break,
+                        c => s.push(c),
+                    }
+                }
+                self.console_write(false, &s).map(|_| 0)
+            }
+            // returns the number of bytes not written
+            SYS_WRITE => {
+                let len = param(cpu, arg, 2)?;
+                let data = read_mem(cpu, param(cpu, arg, 1)?, len.min(MAX_IO))?;
+                let written = self.write(param(cpu, arg, 0)?, &data)?;
+                Ok(len - written)
+            }
+            // returns the number of bytes not read, len at the end of the file
+            SYS_READ => {
+                let len = param(cpu, arg, 2)?;
+                let mut data = vec![0; len.min(MAX_IO) as usize];
+                let n = self.read(param(cpu, arg, 0)?, &mut data)?;
+                if !cpu.write_virt_bytes(param(cpu, arg, 1)?, &data[..n]) {
+                    return Err(EFAULT);
+                }
+                Ok(len - n as u64)
+            }
+            SYS_READC => match self.stdin_read(1) {
+                Ok(c) if c.len() == 1 => Ok(c[0] as u64),
+                Ok(_) => Err(EINVAL),
+                Err(e) => Err(errno(&e)),
+            },
+            SYS_ISTTY => match self.handle(param(cpu, arg, 0)?)? {
+                Handle::File(_) => Ok(0),
+                _ => Ok(1),
+            },
+            SYS_SEEK => {
+                let pos = param(cpu, arg, 1)?;
+                match self.handle(param(cpu, arg, 0)?)? {
+                    Handle::File(file) => file.seek(SeekFrom::Start(pos)).map_err(|e| errno(&e)),
+                    _ => Err(EBADF),
+                }
+                .map(|_| 0)
+            }
+            SYS_FLEN => match self.handle(param(cpu, arg, 0)?)? {
+                Handle::File(file) => file.metadata().map(|m| m.len()).map_err(|e| errno(&e)),
+                _ => Ok(0),
+            },
+            // centiseconds since the start
+            SYS_CLOCK => Ok(self.host_time(|| self.start.elapsed().as_millis() as u64 / 10)),
+            SYS_TIME => Ok(self.host_time(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            })),
+            SYS_ERRNO => Ok(self.errno as u64),
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                // 32-bit callers pass the reason in a1 instead of a parameter block
+                let (reason, subcode) = if op == SYS_EXIT && arg == ADP_STOPPED_APPLICATION_EXIT {
+                    (arg, 0)
+                } else {
+                    (param(cpu, arg, 0)?, param(cpu, arg, 1)?)
+                };
+                let code = match reason {
+                    ADP_STOPPED_APPLICATION_EXIT => subcode as i32,
+                    // abnormal termination, e.g. ADP_Stopped_RunTimeErrorUnknown
+                    _ => 1,
+                };
+                cpu.exit(code);
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                if !cpu.write_virt_bytes(arg, &cpu.bus.time().to_le_bytes()) {
+                    return Err(EFAULT);
+                }
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(TICK_FREQ),
+            _ => {
+                eprintln!("WARN: semihosting: unsupported operation 0x{op:x}");
+                Err(EINVAL)
+            }
+        }
+    }
+
+    fn handle(&mut self, handle: u64) -> Result<&mut Handle, i64> {
+        match self.handles.get_mut(handle as usize) {
+            Some(Some(h)) => Ok(h),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Modes 0-11 are fopen() modes "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b", "a", "ab",
+    /// "a+", "a+b". ":tt" is stdin for reading, stdout for writing and stderr for appending.
+    fn open(&mut self, name: &[u8], mode: u64) -> ShResult {
+        if mode > 11 {
+            return Err(EINVAL);
+        }
+        let handle = if name == TT_NAME {
+            match mode / 4 {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let path = std::str::from_utf8(name).map_err(|_| EINVAL)?;
+            let plus = mode & 2 != 0;
+            let mut options = OpenOptions::new();
+            match mode / 4 {
+                0 => options.read(true).write(plus),
+                1 => options.write(true).read(plus).create(true).truncate(true),
+                _ => options.append(true).read(plus).create(true),
+            };
+            Handle::File(options.open(path).map_err(|e| errno(&e))?)
+        };
+        let free = self.handles.iter().position(|h| h.is_none());
+        let idx = free.unwrap_or(self.handles.len());
+        if idx == self.handles.len() {
+            self.handles.push(None);
+        }
+        self.handles[idx] = Some(handle);
+        Ok(idx as u64)
+    }
+
+    /// Returns the number of written bytes
+    fn write(&mut self, handle: u64, data: &[u8]) -> Result<u64, i64> {
+        let is_stderr = match self.handle(handle)? {
+            Handle::Stdin => return Err(EBADF),
+            Handle::File(file) => return file.write(data).map(|n| n as u64).map_err(|e| errno(&e)),
+            h => matches!(h, Handle::Stderr),
+        };
+        self.console_write(is_stderr, data)?;
+        Ok(data.len() as u64)
+    }
+
+    fn read(&mut self, handle: u64, buf: &mut [u8]) -> Result<usize, i64> {
+        if let Handle::Stdin = self.handle(handle)? {
+            let data = self.stdin_read(buf.len()).map_err(|e| errno(&e))?;
+            buf[..data.len()].copy_from_slice(&data);
+            return Ok(data.len());
+        }
+        match self.handle(handle)? {
+            Handle::File(file) => file.read(buf),
+            _ => return Err(EBADF),
+        }
+        .map_err(|e| errno(&e))
+    }
+
+    fn console_write(&self, is_stderr: bool, data: &[u8]) -> Result<(), i64> {
+        if let Some(cb) = &self.console_out {
+            data.iter().for_each(|&octet| cb(octet));
+            return Ok(());
+        }
+        if is_stderr {
+            io::stderr().write_all(data)
+        } else {
+            io::stdout()
+                .write_all(data)
+                .and_then(|_| io::stdout().flush())
+        }
+        .map_err(|e| errno(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::ram::Ram;
+    use crate::rv64i_cpu::ExecEvent;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const EBREAK: u32 = 0x0010_0073;
+    const ECALL: u32 = 0x0000_0073;
+
+    /// Hart executing semihosting calls at RAM_BASE, the output is collected in the string
+    fn test_cpu() -> (RV64ICpu, Rc<RefCell<String>>) {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(RAM_BASE, 0x1_0000));
+        let mut cpu = RV64ICpu::new(bus);
+        let out = Rc::new(RefCell::new(String::new()));
+        let out_cb = out.clone();
+        let mut semihosting = Semihosting::new();
+        semihosting.register_out_callback(Box::new(move |b| out_cb.borrow_mut().push(b as char)));
+        cpu.enable_semihosting(semihosting);
+        cpu.pc_jump(RAM_BASE);
+        (cpu, out)
+    }
+
+    /// Executes the semihosting call at PC and returns a0
+    fn call(cpu: &mut RV64ICpu, op: u64, arg: u64) -> u64 {
+        let pc = cpu.get_pc();
+        for (i, instr) in [SLLI_MAGIC, EBREAK, SRAI_MAGIC].iter().enumerate() {
+            cpu.bus.write32(pc + i as u64 * 4, *instr).unwrap();
+        }
+        cpu.regs_w64(A0, op);
+        cpu.regs_w64(A1, arg);
+        cpu.exec_continue(3);
+        cpu.regs_r64(A0)
+    }
+
+    #[test]
+    fn test_semihosting_console_and_exit() {
+        let (mut cpu, out) = test_cpu();
+        let msg = RAM_BASE + 0x1000;
+        cpu.bus.write_bytes(msg, b"hello\n\0").unwrap();
+        assert_eq!(call(&mut cpu, SYS_WRITE0, msg), 0);
+        let block = RAM_BASE + 0x2000;
+        cpu.bus.write_bytes(block, b":tt\0").unwrap();
+        // open(":tt", "w", 3)
+        for (i, val) in [block, 4, 3].iter().enumerate() {
+            cpu.bus.write64(block + 0x10 + i as u64 * 8, *val).unwrap();
+        }
+        let tt = call(&mut cpu, SYS_OPEN, block + 0x10);
+        assert_eq!(tt, 0);
+        for (i, val) in [tt, msg, 2].iter().enumerate() {
+            cpu.bus.write64(block + 0x10 + i as u64 * 8, *val).unwrap();
+        }
+        assert_eq!(call(&mut cpu, SYS_WRITE, block + 0x10), 0);
+        assert_eq!(out.borrow().as_str(), "hello\nhe");
+        assert_eq!(call(&mut cpu, SYS_TICKFREQ, 0), TICK_FREQ);
+        // not a file
+        cpu.bus.write64(block, 5).unwrap();
+        assert_eq!(call(&mut cpu, SYS_CLOSE, block), u64::MAX);
+        assert_eq!(call(&mut cpu, SYS_ERRNO, 0), EBADF as u64);
+
+        cpu.bus
+            .write64(block, ADP_STOPPED_APPLICATION_EXIT)
+            .unwrap();
+        cpu.bus.write64(block + 8, 42).unwrap();
+        call(&mut cpu, SYS_EXIT, block);
+        assert!(matches!(cpu.exec_continue(1), ExecEvent::Exit(42)));
+    }
+
+    #[test]
+    fn test_semihosting_bad_lengths() {
+        let (mut cpu, _) = test_cpu();
+        let block = RAM_BASE + 0x2000;
+        cpu.bus.write_bytes(block, b":tt\0").unwrap();
+        // open(":tt", "r", huge), the name is clamped to PATH_MAX and isn't ":tt"
+        for (i, val) in [block, 0, u64::MAX - 0x10].iter().enumerate() {
+            cpu.bus.write64(block + 0x10 + i as u64 * 8, *val).unwrap();
+        }
+        assert_eq!(call(&mut cpu, SYS_OPEN, block + 0x10), u64::MAX);
+        // the name runs past the end of RAM
+        for (i, val) in [RAM_BASE + 0xff00, 0, u64::MAX - 0x10].iter().enumerate() {
+            cpu.bus.write64(block + 0x10 + i as u64 * 8, *val).unwrap();
+        }
+        assert_eq!(call(&mut cpu, SYS_OPEN, block + 0x10), u64::MAX);
+        assert_eq!(call(&mut cpu, SYS_ERRNO, 0), EFAULT as u64);
+        assert_eq!(cpu.read_virt_bytes(0, u64::MAX - 0x10), None);
+        assert_eq!(cpu.read_virt_bytes(RAM_BASE, u64::MAX), None);
+    }
+
+    #[test]
+    fn test_plain_ebreak_traps() {
+        let (mut cpu, _) = test_cpu();
+        cpu.bus.write32(RAM_BASE, EBREAK).unwrap();
+        cpu.bus.write32(RAM_BASE + 4, ECALL).unwrap();
+        cpu.exec_continue(1);
+        assert_eq!(
+            cpu.csr_r64(crate::csr::MCAUSE),
+            Some(crate::csr::EXC_BREAKPOINT)
+        );
+    }
+}
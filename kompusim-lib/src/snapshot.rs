@@ -0,0 +1,548 @@
+// Whole-machine snapshots: the hart (registers, CSRs), the platform time, RAM and the state of
+// the devices (registers, FIFOs, virtqueues) are saved to a versioned binary file and restored
+// into a machine with the same layout (RAM regions and device addresses). ECALL handlers (SBI,
+// Linux syscalls), semihosting and the host side of devices (files, sockets) aren't saved.
+// File format (little endian), version 1:
+//   "KOMPSNAP", version: u32
+//   hart: pc, x0 - x31, f0 - f31, fcsr, number of executed instructions: u64,
+//     LR/SC reservation: bool + u64, CSRs: u32 count of (name, u64)
+//   time: u64
+//   RAM regions: u32 count of (start: u64, size: u64, u32 count of non-zero pages
+//     (offset: u64, bytes))
+//   devices: u32 count of (start: u64, end: u64, state: bytes)
+//   bytes and strings are u32 length followed by the data, bool is u8
+
+use std::fmt;
+use std::path::Path;
+
+use crate::rv64i_disasm::reg_idx2abi;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"KOMPSNAP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// RAM is saved by pages, zero pages are skipped
+const PAGE_SIZE: usize = 4096;
+/// Largest RAM region accepted from a snapshot file
+const MAX_RAM_SIZE: u64 = 16 << 30;
+
+/// Serializes state of the machine parts
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        Default::default()
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.raw(data);
+    }
+
+    /// Data without the length, e.g. a magic
+    pub fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Deserializes state written by StateWriter
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    /// Next len bytes, e.g. a magic
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.data.len() {
+            return Err("snapshot: unexpected end of data".to_string());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn str(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| "snapshot: bad string".to_string())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Number of bytes left
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HartState {
+    pub pc: u64,
+    pub x: [u64; 32],
+    pub f: [u64; 32],
+    pub fcsr: u64,
+    pub num_exec_instr: u64,
+    pub lr_sc_reservation: Option<u64>,
+    /// Privilege level and CSR storage by name, see Csrs::state()
+    pub csrs: Vec<(String, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RamState {
+    pub start: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceState {
+    pub start: u64,
+    pub end: u64,
+    /// Written by Dev::save_state()
+    pub state: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub hart: HartState,
+    /// Platform time (mtime)
+    pub time: u64,
+    pub rams: Vec<RamState>,
+    pub devices: Vec<DeviceState>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.raw(SNAPSHOT_MAGIC);
+        w.u32(SNAPSHOT_VERSION);
+        let hart = &self.hart;
+        w.u64(hart.pc);
+        hart.x.iter().for_each(|&x| w.u64(x));
+        hart.f.iter().for_each(|&f| w.u64(f));
+        w.u64(hart.fcsr);
+        w.u64(hart.num_exec_instr);
+        w.bool(hart.lr_sc_reservation.is_some());
+        w.u64(hart.lr_sc_reservation.unwrap_or(0));
+        w.u32(hart.csrs.len() as u32);
+        for (name, val) in &hart.csrs {
+            w.str(name);
+            w.u64(*val);
+        }
+        w.u64(self.time);
+        w.u32(self.rams.len() as u32);
+        for ram in &self.rams {
+            w.u64(ram.start);
+            w.u64(ram.data.len() as u64);
+            let pages: Vec<(usize, &[u8])> = ram
+                .data
+                .chunks(PAGE_SIZE)
+                .enumerate()
+                .filter(|(_, page)| page.iter().any(|&b| b != 0))
+                .collect();
+            w.u32(pages.len() as u32);
+            for (i, page) in pages {
+                w.u64((i * PAGE_SIZE) as u64);
+                w.bytes(page);
+            }
+        }
+        w.u32(self.devices.len() as u32);
+        for dev in &self.devices {
+            w.u64(dev.start);
+            w.u64(dev.end);
+            w.bytes(&dev.state);
+        }
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot, String> {
+        let mut r = StateReader::new(data);
+        if r.take(SNAPSHOT_MAGIC.len()).ok() != Some(SNAPSHOT_MAGIC) {
+            return Err("not a snapshot file".to_string());
+        }
+        let version = r.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {version}"));
+        }
+        let mut hart = HartState {
+            pc: r.u64()?,
+            ..Default::default()
+        };
+        for x in hart.x.iter_mut() {
+            *x = r.u64()?;
+        }
+        for f in hart.f.iter_mut() {
+            *f = r.u64()?;
+        }
+        hart.fcsr = r.u64()?;
+        hart.num_exec_instr = r.u64()?;
+        let reserved = r.bool()?;
+        let reservation = r.u64()?;
+        hart.lr_sc_reservation = reserved.then_some(reservation);
+        for _ in 0..r.u32()? {
+            hart.csrs.push((r.str()?, r.u64()?));
+        }
+        let time = r.u64()?;
+        let mut rams = Vec::new();
+        for _ in 0..r.u32()? {
+            let start = r.u64()?;
+            let size = r.u64()?;
+            if size > MAX_RAM_SIZE {
+                return Err(format!("snapshot: RAM region of {size} bytes is too large"));
+            }
+            let num_pages = r.u32()? as usize;
+            // a page is at least its offset and length
+            if num_pages.saturating_mul(12) > r.remaining() {
+                return Err("snapshot: unexpected end of data".to_string());
+            }
+            let mut data = vec![0; size as usize];
+            for _ in 0..num_pages {
+                let offset = r.u64()?;
+                let page = r.bytes()?;
+                offset
+                    .checked_add(page.len() as u64)
+                    .filter(|&end| end <= size)
+                    .ok_or("snapshot: RAM page out of the region")?;
+                let offset = offset as usize;
+                data[offset..offset + page.len()].copy_from_slice(page);
+            }
+            rams.push(RamState { start, data });
+        }
+        let mut devices = Vec::new();
+        for _ in 0..r.u32()? {
+            devices.push(DeviceState {
+                start: r.u64()?,
+                end: r.u64()?,
+                state: r.bytes()?.to_vec(),
+            });
+        }
+        if !r.is_empty() {
+            return Err("snapshot: trailing data".to_string());
+        }
+        Ok(Snapshot {
+            hart,
+            time,
+            rams,
+            devices,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| format!("failed to write {path:?}: {e}"))
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, String> {
+        let data = std::fs::read(path).map_err(|e| format!("failed to read {path:?}: {e}"))?;
+        Snapshot::from_bytes(&data).map_err(|e| format!("{path:?}: {e}"))
+    }
+
+    /// Registers, CSRs, memory and devices changed from self to other
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        let (a, b) = (&self.hart, &other.hart);
+        let mut reg = |name: String, old: u64, new: u64| {
+            if old != new {
+                diff.regs.push((name, old, new));
+            }
+        };
+        reg("pc".to_string(), a.pc, b.pc);
+        for i in 1..32 {
+            reg(format!("x{i} ({})", reg_idx2abi(i as u8)), a.x[i], b.x[i]);
+        }
+        for i in 0..32 {
+            reg(format!("f{i}"), a.f[i], b.f[i]);
+        }
+        reg("fcsr".to_string(), a.fcsr, b.fcsr);
+        reg("instret".to_string(), a.num_exec_instr, b.num_exec_instr);
+        reg(
+            "reservation".to_string(),
+            a.lr_sc_reservation.unwrap_or(u64::MAX),
+            b.lr_sc_reservation.unwrap_or(u64::MAX),
+        );
+        reg("time".to_string(), self.time, other.time);
+        for (name, old) in &a.csrs {
+            let new = b.csrs.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+            reg(name.clone(), *old, new.unwrap_or(0));
+        }
+        for (name, new) in &b.csrs {
+            if !a.csrs.iter().any(|(n, _)| n == name) {
+                reg(name.clone(), 0, *new);
+            }
+        }
+        for ram in &self.rams {
+            let end = ram.start + ram.data.len() as u64;
+            match other.rams.iter().find(|r| r.start == ram.start) {
+                Some(other_ram) => {
+                    diff.mem
+                        .extend(changed_ranges(ram.start, &ram.data, &other_ram.data));
+                    if other_ram.data.len() != ram.data.len() {
+                        diff.layout.push(format!(
+                            "RAM at 0x{:x} resized from 0x{:x} to 0x{:x} bytes",
+                            ram.start,
+                            ram.data.len(),
+                            other_ram.data.len()
+                        ));
+                    }
+                }
+                None => diff.layout.push(format!(
+                    "RAM 0x{:x}..0x{end:x} is only in the first snapshot",
+                    ram.start
+                )),
+            }
+        }
+        for ram in &other.rams {
+            if !self.rams.iter().any(|r| r.start == ram.start) {
+                diff.layout.push(format!(
+                    "RAM 0x{:x}..0x{:x} is only in the second snapshot",
+                    ram.start,
+                    ram.start + ram.data.len() as u64
+                ));
+            }
+        }
+        for dev in &self.devices {
+            match other.devices.iter().find(|d| d.start == dev.start) {
+                Some(other_dev) if other_dev.state != dev.state => diff.devices.push(dev.start),
+                Some(_) => {}
+                None => diff.layout.push(format!(
+                    "device at 0x{:x} is only in the first snapshot",
+                    dev.start
+                )),
+            }
+        }
+        for dev in &other.devices {
+            if !self.devices.iter().any(|d| d.start == dev.start) {
+                diff.layout.push(format!(
+                    "device at 0x{:x} is only in the second snapshot",
+                    dev.start
+                ));
+            }
+        }
+        diff
+    }
+}
+
+/// Ranges [start, end) of addresses where the bytes differ, the longer region's tail is changed
+fn changed_ranges(start: u64, a: &[u8], b: &[u8]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let len = a.len().max(b.len());
+    for i in 0..len {
+        if a.get(i) == b.get(i) {
+            continue;
+        }
+        let addr = start + i as u64;
+        match ranges.last_mut() {
+            Some((_, end)) if *end == addr => *end += 1,
+            _ => ranges.push((addr, addr + 1)),
+        }
+    }
+    ranges
+}
+
+/// Differences between two snapshots
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    /// (name, old, new) of registers, CSRs, instret and time
+    pub regs: Vec<(String, u64, u64)>,
+    /// Changed memory ranges [start, end)
+    pub mem: Vec<(u64, u64)>,
+    /// Start addresses of the devices with a different state
+    pub devices: Vec<u64>,
+    /// Differences of the machine layout (RAM regions, devices)
+    pub layout: Vec<String>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == SnapshotDiff::default()
+    }
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "snapshots are identical");
+        }
+        for (name, old, new) in &self.regs {
+            writeln!(f, "{name:<16} 0x{old:016x} -> 0x{new:016x}")?;
+        }
+        for (start, end) in &self.mem {
+            writeln!(f, "memory 0x{start:x}..0x{end:x} ({} bytes)", end - start)?;
+        }
+        for start in &self.devices {
+            writeln!(f, "device at 0x{start:x} state changed")?;
+        }
+        for layout in &self.layout {
+            writeln!(f, "{layout}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_snapshot_file_format_and_diff() {
+    let mut a = Snapshot {
+        hart: HartState {
+            pc: 0x8000_0000,
+            csrs: vec![("mode".to_string(), 3), ("mstatus".to_string(), 0)],
+            ..Default::default()
+        },
+        time: 10,
+        rams: vec![RamState {
+            start: 0x8000_0000,
+            data: vec![0; 3 * PAGE_SIZE],
+        }],
+        devices: vec![DeviceState {
+            start: 0x1001_0000,
+            end: 0x1001_1000,
+            state: vec![1, 2, 3],
+        }],
+    };
+    a.rams[0].data[PAGE_SIZE + 1] = 0x55;
+    let bytes = a.to_bytes();
+    // only the non-zero page is saved
+    assert!(bytes.len() < PAGE_SIZE + 1024);
+    assert_eq!(Snapshot::from_bytes(&bytes), Ok(a.clone()));
+    assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut bad_version = bytes.clone();
+    bad_version[8] = 2;
+    assert_eq!(
+        Snapshot::from_bytes(&bad_version),
+        Err("unsupported snapshot version 2".to_string())
+    );
+    let ram_size = (3 * PAGE_SIZE as u64).to_le_bytes();
+    let size_pos = bytes.windows(8).position(|w| w == ram_size).unwrap();
+    let corrupt = |pos: usize, val: u64| {
+        let mut bytes = bytes.clone();
+        bytes[pos..pos + 8].copy_from_slice(&val.to_le_bytes());
+        Snapshot::from_bytes(&bytes)
+    };
+    assert!(corrupt(size_pos, u64::MAX).is_err());
+    assert!(corrupt(size_pos, MAX_RAM_SIZE + 1).is_err());
+    // page count
+    assert!(corrupt(size_pos + 8, u32::MAX as u64).is_err());
+    // page offset
+    assert!(corrupt(size_pos + 12, u64::MAX - 8).is_err());
+    assert!(corrupt(size_pos + 12, 2 * PAGE_SIZE as u64 + 1).is_err());
+    assert_eq!(corrupt(size_pos + 12, PAGE_SIZE as u64), Ok(a.clone()));
+
+    assert!(a.diff(&a).is_empty());
+    let mut b = a.clone();
+    b.hart.x[10] = 42;
+    b.hart.csrs[1].1 = 0x8;
+    b.rams[0].data[0x10..0x18].fill(0xff);
+    b.rams[0].data[0x20] = 1;
+    b.devices[0].state[0] = 0;
+    let diff = a.diff(&b);
+    assert_eq!(
+        diff.regs,
+        [
+            ("x10 (a0)".to_string(), 0, 42),
+            ("mstatus".to_string(), 0, 0x8)
+        ]
+    );
+    assert_eq!(
+        diff.mem,
+        [(0x8000_0010, 0x8000_0018), (0x8000_0020, 0x8000_0021)]
+    );
+    assert_eq!(diff.devices, [0x1001_0000]);
+}
+
+#[test]
+fn test_machine_snapshot_restore() {
+    use crate::csr;
+    use crate::machine::{DeviceKind, MachineBuilder};
+    let machine = || {
+        MachineBuilder::new()
+            .ram(0x8000_0000, 0x10000)
+            .device(DeviceKind::Uart16550, 0x1000_0000, Some(10))
+            .device(DeviceKind::Clint, 0x200_0000, None)
+            .device(
+                DeviceKind::VirtioRng { seed: Some(1) },
+                0x1000_3000,
+                Some(3),
+            )
+    };
+    let mut cpu = machine()
+        .device(DeviceKind::Plic, 0xc00_0000, None)
+        .build()
+        .unwrap();
+    let change = |cpu: &mut crate::rv64i_cpu::RV64ICpu, val: u64| {
+        cpu.regs_w64(5, val);
+        assert!(cpu.csr_w64(csr::MSCRATCH, val));
+        cpu.bus.write64(0x8000_8000, val).unwrap();
+        // UART scratch register, CLINT mtimecmp, PLIC priority of irq 10
+        cpu.bus.write8(0x1000_0007, val as u8).unwrap();
+        cpu.bus.write64(0x200_4000, val).unwrap();
+        cpu.bus.write32(0xc00_0028, val as u32 & 7).unwrap();
+        cpu.bus.tick();
+    };
+    change(&mut cpu, 5);
+    let snapshot = Snapshot::from_bytes(&cpu.save_snapshot().to_bytes()).unwrap();
+    change(&mut cpu, 6);
+    assert_eq!(snapshot.diff(&cpu.save_snapshot()).devices.len(), 3);
+    cpu.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(cpu.save_snapshot(), snapshot);
+    assert_eq!(cpu.bus.read8(0x1000_0007).unwrap(), 5);
+    assert_eq!(cpu.bus.time(), 1);
+
+    // the machine without PLIC has a different layout
+    let mut cpu = machine().build().unwrap();
+    assert!(cpu.restore_snapshot(&snapshot).is_err());
+}
@@ -0,0 +1,545 @@
+// Instruction trace: the effects of every retired instruction (register and CSR writes, memory
+// accesses) written in the format of Spike's --log-commits, optionally with Spike's -l
+// disassembly lines, or in a compact binary format. Instructions which trap don't retire and
+// aren't traced, like in Spike.
+// Binary format (little endian), version 1:
+//   "KOMPTRAC", version: u32
+//   records: instruction number: u64, hart: u8, privilege: u8, pc: u64, instruction: u32,
+//     u8 count of register writes (kind: u8 0 - x, 1 - f, 2 - CSR, index: u16, value: u64),
+//     u8 count of memory accesses (size: u8 with bit 7 set for writes, address: u64, data: u64)
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::rv64i_disasm::{csr_name, disasm};
+use crate::snapshot::{StateReader, StateWriter};
+
+pub const TRACE_MAGIC: &[u8; 8] = b"KOMPTRAC";
+pub const TRACE_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegWrite {
+    X(u8, u64),
+    F(u8, u64),
+    Csr(u16, u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemAccess {
+    /// Virtual address
+    pub addr: u64,
+    /// 1, 2, 4 or 8 bytes
+    pub size: u8,
+    pub data: u64,
+    pub write: bool,
+}
+
+/// Effects of an executed instruction
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Commit {
+    /// Number of instructions executed before this one
+    pub index: u64,
+    pub hart: u64,
+    /// Privilege level the instruction was executed in
+    pub priv_mode: u8,
+    pub pc: u64,
+    /// 16 bit for compressed instructions
+    pub instr: u32,
+    pub writes: Vec<RegWrite>,
+    /// Loads before stores (AMOs do both)
+    pub mem: Vec<MemAccess>,
+    /// Cause of the exception raised by the instruction, it didn't retire
+    pub trap: Option<u64>,
+}
+
+impl Commit {
+    pub fn is_compressed(&self) -> bool {
+        self.instr & 0b11 != 0b11
+    }
+
+    /// "core   0: 0x0000000080000000 (0x00000297) auipc x5, 0x0" like Spike's -l
+    pub fn spike_disasm(&self) -> String {
+        format!(
+            "core{:4}: 0x{:016x} ({}) {}",
+            self.hart,
+            self.pc,
+            self.spike_instr(),
+            disasm(self.instr, self.pc)
+        )
+    }
+
+    fn spike_instr(&self) -> String {
+        if self.is_compressed() {
+            format!("0x{:04x}", self.instr)
+        } else {
+            format!("0x{:08x}", self.instr)
+        }
+    }
+
+    /// "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000" like Spike's
+    /// --log-commits
+    pub fn spike_commit(&self) -> String {
+        let mut line = format!(
+            "core{:4}: {} 0x{:016x} ({})",
+            self.hart,
+            self.priv_mode,
+            self.pc,
+            self.spike_instr()
+        );
+        for write in &self.writes {
+            line += &match write {
+                RegWrite::X(i, val) => format!(" x{i:<2} 0x{val:016x}"),
+                RegWrite::F(i, val) => format!(" f{i:<2} 0x{val:016x}"),
+                RegWrite::Csr(csr, val) => format!(" c{csr}_{} 0x{val:016x}", csr_name(*csr)),
+            };
+        }
+        for access in self.mem.iter().filter(|a| !a.write) {
+            line += &format!(" mem 0x{:016x}", access.addr);
+        }
+        for access in self.mem.iter().filter(|a| a.write) {
+            let width = access.size as usize * 2;
+            line += &format!(" mem 0x{:016x} 0x{:0width$x}", access.addr, access.data);
+        }
+        line
+    }
+
+    /// Parses a line of Spike's --log-commits, other lines of Spike's log (disassembly,
+    /// exceptions) are None. The sizes of loads aren't logged, they're 0
+    pub fn parse_spike(line: &str) -> Result<Option<Commit>, String> {
+        let hex = |s: &str| {
+            s.strip_prefix("0x")
+                .and_then(|h| u64::from_str_radix(h, 16).ok())
+                .ok_or(format!("wrong hex number {s}"))
+        };
+        let Some((core, rest)) = line.strip_prefix("core").and_then(|l| l.split_once(':')) else {
+            return Ok(None);
+        };
+        let mut tokens = rest.split_whitespace().peekable();
+        let priv_mode = match tokens.peek().map(|t| t.parse::<u8>()) {
+            Some(Ok(priv_mode)) => priv_mode,
+            _ => return Ok(None),
+        };
+        tokens.next();
+        let hart = core
+            .trim()
+            .parse()
+            .map_err(|_| format!("wrong core {core}"))?;
+        let pc = hex(tokens.next().ok_or("no pc")?)?;
+        let instr = tokens
+            .next()
+            .and_then(|t| t.strip_prefix('(')?.strip_suffix(')'))
+            .ok_or("no instruction")?;
+        let mut commit = Commit {
+            hart,
+            priv_mode,
+            pc,
+            instr: hex(instr)? as u32,
+            ..Default::default()
+        };
+        while let Some(token) = tokens.next() {
+            let mut value = || hex(tokens.next().ok_or(format!("no value of {token}"))?);
+            if token == "mem" {
+                let addr = value()?;
+                let data = tokens.next_if(|t| t.starts_with("0x"));
+                // the store size is the width of the data: 2, 4, 8 or 16 digits
+                let size = match data.map(|d| d.len() - 2) {
+                    None => 0,
+                    Some(digits @ (2 | 4 | 8 | 16)) => digits as u8 / 2,
+                    Some(_) => return Err(format!("wrong width of store data {}", data.unwrap())),
+                };
+                commit.mem.push(MemAccess {
+                    addr,
+                    size,
+                    data: data.map(hex).transpose()?.unwrap_or(0),
+                    write: data.is_some(),
+                });
+            } else if let Some(csr) = token.strip_prefix('c').and_then(|c| c.split_once('_')) {
+                let csr = csr.0.parse().map_err(|_| format!("wrong CSR {token}"))?;
+                commit.writes.push(RegWrite::Csr(csr, value()?));
+            } else {
+                let reg = |prefix| {
+                    token
+                        .strip_prefix(prefix)
+                        .and_then(|i| i.parse::<u8>().ok())
+                        .filter(|&i| i < 32)
+                };
+                commit.writes.push(match (reg('x'), reg('f')) {
+                    (Some(i), _) => RegWrite::X(i, value()?),
+                    (_, Some(i)) => RegWrite::F(i, value()?),
+                    _ => return Err(format!("unknown commit item {token}")),
+                });
+            }
+        }
+        Ok(Some(commit))
+    }
+
+    /// An instruction has at most 255 register writes and memory accesses
+    pub fn write_binary(&self, w: &mut StateWriter) {
+        let count = |n: usize| u8::try_from(n).expect("trace: too many effects of an instruction");
+        w.u64(self.index);
+        w.u8(self.hart as u8);
+        w.u8(self.priv_mode);
+        w.u64(self.pc);
+        w.u32(self.instr);
+        w.u8(count(self.writes.len()));
+        for write in &self.writes {
+            let (kind, i, val) = match *write {
+                RegWrite::X(i, val) => (0, i as u16, val),
+                RegWrite::F(i, val) => (1, i as u16, val),
+                RegWrite::Csr(csr, val) => (2, csr, val),
+            };
+            w.u8(kind);
+            w.u16(i);
+            w.u64(val);
+        }
+        w.u8(count(self.mem.len()));
+        for access in &self.mem {
+            w.u8(access.size | (access.write as u8) << 7);
+            w.u64(access.addr);
+            w.u64(access.data);
+        }
+    }
+
+    pub fn read_binary(r: &mut StateReader) -> Result<Commit, String> {
+        let mut commit = Commit {
+            index: r.u64()?,
+            hart: r.u8()? as u64,
+            priv_mode: r.u8()?,
+            pc: r.u64()?,
+            instr: r.u32()?,
+            ..Default::default()
+        };
+        for _ in 0..r.u8()? {
+            let (kind, i, val) = (r.u8()?, r.u16()?, r.u64()?);
+            commit.writes.push(match kind {
+                0 => RegWrite::X(i as u8, val),
+                1 => RegWrite::F(i as u8, val),
+                2 => RegWrite::Csr(i, val),
+                _ => return Err(format!("trace: wrong register kind {kind}")),
+            });
+        }
+        for _ in 0..r.u8()? {
+            let size = r.u8()?;
+            commit.mem.push(MemAccess {
+                size: size & 0x7f,
+                write: size & 0x80 != 0,
+                addr: r.u64()?,
+                data: r.u64()?,
+            });
+        }
+        Ok(commit)
+    }
+}
+
+/// Reads all records of a binary trace
+pub fn read_binary_trace(data: &[u8]) -> Result<Vec<Commit>, String> {
+    let mut r = StateReader::new(data);
+    if r.take(TRACE_MAGIC.len()).ok() != Some(TRACE_MAGIC) {
+        return Err("not a binary trace".to_string());
+    }
+    let version = r.u32()?;
+    if version != TRACE_VERSION {
+        return Err(format!("unsupported trace version {version}"));
+    }
+    let mut commits = Vec::new();
+    while !r.is_empty() {
+        commits.push(Commit::read_binary(&mut r)?);
+    }
+    Ok(commits)
+}
+
+/// Reads the commit lines of a Spike log, the instruction numbers are their order
+pub fn read_spike_trace(text: &str) -> Result<Vec<Commit>, String> {
+    let mut commits = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if let Some(mut commit) =
+            Commit::parse_spike(line).map_err(|e| format!("line {}: {e}", n + 1))?
+        {
+            commit.index = commits.len() as u64;
+            commits.push(commit);
+        }
+    }
+    Ok(commits)
+}
+
+/// Reads a binary trace or a Spike log
+pub fn read_trace_file(path: &Path) -> Result<Vec<Commit>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("failed to read {path:?}: {e}"))?;
+    if data.starts_with(TRACE_MAGIC) {
+        read_binary_trace(&data)
+    } else {
+        read_spike_trace(&String::from_utf8_lossy(&data))
+    }
+    .map_err(|e| format!("{path:?}: {e}"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// Spike's --log-commits
+    Spike,
+    /// Spike's -l and --log-commits: a disassembly line before every commit line
+    SpikeDisasm,
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn parse(s: &str) -> Result<TraceFormat, String> {
+        match s {
+            "spike" => Ok(TraceFormat::Spike),
+            "spike-disasm" => Ok(TraceFormat::SpikeDisasm),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!(
+                "unknown trace format {s}, should be spike, spike-disasm or binary"
+            )),
+        }
+    }
+}
+
+/// Which instructions are traced, the ranges are [start, end)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    pub pc: Option<(u64, u64)>,
+    /// Instruction numbers (the number of instructions executed before)
+    pub instr: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, commit: &Commit) -> bool {
+        let within =
+            |range: Option<(u64, u64)>, val| range.is_none_or(|(s, e)| s <= val && val < e);
+        within(self.pc, commit.pc) && within(self.instr, commit.index)
+    }
+}
+
+/// Parses "start:end" with either side optional (an open range), numbers in hex with "0x"
+pub fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    let number = |n: &str, default| -> Result<u64, String> {
+        let n = n.trim().replace('_', "");
+        if n.is_empty() {
+            return Ok(default);
+        }
+        match n.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => n.parse(),
+        }
+        .map_err(|_| format!("wrong number {n} in range {s}"))
+    };
+    let Some((start, end)) = s.split_once(':') else {
+        return Err(format!("range {s} should be start:end"));
+    };
+    Ok((number(start, 0)?, number(end, u64::MAX)?))
+}
+
+/// Writes the traced instructions
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn new(mut out: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Tracer {
+        if format == TraceFormat::Binary {
+            let mut w = StateWriter::new();
+            w.raw(TRACE_MAGIC);
+            w.u32(TRACE_VERSION);
+            if let Err(e) = out.write_all(&w.into_bytes()) {
+                eprintln!("ERROR: failed to write the trace: {e}");
+            }
+        }
+        Tracer {
+            out,
+            format,
+            filter,
+        }
+    }
+
+    /// Traces to the file, "-" - stdout
+    pub fn to_file(path: &Path, format: TraceFormat, filter: TraceFilter) -> io::Result<Tracer> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Tracer::new(out, format, filter))
+    }
+
+    pub fn trace(&mut self, commit: &Commit) {
+        if commit.trap.is_some() || !self.filter.matches(commit) {
+            return;
+        }
+        let res = match self.format {
+            TraceFormat::Spike => writeln!(self.out, "{}", commit.spike_commit()),
+            TraceFormat::SpikeDisasm => writeln!(
+                self.out,
+                "{}\n{}",
+                commit.spike_disasm(),
+                commit.spike_commit()
+            ),
+            TraceFormat::Binary => {
+                let mut w = StateWriter::new();
+                commit.write_binary(&mut w);
+                self.out.write_all(&w.into_bytes())
+            }
+        };
+        if let Err(e) = res {
+            eprintln!("ERROR: failed to write the trace: {e}. Tracing is stopped");
+            self.out = Box::new(io::sink());
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            eprintln!("ERROR: failed to write the trace: {e}");
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[test]
+fn test_trace_formats() {
+    let mut commit = Commit {
+        index: 5,
+        priv_mode: 3,
+        pc: 0x8000_0000,
+        instr: 0x0000_0297, // auipc t0,0
+        writes: vec![RegWrite::X(5, 0x8000_0000)],
+        ..Default::default()
+    };
+    assert_eq!(
+        commit.spike_commit(),
+        "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000"
+    );
+    commit.writes = vec![RegWrite::Csr(0x300, 0x80)];
+    commit.mem = vec![
+        MemAccess {
+            addr: 0x8000_1000,
+            size: 4,
+            data: 0x42,
+            write: true,
+        },
+        MemAccess {
+            addr: 0x8000_1008,
+            size: 8,
+            data: 0,
+            write: false,
+        },
+    ];
+    assert_eq!(
+        commit.spike_commit(),
+        "core   0: 3 0x0000000080000000 (0x00000297) c768_mstatus 0x0000000000000080 \
+         mem 0x0000000080001008 mem 0x0000000080001000 0x00000042"
+    );
+    let compressed = Commit {
+        instr: 0x4505, // c.li a0,1
+        ..commit.clone()
+    };
+    assert!(compressed.spike_disasm().contains("(0x4505)"));
+
+    let parsed = Commit::parse_spike(&commit.spike_commit())
+        .unwrap()
+        .unwrap();
+    assert_eq!(parsed.writes, commit.writes);
+    assert_eq!(parsed.mem[0].size, 0); // the load
+    assert_eq!(parsed.mem[1], commit.mem[0]);
+    assert_eq!(Commit::parse_spike(&compressed.spike_disasm()), Ok(None));
+    assert_eq!(
+        read_spike_trace(&format!(
+            "{}\ncore   0: exception illegal_instruction, epc 0x0000000080000004\n{}",
+            compressed.spike_disasm(),
+            compressed.spike_commit()
+        ))
+        .unwrap()
+        .len(),
+        1
+    );
+    assert!(Commit::parse_spike("core   0: 3 0x0000000080000000 (0x00000297) v1 0x0").is_err());
+    let store = |data: &str| {
+        Commit::parse_spike(&format!(
+            "core   0: 3 0x0000000080000000 (0x00000297) mem 0x0000000080001000 {data}"
+        ))
+    };
+    assert_eq!(store("0x0042").unwrap().unwrap().mem[0].size, 2);
+    assert!(store("0x042").is_err());
+    assert!(store("0x").is_err());
+    assert!(store("0x000000000000000042").is_err());
+    assert!(store(&format!("0x{}", "0".repeat(300))).is_err());
+
+    let mut w = StateWriter::new();
+    w.raw(TRACE_MAGIC);
+    w.u32(TRACE_VERSION);
+    commit.write_binary(&mut w);
+    compressed.write_binary(&mut w);
+    let data = w.into_bytes();
+    assert_eq!(
+        read_binary_trace(&data).unwrap(),
+        [commit.clone(), compressed]
+    );
+    assert!(read_binary_trace(&data[..data.len() - 1]).is_err());
+
+    let filter = TraceFilter {
+        pc: Some(parse_range("0x80000000:0x80000004").unwrap()),
+        instr: Some(parse_range("5:").unwrap()),
+    };
+    assert!(filter.matches(&commit));
+    commit.index = 4;
+    assert!(!filter.matches(&commit));
+    assert!(parse_range("10").is_err());
+}
+
+#[test]
+#[should_panic(expected = "too many effects")]
+fn test_write_binary_too_many_writes() {
+    let commit = Commit {
+        writes: vec![RegWrite::X(1, 0); 256],
+        ..Default::default()
+    };
+    commit.write_binary(&mut StateWriter::new());
+}
+
+#[test]
+fn test_trace_exec() {
+    use crate::csr;
+    use crate::rv64i_cpu::cpu_with_program;
+
+    let program: [u32; 6] = [
+        0x04200293, // li t0,0x42
+        0x34029373, // csrrw t1,mscratch,t0
+        0x1052b023, // sd t0,0x100(t0) (0x142)
+        0x1002b383, // ld t2,0x100(t0)
+        0x00000073, // ecall (traps, not traced)
+        0x0000006f, // j .
+    ];
+    let mut cpu = cpu_with_program(0, &program);
+    assert!(cpu.csr_w64(csr::MTVEC, 0x14));
+    let path = std::env::temp_dir().join(format!("kompusim-trace-{}.log", std::process::id()));
+    let filter = TraceFilter {
+        pc: None,
+        instr: Some((1, u64::MAX)),
+    };
+    cpu.set_tracer(Some(
+        Tracer::to_file(&path, TraceFormat::Spike, filter).unwrap(),
+    ));
+    cpu.exec_continue(6);
+    cpu.set_tracer(None);
+    let trace = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        trace.lines().collect::<Vec<_>>(),
+        [
+            "core   0: 3 0x0000000000000004 (0x34029373) c832_mscratch 0x0000000000000042 \
+             x6  0x0000000000000000",
+            "core   0: 3 0x0000000000000008 (0x1052b023) mem 0x0000000000000142 \
+             0x0000000000000042",
+            "core   0: 3 0x000000000000000c (0x1002b383) x7  0x0000000000000042 \
+             mem 0x0000000000000142",
+            "core   0: 3 0x0000000000000014 (0x0000006f)",
+        ]
+    );
+}
@@ -0,0 +1,398 @@
+// Comparison of two instruction traces for co-simulation debugging, e.g. ours and one of Spike
+// or an RTL simulation. The traces are aligned at the first common PC (a boot ROM may run before
+// the other trace starts) and compared record by record. The first divergence is reported with
+// the records around it and the registers reconstructed from the register writes of both traces.
+// Known nondeterministic differences are tolerated with ignore rules: the values read from
+// counter CSRs (cycle, time, ...) or from memory ranges (mtime), any difference at given PCs.
+// Values computed later from an ignored value still differ, they need --ignore-pc.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::bits::BitOps;
+use crate::rv64i_disasm::{csr_name, reg_idx2abi};
+use crate::trace::{parse_range, Commit, RegWrite};
+
+/// CSRs ignored by default, they count cycles or time
+pub const NONDETERMINISTIC_CSRS: &str = "cycle,time,instret,mcycle,minstret";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IgnoreRules {
+    /// Values read from these CSRs and written to them
+    pub csrs: Vec<u16>,
+    /// Data of memory accesses in these ranges [start, end) and the loaded registers
+    pub mem: Vec<(u64, u64)>,
+    /// Any difference other than the PC of the instructions in these ranges
+    pub pc: Vec<(u64, u64)>,
+}
+
+impl IgnoreRules {
+    /// Parses a comma separated list of CSR names or numbers
+    pub fn parse_csrs(list: &str) -> Result<Vec<u16>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let number = match name.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                    None => name.parse().ok(),
+                };
+                number
+                    .or_else(|| (0..0x1000).find(|&csr| csr_name(csr) == name))
+                    .ok_or(format!("unknown CSR {name}"))
+            })
+            .collect()
+    }
+
+    /// Parses a comma separated list of ranges "start:end"
+    pub fn parse_ranges(list: &str) -> Result<Vec<(u64, u64)>, String> {
+        list.split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(parse_range)
+            .collect()
+    }
+
+    fn ignored_pc(&self, pc: u64) -> bool {
+        self.pc.iter().any(|&(s, e)| s <= pc && pc < e)
+    }
+
+    fn ignored_mem(&self, addr: u64) -> bool {
+        self.mem.iter().any(|&(s, e)| s <= addr && addr < e)
+    }
+
+    /// The registers written by the instruction have nondeterministic values
+    fn nondeterministic(&self, commit: &Commit) -> bool {
+        let instr = commit.instr;
+        let csr_read = !commit.is_compressed()
+            && instr.bits(6, 0) == 0b111_0011
+            && instr.bits(14, 12) != 0
+            && self.csrs.contains(&(instr.bits(31, 20) as u16));
+        csr_read
+            || commit
+                .mem
+                .iter()
+                .any(|access| !access.write && self.ignored_mem(access.addr))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord)]
+enum Reg {
+    X(u8),
+    F(u8),
+    Csr(u16),
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Reg::X(i) => write!(f, "x{i}"),
+            Reg::F(i) => write!(f, "f{i}"),
+            Reg::Csr(csr) => write!(f, "{}", csr_name(csr)),
+        }
+    }
+}
+
+fn reg_writes(commit: &Commit, ignore: &IgnoreRules) -> BTreeMap<Reg, u64> {
+    let nondeterministic = ignore.nondeterministic(commit);
+    commit
+        .writes
+        .iter()
+        .filter_map(|write| match *write {
+            RegWrite::X(i, val) if !nondeterministic => Some((Reg::X(i), val)),
+            RegWrite::F(i, val) if !nondeterministic => Some((Reg::F(i), val)),
+            RegWrite::Csr(csr, val) if !ignore.csrs.contains(&csr) => Some((Reg::Csr(csr), val)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Differences between two records of the same instruction
+pub fn compare_commits(a: &Commit, b: &Commit, ignore: &IgnoreRules) -> Vec<String> {
+    if a.pc != b.pc {
+        return vec![format!("pc 0x{:x} != 0x{:x}", a.pc, b.pc)];
+    }
+    let mut differences = Vec::new();
+    if ignore.ignored_pc(a.pc) {
+        return differences;
+    }
+    if a.instr != b.instr {
+        differences.push(format!("instruction 0x{:x} != 0x{:x}", a.instr, b.instr));
+    }
+    if a.priv_mode != b.priv_mode {
+        differences.push(format!("privilege {} != {}", a.priv_mode, b.priv_mode));
+    }
+    let (writes_a, writes_b) = (reg_writes(a, ignore), reg_writes(b, ignore));
+    for reg in writes_a
+        .keys()
+        .chain(writes_b.keys().filter(|r| !writes_a.contains_key(r)))
+    {
+        match (writes_a.get(reg), writes_b.get(reg)) {
+            (Some(va), Some(vb)) if va == vb => (),
+            (Some(va), Some(vb)) => differences.push(format!("{reg} 0x{va:x} != 0x{vb:x}")),
+            (Some(_), None) => differences.push(format!("{reg} written only in a")),
+            _ => differences.push(format!("{reg} written only in b")),
+        }
+    }
+    // Spike logs the loads before the stores
+    let mut mem_a: Vec<_> = a.mem.iter().collect();
+    let mut mem_b: Vec<_> = b.mem.iter().collect();
+    mem_a.sort_by_key(|access| access.write);
+    mem_b.sort_by_key(|access| access.write);
+    if mem_a.len() != mem_b.len() {
+        differences.push(format!(
+            "{} memory accesses != {}",
+            mem_a.len(),
+            mem_b.len()
+        ));
+    }
+    for (ma, mb) in mem_a.iter().zip(mem_b) {
+        let kind = if ma.write { "store" } else { "load" };
+        if ma.write != mb.write || ma.addr != mb.addr {
+            differences.push(format!(
+                "{kind} 0x{:x} != {} 0x{:x}",
+                ma.addr,
+                if mb.write { "store" } else { "load" },
+                mb.addr
+            ));
+        } else if ma.data != mb.data && ma.size != 0 && mb.size != 0 && !ignore.ignored_mem(ma.addr)
+        {
+            differences.push(format!(
+                "{kind} 0x{:x} data 0x{:x} != 0x{:x}",
+                ma.addr, ma.data, mb.data
+            ));
+        }
+    }
+    differences
+}
+
+/// Registers reconstructed from the register writes of a trace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegState {
+    pub x: [u64; 32],
+}
+
+impl RegState {
+    fn apply(&mut self, commit: &Commit) {
+        for write in &commit.writes {
+            if let RegWrite::X(i @ 1..=31, val) = *write {
+                self.x[i as usize] = val;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// Positions of the diverging records in the traces
+    pub a: usize,
+    pub b: usize,
+    pub differences: Vec<String>,
+    /// Registers before the diverging instruction
+    pub regs_a: RegState,
+    pub regs_b: RegState,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceDiff {
+    /// Records skipped at the start of the traces to align them
+    pub skipped: (usize, usize),
+    /// Number of records compared equal
+    pub equal: usize,
+    pub divergence: Option<Divergence>,
+    /// Number of records of the traces
+    pub len: (usize, usize),
+}
+
+/// Positions of the first records with the same PC: the start of one trace in the other one
+fn align(a: &[Commit], b: &[Commit]) -> (usize, usize) {
+    let (Some(first_a), Some(first_b)) = (a.first(), b.first()) else {
+        return (0, 0);
+    };
+    if let Some(i) = b.iter().position(|c| c.pc == first_a.pc) {
+        return (0, i);
+    }
+    if let Some(i) = a.iter().position(|c| c.pc == first_b.pc) {
+        return (i, 0);
+    }
+    (0, 0)
+}
+
+pub fn diff_traces(a: &[Commit], b: &[Commit], ignore: &IgnoreRules) -> TraceDiff {
+    let skipped = align(a, b);
+    let (mut regs_a, mut regs_b) = (RegState::default(), RegState::default());
+    a[..skipped.0].iter().for_each(|c| regs_a.apply(c));
+    b[..skipped.1].iter().for_each(|c| regs_b.apply(c));
+    let mut diff = TraceDiff {
+        skipped,
+        equal: 0,
+        divergence: None,
+        len: (a.len(), b.len()),
+    };
+    for (ca, cb) in a[skipped.0..].iter().zip(&b[skipped.1..]) {
+        let differences = compare_commits(ca, cb, ignore);
+        if !differences.is_empty() {
+            diff.divergence = Some(Divergence {
+                a: skipped.0 + diff.equal,
+                b: skipped.1 + diff.equal,
+                differences,
+                regs_a,
+                regs_b,
+            });
+            break;
+        }
+        regs_a.apply(ca);
+        regs_b.apply(cb);
+        diff.equal += 1;
+    }
+    diff
+}
+
+impl TraceDiff {
+    /// Records left in the traces after the compared ones
+    pub fn trailing(&self) -> (usize, usize) {
+        (
+            self.len.0 - self.skipped.0 - self.equal,
+            self.len.1 - self.skipped.1 - self.equal,
+        )
+    }
+
+    /// No divergence and both traces end together, one ending early (e.g. a truncated or hung
+    /// run) is a mismatch
+    pub fn matches(&self) -> bool {
+        self.divergence.is_none() && self.trailing() == (0, 0)
+    }
+
+    /// Text report, the divergence is shown with `context` records before and after it
+    pub fn report(&self, a: &[Commit], b: &[Commit], context: usize) -> String {
+        let mut report = String::new();
+        if self.skipped.0 != 0 {
+            report += &format!(
+                "skipped {} records of a to align it with b\n",
+                self.skipped.0
+            );
+        }
+        if self.skipped.1 != 0 {
+            report += &format!(
+                "skipped {} records of b to align it with a\n",
+                self.skipped.1
+            );
+        }
+        let Some(div) = &self.divergence else {
+            report += &format!("no divergence in {} records", self.equal);
+            match self.trailing() {
+                (0, 0) => {}
+                (0, n) => report += &format!(", a ends early, b has {n} more"),
+                (n, _) => report += &format!(", b ends early, a has {n} more"),
+            }
+            report.push('\n');
+            return report;
+        };
+        report += &format!(
+            "first divergence after {} equal records: a record {} (instruction {}), b record {} \
+             (instruction {})\n",
+            self.equal, div.a, a[div.a].index, div.b, b[div.b].index
+        );
+        for difference in &div.differences {
+            report += &format!("  {difference}\n");
+        }
+        for (name, trace, pos) in [("a", a, div.a), ("b", b, div.b)] {
+            report += &format!("{name}:\n");
+            for (i, commit) in trace
+                .iter()
+                .enumerate()
+                .take(pos + context + 1)
+                .skip(pos.saturating_sub(context))
+            {
+                let mark = if i == pos { ">" } else { " " };
+                report += &format!("{mark} {}\n", commit.spike_commit());
+            }
+        }
+        report += "registers before the instruction (reconstructed from the traces):\n";
+        for i in 1..32 {
+            let (va, vb) = (div.regs_a.x[i], div.regs_b.x[i]);
+            report += &format!(
+                "  x{i:<2} {:<4} 0x{va:016x} 0x{vb:016x}{}\n",
+                reg_idx2abi(i as u8),
+                if va != vb { " *" } else { "" }
+            );
+        }
+        report
+    }
+}
+
+#[test]
+fn test_trace_diff() {
+    use crate::trace::MemAccess;
+
+    let commit = |pc: u64, instr: u32, writes: Vec<RegWrite>| Commit {
+        pc,
+        instr,
+        priv_mode: 3,
+        writes,
+        ..Default::default()
+    };
+    let boot = commit(0x1000, 0x00000297, vec![RegWrite::X(5, 0x1000)]);
+    let a = vec![
+        commit(0x8000_0000, 0x04200293, vec![RegWrite::X(5, 0x42)]), // li t0,0x42
+        commit(0x8000_0004, 0xc0102373, vec![RegWrite::X(6, 1234)]), // rdtime t1
+        Commit {
+            mem: vec![MemAccess {
+                addr: 0x200_bff8,
+                size: 8,
+                data: 1,
+                write: false,
+            }],
+            ..commit(0x8000_0008, 0x0002b383, vec![RegWrite::X(7, 1)]) // ld t2,0(t0)
+        },
+        commit(0x8000_000c, 0x00128293, vec![RegWrite::X(5, 0x43)]), // addi t0,t0,1
+    ];
+    let mut b = vec![boot];
+    b.extend(a.iter().cloned());
+    b[2].writes = vec![RegWrite::X(6, 5678)];
+    b[3].writes = vec![RegWrite::X(7, 2)];
+    b[3].mem[0].data = 2;
+
+    let ignore = IgnoreRules {
+        csrs: IgnoreRules::parse_csrs(NONDETERMINISTIC_CSRS).unwrap(),
+        mem: IgnoreRules::parse_ranges("0x200bff8:0x200c000").unwrap(),
+        pc: vec![],
+    };
+    let diff = diff_traces(&a, &b, &ignore);
+    assert_eq!(diff.skipped, (0, 1));
+    assert_eq!(diff.equal, 4);
+    assert_eq!(diff.divergence, None);
+    assert!(diff
+        .report(&a, &b, 2)
+        .contains("no divergence in 4 records\n"));
+    assert!(diff.matches());
+    // a truncated trace
+    let diff = diff_traces(&a[..3], &b, &ignore);
+    assert_eq!((&diff.divergence, diff.trailing()), (&None, (0, 1)));
+    assert!(!diff.matches());
+    assert!(diff
+        .report(&a[..3], &b, 2)
+        .contains("no divergence in 3 records, a ends early, b has 1 more\n"));
+
+    let diff = diff_traces(&a, &b, &IgnoreRules::default());
+    let div = diff.divergence.as_ref().unwrap();
+    assert_eq!((div.a, div.b), (1, 2));
+    assert_eq!(div.differences, ["x6 0x4d2 != 0x162e"]);
+    assert_eq!(div.regs_a.x[5], 0x42);
+    let report = diff.report(&a, &b, 1);
+    assert!(report.contains("> core   0: 3 0x0000000080000004 (0xc0102373) x6  0x00000000000004d2"));
+    assert!(report.contains("x6  t1   0x0000000000000000 0x0000000000000000\n"));
+
+    b[4].pc = 0x8000_0010;
+    let ignore = IgnoreRules {
+        pc: IgnoreRules::parse_ranges("0x80000004:0x8000000c").unwrap(),
+        ..Default::default()
+    };
+    let div = diff_traces(&a, &b, &ignore).divergence.unwrap();
+    assert_eq!(div.differences, ["pc 0x8000000c != 0x80000010"]);
+    assert_eq!((div.regs_a.x[7], div.regs_b.x[7]), (1, 2));
+    assert_eq!(
+        IgnoreRules::parse_csrs("mcycle, 0xc01"),
+        Ok(vec![0xb00, 0xc01])
+    );
+    assert!(IgnoreRules::parse_csrs("cycles").is_err());
+}
@@ -3,8 +3,11 @@ use owo_colors::OwoColorize;
 use text_io::read;
 
 use kompusim::{
+    backtrace::Backtrace,
     bits::BitOps,
-    rv64i_cpu::RV64IURegs,
+    breakpoint::{Breakpoint, Condition},
+    elf::SymbolMap,
+    rv64i_cpu::{RV64IURegs, WatchHit, WatchKind, Watchpoint},
     rv64i_disasm::{disasm, reg_hex, reg_idx2abi},
 };
 
@@ -12,12 +15,33 @@ use kompusim::{
 pub enum TuiMenuCmd {
     Step(u64),
     Continue,
+    /// Undo N instructions
+    StepBack(u64),
+    /// Step back to the previous breakpoint or watchpoint hit
+    ReverseContinue,
     Quit,
     PrintRegister(u8),
     PrintAllRegisters,
     DumpMem(u64, u64),
     /// Disassembler and list n_instr instructions strarting at PC+pc_offset (pc_offset, n_instr)
     Disasm(i8, usize),
+    AddWatchpoint(Watchpoint),
+    ListWatchpoints,
+    /// Add or replace the breakpoint at the address
+    SetBreakpoint(Breakpoint),
+    ListBreakpoints,
+    /// Enable/disable the breakpoint at the address
+    EnableBreakpoint(u64, bool),
+    /// Ignore the next N hits of the breakpoint at the address
+    IgnoreBreakpoint(u64, u64),
+    /// Delete the breakpoint at the address, all breakpoints if None
+    DeleteBreakpoint(Option<u64>),
+    /// Save the machine snapshot to the file
+    SaveSnapshot(String),
+    /// Restore the machine from the snapshot file
+    LoadSnapshot(String),
+    /// Print the call stack
+    Backtrace,
 }
 
 fn print_green_line() {
@@ -100,6 +124,54 @@ fn parse_pr(s: &str) -> Option<u8> {
     None
 }
 
+/// Parses "w <addr> [len] [r|w|a]", e.g. "w 0x80001000 4 r". Watches 8 bytes for writes by
+/// default.
+fn parse_w(s: &str) -> Option<Watchpoint> {
+    let mut args = s.split_whitespace().skip(1);
+    let addr = u64::from_str_radix(args.next()?.trim_start_matches("0x"), 16).ok()?;
+    let mut watchpoint = Watchpoint {
+        addr,
+        len: 8,
+        kind: WatchKind::Write,
+    };
+    for arg in args {
+        match arg {
+            "r" => watchpoint.kind = WatchKind::Read,
+            "w" => watchpoint.kind = WatchKind::Write,
+            "a" => watchpoint.kind = WatchKind::Access,
+            len => watchpoint.len = len.parse().ok().filter(|&len| len > 0)?,
+        }
+    }
+    Some(watchpoint)
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Parses "b <addr> [if <condition>]" and "tb <addr> [if <condition>]" (temporary)
+fn parse_b(s: &str) -> Result<Breakpoint, String> {
+    let (cmd, args) = s.trim().split_once(' ').ok_or("no address")?;
+    let (addr, condition) = match args.split_once(" if ") {
+        Some((addr, condition)) => (addr, Some(Condition::parse(condition)?)),
+        None => (args, None),
+    };
+    let addr = parse_hex(addr).ok_or(format!("wrong address {addr}"))?;
+    Ok(Breakpoint {
+        condition,
+        temporary: cmd == "tb",
+        ..Breakpoint::new(addr)
+    })
+}
+
+/// Parses "ign <addr> <count>"
+fn parse_ign(s: &str) -> Option<(u64, u64)> {
+    let mut args = s.split_whitespace().skip(1);
+    let addr = parse_hex(args.next()?)?;
+    let count = args.next()?.parse().ok()?;
+    Some((addr, count))
+}
+
 /// Parses command list, e.g.: "li 20" to (-4, 20)
 fn parse_cmd_di(l: &str) -> (i8, usize) {
     if let Some(n_instr) = l.trim().find(|c: char| c.is_ascii_whitespace()) {
@@ -129,12 +201,24 @@ fn print_help() {
          di [N]   disassembler N (default: 10) instructions starting at PC\n\
          c        continue (run until a fault or breakpoint hits)\n\
          s [N]    step N (default: 1) instructions\n\
+         sb [N]   step back N (default: 1) instructions\n\
+         rc       reverse continue (step back until a breakpoint or watchpoint hits)\n\
          sa       step automatically until a fault or breakpoint hits (NOT IMPLEMENTED)\n\
          pr       print all registers\n\
          pr <r>   print register <r>\n\
-         b <a>    set breakpoint (NOT IMPLEMENTED)\n\
-         lb       list breakpoints (NOT IMPLEMENTED)\n\
-         dm [a] [s]   dump memory at address <addr>"
+         bt       print backtrace (call stack)\n\
+         b <a> [if <cond>]   set breakpoint, stop only if <cond> is true, e.g. a0 == 1 && [sp+8] != 0\n\
+         tb <a> [if <cond>]  set temporary breakpoint, deleted when it stops\n\
+         lb       list breakpoints\n\
+         db [a]   delete breakpoint at <a> or all breakpoints\n\
+         en <a>   enable breakpoint\n\
+         dis <a>  disable breakpoint\n\
+         ign <a> <N>  ignore next N hits of breakpoint\n\
+         w <a> [n] [r|w|a]  watch n (default: 8) bytes at <a> for reads, writes (default) or both\n\
+         lw       list watchpoints\n\
+         dm [a] [s]   dump memory at address <addr>\n\
+         save <f> save machine snapshot to file <f>\n\
+         load <f> restore machine from snapshot file <f>"
     );
     // TODO: add dm x0 <size> dump from pointer in x0
 }
@@ -158,12 +242,72 @@ fn parse_command(l: String) -> Option<TuiMenuCmd> {
     if cmd.starts_with('c') {
         return Some(TuiMenuCmd::Continue);
     }
+    if l.starts_with("save") || l.starts_with("load") {
+        match l.split_whitespace().nth(1) {
+            Some(path) if l.starts_with("save") => {
+                return Some(TuiMenuCmd::SaveSnapshot(path.to_string()))
+            }
+            Some(path) => return Some(TuiMenuCmd::LoadSnapshot(path.to_string())),
+            None => {
+                println!("format shoud be: save <file> or load <file>");
+                return None;
+            }
+        }
+    }
+    if cmd == "sb" {
+        return Some(TuiMenuCmd::StepBack(parse_cmd_with_number(&l).unwrap_or(1)));
+    }
+    if cmd == "rc" {
+        return Some(TuiMenuCmd::ReverseContinue);
+    }
+    if cmd == "bt" {
+        return Some(TuiMenuCmd::Backtrace);
+    }
     if cmd.starts_with('s') {
         if let Some(n_steps) = parse_cmd_with_number(&l) {
             return Some(TuiMenuCmd::Step(n_steps));
         }
         return Some(TuiMenuCmd::Step(1));
     }
+    if cmd.trim_end() == "b" || cmd == "tb" {
+        match parse_b(&l) {
+            Ok(breakpoint) => return Some(TuiMenuCmd::SetBreakpoint(breakpoint)),
+            Err(e) => {
+                println!("{e}. Example:\nb 0x80000010 if a0 == 0x42 && [sp+8] != 0");
+                return None;
+            }
+        }
+    }
+    if cmd == "lb" {
+        return Some(TuiMenuCmd::ListBreakpoints);
+    }
+    if cmd == "db" {
+        match l.split_whitespace().nth(1) {
+            None => return Some(TuiMenuCmd::DeleteBreakpoint(None)),
+            Some(addr) => match parse_hex(addr) {
+                Some(addr) => return Some(TuiMenuCmd::DeleteBreakpoint(Some(addr))),
+                None => {
+                    println!("format shoud be: db [hex_addr]");
+                    return None;
+                }
+            },
+        }
+    }
+    if cmd == "en" || l.starts_with("dis") {
+        let addr = l.split_whitespace().nth(1).and_then(parse_hex);
+        if let Some(addr) = addr {
+            return Some(TuiMenuCmd::EnableBreakpoint(addr, cmd == "en"));
+        }
+        println!("format shoud be: en <hex_addr> or dis <hex_addr>");
+        return None;
+    }
+    if l.starts_with("ign") {
+        if let Some((addr, count)) = parse_ign(&l) {
+            return Some(TuiMenuCmd::IgnoreBreakpoint(addr, count));
+        }
+        println!("format shoud be: ign <hex_addr> <count>");
+        return None;
+    }
     if cmd.starts_with("pr") {
         if let Some(reg_i) = parse_pr(&l) {
             return Some(TuiMenuCmd::PrintRegister(reg_i));
@@ -178,6 +322,14 @@ fn parse_command(l: String) -> Option<TuiMenuCmd> {
     } else if cmd.starts_with("di") {
         let (pc_offset, n_instr) = parse_cmd_di(&l);
         return Some(TuiMenuCmd::Disasm(pc_offset, n_instr));
+    } else if cmd.starts_with("lw") {
+        return Some(TuiMenuCmd::ListWatchpoints);
+    } else if cmd.starts_with('w') {
+        if let Some(watchpoint) = parse_w(&l) {
+            return Some(TuiMenuCmd::AddWatchpoint(watchpoint));
+        }
+        println!("format shoud be: w <hex_addr> [len] [r|w|a]. Example:\nw 0x80001000 4 a");
+        return None;
     }
     println!("unrecognized command");
     None
@@ -379,6 +531,46 @@ pub fn print_instr_listing(instructions: Vec<u32>, instr_start_addr: u64, pc_add
 }
 
 #[inline(always)]
+pub fn print_watch_hit(hit: &WatchHit) {
+    let access = if hit.write { "write" } else { "read" };
+    println!(
+        "{} {:?} watchpoint 0x{:x} ({} bytes): {access} of {} bytes at 0x{:x} by PC 0x{:x}",
+        "Hit".red().bold(),
+        hit.watchpoint.kind,
+        hit.watchpoint.addr,
+        hit.watchpoint.len,
+        hit.size,
+        hit.addr,
+        hit.pc
+    );
+    println!("old: 0x{:x}, new: 0x{:x}", hit.old, hit.new);
+}
+
+pub fn print_breakpoints(breakpoints: &[Breakpoint]) {
+    if breakpoints.is_empty() {
+        println!("no breakpoints");
+    }
+    for b in breakpoints {
+        println!("{b}");
+    }
+}
+
+pub fn print_watchpoints(watchpoints: &[Watchpoint]) {
+    if watchpoints.is_empty() {
+        println!("no watchpoints");
+    }
+    for (i, w) in watchpoints.iter().enumerate() {
+        println!("{i}: 0x{:x} {} bytes {:?}", w.addr, w.len, w.kind);
+    }
+}
+
+pub fn print_backtrace(backtrace: &Backtrace, symbols: Option<&SymbolMap>) {
+    for line in backtrace.lines(symbols) {
+        println!("{line}");
+    }
+    println!("(unwound with {})", backtrace.unwind);
+}
+
 pub fn align16(n: u64) -> u64 {
     n & !0xf_u64
 }
@@ -411,7 +603,7 @@ fn __dump_mem(m: Option<&[u8]>, addr: u64, size: u64) -> String {
     for (i, b) in m[..aligned_size as usize].iter().enumerate() {
         let i = i as u64;
         if i == size {
-            if i % 16 != 0 {
+            if !i.is_multiple_of(16) {
                 let mid_blank = if i % 16 < 8 { 1 } else { 0 };
                 let left_blanks = mid_blank + 3 * (16 - (i % 16));
                 line.push_str(&format!("{:1$}", " ", left_blanks as usize));
@@ -419,12 +611,12 @@ fn __dump_mem(m: Option<&[u8]>, addr: u64, size: u64) -> String {
             line.push_str(&format!("| {} |\n", pr_str));
             break;
         }
-        if i > 0 && i % 16 == 0 {
+        if i > 0 && i.is_multiple_of(16) {
             line.push_str(&format!("| {} |\n", pr_str));
             line.push_str(&format!("{:016x} ", aligned_addr + i));
             pr_str.clear();
         }
-        if i % 8 == 0 {
+        if i.is_multiple_of(8) {
             line.push(' ');
         }
         line.push_str(&format!("{:02x} ", b));
@@ -466,6 +658,46 @@ fn test_tui_commands() {
         parse_command("dm 0x800000c0 16".to_string()) == Some(TuiMenuCmd::DumpMem(0x800000c0, 16))
     );
     assert!(parse_command("di 16".to_string()) == Some(TuiMenuCmd::Disasm(-4, 16)));
+    assert!(
+        parse_command("w 0x1000 4 a".to_string())
+            == Some(TuiMenuCmd::AddWatchpoint(Watchpoint {
+                addr: 0x1000,
+                len: 4,
+                kind: WatchKind::Access
+            }))
+    );
+    assert!(parse_w("w 0x1000").is_some_and(|w| w.len == 8 && w.kind == WatchKind::Write));
+    assert!(parse_w("w 0x1000 0").is_none());
+    assert!(parse_command("lw".to_string()) == Some(TuiMenuCmd::ListWatchpoints));
+    assert!(
+        parse_command("tb 0x80000010 if a0 == 1".to_string())
+            == Some(TuiMenuCmd::SetBreakpoint(Breakpoint {
+                condition: Some(Condition::parse("a0 == 1").unwrap()),
+                temporary: true,
+                ..Breakpoint::new(0x80000010)
+            }))
+    );
+    assert!(parse_b("b 0x10 if a0 ==").is_err());
+    assert!(
+        parse_command("dis 0x10".to_string()) == Some(TuiMenuCmd::EnableBreakpoint(0x10, false))
+    );
+    assert!(parse_command("ign 0x10 3".to_string()) == Some(TuiMenuCmd::IgnoreBreakpoint(0x10, 3)));
+    assert!(parse_command("db 0x10".to_string()) == Some(TuiMenuCmd::DeleteBreakpoint(Some(0x10))));
+    assert!(parse_command("db".to_string()) == Some(TuiMenuCmd::DeleteBreakpoint(None)));
+    assert!(parse_command("db x".to_string()).is_none());
+    assert!(parse_command("sb 3".to_string()) == Some(TuiMenuCmd::StepBack(3)));
+    assert!(parse_command("sb".to_string()) == Some(TuiMenuCmd::StepBack(1)));
+    assert!(parse_command("rc".to_string()) == Some(TuiMenuCmd::ReverseContinue));
+    assert!(parse_command("bt".to_string()) == Some(TuiMenuCmd::Backtrace));
+    assert!(
+        parse_command("save m.snap".to_string())
+            == Some(TuiMenuCmd::SaveSnapshot("m.snap".to_string()))
+    );
+    assert!(
+        parse_command("load m.snap".to_string())
+            == Some(TuiMenuCmd::LoadSnapshot("m.snap".to_string()))
+    );
+    assert!(parse_command("save".to_string()).is_none());
 
     assert!(reg_hex(0x1234_5678_9abc_def0) == *"1234_5678_9abc_def0");
     assert!(reg_hex(0x1234) == *"0000_0000_0000_1234");
@@ -1,25 +1,38 @@
 use crate::device::Dev;
+use crate::fdt::DtNode;
+use crate::snapshot::{StateReader, StateWriter};
 
 pub struct Uart {
     #[allow(dead_code)]
     id: String,
     out_callbacks: Vec<Box<dyn Fn(u8)>>,
-    // txdata: u32, // 0x00
-    // rxdata: u32, // 0x04
-    // txctrl: u32, // 0x08
-    // rxctrl: u32, // 0x0c
-    // ie:     u32, // 0x10
-    // ip:     u32, // 0x14
-    // div:    u32, // 0x18
+    txctrl: u32,
+    rxctrl: u32,
+    ie: u32,
+    div: u32,
 }
 
 const TXDATA: u64 = 0x00;
+const RXDATA: u64 = 0x04;
+const TXCTRL: u64 = 0x08;
+const RXCTRL: u64 = 0x0c;
+const IE: u64 = 0x10;
+const IP: u64 = 0x14;
+const DIV: u64 = 0x18;
+
+const RXDATA_EMPTY: u32 = 1 << 31;
+// transmit watermark interrupt pending: the TX FIFO is always empty
+const IP_TXWM: u32 = 1 << 0;
 
 impl Uart {
     pub fn new(id: String) -> Uart {
         Uart {
             id,
             out_callbacks: Vec::new(),
+            txctrl: 0,
+            rxctrl: 0,
+            ie: 0,
+            div: 0,
         }
     }
 
@@ -47,6 +60,12 @@ impl Dev for Uart {
     fn read32(&self, addr: u64) -> u32 {
         match addr {
             TXDATA => 0x0000_0000, // full always is 0, data is alway 0x00 on read
+            RXDATA => RXDATA_EMPTY,
+            TXCTRL => self.txctrl,
+            RXCTRL => self.rxctrl,
+            IE => self.ie,
+            IP => IP_TXWM,
+            DIV => self.div,
             _ => panic!("DBG: Uart: register read not implemented"),
         }
     }
@@ -61,6 +80,11 @@ impl Dev for Uart {
                 let byte = (val & 0xff) as u8;
                 self.execute_out_callbacks(byte);
             }
+            TXCTRL => self.txctrl = val,
+            RXCTRL => self.rxctrl = val,
+            IE => self.ie = val,
+            DIV => self.div = val,
+            RXDATA | IP => eprintln!("WARN: Uart: write to read-only register 0x{addr:x}"),
             _ => panic!("DBG: Uart: register {addr:x} write not implemented"),
         };
     }
@@ -68,4 +92,33 @@ impl Dev for Uart {
     fn write64(&mut self, _addr: u64, _val: u64) {
         todo!();
     }
+
+    fn dt_node(&self) -> Option<DtNode> {
+        Some(DtNode::new("serial", "sifive,uart0"))
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for reg in [self.txctrl, self.rxctrl, self.ie, self.div] {
+            w.u32(reg);
+        }
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.txctrl = r.u32()?;
+        self.rxctrl = r.u32()?;
+        self.ie = r.u32()?;
+        self.div = r.u32()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_uart_registers() {
+    let mut uart = Uart::new("0".to_string());
+    assert_eq!(uart.read32(RXDATA), RXDATA_EMPTY);
+    uart.write32(TXCTRL, 1);
+    uart.write32(DIV, 0x8a);
+    assert_eq!(uart.read32(TXCTRL), 1);
+    assert_eq!(uart.read32(DIV), 0x8a);
+    assert_eq!(uart.read32(IP) & IP_TXWM, IP_TXWM);
 }
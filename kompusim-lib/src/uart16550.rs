@@ -0,0 +1,307 @@
+// NS16550A compatible UART with 8-bit registers (reg-shift = 0). Transmission is immediate, the
+// receive FIFO is filled from the host console input on device polls.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use crate::device::{Dev, DmaMem};
+use crate::fdt::{DtNode, DtProp};
+use crate::snapshot::{StateReader, StateWriter};
+
+pub const UART16550_SIZE: u64 = 0x100;
+
+const CLOCK_FREQ: u32 = 3_686_400;
+const RX_FIFO_SIZE: usize = 16;
+
+// DLAB = 0: RBR (read), THR (write); DLAB = 1: DLL
+const RBR_THR: u64 = 0;
+// DLAB = 0: IER; DLAB = 1: DLM
+const IER: u64 = 1;
+// IIR (read), FCR (write)
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+const MCR_LOOP: u8 = 1 << 4;
+/// Modem status in the loopback mode reflects MCR outputs, otherwise DCD, DSR and CTS are set
+const MSR_CONNECTED: u8 = 0xb0;
+
+pub struct Uart16550 {
+    #[allow(dead_code)]
+    id: String,
+    out_callbacks: Vec<Box<dyn Fn(u8)>>,
+    in_source: Option<Box<dyn FnMut() -> Option<u8>>>,
+    // registers read with side effects are in cells
+    rx_fifo: RefCell<VecDeque<u8>>,
+    /// THR empty interrupt is pending until IIR is read or THR is written
+    thr_ipending: Cell<bool>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+}
+
+impl Uart16550 {
+    pub fn new(id: String) -> Uart16550 {
+        Uart16550 {
+            id,
+            out_callbacks: Vec::new(),
+            in_source: None,
+            rx_fifo: RefCell::new(VecDeque::with_capacity(RX_FIFO_SIZE)),
+            thr_ipending: Cell::new(false),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+        }
+    }
+
+    pub fn register_out_callback(&mut self, cb: Box<dyn Fn(u8)>) {
+        self.out_callbacks.push(cb);
+    }
+
+    /// Source of the received characters, e.g. the host console
+    pub fn register_in_source(&mut self, source: Box<dyn FnMut() -> Option<u8>>) {
+        self.in_source = Some(source);
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    /// Interrupt identification: receive data has higher priority than THR empty
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 {
+            IIR_FIFO_ENABLED
+        } else {
+            0
+        };
+        if self.ier & IER_RDI != 0 && !self.rx_fifo.borrow().is_empty() {
+            fifo | IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thr_ipending.get() {
+            fifo | IIR_THRI
+        } else {
+            fifo | IIR_NO_INT
+        }
+    }
+
+    fn transmit(&mut self, octet: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            let mut rx_fifo = self.rx_fifo.borrow_mut();
+            if rx_fifo.len() < RX_FIFO_SIZE {
+                rx_fifo.push_back(octet);
+            }
+        } else {
+            for cb in &self.out_callbacks {
+                cb(octet);
+            }
+        }
+        // the character has gone immediately
+        self.thr_ipending.set(true);
+    }
+}
+
+// addr is local to the device, i.e bus_address - base_address
+impl Dev for Uart16550 {
+    fn read8(&self, addr: u64) -> u8 {
+        match addr {
+            RBR_THR if self.dlab() => self.divisor as u8,
+            RBR_THR => self.rx_fifo.borrow_mut().pop_front().unwrap_or(0),
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                if iir & 0x0f == IIR_THRI {
+                    self.thr_ipending.set(false);
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx_fifo.borrow().is_empty() {
+                    0
+                } else {
+                    LSR_DR
+                };
+                LSR_THRE | LSR_TEMT | dr
+            }
+            MSR if self.mcr & MCR_LOOP != 0 => (self.mcr & 0x0f) << 4,
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => {
+                eprintln!("WARN: Uart16550: read of unknown register 0x{addr:x}");
+                0
+            }
+        }
+    }
+
+    fn write8(&mut self, addr: u64, val: u8) {
+        match addr {
+            RBR_THR if self.dlab() => self.divisor = (self.divisor & 0xff00) | val as u16,
+            RBR_THR => self.transmit(val),
+            IER if self.dlab() => self.divisor = (self.divisor & 0x00ff) | (val as u16) << 8,
+            IER => {
+                // enabling THR empty interrupt while THR is empty raises it
+                if val & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thr_ipending.set(true);
+                }
+                self.ier = val & 0x0f;
+            }
+            IIR_FCR => {
+                if val & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.borrow_mut().clear();
+                }
+                self.fcr = val & FCR_FIFO_ENABLE;
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val & 0x1f,
+            // LSR and MSR are read-only
+            LSR | MSR => (),
+            SCR => self.scr = val,
+            _ => eprintln!("WARN: Uart16550: write to unknown register 0x{addr:x}"),
+        }
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        self.read8(addr) as u32
+    }
+
+    fn read64(&self, addr: u64) -> u64 {
+        self.read8(addr) as u64
+    }
+
+    fn write32(&mut self, addr: u64, val: u32) {
+        self.write8(addr, val as u8)
+    }
+
+    fn write64(&mut self, addr: u64, val: u64) {
+        self.write8(addr, val as u8)
+    }
+
+    fn dma(&mut self, _mem: &mut dyn DmaMem) {
+        let Some(source) = &mut self.in_source else {
+            return;
+        };
+        let mut rx_fifo = self.rx_fifo.borrow_mut();
+        while rx_fifo.len() < RX_FIFO_SIZE {
+            match source() {
+                Some(octet) => rx_fifo.push_back(octet),
+                None => break,
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.iir() & IIR_NO_INT == 0
+    }
+
+    fn dt_node(&self) -> Option<DtNode> {
+        Some(DtNode {
+            props: vec![
+                ("clock-frequency", DtProp::U32(CLOCK_FREQ)),
+                ("reg-shift", DtProp::U32(0)),
+                ("reg-io-width", DtProp::U32(1)),
+            ],
+            ..DtNode::new("serial", "ns16550a")
+        })
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.rx_fifo.borrow().iter().copied().collect::<Vec<u8>>());
+        w.bool(self.thr_ipending.get());
+        for reg in [self.ier, self.fcr, self.lcr, self.mcr, self.scr] {
+            w.u8(reg);
+        }
+        w.u16(self.divisor);
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        *self.rx_fifo.borrow_mut() = r.bytes()?.iter().copied().collect();
+        self.thr_ipending.set(r.bool()?);
+        self.ier = r.u8()?;
+        self.fcr = r.u8()?;
+        self.lcr = r.u8()?;
+        self.mcr = r.u8()?;
+        self.scr = r.u8()?;
+        self.divisor = r.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_uart16550_tx_rx_and_interrupts() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let out_cb = out.clone();
+        let mut input = VecDeque::from(b"ls".to_vec());
+        let mut uart = Uart16550::new("0".to_string());
+        uart.register_out_callback(Box::new(move |b| out_cb.borrow_mut().push(b)));
+        uart.register_in_source(Box::new(move || input.pop_front()));
+
+        // divisor latch
+        uart.write8(LCR, LCR_DLAB);
+        uart.write8(RBR_THR, 0x01);
+        uart.write8(IER, 0x02);
+        assert_eq!(uart.read8(RBR_THR), 0x01);
+        assert_eq!(uart.read8(IER), 0x02);
+        uart.write8(LCR, 0x03);
+
+        uart.write8(RBR_THR, b'#');
+        assert_eq!(out.borrow().as_slice(), b"#");
+        assert_eq!(uart.read8(LSR), LSR_THRE | LSR_TEMT);
+        assert!(!uart.irq_pending());
+
+        // THR empty interrupt is cleared by reading IIR
+        uart.write8(IER, IER_THRI);
+        assert!(uart.irq_pending());
+        assert_eq!(uart.read8(IIR_FCR), IIR_THRI);
+        assert!(!uart.irq_pending());
+
+        // receive
+        uart.write8(IER, IER_RDI);
+        uart.dma(&mut NoMem);
+        assert!(uart.irq_pending());
+        assert_eq!(uart.read8(IIR_FCR), IIR_RDI);
+        assert_eq!(uart.read8(LSR) & LSR_DR, LSR_DR);
+        assert_eq!(uart.read8(RBR_THR), b'l');
+        assert_eq!(uart.read8(RBR_THR), b's');
+        assert_eq!(uart.read8(LSR) & LSR_DR, 0);
+        assert!(!uart.irq_pending());
+    }
+
+    struct NoMem;
+
+    impl DmaMem for NoMem {
+        fn read(&self, addr: u64, _buf: &mut [u8]) -> Result<(), String> {
+            Err(format!("no memory @ 0x{addr:x}"))
+        }
+
+        fn write(&mut self, addr: u64, _buf: &[u8]) -> Result<(), String> {
+            Err(format!("no memory @ 0x{addr:x}"))
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Undo log of the executed instructions for stepping backwards (reverse execution). Every
+// executed instruction appends the state it overwrites: PC, registers, CSRs and RAM, undoing
+// restores it in reverse order. Devices (UART, timers, DMA) and A/D bits of page table entries
+// aren't rewound.
+
+use std::collections::VecDeque;
+
+use crate::csr::Csrs;
+
+/// Number of the last executed instructions which can be undone by default
+pub const DEFAULT_UNDO_BUDGET: usize = 100_000;
+
+/// State overwritten by an executed instruction
+pub(crate) enum UndoRecord {
+    /// Starts the records of an instruction: the state before it
+    Instr {
+        pc: u64,
+        num_exec_instr: u64,
+        lr_sc_reservation: Option<u64>,
+    },
+    /// Register index and its old value
+    Reg(u8, u64),
+    /// Old size (1 - 8) bytes of RAM at the virtual and physical address
+    Mem {
+        vaddr: u64,
+        paddr: u64,
+        size: u8,
+        old: u64,
+    },
+    /// All CSRs before the first CSR change of the instruction, they change rarely (mostly on
+    /// traps) so it's cheaper than logging every implicit change
+    Csrs(Box<Csrs>),
+}
+
+#[derive(Default)]
+pub(crate) struct UndoLog {
+    /// Maximum number of instructions in the log, 0 - disabled
+    budget: usize,
+    records: VecDeque<UndoRecord>,
+    /// Number of Instr records
+    n_instr: usize,
+    /// An instruction is being executed, changes out of it (e.g. by a debugger) aren't logged
+    recording: bool,
+    /// CSRs are already saved for the current instruction
+    csrs_saved: bool,
+}
+
+impl UndoLog {
+    pub fn new(budget: usize) -> UndoLog {
+        UndoLog {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Number of instructions which can be undone
+    pub fn depth(&self) -> usize {
+        self.n_instr
+    }
+
+    /// Starts logging of an instruction, the oldest instruction is dropped if over the budget
+    pub fn begin_instr(&mut self, pc: u64, num_exec_instr: u64, lr_sc_reservation: Option<u64>) {
+        if self.budget == 0 {
+            return;
+        }
+        while self.n_instr >= self.budget {
+            self.records.pop_front();
+            while !matches!(self.records.front(), None | Some(UndoRecord::Instr { .. })) {
+                self.records.pop_front();
+            }
+            self.n_instr -= 1;
+        }
+        self.records.push_back(UndoRecord::Instr {
+            pc,
+            num_exec_instr,
+            lr_sc_reservation,
+        });
+        self.n_instr += 1;
+        self.recording = true;
+        self.csrs_saved = false;
+    }
+
+    pub fn end_instr(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.recording {
+            self.records.push_back(record);
+        }
+    }
+
+    pub fn save_csrs(&mut self, csrs: &Csrs) {
+        if self.recording && !self.csrs_saved {
+            self.records
+                .push_back(UndoRecord::Csrs(Box::new(csrs.clone())));
+            self.csrs_saved = true;
+        }
+    }
+
+    /// Removes the records of the last instruction, the latest first and its Instr record last
+    pub fn pop_instr(&mut self) -> Option<Vec<UndoRecord>> {
+        if self.n_instr == 0 {
+            return None;
+        }
+        let mut records = Vec::new();
+        while let Some(record) = self.records.pop_back() {
+            let instr = matches!(record, UndoRecord::Instr { .. });
+            records.push(record);
+            if instr {
+                break;
+            }
+        }
+        self.n_instr -= 1;
+        Some(records)
+    }
+}
+
+#[test]
+fn test_undo_log_budget() {
+    let mut log = UndoLog::new(2);
+    for pc in 0..3 {
+        log.begin_instr(pc, pc, None);
+        log.push(UndoRecord::Reg(1, pc));
+        log.end_instr();
+    }
+    // changes out of instructions aren't logged
+    log.push(UndoRecord::Reg(2, 0));
+    assert_eq!(log.depth(), 2);
+    let records = log.pop_instr().unwrap();
+    assert!(matches!(
+        records[..],
+        [UndoRecord::Reg(1, 2), UndoRecord::Instr { pc: 2, .. }]
+    ));
+    let records = log.pop_instr().unwrap();
+    assert!(matches!(
+        records[..],
+        [UndoRecord::Reg(1, 1), UndoRecord::Instr { pc: 1, .. }]
+    ));
+    assert!(log.pop_instr().is_none());
+}
@@ -0,0 +1,712 @@
+// Virtio over MMIO transport (virtio v1.1 spec, section 4.2).
+// Only split virtqueues without indirect descriptors are supported.
+
+use crate::device::{Dev, DmaMem};
+use crate::fdt::DtNode;
+use crate::snapshot::{StateReader, StateWriter};
+
+// trick with mod and use to disable rustfmt for the following defines
+#[rustfmt::skip]
+mod virtio_mmio_regs {
+pub const MAGIC_VALUE: u64         = 0x000;
+pub const VERSION: u64             = 0x004;
+pub const DEVICE_ID: u64           = 0x008;
+pub const VENDOR_ID: u64           = 0x00c;
+pub const DEVICE_FEATURES: u64     = 0x010;
+pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+pub const DRIVER_FEATURES: u64     = 0x020;
+pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+pub const QUEUE_SEL: u64           = 0x030;
+pub const QUEUE_NUM_MAX: u64       = 0x034;
+pub const QUEUE_NUM: u64           = 0x038;
+pub const QUEUE_READY: u64         = 0x044;
+pub const QUEUE_NOTIFY: u64        = 0x050;
+pub const INTERRUPT_STATUS: u64    = 0x060;
+pub const INTERRUPT_ACK: u64       = 0x064;
+pub const STATUS: u64              = 0x070;
+pub const QUEUE_DESC_LOW: u64      = 0x080;
+pub const QUEUE_DESC_HIGH: u64     = 0x084;
+pub const QUEUE_DRIVER_LOW: u64    = 0x090;
+pub const QUEUE_DRIVER_HIGH: u64   = 0x094;
+pub const QUEUE_DEVICE_LOW: u64    = 0x0a0;
+pub const QUEUE_DEVICE_HIGH: u64   = 0x0a4;
+pub const CONFIG_GENERATION: u64   = 0x0fc;
+pub const CONFIG: u64              = 0x100;
+}
+use virtio_mmio_regs::*;
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+/// "KOMP" in little endian
+const VENDOR: u32 = 0x504d_4f4b;
+/// Size of the virtio-mmio register window
+pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
+/// Maximum number of descriptors in a virtqueue
+pub const VIRTQ_SIZE_MAX: u16 = 256;
+
+/// Device complies with the virtio spec v1 (not legacy)
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Interrupt status bit: the device has used a buffer in a virtqueue
+pub const VIRTIO_INT_USED_RING: u32 = 1 << 0;
+/// Interrupt status bit: the device configuration has changed
+pub const VIRTIO_INT_CONFIG: u32 = 1 << 1;
+
+/// Device status bit: the driver is set up and ready to drive the device
+const STATUS_DRIVER_OK: u32 = 4;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Device type specific part of a virtio device
+pub trait VirtioDevice {
+    /// Virtio device ID (1 - network card, 3 - console, 4 - entropy source, ...)
+    fn device_id(&self) -> u32;
+    /// Device specific feature bits. VIRTIO_F_VERSION_1 is added by the transport.
+    fn features(&self) -> u64;
+    /// At most MAX_QUEUES
+    fn num_queues(&self) -> usize;
+    /// Reads a byte of the device configuration space
+    fn read_config(&self, offset: u64) -> u8;
+    fn write_config(&mut self, _offset: u64, _val: u8) {}
+    /// The driver notified the device about new buffers in queues[queue].
+    /// Returns VIRTIO_INT_* bits to raise.
+    fn queue_notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut dyn DmaMem)
+        -> u32;
+    /// Called periodically, e.g. to deliver input. Returns VIRTIO_INT_* bits to raise.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _mem: &mut dyn DmaMem) -> u32 {
+        0
+    }
+    /// The driver reset the device
+    fn reset(&mut self) {}
+    /// Saves the device specific state for a snapshot
+    fn save_state(&self, _w: &mut StateWriter) {}
+    /// Restores the state written by save_state()
+    fn restore_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// One descriptor of a descriptor chain
+#[derive(Clone, Copy, Debug)]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+}
+
+impl VirtqDesc {
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// Descriptor chain popped from the available ring
+pub struct DescChain {
+    /// Index of the head descriptor, returned to the driver in the used ring
+    pub head: u16,
+    pub descs: Vec<VirtqDesc>,
+}
+
+impl DescChain {
+    /// Gathers all device-readable buffers of the chain. The buffer sizes come from the guest,
+    /// a chain longer than max_len is an error.
+    pub fn read_all(&self, mem: &dyn DmaMem, max_len: usize) -> Result<Vec<u8>, String> {
+        let readable = || self.descs.iter().filter(|d| !d.is_write_only());
+        let len = readable()
+            .try_fold(0usize, |len, d| len.checked_add(d.len as usize))
+            .filter(|&len| len <= max_len)
+            .ok_or(format!(
+                "virtq: chain {} is longer than {max_len} bytes",
+                self.head
+            ))?;
+        let mut data = vec![0; len];
+        let mut start = 0;
+        for d in readable() {
+            let end = start + d.len as usize;
+            mem.read(d.addr, &mut data[start..end])?;
+            start = end;
+        }
+        Ok(data)
+    }
+
+    /// Scatters data over device-writable buffers of the chain. Returns number of written bytes.
+    pub fn write_all(&self, mem: &mut dyn DmaMem, data: &[u8]) -> Result<u32, String> {
+        let mut written = 0;
+        for d in self.descs.iter().filter(|d| d.is_write_only()) {
+            if written == data.len() {
+                break;
+            }
+            let n = (d.len as usize).min(data.len() - written);
+            mem.write(d.addr, &data[written..written + n])?;
+            written += n;
+        }
+        Ok(written as u32)
+    }
+
+    /// Total size of device-writable buffers, saturated at u32::MAX
+    pub fn writable_len(&self) -> u32 {
+        self.descs
+            .iter()
+            .filter(|d| d.is_write_only())
+            .fold(0, |len, d| len.saturating_add(d.len))
+    }
+}
+
+/// Address of an element of a ring the guest placed at base
+fn ring_addr(base: u64, offs: u64) -> Result<u64, String> {
+    base.checked_add(offs)
+        .ok_or(format!("virtq: ring at 0x{base:x} wraps the address space"))
+}
+
+/// Split virtqueue
+#[derive(Default, Clone)]
+pub struct Virtqueue {
+    pub num: u16,
+    pub ready: bool,
+    /// Descriptor table address
+    pub desc: u64,
+    /// Available (driver) ring address
+    pub driver: u64,
+    /// Used (device) ring address
+    pub device: u64,
+    last_avail_idx: u16,
+}
+
+impl Virtqueue {
+    /// Returns true if the driver has made buffers available
+    pub fn has_avail(&self, mem: &dyn DmaMem) -> bool {
+        if !self.ready || self.num == 0 {
+            return false;
+        }
+        match ring_addr(self.driver, 2).and_then(|idx| mem.read16(idx)) {
+            Ok(avail_idx) => avail_idx != self.last_avail_idx,
+            Err(_) => false,
+        }
+    }
+
+    /// Pops next descriptor chain from the available ring
+    pub fn pop(&mut self, mem: &dyn DmaMem) -> Result<Option<DescChain>, String> {
+        if !self.has_avail(mem) {
+            return Ok(None);
+        }
+        let ring_i = (self.last_avail_idx % self.num) as u64;
+        let head = mem.read16(ring_addr(self.driver, 4 + 2 * ring_i)?)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        let mut descs = Vec::new();
+        let mut i = head;
+        loop {
+            if i >= self.num || descs.len() > self.num as usize {
+                return Err(format!("virtq: broken descriptor chain at {i}"));
+            }
+            // addr (64 bit), len (32 bit), flags (16 bit), next (16 bit)
+            let mut d = [0; 16];
+            mem.read(ring_addr(self.desc, 16 * i as u64)?, &mut d)?;
+            let flags = u16::from_le_bytes([d[12], d[13]]);
+            descs.push(VirtqDesc {
+                addr: u64::from_le_bytes(d[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(d[8..12].try_into().unwrap()),
+                flags,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = u16::from_le_bytes([d[14], d[15]]);
+        }
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    /// Returns a processed descriptor chain to the driver via the used ring
+    pub fn push_used(&mut self, mem: &mut dyn DmaMem, head: u16, len: u32) -> Result<(), String> {
+        let idx_addr = ring_addr(self.device, 2)?;
+        let used_idx = mem.read16(idx_addr)?;
+        let elem = ring_addr(self.device, 4 + 8 * (used_idx % self.num) as u64)?;
+        // id (32 bit), len (32 bit)
+        let mut e = [0; 8];
+        e[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        e[4..].copy_from_slice(&len.to_le_bytes());
+        mem.write(elem, &e)?;
+        mem.write16(idx_addr, used_idx.wrapping_add(1))
+    }
+}
+
+/// Virtio-mmio transport wrapping a virtio device
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+    status: u32,
+    config_generation: u32,
+    /// Bitmask of notified queues waiting for processing
+    pending_notify: u32,
+}
+
+/// Queues a device can have, one bit per queue in pending_notify
+pub const MAX_QUEUES: usize = 32;
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> VirtioMmio<D> {
+        assert!(device.num_queues() <= MAX_QUEUES, "too many virtqueues");
+        let queues = vec![Virtqueue::default(); device.num_queues()];
+        VirtioMmio {
+            device,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+            pending_notify: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Features negotiated by the driver
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues
+            .iter_mut()
+            .for_each(|q| *q = Virtqueue::default());
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.pending_notify = 0;
+        self.device.reset();
+    }
+
+    fn cur_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    fn cur_queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn raise(&mut self, int_bits: u32) {
+        if int_bits & VIRTIO_INT_CONFIG != 0 {
+            self.config_generation = self.config_generation.wrapping_add(1);
+        }
+        self.interrupt_status |= int_bits;
+    }
+}
+
+// addr is local to the device, i.e bus_address - base_address
+impl<D: VirtioDevice> Dev for VirtioMmio<D> {
+    fn read8(&self, addr: u64) -> u8 {
+        if addr >= CONFIG {
+            self.device.read_config(addr - CONFIG)
+        } else {
+            self.read32(addr & !0x3).to_le_bytes()[(addr & 0x3) as usize]
+        }
+    }
+
+    fn write8(&mut self, addr: u64, val: u8) {
+        if addr >= CONFIG {
+            self.device.write_config(addr - CONFIG, val)
+        } else {
+            eprintln!("WARN: virtio: byte write to register 0x{addr:x} ignored")
+        }
+    }
+
+    fn read32(&self, addr: u64) -> u32 {
+        match addr {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.cur_queue().map_or(0, |_| VIRTQ_SIZE_MAX as u32),
+            QUEUE_READY => self.cur_queue().map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.config_generation,
+            _ if addr >= CONFIG => u32::from_le_bytes([
+                self.device.read_config(addr - CONFIG),
+                self.device.read_config(addr - CONFIG + 1),
+                self.device.read_config(addr - CONFIG + 2),
+                self.device.read_config(addr - CONFIG + 3),
+            ]),
+            _ => {
+                eprintln!("WARN: virtio: read of register 0x{addr:x} is not supported");
+                0
+            }
+        }
+    }
+
+    fn read64(&self, addr: u64) -> u64 {
+        self.read32(addr) as u64 | (self.read32(addr + 4) as u64) << 32
+    }
+
+    fn write32(&mut self, addr: u64, val: u32) {
+        match addr {
+            DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = self.driver_features & !0xffff_ffff | val as u64,
+                1 => self.driver_features = self.driver_features & 0xffff_ffff | (val as u64) << 32,
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            QUEUE_SEL => self.queue_sel = val,
+            QUEUE_NUM => {
+                if let Some(q) = self.cur_queue_mut() {
+                    q.num = (val as u16).min(VIRTQ_SIZE_MAX);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.cur_queue_mut() {
+                    q.ready = val & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                // the guest writes any queue index, a missing queue is ignored
+                if (val as usize) < self.queues.len() {
+                    self.pending_notify |= 1 << val;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !val,
+            STATUS => {
+                if val == 0 {
+                    self.reset();
+                } else {
+                    self.status = val;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.cur_queue_mut() {
+                    let reg = match addr {
+                        QUEUE_DESC_LOW | QUEUE_DESC_HIGH => &mut q.desc,
+                        QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => &mut q.driver,
+                        _ => &mut q.device,
+                    };
+                    if addr & 0x4 == 0 {
+                        *reg = *reg & !0xffff_ffff | val as u64;
+                    } else {
+                        *reg = *reg & 0xffff_ffff | (val as u64) << 32;
+                    }
+                }
+            }
+            _ if addr >= CONFIG => {
+                for (i, b) in val.to_le_bytes().iter().enumerate() {
+                    self.device.write_config(addr - CONFIG + i as u64, *b);
+                }
+            }
+            _ => eprintln!("WARN: virtio: write of register 0x{addr:x} is not supported"),
+        }
+    }
+
+    fn write64(&mut self, addr: u64, val: u64) {
+        self.write32(addr, val as u32);
+        self.write32(addr + 4, (val >> 32) as u32);
+    }
+
+    fn dma(&mut self, mem: &mut dyn DmaMem) {
+        // the driver has not finished the initialization yet
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+        let mut int_bits = 0;
+        while self.pending_notify != 0 {
+            let queue = self.pending_notify.trailing_zeros() as usize;
+            self.pending_notify &= !(1 << queue);
+            int_bits |= self.device.queue_notify(queue, &mut self.queues, mem);
+        }
+        int_bits |= self.device.poll(&mut self.queues, mem);
+        self.raise(int_bits);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn dt_node(&self) -> Option<DtNode> {
+        Some(DtNode::new("virtio_mmio", "virtio,mmio"))
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.device_features_sel);
+        w.u32(self.driver_features_sel);
+        w.u64(self.driver_features);
+        w.u32(self.queue_sel);
+        w.u32(self.interrupt_status);
+        w.u32(self.status);
+        w.u32(self.config_generation);
+        w.u32(self.pending_notify);
+        for q in &self.queues {
+            w.u16(q.num);
+            w.bool(q.ready);
+            w.u64(q.desc);
+            w.u64(q.driver);
+            w.u64(q.device);
+            w.u16(q.last_avail_idx);
+        }
+        self.device.save_state(w);
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.device_features_sel = r.u32()?;
+        self.driver_features_sel = r.u32()?;
+        self.driver_features = r.u64()?;
+        self.queue_sel = r.u32()?;
+        self.interrupt_status = r.u32()?;
+        self.status = r.u32()?;
+        self.config_generation = r.u32()?;
+        self.pending_notify = r.u32()?;
+        for q in self.queues.iter_mut() {
+            q.num = r.u16()?;
+            q.ready = r.bool()?;
+            q.desc = r.u64()?;
+            q.driver = r.u64()?;
+            q.device = r.u64()?;
+            q.last_avail_idx = r.u16()?;
+        }
+        self.device.restore_state(r)
+    }
+}
+
+/// Minimal virtio driver for unit tests of virtio devices
+#[cfg(test)]
+pub(crate) mod test_driver {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::device::Device;
+    use crate::ram::Ram;
+
+    pub const DEV_BASE: u64 = 0x1000_1000;
+    pub const RAM_BASE: u64 = 0x8000_0000;
+
+    /// Returns a bus with 128 KiB of RAM at RAM_BASE and the device at DEV_BASE
+    pub fn new_bus(dev: Box<dyn Dev>) -> Bus {
+        let mut bus = Bus::new();
+        bus.attach_ram(Ram::new(RAM_BASE, 0x20000));
+        bus.attach_device(Device::new(dev, DEV_BASE, VIRTIO_MMIO_SIZE));
+        bus
+    }
+
+    /// Places the rings of queue q at RAM_BASE + q * 0x1000 and makes it ready.
+    /// Returns the descriptor table address.
+    pub fn setup_queue(bus: &mut Bus, q: u32) -> u64 {
+        let desc = RAM_BASE + q as u64 * 0x1000;
+        bus.write32(DEV_BASE + QUEUE_SEL, q).unwrap();
+        bus.write32(DEV_BASE + QUEUE_NUM, 4).unwrap();
+        bus.write32(DEV_BASE + QUEUE_DESC_LOW, desc as u32).unwrap();
+        bus.write32(DEV_BASE + QUEUE_DESC_HIGH, (desc >> 32) as u32)
+            .unwrap();
+        bus.write32(DEV_BASE + QUEUE_DRIVER_LOW, (desc + 0x100) as u32)
+            .unwrap();
+        bus.write32(DEV_BASE + QUEUE_DRIVER_HIGH, 0).unwrap();
+        bus.write32(DEV_BASE + QUEUE_DEVICE_LOW, (desc + 0x200) as u32)
+            .unwrap();
+        bus.write32(DEV_BASE + QUEUE_DEVICE_HIGH, 0).unwrap();
+        bus.write32(DEV_BASE + QUEUE_READY, 1).unwrap();
+        desc
+    }
+
+    pub fn driver_ok(bus: &mut Bus) {
+        // ACKNOWLEDGE | DRIVER | FEATURES_OK | DRIVER_OK
+        bus.write32(DEV_BASE + STATUS, 0xb | STATUS_DRIVER_OK)
+            .unwrap();
+    }
+
+    /// Puts one buffer into descriptor 0 and makes it available
+    pub fn add_buf(bus: &mut Bus, desc: u64, buf: u64, len: u32, write_only: bool) {
+        let avail_idx = bus.read32(desc + 0x100).unwrap() >> 16;
+        bus.write64(desc, buf).unwrap();
+        bus.write32(desc + 8, len).unwrap();
+        // flags, next
+        bus.write32(
+            desc + 12,
+            if write_only {
+                VIRTQ_DESC_F_WRITE as u32
+            } else {
+                0
+            },
+        )
+        .unwrap();
+        // avail ring[idx] = desc 0
+        bus.write8(desc + 0x100 + 4 + 2 * (avail_idx as u64 % 4), 0)
+            .unwrap();
+        // avail flags = 0, idx += 1
+        bus.write32(desc + 0x100, (avail_idx + 1) << 16).unwrap();
+    }
+
+    pub fn notify(bus: &mut Bus, q: u32) {
+        bus.write32(DEV_BASE + QUEUE_NOTIFY, q).unwrap();
+    }
+
+    /// Returns (used_idx, length of the last used buffer)
+    pub fn last_used(bus: &Bus, desc: u64) -> (u32, u32) {
+        let used_idx = bus.read32(desc + 0x200).unwrap() >> 16;
+        let last = (used_idx + 3) % 4;
+        (
+            used_idx,
+            bus.read32(desc + 0x200 + 4 + 8 * last as u64 + 4).unwrap(),
+        )
+    }
+
+    pub fn interrupt_status(bus: &Bus) -> u32 {
+        bus.read32(DEV_BASE + INTERRUPT_STATUS).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x8000_0000;
+
+    /// RAM at BASE
+    struct TestMem(Vec<u8>);
+
+    impl TestMem {
+        fn range(&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>, String> {
+            addr.checked_sub(BASE)
+                .and_then(|start| Some(start as usize..(start as usize).checked_add(len)?))
+                .filter(|r| r.end <= self.0.len())
+                .ok_or(format!("fault @ 0x{addr:x}"))
+        }
+    }
+
+    impl DmaMem for TestMem {
+        fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), String> {
+            buf.copy_from_slice(&self.0[self.range(addr, buf.len())?]);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), String> {
+            let r = self.range(addr, buf.len())?;
+            self.0[r].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// Queue of 4 descriptors at BASE, the available ring at BASE + 0x100 has `head` and the used
+    /// ring is at BASE + 0x200
+    fn test_queue(mem: &mut TestMem, head: u16) -> Virtqueue {
+        mem.write16(BASE + 0x100 + 2, 1).unwrap();
+        mem.write16(BASE + 0x100 + 4, head).unwrap();
+        Virtqueue {
+            num: 4,
+            ready: true,
+            desc: BASE,
+            driver: BASE + 0x100,
+            device: BASE + 0x200,
+            last_avail_idx: 0,
+        }
+    }
+
+    fn set_desc(mem: &mut TestMem, i: u64, addr: u64, len: u32, next: Option<u16>) {
+        let d = BASE + 16 * i;
+        mem.write(d, &addr.to_le_bytes()).unwrap();
+        mem.write32(d + 8, len).unwrap();
+        let flags = if next.is_some() { VIRTQ_DESC_F_NEXT } else { 0 };
+        mem.write16(d + 12, flags).unwrap();
+        mem.write16(d + 14, next.unwrap_or(0)).unwrap();
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut mem = TestMem(vec![0; 0x1000]);
+        mem.write(BASE + 0x800, b"hello").unwrap();
+        set_desc(&mut mem, 2, BASE + 0x800, 2, Some(3));
+        set_desc(&mut mem, 3, BASE + 0x802, 3, None);
+        let mut q = test_queue(&mut mem, 2);
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.read_all(&mem, 5).unwrap(), b"hello");
+        assert!(chain.read_all(&mem, 4).is_err());
+        q.push_used(&mut mem, chain.head, 0).unwrap();
+        assert_eq!(mem.read32(BASE + 0x200 + 4).unwrap(), 2);
+        assert_eq!(mem.read16(BASE + 0x200 + 2).unwrap(), 1);
+        assert!(q.pop(&mem).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_malformed_chains() {
+        let mut mem = TestMem(vec![0; 0x1000]);
+        // 0 -> 1 -> 0 loops
+        set_desc(&mut mem, 0, BASE + 0x800, 1, Some(1));
+        set_desc(&mut mem, 1, BASE + 0x800, 1, Some(0));
+        assert!(test_queue(&mut mem, 0).pop(&mem).is_err());
+        // next is out of the queue
+        set_desc(&mut mem, 1, BASE + 0x800, 1, Some(4));
+        assert!(test_queue(&mut mem, 0).pop(&mem).is_err());
+        assert!(test_queue(&mut mem, 4).pop(&mem).is_err());
+
+        // 4 GiB buffers aren't allocated
+        set_desc(&mut mem, 0, BASE, u32::MAX, Some(1));
+        set_desc(&mut mem, 1, BASE, u32::MAX, None);
+        let chain = test_queue(&mut mem, 0).pop(&mem).unwrap().unwrap();
+        assert!(chain.read_all(&mem, 1 << 16).is_err());
+        // a buffer wrapping the address space
+        set_desc(&mut mem, 0, u64::MAX - 1, 4, None);
+        let chain = test_queue(&mut mem, 0).pop(&mem).unwrap().unwrap();
+        assert!(chain.read_all(&mem, 1 << 16).is_err());
+    }
+
+    #[test]
+    fn test_writable_len() {
+        let desc = |len, flags| VirtqDesc {
+            addr: BASE,
+            len,
+            flags,
+        };
+        let chain = DescChain {
+            head: 0,
+            descs: vec![
+                desc(16, VIRTQ_DESC_F_WRITE),
+                desc(8, 0),
+                desc(u32::MAX, VIRTQ_DESC_F_WRITE),
+            ],
+        };
+        assert_eq!(chain.writable_len(), u32::MAX);
+        assert_eq!(
+            DescChain {
+                head: 0,
+                descs: chain.descs[..2].to_vec()
+            }
+            .writable_len(),
+            16
+        );
+    }
+
+    #[test]
+    fn test_wrapping_rings() {
+        let mut mem = TestMem(vec![0; 0x1000]);
+        set_desc(&mut mem, 0, BASE + 0x800, 1, None);
+        let mut q = test_queue(&mut mem, 1);
+        q.desc = u64::MAX - 0xf;
+        assert!(q.pop(&mem).is_err());
+        let mut q = test_queue(&mut mem, 0);
+        q.device = u64::MAX - 1;
+        let chain = q.pop(&mem).unwrap().unwrap();
+        assert!(q.push_used(&mut mem, chain.head, 0).is_err());
+        q.driver = u64::MAX - 1;
+        assert!(!q.has_avail(&mem));
+        assert!(q.pop(&mem).unwrap().is_none());
+    }
+}
@@ -0,0 +1,203 @@
+// Virtio console device (virtio v1.1 spec, section 5.3). Only the first port is supported
+// (no VIRTIO_CONSOLE_F_MULTIPORT), which is enough for a Linux hvc console.
+
+use crate::device::DmaMem;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::virtio::{VirtioDevice, Virtqueue, VIRTIO_INT_USED_RING};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// Device supports emergency write via the emerg_wr configuration field
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// Offset of emerg_wr in struct virtio_console_config
+const CONFIG_EMERG_WR: u64 = 8;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Longest output buffer accepted from the driver
+const MAX_TX_SZ: usize = 65536;
+
+pub struct VirtioConsole {
+    #[allow(dead_code)]
+    id: String,
+    out_callbacks: Vec<Box<dyn Fn(u8)>>,
+    /// Polled for the host input
+    in_source: Option<Box<dyn FnMut() -> Option<u8>>>,
+    /// Host input which didn't fit into the guest RX buffers yet
+    rx_pending: Vec<u8>,
+}
+
+impl VirtioConsole {
+    pub fn new(id: String) -> VirtioConsole {
+        VirtioConsole {
+            id,
+            out_callbacks: Vec::new(),
+            in_source: None,
+            rx_pending: Vec::new(),
+        }
+    }
+
+    pub fn register_out_callback(&mut self, cb: Box<dyn Fn(u8)>) {
+        self.out_callbacks.push(cb);
+    }
+
+    /// Registers a source of the host input, it returns None when no input is pending
+    pub fn register_in_source(&mut self, source: Box<dyn FnMut() -> Option<u8>>) {
+        self.in_source = Some(source);
+    }
+
+    fn execute_out_callbacks(&self, octet: u8) {
+        for cb in &self.out_callbacks {
+            cb(octet);
+        }
+    }
+
+    fn transmit(&mut self, txq: &mut Virtqueue, mem: &mut dyn DmaMem) -> Result<u32, String> {
+        let mut int_bits = 0;
+        while let Some(chain) = txq.pop(mem)? {
+            for octet in chain.read_all(mem, MAX_TX_SZ)? {
+                self.execute_out_callbacks(octet);
+            }
+            txq.push_used(mem, chain.head, 0)?;
+            int_bits |= VIRTIO_INT_USED_RING;
+        }
+        Ok(int_bits)
+    }
+
+    fn receive(&mut self, rxq: &mut Virtqueue, mem: &mut dyn DmaMem) -> Result<u32, String> {
+        if let Some(source) = &mut self.in_source {
+            while let Some(octet) = source() {
+                self.rx_pending.push(octet);
+            }
+        }
+        let mut int_bits = 0;
+        while !self.rx_pending.is_empty() && rxq.has_avail(mem) {
+            let Some(chain) = rxq.pop(mem)? else {
+                break;
+            };
+            let written = chain.write_all(mem, &self.rx_pending)?;
+            self.rx_pending.drain(..written as usize);
+            rxq.push_used(mem, chain.head, written)?;
+            int_bits |= VIRTIO_INT_USED_RING;
+        }
+        Ok(int_bits)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    // struct virtio_console_config { le16 cols; le16 rows; le32 max_nr_ports; le32 emerg_wr; }
+    fn read_config(&self, offset: u64) -> u8 {
+        match offset {
+            // max_nr_ports = 1
+            4 => 1,
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, val: u8) {
+        if offset == CONFIG_EMERG_WR {
+            self.execute_out_callbacks(val);
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        mem: &mut dyn DmaMem,
+    ) -> u32 {
+        let res = match queue {
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem),
+            RX_QUEUE => self.receive(&mut queues[RX_QUEUE], mem),
+            _ => Ok(0),
+        };
+        res.unwrap_or_else(|e| {
+            eprintln!("ERROR: virtio-console: {e}");
+            0
+        })
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut dyn DmaMem) -> u32 {
+        self.receive(&mut queues[RX_QUEUE], mem)
+            .unwrap_or_else(|e| {
+                eprintln!("ERROR: virtio-console: {e}");
+                0
+            })
+    }
+
+    fn reset(&mut self) {
+        self.rx_pending.clear();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.rx_pending);
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.rx_pending = r.bytes()?.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::test_driver::*;
+    use crate::virtio::VirtioMmio;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_virtio_console_tx_rx() {
+        let out = Rc::new(RefCell::new(String::new()));
+        let out_cb = out.clone();
+        let mut input: VecDeque<u8> = b"ls\n".iter().copied().collect();
+        let mut console = VirtioConsole::new("0".to_string());
+        console.register_out_callback(Box::new(move |b| out_cb.borrow_mut().push(b as char)));
+        console.register_in_source(Box::new(move || input.pop_front()));
+        let mut bus = new_bus(Box::new(VirtioMmio::new(console)));
+        assert_eq!(bus.read32(DEV_BASE + 0x008).unwrap(), VIRTIO_ID_CONSOLE);
+
+        let rx_desc = setup_queue(&mut bus, RX_QUEUE as u32);
+        let tx_desc = setup_queue(&mut bus, TX_QUEUE as u32);
+        driver_ok(&mut bus);
+
+        let tx_buf = RAM_BASE + 0x5000;
+        bus.write32(tx_buf, u32::from_le_bytes(*b"hvc0")).unwrap();
+        add_buf(&mut bus, tx_desc, tx_buf, 4, false);
+        notify(&mut bus, TX_QUEUE as u32);
+        assert_eq!(out.borrow().as_str(), "hvc0");
+
+        let rx_buf = RAM_BASE + 0x4000;
+        add_buf(&mut bus, rx_desc, rx_buf, 2, true);
+        notify(&mut bus, RX_QUEUE as u32);
+        // input didn't fit into one buffer
+        assert_eq!(last_used(&bus, rx_desc), (1, 2));
+        assert_eq!(bus.read8(rx_buf).unwrap(), b'l');
+        assert_eq!(bus.read8(rx_buf + 1).unwrap(), b's');
+        add_buf(&mut bus, rx_desc, rx_buf, 2, true);
+        bus.poll_devices();
+        assert_eq!(last_used(&bus, rx_desc), (2, 1));
+        assert_eq!(bus.read8(rx_buf).unwrap(), b'\n');
+
+        // emergency write
+        bus.write8(DEV_BASE + 0x100 + CONFIG_EMERG_WR, b'!')
+            .unwrap();
+        assert_eq!(out.borrow().as_str(), "hvc0!");
+    }
+}
@@ -0,0 +1,438 @@
+// Virtio network device (virtio v1.1 spec, section 5.1) with userspace backends
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::device::DmaMem;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::virtio::{VirtioDevice, Virtqueue, VIRTIO_INT_CONFIG, VIRTIO_INT_USED_RING};
+
+const VIRTIO_ID_NET: u32 = 1;
+
+/// Device has given MAC address
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Configuration status field is available
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Size of struct virtio_net_hdr (with num_buffers field since virtio v1)
+const VIRTIO_NET_HDR_SZ: usize = 12;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// Maximum ethernet frame we accept from a backend
+const MAX_FRAME_SZ: usize = 65536;
+
+/// Where ethernet frames of a virtio-net device go to and come from
+pub trait NetBackend {
+    /// Transmit one ethernet frame
+    fn send(&mut self, frame: &[u8]);
+    /// Receive one ethernet frame if any is pending
+    fn recv(&mut self) -> Option<Vec<u8>>;
+    fn link_up(&self) -> bool {
+        true
+    }
+}
+
+/// In-process loopback: every transmitted frame is received back
+#[derive(Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Removes a stale socket left at path by a previous run, any other file is an error
+#[cfg(unix)]
+pub(crate) fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{path:?} exists and is not a socket"),
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Connects two simulator instances over local Unix datagram sockets
+#[cfg(unix)]
+pub struct UnixSocketNet {
+    sock: UnixDatagram,
+    local: PathBuf,
+    peer: PathBuf,
+    /// Receive buffer reused by every recv()
+    buf: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl UnixSocketNet {
+    /// Binds to local socket path and sends frames to the peer socket path
+    pub fn new(local: &Path, peer: &Path) -> std::io::Result<UnixSocketNet> {
+        remove_stale_socket(local)?;
+        let sock = UnixDatagram::bind(local)?;
+        sock.set_nonblocking(true)?;
+        Ok(UnixSocketNet {
+            sock,
+            local: local.to_path_buf(),
+            peer: peer.to_path_buf(),
+            buf: vec![0; MAX_FRAME_SZ],
+        })
+    }
+}
+
+/// The peer sees the link going down
+#[cfg(unix)]
+impl Drop for UnixSocketNet {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.local);
+    }
+}
+
+#[cfg(unix)]
+impl NetBackend for UnixSocketNet {
+    fn send(&mut self, frame: &[u8]) {
+        // the peer might be not started yet - the frame is dropped as on a real wire
+        let _ = self.sock.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        match self.sock.recv(&mut self.buf) {
+            Ok(n) => Some(self.buf[..n].to_vec()),
+            Err(_) => None,
+        }
+    }
+
+    /// The link is up while the peer instance is running
+    fn link_up(&self) -> bool {
+        self.peer.exists()
+    }
+}
+
+/// Writes all transmitted frames to a pcap file, nothing is ever received
+pub struct PcapWriter {
+    out: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn new(path: &Path) -> std::io::Result<PcapWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&0xa1b2_c3d4_u32.to_le_bytes())?; // magic
+        out.write_all(&2_u16.to_le_bytes())?; // version major
+        out.write_all(&4_u16.to_le_bytes())?; // version minor
+        out.write_all(&0_i32.to_le_bytes())?; // GMT to local correction
+        out.write_all(&0_u32.to_le_bytes())?; // accuracy of timestamps
+        out.write_all(&(MAX_FRAME_SZ as u32).to_le_bytes())?; // snapshot length
+        out.write_all(&1_u32.to_le_bytes())?; // link-layer type: ethernet
+        out.flush()?;
+        Ok(PcapWriter { out })
+    }
+
+    fn write_record(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.out.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&ts.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(frame)?;
+        self.out.flush()
+    }
+}
+
+impl NetBackend for PcapWriter {
+    fn send(&mut self, frame: &[u8]) {
+        if let Err(e) = self.write_record(frame) {
+            eprintln!("ERROR: virtio-net: failed to write pcap record: {e}");
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Parses a network backend specification:
+/// "loopback", "pcap:<file>" or "unix:<local_socket>,<peer_socket>"
+pub fn parse_net_backend(spec: &str) -> Result<Box<dyn NetBackend>, String> {
+    if spec == "loopback" {
+        return Ok(Box::<Loopback>::default());
+    }
+    if let Some(path) = spec.strip_prefix("pcap:") {
+        return PcapWriter::new(Path::new(path))
+            .map(|b| Box::new(b) as Box<dyn NetBackend>)
+            .map_err(|e| format!("failed to create {path}: {e}"));
+    }
+    #[cfg(unix)]
+    if let Some(paths) = spec.strip_prefix("unix:") {
+        let Some((local, peer)) = paths.split_once(',') else {
+            return Err("format should be: unix:<local_socket>,<peer_socket>".to_string());
+        };
+        return UnixSocketNet::new(Path::new(local), Path::new(peer))
+            .map(|b| Box::new(b) as Box<dyn NetBackend>)
+            .map_err(|e| format!("failed to bind {local}: {e}"));
+    }
+    Err(format!("unknown network backend: {spec}"))
+}
+
+/// Parses MAC address string "52:54:00:12:34:56"
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let octets: Option<Vec<u8>> = s
+        .split(':')
+        .map(|o| {
+            // from_str_radix() accepts a sign
+            if o.len() != 2 || !o.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            u8::from_str_radix(o, 16).ok()
+        })
+        .collect();
+    octets
+        .and_then(|o| o.try_into().ok())
+        .ok_or_else(|| format!("wrong MAC address: {s}"))
+}
+
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    /// Link status as reported to the driver
+    link_up: bool,
+    /// Frame received from the backend while the driver had no RX buffers
+    rx_pending: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> VirtioNet {
+        let link_up = backend.link_up();
+        VirtioNet {
+            mac,
+            backend,
+            link_up,
+            rx_pending: None,
+        }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn status(&self) -> u16 {
+        if self.link_up {
+            VIRTIO_NET_S_LINK_UP
+        } else {
+            0
+        }
+    }
+
+    fn transmit(&mut self, txq: &mut Virtqueue, mem: &mut dyn DmaMem) -> Result<u32, String> {
+        let mut int_bits = 0;
+        while let Some(chain) = txq.pop(mem)? {
+            let packet = chain.read_all(mem, VIRTIO_NET_HDR_SZ + MAX_FRAME_SZ)?;
+            if packet.len() > VIRTIO_NET_HDR_SZ {
+                self.backend.send(&packet[VIRTIO_NET_HDR_SZ..]);
+            }
+            txq.push_used(mem, chain.head, 0)?;
+            int_bits |= VIRTIO_INT_USED_RING;
+        }
+        Ok(int_bits)
+    }
+
+    fn receive(&mut self, rxq: &mut Virtqueue, mem: &mut dyn DmaMem) -> Result<u32, String> {
+        let mut int_bits = 0;
+        while let Some(frame) = self.rx_pending.take().or_else(|| self.backend.recv()) {
+            if !rxq.has_avail(mem) {
+                self.rx_pending = Some(frame);
+                break;
+            }
+            let Some(chain) = rxq.pop(mem)? else {
+                break;
+            };
+            let mut packet = vec![0; VIRTIO_NET_HDR_SZ];
+            // num_buffers = 1
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+            let written = chain.write_all(mem, &packet)?;
+            rxq.push_used(mem, chain.head, written)?;
+            int_bits |= VIRTIO_INT_USED_RING;
+        }
+        Ok(int_bits)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    // struct virtio_net_config { u8 mac[6]; le16 status; ... }
+    fn read_config(&self, offset: u64) -> u8 {
+        match offset {
+            0..=5 => self.mac[offset as usize],
+            6 | 7 => self.status().to_le_bytes()[offset as usize - 6],
+            _ => 0,
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        mem: &mut dyn DmaMem,
+    ) -> u32 {
+        let res = match queue {
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem),
+            RX_QUEUE => self.receive(&mut queues[RX_QUEUE], mem),
+            _ => Ok(0),
+        };
+        res.unwrap_or_else(|e| {
+            eprintln!("ERROR: virtio-net: {e}");
+            0
+        })
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut dyn DmaMem) -> u32 {
+        let mut int_bits = 0;
+        if self.backend.link_up() != self.link_up {
+            self.link_up = !self.link_up;
+            int_bits |= VIRTIO_INT_CONFIG;
+        }
+        int_bits
+            | self
+                .receive(&mut queues[RX_QUEUE], mem)
+                .unwrap_or_else(|e| {
+                    eprintln!("ERROR: virtio-net: {e}");
+                    0
+                })
+    }
+
+    fn reset(&mut self) {
+        self.rx_pending = None;
+    }
+
+    // frames queued in the backend aren't saved
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.link_up);
+        w.bool(self.rx_pending.is_some());
+        w.bytes(self.rx_pending.as_deref().unwrap_or_default());
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.link_up = r.bool()?;
+        let pending = r.bool()?;
+        let frame = r.bytes()?;
+        self.rx_pending = pending.then(|| frame.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::test_driver::*;
+    use crate::virtio::VirtioMmio;
+
+    #[test]
+    fn test_virtio_net_loopback() {
+        let mac = parse_mac("52:54:00:12:34:56").unwrap();
+        let net = VirtioNet::new(mac, parse_net_backend("loopback").unwrap());
+        let mut bus = new_bus(Box::new(VirtioMmio::new(net)));
+
+        assert_eq!(bus.read32(DEV_BASE).unwrap(), 0x7472_6976);
+        assert_eq!(bus.read32(DEV_BASE + 0x008).unwrap(), VIRTIO_ID_NET);
+        assert_eq!(bus.read8(DEV_BASE + 0x100 + 5).unwrap(), 0x56);
+        // link is up
+        assert_eq!(bus.read8(DEV_BASE + 0x100 + 6).unwrap(), 1);
+
+        let rx_desc = setup_queue(&mut bus, RX_QUEUE as u32);
+        let tx_desc = setup_queue(&mut bus, TX_QUEUE as u32);
+        driver_ok(&mut bus);
+
+        let rx_buf = RAM_BASE + 0x4000;
+        add_buf(&mut bus, rx_desc, rx_buf, 1514, true);
+        let tx_buf = RAM_BASE + 0x5000;
+        let tx_len = VIRTIO_NET_HDR_SZ as u32 + 4;
+        bus.write32(tx_buf + VIRTIO_NET_HDR_SZ as u64, 0xdead_beef)
+            .unwrap();
+        add_buf(&mut bus, tx_desc, tx_buf, tx_len, false);
+        notify(&mut bus, TX_QUEUE as u32);
+
+        // TX buffer is used
+        assert_eq!(last_used(&bus, tx_desc).0, 1);
+        // frame came back through the RX queue
+        assert_eq!(last_used(&bus, rx_desc), (1, tx_len));
+        assert_eq!(
+            bus.read32(rx_buf + VIRTIO_NET_HDR_SZ as u64).unwrap(),
+            0xdead_beef
+        );
+        assert_eq!(interrupt_status(&bus), VIRTIO_INT_USED_RING);
+    }
+
+    #[test]
+    fn test_parse_net_options() {
+        assert_eq!(parse_mac("02:00:00:00:00:0a"), Ok([0x02, 0, 0, 0, 0, 0x0a]));
+        assert!(parse_mac("02:00:00:00:00").is_err());
+        assert!(parse_mac("02:00:00:00:00:zz").is_err());
+        assert!(parse_mac("02:00:00:00:00:zz:11").is_err());
+        assert!(parse_mac("02:00:00:00:00:0a:11").is_err());
+        assert!(parse_mac("2:00:00:00:00:0a").is_err());
+        assert!(parse_mac("02:00:00:00:00:+a").is_err());
+        assert!(parse_net_backend("tap0").is_err());
+        assert!(parse_net_backend("unix:/tmp/a").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_net() {
+        let dir = std::env::temp_dir().join(format!("kompusim-net-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.sock"), dir.join("b.sock"));
+        // a regular file at the socket path is kept
+        std::fs::write(&a, "data").unwrap();
+        assert!(UnixSocketNet::new(&a, &b).is_err());
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "data");
+        std::fs::remove_file(&a).unwrap();
+
+        let mut net_a = UnixSocketNet::new(&a, &b).unwrap();
+        assert!(!net_a.link_up());
+        let mut net_b = UnixSocketNet::new(&b, &a).unwrap();
+        assert!(net_a.link_up() && net_b.link_up());
+        net_a.send(&[1, 2, 3]);
+        assert_eq!(net_b.recv(), Some(vec![1, 2, 3]));
+        assert_eq!(net_b.recv(), None);
+        // a stale socket is replaced
+        drop(net_b);
+        assert!(!net_a.link_up());
+        std::os::unix::net::UnixDatagram::bind(&b).unwrap();
+        let net_b = UnixSocketNet::new(&b, &a).unwrap();
+        assert!(net_a.link_up());
+        drop((net_a, net_b));
+        assert!(!a.exists() && !b.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+// Virtio entropy device (virtio v1.1 spec, section 5.4).
+// Entropy comes from a seeded pseudo-random generator, so simulation runs stay reproducible.
+
+use crate::device::DmaMem;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::virtio::{VirtioDevice, Virtqueue, VIRTIO_INT_USED_RING};
+
+const VIRTIO_ID_ENTROPY: u32 = 4;
+
+const REQUEST_QUEUE: usize = 0;
+
+/// Entropy per request, larger buffers are filled partially
+const MAX_ENTROPY: usize = 65536;
+
+pub const DEFAULT_RNG_SEED: u64 = 0x4b6f_6d70_7553_696d;
+
+/// Deterministic xorshift64* generator seeded through splitmix64
+pub struct DetRng {
+    state: u64,
+}
+
+impl DetRng {
+    pub fn new(seed: u64) -> DetRng {
+        // splitmix64 scrambles the seed, it also guarantees non-zero state for seed 0
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        DetRng {
+            state: if z == 0 { DEFAULT_RNG_SEED } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let r = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&r[..chunk.len()]);
+        }
+    }
+}
+
+pub struct VirtioRng {
+    rng: DetRng,
+}
+
+impl VirtioRng {
+    pub fn new(seed: u64) -> VirtioRng {
+        VirtioRng {
+            rng: DetRng::new(seed),
+        }
+    }
+
+    fn fill_requests(&mut self, reqq: &mut Virtqueue, mem: &mut dyn DmaMem) -> Result<u32, String> {
+        let mut int_bits = 0;
+        while let Some(chain) = reqq.pop(mem)? {
+            let len = (chain.writable_len() as usize).min(MAX_ENTROPY);
+            let mut entropy = vec![0; len];
+            self.rng.fill(&mut entropy);
+            let written = chain.write_all(mem, &entropy)?;
+            reqq.push_used(mem, chain.head, written)?;
+            int_bits |= VIRTIO_INT_USED_RING;
+        }
+        Ok(int_bits)
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    // The entropy device has no configuration space
+    fn read_config(&self, _offset: u64) -> u8 {
+        0
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        mem: &mut dyn DmaMem,
+    ) -> u32 {
+        if queue != REQUEST_QUEUE {
+            return 0;
+        }
+        self.fill_requests(&mut queues[REQUEST_QUEUE], mem)
+            .unwrap_or_else(|e| {
+                eprintln!("ERROR: virtio-rng: {e}");
+                0
+            })
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.rng.state);
+    }
+
+    fn restore_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.rng.state = r.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::test_driver::*;
+    use crate::virtio::VirtioMmio;
+
+    #[test]
+    fn test_det_rng_is_reproducible() {
+        let mut a = DetRng::new(42);
+        let mut b = DetRng::new(42);
+        let mut c = DetRng::new(43);
+        let (mut buf_a, mut buf_b, mut buf_c) = ([0; 13], [0; 13], [0; 13]);
+        a.fill(&mut buf_a);
+        b.fill(&mut buf_b);
+        c.fill(&mut buf_c);
+        assert_eq!(buf_a, buf_b);
+        assert_ne!(buf_a, buf_c);
+        assert_ne!(DetRng::new(0).next_u64(), 0);
+    }
+
+    #[test]
+    fn test_virtio_rng_request() {
+        let mut bus = new_bus(Box::new(VirtioMmio::new(VirtioRng::new(7))));
+        assert_eq!(bus.read32(DEV_BASE + 0x008).unwrap(), VIRTIO_ID_ENTROPY);
+        let desc = setup_queue(&mut bus, REQUEST_QUEUE as u32);
+        driver_ok(&mut bus);
+        let buf = RAM_BASE + 0x4000;
+        add_buf(&mut bus, desc, buf, 16, true);
+        // notifications of missing queues are ignored
+        for q in [1, 31, 32, u32::MAX] {
+            notify(&mut bus, q);
+        }
+        assert_eq!(last_used(&bus, desc), (0, 0));
+        notify(&mut bus, REQUEST_QUEUE as u32);
+        assert_eq!(last_used(&bus, desc), (1, 16));
+        let mut expected = DetRng::new(7);
+        assert_eq!(bus.read64(buf).unwrap(), expected.next_u64());
+        assert_eq!(bus.read64(buf + 8).unwrap(), expected.next_u64());
+
+        // a huge buffer gets a partial fill
+        add_buf(&mut bus, desc, RAM_BASE + 0x8000, u32::MAX, true);
+        notify(&mut bus, REQUEST_QUEUE as u32);
+        assert_eq!(last_used(&bus, desc), (2, MAX_ENTROPY as u32));
+    }
+}
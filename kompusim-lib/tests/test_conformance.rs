@@ -0,0 +1,140 @@
+// Runs the conformance runner on a directory of generated riscv-tests style ELFs: the program
+// stores 0x123 to the signature and reports its status through tohost.
+
+use std::path::Path;
+use std::process::Command;
+
+use kompusim::conformance::{run_dir, Outcome};
+
+const BASE: u64 = 0x8000_0000;
+const CODE: usize = 0x100;
+const TOHOST: usize = 0x200;
+const SIGNATURE: usize = 0x300;
+const SEGMENT_SIZE: usize = 0x400;
+
+fn program(status: u32) -> [u32; 6] {
+    [
+        0x00000297,                // auipc t0,0
+        0x12300313,                // li t1,0x123
+        0x2062a023,                // sw t1,0x200(t0) (begin_signature)
+        0x00000313 | status << 20, // li t1,status
+        0x1062b023,                // sd t1,0x100(t0) (tohost)
+        0xffdff06f,                // j -4
+    ]
+}
+
+fn put(elf: &mut [u8], offs: usize, bytes: &[u8]) {
+    elf[offs..offs + bytes.len()].copy_from_slice(bytes);
+}
+
+/// ELF with one PT_LOAD segment at BASE (headers, code, tohost, signature) and a symbol table
+fn test_elf(status: u32) -> Vec<u8> {
+    let mut elf = vec![0u8; SEGMENT_SIZE];
+    put(&mut elf, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut elf, 16, &2u16.to_le_bytes()); // ET_EXEC
+    put(&mut elf, 18, &243u16.to_le_bytes()); // EM_RISCV
+    put(&mut elf, 24, &(BASE + CODE as u64).to_le_bytes());
+    put(&mut elf, 32, &64u64.to_le_bytes());
+    put(&mut elf, 52, &64u16.to_le_bytes());
+    put(&mut elf, 54, &56u16.to_le_bytes());
+    put(&mut elf, 56, &1u16.to_le_bytes());
+    put(&mut elf, 64, &1u32.to_le_bytes()); // PT_LOAD
+    put(&mut elf, 64 + 4, &7u32.to_le_bytes()); // RWX
+    put(&mut elf, 64 + 16, &BASE.to_le_bytes());
+    put(&mut elf, 64 + 24, &BASE.to_le_bytes());
+    put(&mut elf, 64 + 32, &(SEGMENT_SIZE as u64).to_le_bytes());
+    put(&mut elf, 64 + 40, &(SEGMENT_SIZE as u64).to_le_bytes());
+    let code: Vec<u8> = program(status)
+        .iter()
+        .flat_map(|i| i.to_le_bytes())
+        .collect();
+    put(&mut elf, CODE, &code);
+
+    let symbols = [
+        ("tohost", TOHOST),
+        ("begin_signature", SIGNATURE),
+        ("end_signature", SIGNATURE + 8),
+    ];
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 24];
+    for (name, offs) in symbols {
+        let mut sym = [0u8; 24];
+        sym[0..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+        sym[8..16].copy_from_slice(&(BASE + offs as u64).to_le_bytes());
+        symtab.extend_from_slice(&sym);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let symtab_off = elf.len() as u64;
+    elf.extend_from_slice(&symtab);
+    let strtab_off = elf.len() as u64;
+    elf.extend_from_slice(&strtab);
+    // section headers: NULL, .symtab (SHT_SYMTAB, link 2), .strtab (SHT_STRTAB)
+    let shoff = elf.len();
+    elf.resize(shoff + 3 * 64, 0);
+    let sh = shoff + 64;
+    put(&mut elf, sh + 4, &2u32.to_le_bytes());
+    put(&mut elf, sh + 24, &symtab_off.to_le_bytes());
+    put(&mut elf, sh + 32, &(symtab.len() as u64).to_le_bytes());
+    put(&mut elf, sh + 40, &2u32.to_le_bytes());
+    let sh = sh + 64;
+    put(&mut elf, sh + 4, &3u32.to_le_bytes());
+    put(&mut elf, sh + 24, &strtab_off.to_le_bytes());
+    put(&mut elf, sh + 32, &(strtab.len() as u64).to_le_bytes());
+    put(&mut elf, 40, &(shoff as u64).to_le_bytes());
+    put(&mut elf, 58, &64u16.to_le_bytes());
+    put(&mut elf, 60, &3u16.to_le_bytes());
+    elf
+}
+
+fn write_tests(dir: &Path) {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir.join("arch/references")).unwrap();
+    // pass is status 1, failure of test 3 is 3 << 1 | 1
+    std::fs::write(dir.join("rv64ui-p-pass"), test_elf(1)).unwrap();
+    std::fs::write(dir.join("rv64ui-p-fail"), test_elf(3 << 1 | 1)).unwrap();
+    std::fs::write(dir.join("README"), "not an ELF").unwrap();
+    std::fs::write(dir.join("arch/sig-01.elf"), test_elf(1)).unwrap();
+    std::fs::write(dir.join("arch/sig-02.elf"), test_elf(1)).unwrap();
+    // the segment wraps the address space
+    let mut wrap = test_elf(1);
+    put(&mut wrap, 64 + 40, &u64::MAX.to_le_bytes());
+    std::fs::write(dir.join("rv64ui-p-wrap"), wrap).unwrap();
+    let refs = dir.join("arch/references");
+    std::fs::write(refs.join("sig-01.reference_output"), "00000123\n00000000\n").unwrap();
+    std::fs::write(refs.join("sig-02.reference_output"), "00000124\n00000000\n").unwrap();
+}
+
+#[test]
+fn test_conformance_runner() {
+    let dir = std::env::temp_dir().join(format!("kompusim-conformance-{}", std::process::id()));
+    write_tests(&dir);
+    let results = run_dir(&dir, None, 100_000).unwrap();
+    let summary: Vec<(String, bool, bool)> = results
+        .iter()
+        .map(|r| {
+            let name = r.path.strip_prefix(&dir).unwrap().display().to_string();
+            (name, r.passed(), r.signature_checked)
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("arch/sig-01.elf".to_string(), true, true),
+            ("arch/sig-02.elf".to_string(), false, true),
+            ("rv64ui-p-fail".to_string(), false, false),
+            ("rv64ui-p-pass".to_string(), true, false),
+            ("rv64ui-p-wrap".to_string(), false, false),
+        ]
+    );
+    assert!(matches!(&results[2].outcome, Outcome::Fail(reason) if reason == "test 3 failed"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_kompusim"))
+        .arg("conformance")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("2 passed, 3 failed"));
+    assert_eq!(output.status.code(), Some(1));
+}
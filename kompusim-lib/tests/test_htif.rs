@@ -0,0 +1,42 @@
+// Runs a raw binary talking to the simulator through HTIF at --tohost: it prints 'H' with the
+// console device, waits until tohost is consumed and exits with status 7 like a riscv-tests
+// failure of test 7.
+
+use std::process::Command;
+
+const PROGRAM: [u32; 12] = [
+    0x00000297, // auipc t0,0
+    0x10028293, // addi t0,t0,0x100 (tohost)
+    0x04800313, // li t1,'H'
+    0x10100393, // li t2,0x101
+    0x03039393, // slli t2,t2,48 (device 1, command 1: putchar)
+    0x00736333, // or t1,t1,t2
+    0x0062b023, // sd t1,0(t0)
+    0x0002b303, // ld t1,0(t0)
+    0xfe031ee3, // bnez t1,-4
+    0x00f00313, // li t1,15 (7 << 1 | 1)
+    0x0062b023, // sd t1,0(t0)
+    0x0000006f, // j .
+];
+
+#[test]
+fn test_htif_console_and_exit_code() {
+    let path = std::env::temp_dir().join(format!("kompusim-htif-{}.bin", std::process::id()));
+    let image: Vec<u8> = PROGRAM.iter().flat_map(|i| i.to_le_bytes()).collect();
+    std::fs::write(&path, image).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_kompusim"))
+        .args([
+            "exec",
+            "--load-addr",
+            "0x80000000",
+            "--tohost",
+            "0x80000100",
+        ])
+        .arg("--bin")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with('H'));
+    assert_eq!(output.status.code(), Some(7));
+}
@@ -1,4 +1,8 @@
 use kompusim::bus::Bus;
+use kompusim::clint::Clint;
+use kompusim::csr;
+use kompusim::device::Device;
+use kompusim::ram::Ram;
 use kompusim::rv64i_cpu::RV64ICpu;
 
 #[test]
@@ -307,7 +311,8 @@ fn test_instruction_jalr() {
 // lbu rd, offset12(rs1)
 fn test_instruction_lbu() {
     let mut bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
-    bus.write64(0x0000_0000_0000_003c, 0x_abcd_ef01_2345_6789);
+    bus.write64(0x0000_0000_0000_003c, 0x_abcd_ef01_2345_6789)
+        .unwrap();
     let mut cpu = RV64ICpu::new(bus);
 
     cpu.regs_w64(6, 0xa5a5_a5a5_a5a5_a5a5);
@@ -325,8 +330,8 @@ fn test_instruction_lb() {
     let bus = Bus::new_with_ram(0x0, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
 
-    cpu.bus.write8(0x3c - 1, 0x_89);
-    cpu.bus.write8(0x3c + 2047, 0x_79);
+    cpu.bus.write8(0x3c - 1, 0x_89).unwrap();
+    cpu.bus.write8(0x3c + 2047, 0x_79).unwrap();
 
     cpu.regs_w64(6, 0xa5a5_a5a5_a5a5_a5a5);
     cpu.regs_w64(10, 0x0000_0000_0000_003c);
@@ -346,7 +351,7 @@ fn test_instruction_lb() {
 // lw x7, 0x0(x5)
 fn test_instruction_lw() {
     let mut bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
-    bus.write32(0x0000_0000_0000_0000, 0xa5a5_a5a5);
+    bus.write32(0x0000_0000_0000_0000, 0xa5a5_a5a5).unwrap();
     let mut cpu = RV64ICpu::new(bus);
     cpu.regs_w64(7, 0x_dead_beef_dead_beef);
     cpu.execute_instr(0x_0002_a383);
@@ -364,7 +369,7 @@ fn test_instruction_sw() {
     cpu.regs_w64(5, 0x10); // address
     cpu.regs_w64(6, 0xdead_beef); // what to store
     cpu.execute_instr(0x0062a023);
-    assert!(cpu.bus.read32(0x10) == 0xdead_beef);
+    assert!(cpu.bus.read32(0x10).unwrap() == 0xdead_beef);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -380,7 +385,7 @@ fn test_instruction_sb() {
     cpu.regs_w64(17, 0x_baad_c0fe_dead_beef);
     // sb x17, -1982(x20)
     cpu.execute_instr(0x_851a_0123);
-    assert_eq!(cpu.bus.read64(0x0), 0x_0000_0000_0000_00ef);
+    assert_eq!(cpu.bus.read64(0x0).unwrap(), 0x_0000_0000_0000_00ef);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -445,7 +450,7 @@ fn test_instruction_sub() {
 fn test_instruction_lrw() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    cpu.bus.write32(0x0, 0x0000_beef);
+    cpu.bus.write32(0x0, 0x0000_beef).unwrap();
     cpu.execute_instr(0x_1000_20af);
     assert_eq!(cpu.regs_r64(1), 0x0000_beef);
     assert_eq!(cpu.get_pc(), 4);
@@ -456,13 +461,13 @@ fn test_instruction_lrw() {
 fn test_amoswap() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    cpu.bus.write32(0x0, 0x0000_beef);
-    assert!(cpu.bus.read32(0x0) == 0x0000_beef);
+    cpu.bus.write32(0x0, 0x0000_beef).unwrap();
+    assert!(cpu.bus.read32(0x0).unwrap() == 0x0000_beef);
     cpu.regs_w64(5, 0xc0fe);
     // amoswap.w.aq  x6, x5, (x10) # x6 <= mem[x10]; mem[x10] <= x5
     cpu.execute_instr(0x_0c55_232f);
     assert_eq!(cpu.regs_r64(6), 0x0000_beef);
-    assert_eq!(cpu.bus.read32(0x0), 0xc0fe);
+    assert_eq!(cpu.bus.read32(0x0).unwrap(), 0xc0fe);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -471,13 +476,13 @@ fn test_amoswap() {
 fn test_amoadd() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    cpu.bus.write32(0x0, 0x0000_0001);
+    cpu.bus.write32(0x0, 0x0000_0001).unwrap();
     cpu.regs_w64(1, 0x1);
     // amoadd.w rd, rs2, rs1 # rd <= mem[rs1]; mem[rs1] <= rd + rs2
     // amoadd.w.aq x2, x1, (x0)
     cpu.execute_instr(0x_0410_212f);
     assert_eq!(cpu.regs_r64(2), 0x1);
-    assert_eq!(cpu.bus.read32(0x0), 0x0000_0002);
+    assert_eq!(cpu.bus.read32(0x0).unwrap(), 0x0000_0002);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -486,13 +491,13 @@ fn test_amoadd() {
 fn test_amoadd_rd_equals_rs1() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    cpu.bus.write32(0x0, 0x0000_0001);
+    cpu.bus.write32(0x0, 0x0000_0001).unwrap();
     cpu.regs_w64(17, 0x2);
     // amoadd.w rd,  rs2, (rs1) # rd <= mem[rs1]; mem[rs1] <= rd + rs2
     // amoadd.w x16, x17, (x16)
     cpu.execute_instr(0x_0118_282f);
     assert_eq!(cpu.regs_r64(16), 0x1);
-    assert_eq!(cpu.bus.read32(0x0), 0x0000_0003);
+    assert_eq!(cpu.bus.read32(0x0).unwrap(), 0x0000_0003);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -501,12 +506,12 @@ fn test_amoadd_rd_equals_rs1() {
 fn test_sd() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    assert!(cpu.bus.read32(0x10) == 0);
+    assert!(cpu.bus.read32(0x10).unwrap() == 0);
     cpu.regs_w64(5, 0x10); // address
     cpu.regs_w64(6, 0x_badc_0ffe_dead_beef); // what to store
     cpu.execute_instr(0x0062_b023);
-    assert!(cpu.bus.read32(0x10) == 0x_dead_beef);
-    assert!(cpu.bus.read32(0x14) == 0x_badc0ffe);
+    assert!(cpu.bus.read32(0x10).unwrap() == 0x_dead_beef);
+    assert!(cpu.bus.read32(0x14).unwrap() == 0x_badc0ffe);
     assert_eq!(cpu.get_pc(), 4);
 }
 
@@ -515,9 +520,9 @@ fn test_sd() {
 fn test_ld() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    assert!(cpu.bus.read32(0x10) == 0);
+    assert!(cpu.bus.read32(0x10).unwrap() == 0);
     cpu.regs_w64(5, 0x10); // address
-    cpu.bus.write64(0x10, 0x_badc_0ffe_dead_beef);
+    cpu.bus.write64(0x10, 0x_badc_0ffe_dead_beef).unwrap();
     cpu.execute_instr(0x_0002_b303);
     assert_eq!(cpu.regs_r64(6), 0x_badc_0ffe_dead_beef);
     assert_eq!(cpu.get_pc(), 4);
@@ -909,7 +914,7 @@ fn test_lwu() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
 
-    cpu.bus.write32(0, 0xdead_beef);
+    cpu.bus.write32(0, 0xdead_beef).unwrap();
     cpu.regs_w64(15, 0x_ffff_ffff_ffff_ffff);
     cpu.regs_w64(8, 52);
     // lwu x15, -52(x8)
@@ -1058,6 +1063,188 @@ fn test_wfi() {
     assert_eq!(cpu.get_pc(), 4);
 }
 
+// M extension: mul, mulh, div, divu, rem
+#[test]
+fn test_muldiv() {
+    let mut cpu = RV64ICpu::default();
+    cpu.regs_w64(10, -6_i64 as u64);
+    cpu.regs_w64(11, 4);
+    // mul a0, a0, a1
+    cpu.execute_instr(0x_02b5_0533);
+    assert_eq!(cpu.regs_r64(10), -24_i64 as u64);
+    // mulh a0, a0, a1
+    cpu.execute_instr(0x_02b5_1533);
+    assert_eq!(cpu.regs_r64(10), u64::MAX);
+    cpu.regs_w64(10, -7_i64 as u64);
+    // div a0, a0, a1
+    cpu.execute_instr(0x_02b5_4533);
+    assert_eq!(cpu.regs_r64(10), -1_i64 as u64);
+    cpu.regs_w64(10, -7_i64 as u64);
+    // rem a0, a0, a1
+    cpu.execute_instr(0x_02b5_6533);
+    assert_eq!(cpu.regs_r64(10), -3_i64 as u64);
+    // divu a0, a0, a1: division by zero gives all ones
+    cpu.regs_w64(11, 0);
+    cpu.execute_instr(0x_02b5_5533);
+    assert_eq!(cpu.regs_r64(10), u64::MAX);
+    assert_eq!(cpu.get_pc(), 5 * 4);
+}
+
+#[test]
+fn test_srai_sraiw_addw() {
+    let mut cpu = RV64ICpu::default();
+    cpu.regs_w64(10, 0x_8000_0000_0000_0000);
+    // srai a0, a0, 4
+    cpu.execute_instr(0x_4045_5513);
+    assert_eq!(cpu.regs_r64(10), 0x_f800_0000_0000_0000);
+    cpu.regs_w64(10, 0x_0000_0000_8000_0000);
+    // sraiw a0, a0, 4
+    cpu.execute_instr(0x_4045_551b);
+    assert_eq!(cpu.regs_r64(10), 0x_ffff_ffff_f800_0000);
+    cpu.regs_w64(10, 0x_7fff_ffff);
+    cpu.regs_w64(11, 1);
+    // addw a0, a0, a1
+    cpu.execute_instr(0x_00b5_053b);
+    assert_eq!(cpu.regs_r64(10), 0x_ffff_ffff_8000_0000);
+}
+
+#[test]
+fn test_amomax_lr_sc_d() {
+    let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
+    let mut cpu = RV64ICpu::new(bus);
+    cpu.bus.write64(0x8, 5).unwrap();
+    cpu.regs_w64(12, 0x8);
+    cpu.regs_w64(11, 7);
+    // amomax.d a0, a1, (a2)
+    cpu.execute_instr(0x_a0b6_352f);
+    assert_eq!(cpu.regs_r64(10), 5);
+    assert_eq!(cpu.bus.read64(0x8).unwrap(), 7);
+    // sc.d a0, a1, (a2) without reservation fails
+    cpu.regs_w64(11, 9);
+    cpu.execute_instr(0x_18b6_352f);
+    assert_eq!(cpu.regs_r64(10), 1);
+    assert_eq!(cpu.bus.read64(0x8).unwrap(), 7);
+    // lr.d a0, (a2)
+    cpu.execute_instr(0x_1006_352f);
+    assert_eq!(cpu.regs_r64(10), 7);
+    // sc.d a0, a1, (a2)
+    cpu.execute_instr(0x_18b6_352f);
+    assert_eq!(cpu.regs_r64(10), 0);
+    assert_eq!(cpu.bus.read64(0x8).unwrap(), 9);
+}
+
+#[test]
+fn test_ecall_mret() {
+    let mut cpu = RV64ICpu::default();
+    cpu.regs_w64(4, 0x100);
+    // csrrw x0, mtvec, x4
+    cpu.execute_instr(0x_3052_1073);
+    // ecall
+    cpu.execute_instr(0x_0000_0073);
+    assert_eq!(cpu.get_pc(), 0x100);
+    assert_eq!(cpu.csr_r64(csr::MCAUSE), Some(csr::EXC_ECALL_M));
+    assert_eq!(cpu.csr_r64(csr::MEPC), Some(4));
+    // mret
+    cpu.execute_instr(0x_3020_0073);
+    assert_eq!(cpu.get_pc(), 4);
+    assert_eq!(cpu.get_priv_mode(), csr::PrivMode::Machine);
+}
+
+#[test]
+fn test_illegal_csr_write_traps() {
+    let mut cpu = RV64ICpu::default();
+    cpu.regs_w64(4, 0x200);
+    // csrrw x0, mtvec, x4
+    cpu.execute_instr(0x_3052_1073);
+    // csrrw x1, mhartid, x4: mhartid is read-only
+    cpu.execute_instr(0x_f142_10f3);
+    assert_eq!(cpu.get_pc(), 0x200);
+    assert_eq!(cpu.csr_r64(csr::MCAUSE), Some(csr::EXC_ILLEGAL_INSTR));
+    assert_eq!(cpu.csr_r64(csr::MTVAL), Some(0x_f142_10f3));
+    assert_eq!(cpu.csr_r64(csr::MEPC), Some(4));
+}
+
+#[test]
+fn test_timer_interrupt() {
+    let mut bus = Bus::new();
+    bus.attach_ram(Ram::new(0x8000_0000, 0x1_0000));
+    let clint = Box::new(Clint::new(bus.clock()));
+    bus.attach_device(Device::new(clint, 0x200_0000, 0x1_0000));
+    let mut cpu = RV64ICpu::new(bus);
+    // fill RAM with nops
+    for addr in (0x8000_0000..0x8001_0000).step_by(4) {
+        cpu.bus.write32(addr, 0x_0000_0013).unwrap();
+    }
+    cpu.pc_jump(0x8000_0000);
+    cpu.regs_w64(4, 0x8000_0100);
+    cpu.regs_w64(5, csr::MIP_MTIP);
+    cpu.regs_w64(6, csr::MSTATUS_MIE);
+    // csrrw x0, mtvec, x4
+    cpu.execute_instr(0x_3052_1073);
+    // csrrs x0, mie, x5
+    cpu.execute_instr(0x_3042_a073);
+    // csrrs x0, mstatus, x6
+    cpu.execute_instr(0x_3003_2073);
+    // mtimecmp
+    cpu.bus.write64(0x200_4000, 10).unwrap();
+    cpu.exec_continue(2000);
+    assert_eq!(
+        cpu.csr_r64(csr::MCAUSE),
+        Some(csr::MCAUSE_INTERRUPT | csr::IRQ_M_TIMER)
+    );
+    // interrupts are disabled in the handler
+    assert_eq!(cpu.csr_r64(csr::MSTATUS).unwrap() & csr::MSTATUS_MIE, 0);
+    assert!(cpu.get_pc() > 0x8000_0100);
+}
+
+// S-mode with Sv39: 1 GiB identity mapped superpage at 0x8000_0000, everything else is unmapped
+#[test]
+fn test_sv39_load_page_fault() {
+    let mut bus = Bus::new();
+    bus.attach_ram(Ram::new(0x8000_0000, 0x4000));
+    let mut cpu = RV64ICpu::new(bus);
+    // root page table at 0x8000_1000, entry 2: V, R, W, X, A, D
+    cpu.bus
+        .write64(0x8000_1000 + 2 * 8, (0x8_0000 << 10) | 0xcf);
+    cpu.bus.write64(0x8000_2008, 0x42).unwrap();
+    // ld a0, 8(a2)
+    cpu.bus.write32(0x8000_0000, 0x_0086_3503).unwrap();
+    // ld a0, 0(a1)
+    cpu.bus.write32(0x8000_0004, 0x_0005_b503).unwrap();
+    cpu.regs_w64(5, csr::SATP_MODE_SV39 << 60 | 0x8_0001);
+    cpu.regs_w64(6, 0x8000_0000);
+    cpu.regs_w64(7, 1 << 11);
+    cpu.regs_w64(8, 1 << csr::EXC_LOAD_PAGE_FAULT);
+    cpu.regs_w64(9, 0x8000_0100);
+    cpu.regs_w64(11, 0x1000);
+    cpu.regs_w64(12, 0x8000_2000);
+    // csrw satp, t0; csrw mepc, t1; csrs mstatus, t2; csrw medeleg, s0; csrw stvec, s1
+    for instr in [
+        0x_1802_9073,
+        0x_3413_1073,
+        0x_3003_a073,
+        0x_3024_1073,
+        0x_1054_9073,
+    ] {
+        cpu.execute_instr(instr);
+    }
+    // mret to S-mode
+    cpu.execute_instr(0x_3020_0073);
+    assert_eq!(cpu.get_priv_mode(), csr::PrivMode::Supervisor);
+    assert_eq!(cpu.get_pc(), 0x8000_0000);
+
+    cpu.exec_continue(1);
+    assert_eq!(cpu.regs_r64(10), 0x42);
+    // the fault is delegated to S-mode
+    cpu.exec_continue(1);
+    assert_eq!(cpu.get_pc(), 0x8000_0100);
+    assert_eq!(cpu.csr_r64(csr::SCAUSE), Some(csr::EXC_LOAD_PAGE_FAULT));
+    assert_eq!(cpu.csr_r64(csr::STVAL), Some(0x1000));
+    assert_eq!(cpu.csr_r64(csr::SEPC), Some(0x8000_0004));
+    // accessed bit is set by the page walk
+    assert_eq!(cpu.bus.read64(0x8000_1010).unwrap() & 0x40, 0x40);
+}
+
 // #[test]
 // fn test_intermixed_instruction {
 //     // TODO:
@@ -0,0 +1,94 @@
+// Boots Linux with a busybox initramfs to the shell prompt on the 16550 UART within an
+// instruction budget. The images aren't part of the repository, the test is run (in release mode,
+// the boot takes a few hundred million instructions) with:
+//   KOMPUSIM_LINUX_FW=fw_jump.bin KOMPUSIM_LINUX_KERNEL=Image KOMPUSIM_LINUX_INITRD=rootfs.cpio \
+//     cargo test --release --test test_linux -- --ignored
+// KOMPUSIM_LINUX_FW can also be fw_payload.bin with the kernel (and initramfs) built in.
+// test_linux_boot_with_builtin_sbi boots the kernel directly in S-mode with the built-in SBI, it
+// needs only KOMPUSIM_LINUX_KERNEL and KOMPUSIM_LINUX_INITRD.
+// The hart has no F/D (misa doesn't report them, mstatus.FS is read-only 0): the kernel must be
+// built with CONFIG_FPU=n for rv64imac and the busybox initramfs for rv64imac/lp64.
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use kompusim::fdt::{generate_dtb, place_dtb, place_initrd};
+use kompusim::machine::{MachineBuilder, MachineConfig};
+
+const FW_ADDR: u64 = 0x8000_0000;
+const KERNEL_ADDR: u64 = 0x8020_0000;
+const DEFAULT_MAX_INSTR: u64 = 2_000_000_000;
+const CHUNK: u64 = 1_000_000;
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The images of the ignored tests are required when they're run
+fn required_env_path(name: &str) -> PathBuf {
+    env_path(name).unwrap_or_else(|| panic!("{name} must be set, see the top of test_linux.rs"))
+}
+
+/// busybox sh prompt, e.g. "/ # " or "~ # "
+fn has_prompt(out: &str) -> bool {
+    out.contains("/ # ") || out.contains("~ # ")
+}
+
+/// Boots the firmware (at FW_ADDR) or the kernel (with the built-in SBI at KERNEL_ADDR) until
+/// the shell prompt and checks the console output
+fn boot_to_shell(fw: Option<PathBuf>, kernel: Option<PathBuf>) {
+    let max_instr = std::env::var("KOMPUSIM_LINUX_MAX_INSTR")
+        .map(|n| n.parse().expect("wrong KOMPUSIM_LINUX_MAX_INSTR"))
+        .unwrap_or(DEFAULT_MAX_INSTR);
+    let profile = concat!(env!("CARGO_MANIFEST_DIR"), "/../machines/linux.toml");
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_cb = out.clone();
+    let machine =
+        MachineBuilder::from_config(MachineConfig::from_file(Path::new(profile)).unwrap())
+            .sbi(fw.is_none())
+            .console_out(Box::new(move |b| out_cb.borrow_mut().push(b as char)));
+    let mut dt_config = machine.config().dt_config();
+    let mut cpu = machine.build().unwrap();
+    if let Some(fw) = &fw {
+        cpu.bus.load_file(FW_ADDR, fw).unwrap();
+    }
+    if let Some(kernel) = kernel {
+        cpu.bus.load_file(KERNEL_ADDR, &kernel).unwrap();
+    }
+    if let Some(initrd) = env_path("KOMPUSIM_LINUX_INITRD") {
+        let image = std::fs::read(initrd).unwrap();
+        dt_config.initrd = Some(place_initrd(&mut cpu, &image).unwrap());
+    }
+    cpu.pc_jump(if fw.is_some() { FW_ADDR } else { KERNEL_ADDR });
+    let dtb = generate_dtb(&cpu.bus, &dt_config);
+    place_dtb(&mut cpu, &dtb).unwrap();
+
+    let mut executed = 0;
+    while executed < max_instr && !has_prompt(&out.borrow()) {
+        cpu.exec_continue(CHUNK);
+        executed += CHUNK;
+    }
+    let out = out.borrow();
+    assert!(out.contains("Linux version"), "no kernel banner in:\n{out}");
+    assert!(
+        has_prompt(&out),
+        "no shell prompt after {executed} instructions:\n{out}"
+    );
+}
+
+#[test]
+#[ignore = "needs OpenSBI and Linux images in KOMPUSIM_LINUX_FW, KOMPUSIM_LINUX_KERNEL"]
+fn test_linux_boot_to_shell() {
+    let fw = required_env_path("KOMPUSIM_LINUX_FW");
+    boot_to_shell(Some(fw), env_path("KOMPUSIM_LINUX_KERNEL"));
+}
+
+#[test]
+#[ignore = "needs Linux image in KOMPUSIM_LINUX_KERNEL"]
+fn test_linux_boot_with_builtin_sbi() {
+    let kernel = required_env_path("KOMPUSIM_LINUX_KERNEL");
+    boot_to_shell(None, Some(kernel));
+}
@@ -0,0 +1,113 @@
+// Runs small static RV64 Linux programs in the user-mode emulation. The ELF files are built in the
+// test: a header, one PT_LOAD segment with the header and the code.
+#![cfg(unix)]
+
+use std::cell::RefCell;
+use std::process::Command;
+use std::rc::Rc;
+
+use kompusim::elf::Elf;
+use kompusim::linux_user::{load_program, run_program};
+
+const LOAD_ADDR: u64 = 0x1_0000;
+const CODE_OFFSET: usize = 64 + 56;
+
+/// Prints argv[1] with write(1, ...) and exits with argc
+const ECHO_ARG1: [u32; 14] = [
+    0x00013403, // ld s0,0(sp)
+    0x01013583, // ld a1,16(sp)
+    0x00058613, // mv a2,a1
+    0x00064283, // lbu t0,0(a2)
+    0x00028663, // beqz t0,+12
+    0x00160613, // addi a2,a2,1
+    0xff5ff06f, // j -12
+    0x40b60633, // sub a2,a2,a1
+    0x00100513, // li a0,1
+    0x04000893, // li a7,64 (write)
+    0x00000073, // ecall
+    0x00040513, // mv a0,s0
+    0x05d00893, // li a7,93 (exit)
+    0x00000073, // ecall
+];
+
+/// Static executable (ET_EXEC) with the code at the entry point
+fn static_elf(code: &[u32]) -> Vec<u8> {
+    let mut elf = vec![0u8; CODE_OFFSET];
+    elf[0..4].copy_from_slice(b"\x7fELF");
+    elf[4] = 2; // ELFCLASS64
+    elf[5] = 1; // ELFDATA2LSB
+    elf[6] = 1; // EV_CURRENT
+    elf[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf[18..20].copy_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    elf[24..32].copy_from_slice(&(LOAD_ADDR + CODE_OFFSET as u64).to_le_bytes());
+    elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+    elf[52..54].copy_from_slice(&64u16.to_le_bytes());
+    elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+    elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+    let size = (CODE_OFFSET + code.len() * 4) as u64;
+    let ph = 64;
+    elf[ph..ph + 4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    elf[ph + 4..ph + 8].copy_from_slice(&5u32.to_le_bytes()); // PF_R | PF_X
+    elf[ph + 16..ph + 24].copy_from_slice(&LOAD_ADDR.to_le_bytes());
+    elf[ph + 24..ph + 32].copy_from_slice(&LOAD_ADDR.to_le_bytes());
+    elf[ph + 32..ph + 40].copy_from_slice(&size.to_le_bytes());
+    elf[ph + 40..ph + 48].copy_from_slice(&size.to_le_bytes());
+    elf.extend(code.iter().flat_map(|i| i.to_le_bytes()));
+    elf
+}
+
+/// Runs the program and returns its exit status and output
+fn run(code: &[u32], argv: &[&str]) -> (i32, String) {
+    let elf = Elf::parse(&static_elf(code)).unwrap();
+    let argv: Vec<String> = argv.iter().map(|a| a.to_string()).collect();
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_cb = out.clone();
+    let console_out = Box::new(move |b| out_cb.borrow_mut().push(b as char));
+    let mut cpu = load_program(&elf, &argv, &["HOME=/".to_string()], Some(console_out)).unwrap();
+    let code = run_program(&mut cpu, 10_000).unwrap();
+    let out = out.borrow().clone();
+    (code, out)
+}
+
+#[test]
+fn test_linux_user_write_and_exit() {
+    assert_eq!(
+        run(&ECHO_ARG1, &["echo", "kompusim"]),
+        (2, "kompusim".to_string())
+    );
+    assert_eq!(run(&ECHO_ARG1, &["echo", "", "x"]), (3, String::new()));
+}
+
+#[test]
+fn test_linux_user_fault() {
+    // illegal instruction is reported like SIGILL
+    assert_eq!(run(&[0x0000_0000], &["ill"]), (128 + 4, String::new()));
+    // NULL pointer dereferences are reported like SIGSEGV
+    assert_eq!(run(&[0x00003503], &["ld"]), (128 + 11, String::new())); // ld a0,0(zero)
+    assert_eq!(run(&[0x00a03023], &["sd"]), (128 + 11, String::new())); // sd a0,0(zero)
+}
+
+#[test]
+fn test_linux_user_dynamic_rejected() {
+    let mut image = static_elf(&ECHO_ARG1);
+    // turn the segment into PT_INTERP
+    image[64..68].copy_from_slice(&3u32.to_le_bytes());
+    let elf = Elf::parse(&image).unwrap();
+    let err = load_program(&elf, &[], &[], None).err().unwrap();
+    assert!(err.contains("-static"));
+}
+
+#[test]
+fn test_linux_user_cli() {
+    let path = std::env::temp_dir().join(format!("kompusim-echo-{}", std::process::id()));
+    std::fs::write(&path, static_elf(&ECHO_ARG1)).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_kompusim"))
+        .arg("user")
+        .arg(&path)
+        .args(["--hello", "world"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "--hello");
+    assert_eq!(output.status.code(), Some(3));
+}
@@ -0,0 +1,104 @@
+// Boots firmware at the start of RAM that hands over to a payload in S-mode with the hart id in a0
+// and the device tree in a1, the payload checks both and prints "OK".
+// test_firmware_handover runs a stub firmware, test_opensbi_banner OpenSBI generic platform
+// firmware (fw_jump.bin or fw_payload.bin, the payload is replaced by the test one). The firmware
+// isn't part of the repository, the test is run with:
+//   KOMPUSIM_OPENSBI_FW=/path/to/fw_jump.bin cargo test --test test_opensbi -- --ignored
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use kompusim::csr::PrivMode;
+use kompusim::fdt::{generate_dtb, place_dtb};
+use kompusim::machine::{MachineBuilder, MachineConfig};
+use kompusim::rv64i_cpu::RV64ICpu;
+
+const FW_ADDR: u64 = 0x8000_0000;
+const PAYLOAD_ADDR: u64 = 0x8020_0000;
+const MAX_INSTR: u64 = 50_000_000;
+
+/// Jumps to PAYLOAD_ADDR in S-mode keeping a0 and a1
+const STUB_FW: [u32; 9] = [
+    0x00100293, // li t0,1
+    0x01f29293, // slli t0,t0,31
+    0x00200337, // lui t1,0x200
+    0x006282b3, // add t0,t0,t1
+    0x34129073, // csrw mepc,t0
+    0x00001337, // lui t1,0x1
+    0x8003031b, // addiw t1,t1,-2048
+    0x30031073, // csrw mstatus,t1
+    0x30200073, // mret
+];
+
+/// Prints "OK" to the SiFive UART if a0 is 0 and a1 points to a device tree, "E" otherwise
+const PAYLOAD: [u32; 12] = [
+    0x0005a283, // lw t0,0(a1)
+    0xedfe1337, // lui t1,0xedfe1
+    0xdd030313, // addi t1,t1,-560 (FDT magic read little endian)
+    0x100103b7, // lui t2,0x10010
+    0x04500e13, // li t3,'E'
+    0x00629a63, // bne t0,t1,fail
+    0x00051863, // bnez a0,fail
+    0x04f00e13, // li t3,'O'
+    0x01c3a023, // sw t3,0(t2)
+    0x04b00e13, // li t3,'K'
+    0x01c3a023, // fail: sw t3,0(t2)
+    0x0000006f, // j .
+];
+
+fn write_code(cpu: &mut RV64ICpu, addr: u64, code: &[u32]) {
+    let bytes: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+    cpu.bus.write_bytes(addr, &bytes).unwrap();
+}
+
+/// Boots the firmware (the stub one if None) with the test payload until it prints "OK", returns
+/// the hart and the console output
+fn boot(fw: Option<PathBuf>) -> (RV64ICpu, String) {
+    let profile = concat!(env!("CARGO_MANIFEST_DIR"), "/../machines/opensbi.toml");
+    let out = Rc::new(RefCell::new(String::new()));
+    let out_cb = out.clone();
+    let machine =
+        MachineBuilder::from_config(MachineConfig::from_file(Path::new(profile)).unwrap())
+            .console_out(Box::new(move |b| out_cb.borrow_mut().push(b as char)));
+    let dt_config = machine.config().dt_config();
+    let mut cpu = machine.build().unwrap();
+    match fw {
+        Some(fw) => cpu.bus.load_file(FW_ADDR, &fw).unwrap(),
+        None => write_code(&mut cpu, FW_ADDR, &STUB_FW),
+    }
+    write_code(&mut cpu, PAYLOAD_ADDR, &PAYLOAD);
+    cpu.pc_jump(FW_ADDR);
+    let dtb = generate_dtb(&cpu.bus, &dt_config);
+    place_dtb(&mut cpu, &dtb).unwrap();
+
+    for _ in 0..MAX_INSTR / 100_000 {
+        cpu.exec_continue(100_000);
+        if out.borrow().ends_with("OK") || out.borrow().ends_with('E') {
+            break;
+        }
+    }
+    let out = out.borrow().clone();
+    (cpu, out)
+}
+
+#[test]
+fn test_firmware_handover() {
+    let (cpu, out) = boot(None);
+    assert_eq!(out, "OK");
+    assert_eq!(cpu.get_priv_mode(), PrivMode::Supervisor);
+}
+
+#[test]
+#[ignore = "needs OpenSBI firmware in KOMPUSIM_OPENSBI_FW"]
+fn test_opensbi_banner() {
+    let fw = std::env::var_os("KOMPUSIM_OPENSBI_FW")
+        .filter(|fw| !fw.is_empty())
+        .map(PathBuf::from)
+        .expect("KOMPUSIM_OPENSBI_FW must be set to OpenSBI fw_jump.bin or fw_payload.bin");
+    let (cpu, out) = boot(Some(fw));
+    assert!(out.contains("OpenSBI"), "no OpenSBI banner in:\n{out}");
+    assert!(out.contains("Platform Name"), "no platform info in:\n{out}");
+    assert!(out.ends_with("OK"), "the payload didn't run:\n{out}");
+    assert_eq!(cpu.get_priv_mode(), PrivMode::Supervisor);
+}
@@ -0,0 +1,57 @@
+// Records a program copying the bytes typed to the 16550 UART into RAM, then replays the input log
+// without the console and checks the machine ends up in exactly the same state.
+
+use kompusim::machine::{DeviceKind, MachineBuilder};
+use kompusim::replay::InputLog;
+use kompusim::rv64i_cpu::RV64ICpu;
+
+const PROGRAM: [u32; 9] = [
+    0x100002b7, // lui t0,0x10000 (UART)
+    0x00001317, // auipc t1,0x1 (buffer at 0x80001004)
+    0x0052c383, // loop: lbu t2,5(t0) (LSR)
+    0x0013f393, // andi t2,t2,1 (data ready)
+    0xfe038ce3, // beqz t2,loop
+    0x0002c383, // lbu t2,0(t0) (RBR)
+    0x00730023, // sb t2,0(t1)
+    0x00130313, // addi t1,t1,1
+    0xfe9ff06f, // j loop
+];
+const N_INSTR: u64 = 50_000;
+
+fn machine(log: InputLog, console_in: Option<Box<dyn FnMut() -> Option<u8>>>) -> RV64ICpu {
+    let mut machine = MachineBuilder::new()
+        .ram(0x8000_0000, 0x2000)
+        .device(DeviceKind::Uart16550, 0x1000_0000, None)
+        .input_log(log);
+    if let Some(console_in) = console_in {
+        machine = machine.console_in(console_in);
+    }
+    let mut cpu = machine.build().unwrap();
+    let image: Vec<u8> = PROGRAM.iter().flat_map(|i| i.to_le_bytes()).collect();
+    cpu.bus.write_bytes(0x8000_0000, &image).unwrap();
+    cpu
+}
+
+#[test]
+fn test_record_replay_console_input() {
+    let path = std::env::temp_dir().join(format!("kompusim-replay-{}.log", std::process::id()));
+    // a byte arrives now and then, like typing on the host console
+    let mut polls = 0_u32;
+    let typing = Box::new(move || {
+        polls += 1;
+        polls
+            .is_multiple_of(37)
+            .then_some(b'a' + (polls / 37 % 26) as u8)
+    });
+    let mut cpu = machine(InputLog::record(&path).unwrap(), Some(typing));
+    cpu.exec_continue(N_INSTR);
+    let recorded = cpu.save_snapshot();
+    assert_ne!(cpu.bus.read8(0x8000_1004).unwrap(), 0);
+
+    let log = InputLog::replay(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut cpu = machine(log.clone(), None);
+    cpu.exec_continue(N_INSTR);
+    assert_eq!(log.remaining(), 0);
+    assert!(cpu.save_snapshot() == recorded);
+}
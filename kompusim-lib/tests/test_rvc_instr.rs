@@ -152,7 +152,7 @@ fn test_rv_instr_c_sdsp() {
     cpu.regs_w64(8, 0xdead_c0de_dead_c0de);
     // c.sdsp x8, 128(x2)
     cpu.execute_rvc_instr(0x_e122);
-    assert_eq!(cpu.bus.read64(128), 0xdead_c0de_dead_c0de);
+    assert_eq!(cpu.bus.read64(128).unwrap(), 0xdead_c0de_dead_c0de);
     assert_eq!(cpu.get_pc(), 2);
 }
 
@@ -161,7 +161,7 @@ fn test_rv_instr_c_sdsp() {
 fn test_rvc_instr_ldsp() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
-    cpu.bus.write64(8, 0x_dead_beef_dead_beef);
+    cpu.bus.write64(8, 0x_dead_beef_dead_beef).unwrap();
     // SP/x2 points at 0
     // c.ldsp x8, 8(x2)
     cpu.execute_rvc_instr(0x_6422);
@@ -231,12 +231,12 @@ fn test_rvc_instr_c_ld() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
 
-    cpu.bus.write64(0, 0x_dead_beef_baad_c0fe);
+    cpu.bus.write64(0, 0x_dead_beef_baad_c0fe).unwrap();
     // c.ld x15, 0(x15)
     cpu.execute_rvc_instr(0x_639c);
     assert_eq!(cpu.regs_r64(15), 0x_dead_beef_baad_c0fe);
 
-    cpu.bus.write64(256 + 120, 0x_dead_c0de_dead_c0de);
+    cpu.bus.write64(256 + 120, 0x_dead_c0de_dead_c0de).unwrap();
     cpu.regs_w64(10, 256);
     // c.ld x15, 120(x10)
     cpu.execute_rvc_instr(0x_7d3c);
@@ -255,13 +255,13 @@ fn test_rvc_instr_c_sw() {
     cpu.regs_w64(14, 0x_dead_beef_baad_c0fe);
     // c.sw x14, 0(x15)
     cpu.execute_rvc_instr(0x_c398);
-    assert_eq!(cpu.bus.read64(0), 0x_0000_0000_baad_c0fe);
+    assert_eq!(cpu.bus.read64(0).unwrap(), 0x_0000_0000_baad_c0fe);
 
     cpu.regs_w64(14, 0x_dead_beef_baad_c0fe);
     cpu.regs_w64(15, 256);
     // c.sw x14, 12(x15)
     cpu.execute_rvc_instr(0x_c7d8);
-    assert_eq!(cpu.bus.read64(12 + 256), 0x_0000_0000_baad_c0fe);
+    assert_eq!(cpu.bus.read64(12 + 256).unwrap(), 0x_0000_0000_baad_c0fe);
 
     assert_eq!(cpu.get_pc(), 4);
 }
@@ -273,13 +273,13 @@ fn test_rvc_lw() {
     let bus = Bus::new_with_ram(0x0000_0000_0000_0000, 4 * 1024);
     let mut cpu = RV64ICpu::new(bus);
 
-    cpu.bus.write32(0, 0x_dead_beef);
+    cpu.bus.write32(0, 0x_dead_beef).unwrap();
     // c.lw x15, 0(x15)
     cpu.execute_rvc_instr(0x_439c);
     assert_eq!(cpu.regs_r64(15), 0x_ffff_ffff_dead_beef);
 
     cpu.regs_w64(15, 0);
-    cpu.bus.write32(92, 0x_c0de_c001);
+    cpu.bus.write32(92, 0x_c0de_c001).unwrap();
     // c.lw x15, 92(x15)
     cpu.execute_rvc_instr(0x_4ffc);
     assert_eq!(cpu.regs_r64(15), 0x_ffff_ffff_c0de_c001);
@@ -516,13 +516,13 @@ fn test_rvc_instr_c_sd() {
     cpu.regs_w64(14, 0x_dead_beef_baad_c0fe);
     // c.sd x14, 0(x15)
     cpu.execute_rvc_instr(0x_e398);
-    assert_eq!(cpu.bus.read64(0), 0x_dead_beef_baad_c0fe);
+    assert_eq!(cpu.bus.read64(0).unwrap(), 0x_dead_beef_baad_c0fe);
 
     cpu.regs_w64(14, 0x_dead_beef_baad_c0fe);
     cpu.regs_w64(15, 256);
     // c.sd x14, 248(x15)
     cpu.execute_rvc_instr(0x_fff8);
-    assert_eq!(cpu.bus.read64(248 + 256), 0x_dead_beef_baad_c0fe);
+    assert_eq!(cpu.bus.read64(248 + 256).unwrap(), 0x_dead_beef_baad_c0fe);
 
     assert_eq!(cpu.get_pc(), 4);
 }
@@ -0,0 +1,25 @@
+// Runs tests/test_programs/semihosting_hello: it prints with SYS_WRITE0 and exits with status 3
+// through SYS_EXIT, which becomes the exit code of the simulator.
+
+use std::process::Command;
+
+#[test]
+fn test_semihosting_hello_exit_code() {
+    let bin = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../tests/test_programs/semihosting_hello/out/semihosting_hello.bin"
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_kompusim"))
+        .args([
+            "exec",
+            "--load-addr",
+            "0x80000000",
+            "--semihosting",
+            "--bin",
+            bin,
+        ])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Hello, semihosting!\n"));
+    assert_eq!(output.status.code(), Some(3));
+}
@@ -0,0 +1,23 @@
+#!/usr/bin/env bash
+# Boots Linux to a busybox shell in the TUI simulator.
+# FW - OpenSBI generic platform firmware (fw_jump.bin), KERNEL - Linux Image built with
+# CONFIG_FPU=n for rv64imac, INITRD - busybox initramfs (cpio) built for rv64imac/lp64 too.
+# The simulated hart has no F/D: misa doesn't report them and mstatus.FS is read-only 0, so a
+# kernel or userspace using the FPU doesn't boot.
+set -e
+
+FW=${FW:-tests/test_programs/linux_kernel/fw_jump.bin}
+KERNEL=${KERNEL:-tests/test_programs/linux_kernel/Image}
+INITRD=${INITRD:-tests/test_programs/linux_kernel/rootfs.cpio}
+
+cargo build --release --package kompusim
+
+./target/release/kompusim exec \
+  --machine machines/linux.toml \
+  --load-addr 0x0000000080000000 \
+  --bin ${FW} \
+  --payload ${KERNEL} \
+  --payload-addr 0x0000000080200000 \
+  --initrd ${INITRD} \
+  --dtb \
+  $@
@@ -0,0 +1,10 @@
+set -e
+
+cargo build --release --package kompusim
+
+# the exit code of the program (3) is the exit code of the simulator
+./target/release/kompusim exec \
+  --load-addr 0x0000000080000000 \
+  --semihosting \
+  --bin tests/test_programs/semihosting_hello/out/semihosting_hello.bin \
+  $@
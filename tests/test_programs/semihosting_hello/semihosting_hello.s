@@ -0,0 +1,34 @@
+.align 2
+.equ SYS_WRITE0,        0x04
+.equ SYS_EXIT,          0x18
+.equ ADP_Stopped_ApplicationExit, 0x20026
+
+.section .text
+.globl _start
+
+_start:
+    li    a0, SYS_WRITE0  # a0 = semihosting operation
+    la    a1, msg         # a1 = address of null-terminated string
+    jal   semihost_call
+
+    li    a0, SYS_EXIT    # exit with status 3: a1 points to the parameter block
+    la    a1, exit_block  # {reason, exit code}
+    jal   semihost_call
+
+halt:
+    j     halt            # not reached
+
+.balign 16                # the magic sequence mustn't cross a page boundary
+semihost_call:            # (and must be uncompressed: built with rv64g)
+    slli  zero, zero, 0x1f
+    ebreak
+    srai  zero, zero, 7
+    ret
+
+.balign 8
+exit_block:
+    .dword ADP_Stopped_ApplicationExit
+    .dword 3
+
+msg:
+    .string "Hello, semihosting!\n"